@@ -37,6 +37,17 @@ const CHORD_FORMULAS = new Map<string, number[]>([
   ["m9", [0, 3, 7, 10, 14]],
 
   ["flat5", [0, 4, 6]],
+
+  ["it6", [0, 4, 10]],
+  ["fr6", [0, 4, 6, 10]],
+  ["ger6", [0, 4, 7, 10]],
+
+  ["add9", [0, 4, 7, 14]],
+  ["sus4add9", [0, 5, 7, 14]],
+  ["7sus4", [0, 5, 7, 10]],
+  ["7b9", [0, 4, 7, 10, 13]],
+  ["7#9", [0, 4, 7, 10, 15]],
+  ["13", [0, 4, 7, 10, 14, 21]],
 ]);
 
 const calculateInversions = (rootPositionNotes: number[]) => {
@@ -93,7 +104,7 @@ const main = () => {
   const generatedData = generateChordData();
   const finalDataObject = mapToObject(generatedData);
 
-  const outputPath = path.resolve(__dirname, "chords.json");
+  const outputPath = path.resolve(__dirname, "../theory/data/chords.json");
   fs.writeFileSync(outputPath, JSON.stringify(finalDataObject, null, 2));
 
   console.log(
@@ -0,0 +1,431 @@
+//! Rhythmic comping: patterns that replay the held chord on a sixteenth-note grid instead of
+//! sustaining it as one block.
+//!
+//! Patterns are written in bars of 4/4. In other time signatures every bar of the host starts
+//! the next bar of the pattern from its first step: a bar of 3/4 plays the first twelve steps, and
+//! a bar of 5/4 plays all sixteen and then the first four again.
+
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+
+/// Steps in a bar of a pattern, one per sixteenth note of 4/4.
+pub const STEPS_PER_BAR: usize = 16;
+pub const MAX_BARS: usize = 4;
+pub const MAX_STEPS: usize = STEPS_PER_BAR * MAX_BARS;
+
+/// Velocities of plain and accented hits.
+const VELOCITY: f32 = 0.65;
+const ACCENT_VELOCITY: f32 = 1.0;
+
+/// Which of the held notes a step plays.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum NoteSubset {
+    #[default]
+    Full,
+    /// Only the lowest note
+    Bass,
+    /// Everything but the lowest note
+    Upper,
+}
+
+impl NoteSubset {
+    pub const ALL: [NoteSubset; 3] = [NoteSubset::Full, NoteSubset::Bass, NoteSubset::Upper];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            NoteSubset::Full => "Full",
+            NoteSubset::Bass => "Bass",
+            NoteSubset::Upper => "Upper",
+        }
+    }
+
+    /// The held notes this subset plays, lowest first.
+    fn select(self, held: &[bool; 128]) -> impl Iterator<Item = u8> + '_ {
+        let lowest = held.iter().position(|&held| held);
+        (0..128u8).filter(move |&note| {
+            held[note as usize]
+                && match self {
+                    NoteSubset::Full => true,
+                    NoteSubset::Bass => Some(note as usize) == lowest,
+                    NoteSubset::Upper => Some(note as usize) != lowest,
+                }
+        })
+    }
+}
+
+/// One sixteenth of a pattern.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Step {
+    pub on: bool,
+    pub accent: bool,
+    pub subset: NoteSubset,
+    /// How many steps the notes are held for, at least 1
+    pub length: u8,
+}
+
+impl Step {
+    pub const REST: Step = Step {
+        on: false,
+        accent: false,
+        subset: NoteSubset::Full,
+        length: 1,
+    };
+
+    /// The character a step starts with in [`CompPattern::parse`]'s notation.
+    pub fn symbol(&self) -> char {
+        let symbol = match self.subset {
+            NoteSubset::Full => 'x',
+            NoteSubset::Bass => 'b',
+            NoteSubset::Upper => 'u',
+        };
+        match (self.on, self.accent) {
+            (false, _) => '-',
+            (true, false) => symbol,
+            (true, true) => symbol.to_ascii_uppercase(),
+        }
+    }
+}
+
+impl Default for Step {
+    fn default() -> Self {
+        Self::REST
+    }
+}
+
+/// A comping rhythm of one to four bars. Fixed-size so it can be sent to the audio thread and
+/// stored there without allocating.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompPattern {
+    pub bars: u8,
+    pub steps: [Step; MAX_STEPS],
+}
+
+impl Default for CompPattern {
+    fn default() -> Self {
+        pattern_library()[0].1
+    }
+}
+
+impl CompPattern {
+    /// Parses a pattern written one character per sixteenth: `x` plays the full chord, `b` the
+    /// bass note and `u` the upper notes, in upper case when accented. Each `=` after a hit holds
+    /// it one step longer and `-` is a rest. Spaces and `|` are ignored, and the pattern must fill
+    /// one to four whole bars, such as `"X==x==x=X==x==x="`.
+    pub fn parse(notation: &str) -> Option<Self> {
+        let mut steps = [Step::REST; MAX_STEPS];
+        let mut len = 0;
+        let mut last_hit: Option<usize> = None;
+        for c in notation.chars().filter(|c| !matches!(c, ' ' | '|')) {
+            if len == MAX_STEPS {
+                return None;
+            }
+            let subset = match c.to_ascii_lowercase() {
+                'x' => Some(NoteSubset::Full),
+                'b' => Some(NoteSubset::Bass),
+                'u' => Some(NoteSubset::Upper),
+                '-' => {
+                    last_hit = None;
+                    None
+                }
+                '=' => {
+                    steps[last_hit?].length += 1;
+                    None
+                }
+                _ => return None,
+            };
+            if let Some(subset) = subset {
+                steps[len] = Step {
+                    on: true,
+                    accent: c.is_ascii_uppercase(),
+                    subset,
+                    length: 1,
+                };
+                last_hit = Some(len);
+            }
+            len += 1;
+        }
+
+        (len > 0 && len % STEPS_PER_BAR == 0).then_some(Self {
+            bars: (len / STEPS_PER_BAR) as u8,
+            steps,
+        })
+    }
+
+    /// Number of steps before the pattern repeats.
+    pub fn step_count(&self) -> usize {
+        self.bars.clamp(1, MAX_BARS as u8) as usize * STEPS_PER_BAR
+    }
+}
+
+/// Named patterns offered in the editor. The first one is the default.
+pub fn pattern_library() -> &'static [(&'static str, CompPattern)] {
+    static LIBRARY: OnceLock<Vec<(&'static str, CompPattern)>> = OnceLock::new();
+    LIBRARY.get_or_init(|| {
+        [
+            ("Pop eighths", "X=x=x=x=X=x=x=x="),
+            ("Ballad", "B===u===u===u==="),
+            ("Reggae skank", "----U-------U---"),
+            ("Charleston", "X=====x=--------"),
+            ("Bossa nova", "X==u==u=b=u==u== | B===--u=b=u=----"),
+            ("Tresillo", "X==x==x=X==x==x="),
+            ("Funk sixteenths", "X--x--x-x-X--x-x"),
+            ("Whole notes", "X==============="),
+        ]
+        .into_iter()
+        .filter_map(|(name, notation)| Some((name, CompPattern::parse(notation)?)))
+        .collect()
+    })
+}
+
+/// A note the comping player starts or stops, at a sample offset into the buffer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompEvent {
+    NoteOn {
+        timing: u32,
+        note: u8,
+        velocity: f32,
+    },
+    NoteOff {
+        timing: u32,
+        note: u8,
+    },
+}
+
+/// The host's tempo and time signature in pattern steps.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompTiming {
+    pub samples_per_step: f64,
+    /// Sixteenth notes in a bar, such as 12 in 3/4 and 6/8 or 14 in 7/8
+    pub steps_per_bar: f64,
+}
+
+/// Plays a pattern over the held notes on the audio thread.
+#[derive(Debug, Clone)]
+pub struct CompPlayer {
+    /// Samples from the start of the next buffer until each sounding note ends, by note number
+    sounding: [Option<f64>; 128],
+    /// Position in steps from the start of a bar while the host transport isn't running
+    free_position: f64,
+    /// Whether any notes were held during the last buffer
+    holding: bool,
+}
+
+impl Default for CompPlayer {
+    fn default() -> Self {
+        Self {
+            sounding: [None; 128],
+            free_position: 0.0,
+            holding: false,
+        }
+    }
+}
+
+impl CompPlayer {
+    /// Stops every sounding note at `timing`.
+    pub fn release_all(&mut self, timing: u32, mut emit: impl FnMut(CompEvent)) {
+        for (note, sounding) in self.sounding.iter_mut().enumerate() {
+            if sounding.take().is_some() {
+                emit(CompEvent::NoteOff {
+                    timing,
+                    note: note as u8,
+                });
+            }
+        }
+    }
+
+    fn release_until(&mut self, limit: f64, mut emit: impl FnMut(CompEvent)) {
+        for (note, sounding) in self.sounding.iter_mut().enumerate() {
+            if let Some(end) = *sounding
+                && end < limit
+            {
+                *sounding = None;
+                emit(CompEvent::NoteOff {
+                    timing: end.max(0.0) as u32,
+                    note: note as u8,
+                });
+            }
+        }
+    }
+
+    /// Plays the steps that start in this buffer. `host_position` is the transport position in
+    /// steps when the host is playing, counted so that bars start at multiples of
+    /// `timing.steps_per_bar`, which keeps the pattern on the host's bars; otherwise the player
+    /// keeps its own time, starting the pattern over whenever a chord is played after silence.
+    /// Notes that are no longer held stop straight away.
+    pub fn process(
+        &mut self,
+        pattern: &CompPattern,
+        held: &[bool; 128],
+        host_position: Option<f64>,
+        timing: CompTiming,
+        buffer_len: usize,
+        mut emit: impl FnMut(CompEvent),
+    ) {
+        let CompTiming {
+            samples_per_step,
+            steps_per_bar,
+        } = timing;
+        for (note, sounding) in self.sounding.iter_mut().enumerate() {
+            if !held[note] && sounding.take().is_some() {
+                emit(CompEvent::NoteOff {
+                    timing: 0,
+                    note: note as u8,
+                });
+            }
+        }
+
+        let holding = held.contains(&true);
+        if holding && !self.holding {
+            self.free_position = 0.0;
+        }
+        self.holding = holding;
+
+        // A step belongs to the buffer its rounded sample offset falls in, so one that lands on a
+        // buffer boundary is played exactly once
+        let start = host_position.unwrap_or(self.free_position);
+        let earliest = start - 0.5 / samples_per_step;
+        let mut bar = (earliest / steps_per_bar).floor();
+        let mut step = (earliest - bar * steps_per_bar).ceil().max(0.0);
+        let pattern_bars = (pattern.step_count() / STEPS_PER_BAR) as i64;
+        loop {
+            if step >= steps_per_bar {
+                bar += 1.0;
+                step = 0.0;
+            }
+            let offset = (bar * steps_per_bar + step - start) * samples_per_step;
+            let timing = offset.round().max(0.0) as u32;
+            if timing as usize >= buffer_len {
+                break;
+            }
+            // Releasing first lets a note played on consecutive steps retrigger
+            self.release_until(offset + 1.0, &mut emit);
+
+            let pattern_bar = (bar as i64).rem_euclid(pattern_bars) as usize;
+            let hit = pattern.steps[pattern_bar * STEPS_PER_BAR + step as usize % STEPS_PER_BAR];
+            if hit.on {
+                let velocity = if hit.accent {
+                    ACCENT_VELOCITY
+                } else {
+                    VELOCITY
+                };
+                for note in hit.subset.select(held) {
+                    if self.sounding[note as usize].is_some() {
+                        emit(CompEvent::NoteOff { timing, note });
+                    }
+                    emit(CompEvent::NoteOn {
+                        timing,
+                        note,
+                        velocity,
+                    });
+                    self.sounding[note as usize] =
+                        Some(offset + hit.length.max(1) as f64 * samples_per_step);
+                }
+            }
+            step += 1.0;
+        }
+        self.release_until(buffer_len as f64, &mut emit);
+
+        for end in self.sounding.iter_mut().flatten() {
+            *end -= buffer_len as f64;
+        }
+        self.free_position = start + buffer_len as f64 / samples_per_step;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One sample per step, so step numbers and timings are the same.
+    fn timing(steps_per_bar: f64) -> CompTiming {
+        CompTiming {
+            samples_per_step: 1.0,
+            steps_per_bar,
+        }
+    }
+
+    /// The steps the held note starts on over `len` steps, played in buffers of `buffer_len`.
+    fn hits(
+        pattern: &str,
+        timing: CompTiming,
+        host_start: Option<f64>,
+        len: usize,
+        buffer_len: usize,
+    ) -> Vec<u32> {
+        let pattern = CompPattern::parse(pattern).unwrap();
+        let mut held = [false; 128];
+        held[60] = true;
+        let mut player = CompPlayer::default();
+        let mut hits = Vec::new();
+        for buffer_start in (0..len).step_by(buffer_len) {
+            let host_position = host_start.map(|start| start + buffer_start as f64);
+            player.process(
+                &pattern,
+                &held,
+                host_position,
+                timing,
+                buffer_len,
+                |event| {
+                    if let CompEvent::NoteOn { timing, .. } = event {
+                        hits.push(buffer_start as u32 + timing);
+                    }
+                },
+            );
+        }
+        hits
+    }
+
+    #[test]
+    fn plays_four_four_bars_unchanged() {
+        assert_eq!(
+            hits(
+                "X---x---x-x-x--- | x---------------",
+                timing(16.0),
+                None,
+                48,
+                6
+            ),
+            [0, 4, 8, 10, 12, 16, 32, 36, 40, 42, 44]
+        );
+    }
+
+    #[test]
+    fn restarts_the_pattern_bar_at_each_bar_line() {
+        // 3/4: the last beat of each pattern bar is cut off
+        assert_eq!(
+            hits(
+                "X---x---x---x-x- | x---------------",
+                timing(12.0),
+                None,
+                35,
+                7
+            ),
+            [0, 4, 8, 12, 24, 28, 32]
+        );
+        // 5/4: the fifth beat starts the pattern bar over
+        assert_eq!(
+            hits("X-----x---------", timing(20.0), None, 40, 4),
+            [0, 6, 16, 20, 26, 36]
+        );
+        // 7/8
+        assert_eq!(
+            hits("X-x-x-x-x-x-x-x-", timing(14.0), None, 28, 4),
+            [0, 2, 4, 6, 8, 10, 12, 14, 16, 18, 20, 22, 24, 26]
+        );
+    }
+
+    #[test]
+    fn follows_the_host_bars() {
+        // Starting halfway through the second bar of 3/4, which plays the pattern's second bar
+        assert_eq!(
+            hits(
+                "X---x---x---x--- | x-----x---------",
+                timing(12.0),
+                Some(18.0),
+                20,
+                4
+            ),
+            [0, 6, 10, 14, 18]
+        );
+    }
+}
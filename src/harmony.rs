@@ -0,0 +1,342 @@
+//! Harmony metadata for plugins further down the chain, such as harmonizers and scale-aware
+//! effects: the current key and chord, sent whenever either changes.
+//!
+//! # SysEx format
+//!
+//! Twelve bytes, with every byte between the framing bytes seven bits wide:
+//!
+//! | Byte | Value |
+//! |------|-------|
+//! | 0 | `F0`, start of SysEx |
+//! | 1 | `7D`, the manufacturer ID for non-commercial use |
+//! | 2, 3 | `50 43`, ASCII `PC` |
+//! | 4 | Format version, currently `01` |
+//! | 5 | Key root as a pitch class, from 0 for C to 11 for B |
+//! | 6 | Key type: 0 for major, 1 for minor |
+//! | 7 | Chord root as a pitch class, or `7F` when no chord is playing |
+//! | 8 | Chord type: its position in [`ChordType::ALL`], or `40` plus the slot of a custom type |
+//! | 9 | Chord tones C to F♯ as bits 0 to 6 |
+//! | 10 | Chord tones G to B as bits 0 to 4 |
+//! | 11 | `F7`, end of SysEx |
+//!
+//! Without a chord, bytes 8 to 10 are 0. Chord types are only ever added to the end of
+//! [`ChordType::ALL`], so their numbers stay the same between versions. The chord tones are pitch
+//! classes rather than intervals so a receiver can follow chord types it doesn't know.
+//!
+//! # Control changes
+//!
+//! For devices without SysEx, the same information goes out on channel 1 as values 0 to 127:
+//! CC 102 is the key root, CC 103 the key type, CC 104 the chord root (127 without a chord) and
+//! CC 105 the chord type. The chord tones are only in the SysEx message.
+
+use nih_plug::prelude::{NoteEvent, SysExMessage};
+use serde::{Deserialize, Serialize};
+
+use crate::theory::{ChordId, ChordType, PitchClass, Scale, ScaleType};
+
+pub const MANUFACTURER_ID: u8 = 0x7d;
+pub const FORMAT_VERSION: u8 = 1;
+pub const MESSAGE_LEN: usize = 12;
+const HEADER: [u8; 5] = [0xf0, MANUFACTURER_ID, b'P', b'C', FORMAT_VERSION];
+const NO_CHORD: u8 = 0x7f;
+const CUSTOM_TYPE_OFFSET: u8 = 0x40;
+
+/// The first of the four controller numbers used by [`HarmonyMessage::control_changes`].
+pub const FIRST_CC: u8 = 102;
+
+/// How the harmony is announced, if at all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum HarmonyOutput {
+    #[default]
+    Off,
+    ControlChange,
+    SysEx,
+}
+
+impl HarmonyOutput {
+    pub const ALL: [HarmonyOutput; 3] = [
+        HarmonyOutput::Off,
+        HarmonyOutput::ControlChange,
+        HarmonyOutput::SysEx,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            HarmonyOutput::Off => "Off",
+            HarmonyOutput::ControlChange => "MIDI CC",
+            HarmonyOutput::SysEx => "SysEx",
+        }
+    }
+}
+
+/// The key and chord at one moment, in the format described in the [module docs](self).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HarmonyMessage {
+    pub scale: Scale,
+    pub chord: Option<ChordId>,
+    /// The chord's pitch classes, bit 0 for C to bit 11 for B
+    pub chord_tones: u16,
+}
+
+/// Collects MIDI notes into a set of pitch classes, bit 0 for C to bit 11 for B.
+pub fn pitch_class_mask(notes: impl IntoIterator<Item = i32>) -> u16 {
+    notes
+        .into_iter()
+        .fold(0, |mask, note| mask | 1 << note.rem_euclid(12))
+}
+
+fn chord_type_code(chord_type: ChordType) -> u8 {
+    match chord_type {
+        ChordType::Custom(index) => CUSTOM_TYPE_OFFSET + index,
+        builtin => ChordType::ALL
+            .iter()
+            .position(|&chord_type| chord_type == builtin)
+            .unwrap_or_default() as u8,
+    }
+}
+
+fn chord_type_from_code(code: u8) -> Option<ChordType> {
+    match code.checked_sub(CUSTOM_TYPE_OFFSET) {
+        Some(index) => Some(ChordType::Custom(index)),
+        None => ChordType::ALL.get(code as usize).copied(),
+    }
+}
+
+impl HarmonyMessage {
+    fn scale_type_code(&self) -> u8 {
+        match self.scale.scale_type {
+            ScaleType::Major => 0,
+            ScaleType::Minor => 1,
+        }
+    }
+
+    /// The message as control changes on `channel`, counting from 0.
+    pub fn control_changes<S>(&self, timing: u32, channel: u8) -> [NoteEvent<S>; 4] {
+        let (chord_root, chord_type) = match self.chord {
+            Some(chord) => (chord.root.semitones(), chord_type_code(chord.chord_type)),
+            None => (NO_CHORD, 0),
+        };
+        let values = [
+            self.scale.root.semitones(),
+            self.scale_type_code(),
+            chord_root,
+            chord_type,
+        ];
+        std::array::from_fn(|index| NoteEvent::MidiCC {
+            timing,
+            channel,
+            cc: FIRST_CC + index as u8,
+            value: values[index] as f32 / 127.0,
+        })
+    }
+}
+
+impl SysExMessage for HarmonyMessage {
+    type Buffer = [u8; MESSAGE_LEN];
+
+    fn from_buffer(buffer: &[u8]) -> Option<Self> {
+        let body = buffer.strip_prefix(&HEADER)?.strip_suffix(&[0xf7])?;
+        let &[
+            scale_root,
+            scale_type,
+            chord_root,
+            chord_type,
+            tones_low,
+            tones_high,
+        ] = body
+        else {
+            return None;
+        };
+        if scale_root > 11 {
+            return None;
+        }
+        let scale_type = match scale_type {
+            0 => ScaleType::Major,
+            1 => ScaleType::Minor,
+            _ => return None,
+        };
+        let chord = match chord_root {
+            NO_CHORD => None,
+            0..=11 => Some(ChordId::new(
+                PitchClass::new(chord_root),
+                chord_type_from_code(chord_type)?,
+            )),
+            _ => return None,
+        };
+
+        Some(Self {
+            scale: Scale::new(PitchClass::new(scale_root), scale_type),
+            chord,
+            chord_tones: chord.map_or(0, |_| {
+                (tones_low & 0x7f) as u16 | ((tones_high & 0x1f) as u16) << 7
+            }),
+        })
+    }
+
+    fn to_buffer(self) -> (Self::Buffer, usize) {
+        let (chord_root, chord_type, chord_tones) = match self.chord {
+            Some(chord) => (
+                chord.root.semitones(),
+                chord_type_code(chord.chord_type),
+                self.chord_tones,
+            ),
+            None => (NO_CHORD, 0, 0),
+        };
+        let mut buffer = [0; MESSAGE_LEN];
+        buffer[..HEADER.len()].copy_from_slice(&HEADER);
+        buffer[5] = self.scale.root.semitones();
+        buffer[6] = self.scale_type_code();
+        buffer[7] = chord_root;
+        buffer[8] = chord_type;
+        buffer[9] = (chord_tones & 0x7f) as u8;
+        buffer[10] = (chord_tones >> 7 & 0x1f) as u8;
+        buffer[11] = 0xf7;
+        (buffer, MESSAGE_LEN)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::theory::{ChordVocabulary, CustomChordType, MAX_CUSTOM_CHORD_TYPES};
+
+    /// Every key with no chord, then with every chord of every chord type in `vocabulary`.
+    fn every_message(vocabulary: &ChordVocabulary) -> Vec<HarmonyMessage> {
+        let mut messages = Vec::new();
+        for scale_type in ScaleType::ALL {
+            for key_root in 0..12 {
+                let scale = Scale::new(PitchClass::new(key_root), scale_type);
+                messages.push(HarmonyMessage {
+                    scale,
+                    chord: None,
+                    chord_tones: 0,
+                });
+                for chord_root in 0..12 {
+                    for chord_type in vocabulary.chord_types() {
+                        let tones = vocabulary
+                            .intervals(chord_type)
+                            .iter()
+                            .map(|&interval| chord_root as i32 + interval as i32);
+                        messages.push(HarmonyMessage {
+                            scale,
+                            chord: Some(ChordId::new(PitchClass::new(chord_root), chord_type)),
+                            chord_tones: pitch_class_mask(tones),
+                        });
+                    }
+                }
+            }
+        }
+        messages
+    }
+
+    fn vocabulary_with_every_custom_slot() -> ChordVocabulary {
+        let mut vocabulary = ChordVocabulary::new();
+        for index in 0..MAX_CUSTOM_CHORD_TYPES {
+            vocabulary
+                .register(CustomChordType {
+                    key: format!("custom{}", index),
+                    suffix: format!("c{}", index),
+                    intervals: vec![0, 1, 2 + index as u8 % 10],
+                })
+                .unwrap();
+        }
+        vocabulary
+    }
+
+    /// Reads the four control changes back the way a receiver would.
+    fn decode_control_changes(events: &[NoteEvent<()>; 4]) -> Option<(Scale, Option<ChordId>)> {
+        let mut values = [0; 4];
+        for (index, event) in events.iter().enumerate() {
+            let NoteEvent::MidiCC {
+                channel: 0,
+                cc,
+                value,
+                ..
+            } = *event
+            else {
+                return None;
+            };
+            assert_eq!(cc, FIRST_CC + index as u8);
+            values[index] = (value * 127.0).round() as u8;
+        }
+        let [key_root, key_type, chord_root, chord_type] = values;
+        let scale_type = *ScaleType::ALL.get(key_type as usize)?;
+        let chord = match chord_root {
+            NO_CHORD => None,
+            root => Some(ChordId::new(
+                PitchClass::new(root),
+                chord_type_from_code(chord_type)?,
+            )),
+        };
+        Some((Scale::new(PitchClass::new(key_root), scale_type), chord))
+    }
+
+    #[test]
+    fn every_message_round_trips_through_sysex() {
+        for message in every_message(&vocabulary_with_every_custom_slot()) {
+            let (buffer, len) = message.to_buffer();
+            assert_eq!(len, MESSAGE_LEN);
+            assert_eq!((buffer[0], buffer[MESSAGE_LEN - 1]), (0xf0, 0xf7));
+            assert!(
+                buffer[1..MESSAGE_LEN - 1].iter().all(|&byte| byte < 0x80),
+                "{:?}",
+                message
+            );
+            assert_eq!(HarmonyMessage::from_buffer(&buffer), Some(message));
+        }
+    }
+
+    #[test]
+    fn every_message_round_trips_through_control_changes() {
+        for message in every_message(&vocabulary_with_every_custom_slot()) {
+            let events = message.control_changes::<()>(0, 0);
+            assert_eq!(
+                decode_control_changes(&events),
+                Some((message.scale, message.chord))
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_sysex() {
+        let message = every_message(&ChordVocabulary::new())[1];
+        let (buffer, _) = message.to_buffer();
+        assert_eq!(
+            HarmonyMessage::from_buffer(&buffer[..MESSAGE_LEN - 1]),
+            None
+        );
+        assert_eq!(
+            HarmonyMessage::from_buffer(&[buffer.as_slice(), &[0]].concat()),
+            None
+        );
+        for (index, byte) in [
+            (1, 0x7e),
+            (4, 2),
+            (5, 12),
+            (6, 2),
+            (7, 12),
+            (8, ChordType::ALL.len() as u8),
+        ] {
+            let mut corrupt = buffer;
+            corrupt[index] = byte;
+            assert_eq!(
+                HarmonyMessage::from_buffer(&corrupt),
+                None,
+                "byte {} = {:#x}",
+                index,
+                byte
+            );
+        }
+    }
+
+    #[test]
+    fn chord_types_keep_their_numbers() {
+        assert_eq!(chord_type_code(ChordType::Major), 0);
+        assert_eq!(
+            chord_type_code(ChordType::ALL[ChordType::ALL.len() - 1]),
+            ChordType::ALL.len() as u8 - 1
+        );
+        assert_eq!(chord_type_code(ChordType::Custom(3)), 0x43);
+        assert_eq!(pitch_class_mask([60, 64, 67, -1]), 0b1000_1001_0001);
+    }
+}
@@ -0,0 +1,64 @@
+//! The parts of the host the audio thread uses, behind a trait so processing can also be driven
+//! without a plugin host, as the tests do.
+
+use nih_plug::prelude::{NoteEvent, ProcessContext};
+
+use crate::PerfectChords;
+use crate::harmony::HarmonyMessage;
+
+/// The host's transport as of the current buffer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TransportInfo {
+    pub playing: bool,
+    pub sample_rate: f32,
+    pub tempo: Option<f64>,
+    pub time_sig_numerator: Option<i32>,
+    pub time_sig_denominator: Option<i32>,
+    /// Position in quarter notes
+    pub pos_beats: Option<f64>,
+    pub bar_start_pos_beats: Option<f64>,
+    pub bar_number: Option<i32>,
+}
+
+impl TransportInfo {
+    /// The length of a bar in quarter notes in the host's time signature, or of a bar of 4/4 when
+    /// the host doesn't say.
+    pub fn beats_per_bar(&self) -> f64 {
+        match (self.time_sig_numerator, self.time_sig_denominator) {
+            (Some(numerator), Some(denominator)) if numerator > 0 && denominator > 0 => {
+                numerator as f64 * 4.0 / denominator as f64
+            }
+            _ => 4.0,
+        }
+    }
+
+    /// Samples per quarter note at the host tempo, or at 120 BPM when there is none.
+    pub fn samples_per_beat(&self) -> f64 {
+        self.sample_rate as f64 * 60.0 / self.tempo.unwrap_or(120.0)
+    }
+}
+
+pub trait Host {
+    fn transport(&self) -> TransportInfo;
+    fn send_event(&mut self, event: NoteEvent<HarmonyMessage>);
+}
+
+impl<C: ProcessContext<PerfectChords>> Host for C {
+    fn transport(&self) -> TransportInfo {
+        let transport = ProcessContext::transport(self);
+        TransportInfo {
+            playing: transport.playing,
+            sample_rate: transport.sample_rate,
+            tempo: transport.tempo,
+            time_sig_numerator: transport.time_sig_numerator,
+            time_sig_denominator: transport.time_sig_denominator,
+            pos_beats: transport.pos_beats(),
+            bar_start_pos_beats: transport.bar_start_pos_beats(),
+            bar_number: transport.bar_number(),
+        }
+    }
+
+    fn send_event(&mut self, event: NoteEvent<HarmonyMessage>) {
+        ProcessContext::send_event(self, event);
+    }
+}
@@ -1,70 +1,89 @@
-use crossbeam_channel::{Receiver, Sender};
+use crossbeam_channel::{Receiver, Sender, TrySendError};
 use nih_plug::prelude::*;
-use nih_plug_egui::{create_egui_editor, egui, EguiState};
-use serde::Deserialize;
+use nih_plug_egui::{EguiState, create_egui_editor, egui};
 use std::collections::{HashMap, HashSet};
-use std::sync::Arc;
-use log::info;
-
-#[derive(Deserialize, Debug, Clone)]
-struct ChordVoicing {
-    inversions: Vec<Vec<u8>>,
-}
-
-type ChordTable = HashMap<String, HashMap<String, ChordVoicing>>;
-
-#[derive(Debug, Clone)]
-struct DiatonicChord {
-    root_note: String,
-    chord_type: String,
-    degree: String,
-}
-
-type ScaleMap = HashMap<String, Vec<DiatonicChord>>;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU8, AtomicU16, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+mod comping;
+pub mod harmony;
+mod host;
+mod mpe;
+mod osc;
+mod presets;
+mod quantize;
+
+pub use perfect_chords_theory as theory;
+pub use perfect_chords_theory::midi_file;
+
+use comping::{
+    CompEvent, CompPattern, CompPlayer, CompTiming, MAX_BARS, NoteSubset, STEPS_PER_BAR,
+    pattern_library,
+};
+use harmony::{HarmonyMessage, HarmonyOutput};
+use host::Host;
+use mpe::VoiceAllocator;
+use osc::{OscControl, OscSettings};
+use presets::{ChordInversion, Preset, PresetComping, PresetEntry};
+use quantize::Quantize;
+use theory::guitar::{ChordShape, ShapeOptions, TUNINGS};
+use theory::{
+    Cadence, ChordId, ChordNotes, ChordSection, ChordSymbol, ChordTable, ChordType,
+    ChordVocabulary, CustomChordType, GeneratorOptions, GridColumn, KeyboardMapping,
+    NoteRangePolicy, NoteRole, OCTAVE_RANGE, PitchClass, RetuneTable, ScalaScale, Scale, ScaleMap,
+    ScaleType, Tuning, get_chromatic_columns, get_diatonic_columns, get_extended_sections,
+    get_scale_map,
+};
+
+/// Capacity of the editor-to-audio queue. A bounded queue never allocates on the audio thread; the
+/// editor drops messages with `try_send` if the audio thread has stopped draining it.
+const MIDI_QUEUE_CAPACITY: usize = 4096;
+
+/// Capacity of the audio-to-editor queue. Events are dropped while it is full, which only happens
+/// when no editor is open to drain it.
+const GUI_EVENT_CAPACITY: usize = 1024;
+
+/// How many retired values the audio thread can hold on to while the audio-to-editor queue is full.
+/// Once they are used up it stops taking messages until the queue has room again.
+const RETIRED_CAPACITY: usize = 16;
+
+/// Sized for every chord so inversion changes never reallocate on the audio thread. Maps sent to
+/// the audio thread to replace its own are built with this capacity too.
+const INVERSION_MAP_CAPACITY: usize = 12 * (ChordType::ALL.len() + theory::MAX_CUSTOM_CHORD_TYPES);
+
+/// The chord vocabulary of one plugin instance, shared by the editor, which defines custom chord
+/// types, and the OSC listener, which looks chord types up by key.
+#[derive(Clone)]
+struct SharedVocabulary(Arc<RwLock<ChordVocabulary>>);
 
-fn get_scale_map() -> ScaleMap {
-    let mut scales = HashMap::new();
-    let notes = [
-        "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
-    ];
-    let major_pattern = [0, 2, 4, 5, 7, 9, 11];
-    let minor_pattern = [0, 2, 3, 5, 7, 8, 10];
-    let major_chord_types = ["maj", "m", "m", "maj", "maj", "m", "dim"];
-    let minor_chord_types = ["m", "dim", "maj", "m", "m", "maj", "maj"];
-    let major_degrees = ["I", "ii", "iii", "IV", "V", "vi", "vii°"];
-    let minor_degrees = ["i", "ii°", "III", "iv", "v", "VI", "VII"];
-
-    for i in 0..12 {
-        let major_key = format!("{} Major", notes[i]);
-        let mut major_diatonics = Vec::new();
-        for j in 0..7 {
-            major_diatonics.push(DiatonicChord {
-                root_note: notes[(i + major_pattern[j]) % 12].to_string(),
-                chord_type: major_chord_types[j].to_string(),
-                degree: major_degrees[j].to_string(),
-            });
-        }
-        scales.insert(major_key, major_diatonics);
+impl SharedVocabulary {
+    fn new(vocabulary: ChordVocabulary) -> Self {
+        Self(Arc::new(RwLock::new(vocabulary)))
+    }
 
-        let minor_key = format!("{} Minor", notes[i]);
-        let mut minor_diatonics = Vec::new();
-        for j in 0..7 {
-            minor_diatonics.push(DiatonicChord {
-                root_note: notes[(i + minor_pattern[j]) % 12].to_string(),
-                chord_type: minor_chord_types[j].to_string(),
-                degree: minor_degrees[j].to_string(),
-            });
-        }
-        scales.insert(minor_key, minor_diatonics);
+    fn read(&self) -> RwLockReadGuard<'_, ChordVocabulary> {
+        self.0.read().unwrap_or_else(|err| err.into_inner())
     }
 
-    scales
+    fn write(&self) -> RwLockWriteGuard<'_, ChordVocabulary> {
+        self.0.write().unwrap_or_else(|err| err.into_inner())
+    }
 }
 
-#[derive(Clone, PartialEq, Eq, Debug, Hash)]
-struct ChordId {
-    root_note: String,
-    chord_type: String,
+/// What a preset changes on the audio thread, sent as one message so a preset is never applied in
+/// part. The maps are swapped with the audio thread's, so it has nothing to allocate.
+struct PresetSettings {
+    scale: Scale,
+    octave: i8,
+    note_range_policy: NoteRangePolicy,
+    key_mappings: HashMap<egui::Key, ChordId>,
+    inversion_map: HashMap<ChordId, u8>,
+    /// The comping pattern, or `None` to sustain chords
+    comp_pattern: Option<CompPattern>,
+    quantize: Quantize,
+    min_note_length: bool,
+    harmony_output: HarmonyOutput,
 }
 
 enum MidiMessage {
@@ -72,19 +91,183 @@ enum MidiMessage {
     ChordOff,
     SetInversionChord(ChordId),
     UpdateOctave(i8),
+    UpdateNoteRangePolicy(NoteRangePolicy),
     UpdateInversion(ChordId, u8),
-    UpdateScale(String),
+    /// Sets the inversion of a chord, or of the inversion chord with `None`, wrapping around past
+    /// its last inversion. Sent by remote control, which doesn't know the chord table.
+    SelectInversion(Option<ChordId>, u8),
+    UpdateScale(Scale),
     UpdateKeyMapping(egui::Key, ChordId),
+    /// Replaces every key mapping
+    UpdateKeyMappings(HashMap<egui::Key, ChordId>),
+    ApplyPreset(Box<PresetSettings>),
+    UpdateChordTable(Arc<ChordTable>),
     KeyChordOn(egui::Key),
     KeyChordOff(egui::Key),
-    UpdatePlayingKeys(HashSet<egui::Key>),
+    AuditionNoteOn(u8),
+    AuditionNoteOff,
+    VoicingOn(ChordId, ChordNotes),
+    /// Plays the chords one after another in time with the host, each held for the given number
+    /// of beats.
+    PlayProgression(Arc<[ChordId]>, u32),
+    StopProgression,
+    UpdateHarmonyOutput(HarmonyOutput),
+    UpdateQuantize(Quantize),
+    /// Hold quantized chords for at least one grid step
+    UpdateMinNoteLength(bool),
+    /// Per-note pressure for the notes of the chord held with the mouse, from 0 to 1
+    MousePressure(f32),
+    /// Comps the held chords with a pattern, or sustains them again with `None`
+    UpdateComping(Option<Box<CompPattern>>),
+    /// Retunes every note through the table, or goes back to 12-TET with `None`
+    UpdateTuning(Option<Box<RetuneTable>>),
+}
+
+/// Settings changes applied by the audio thread, sent back so the editor mirrors them no matter
+/// where the change came from.
+enum GuiEvent {
+    Octave(i8),
+    NoteRangePolicy(NoteRangePolicy),
+    Scale(Scale),
+    InversionChord(ChordId),
+    Inversion(ChordId, u8),
+    KeyMapping(egui::Key, ChordId),
+    Retired(Retired),
+}
+
+/// Allocations the audio thread is done with, sent to the editor thread to be freed.
+enum Retired {
+    ChordTable(Arc<ChordTable>),
+    /// A progression that finished or was replaced
+    Progression(Arc<[ChordId]>),
+    /// A comping pattern that has been copied into the audio thread's state
+    CompPattern(Box<CompPattern>),
+    /// Applied settings, holding the maps they replaced
+    Preset(Box<PresetSettings>),
+    KeyMappings(HashMap<egui::Key, ChordId>),
+    Tuning(Box<RetuneTable>),
+}
+
+/// Shows the outcome of the last action in a view, with failures in the error color.
+fn show_message(ui: &mut egui::Ui, message: &Option<Result<String, String>>) {
+    match message {
+        Some(Ok(message)) => {
+            ui.label(message);
+        }
+        Some(Err(message)) => {
+            ui.colored_label(ui.visuals().error_fg_color, message);
+        }
+        None => {}
+    }
+}
+
+fn role_color(role: NoteRole) -> egui::Color32 {
+    match role {
+        NoteRole::Root => egui::Color32::from_rgb(230, 100, 100),
+        NoteRole::Third => egui::Color32::from_rgb(100, 150, 255),
+        NoteRole::Fifth => egui::Color32::from_rgb(100, 200, 100),
+        NoteRole::Other => egui::Color32::from_rgb(230, 180, 80),
+    }
+}
+
+/// The notes currently sounding on the audio thread, published as one atomic per MIDI note so
+/// the editor's keyboard can read them without locking.
+struct SoundingNotes {
+    roles: [AtomicU8; 128],
+}
+
+impl SoundingNotes {
+    fn new() -> Self {
+        Self {
+            roles: std::array::from_fn(|_| AtomicU8::new(0)),
+        }
+    }
+
+    fn role(&self, note: u8) -> Option<NoteRole> {
+        match self.roles[note as usize & 127].load(Ordering::Relaxed) {
+            1 => Some(NoteRole::Root),
+            2 => Some(NoteRole::Third),
+            3 => Some(NoteRole::Fifth),
+            4 => Some(NoteRole::Other),
+            _ => None,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.roles
+            .iter()
+            .all(|role| role.load(Ordering::Relaxed) == 0)
+    }
+
+    fn publish(&self, roles: &[Option<NoteRole>; 128]) {
+        for (slot, role) in self.roles.iter().zip(roles) {
+            let value = match role {
+                None => 0,
+                Some(NoteRole::Root) => 1,
+                Some(NoteRole::Third) => 2,
+                Some(NoteRole::Fifth) => 3,
+                Some(NoteRole::Other) => 4,
+            };
+            slot.store(value, Ordering::Relaxed);
+        }
+    }
+}
+
+const MAX_SOUNDING_CHORDS: usize = 16;
+const NO_CHORD: u16 = u16::MAX;
+
+/// Packs a chord into a `u16` so it fits in an atomic.
+fn encode_chord(chord_id: ChordId) -> u16 {
+    let type_index = match chord_id.chord_type {
+        ChordType::Custom(index) => ChordType::ALL.len() + index as usize,
+        chord_type => ChordType::ALL
+            .iter()
+            .position(|&t| t == chord_type)
+            .unwrap_or(0),
+    };
+    (chord_id.root.semitones() as u16) << 8 | type_index as u16
+}
+
+/// What the audio thread is actually playing, published through atomics so the editor can read it
+/// every frame without locking or waiting on the audio thread.
+struct SharedState {
+    sounding_notes: SoundingNotes,
+    sounding_chords: [AtomicU16; MAX_SOUNDING_CHORDS],
+}
+
+impl SharedState {
+    fn new() -> Self {
+        Self {
+            sounding_notes: SoundingNotes::new(),
+            sounding_chords: std::array::from_fn(|_| AtomicU16::new(NO_CHORD)),
+        }
+    }
+
+    fn is_chord_sounding(&self, chord_id: ChordId) -> bool {
+        let bits = encode_chord(chord_id);
+        self.sounding_chords
+            .iter()
+            .any(|slot| slot.load(Ordering::Relaxed) == bits)
+    }
+
+    /// Stores up to [`MAX_SOUNDING_CHORDS`] chords, clearing the remaining slots.
+    fn publish_chords(&self, chords: impl Iterator<Item = ChordId>) {
+        let mut chords = chords.map(encode_chord);
+        for slot in &self.sounding_chords {
+            slot.store(chords.next().unwrap_or(NO_CHORD), Ordering::Relaxed);
+        }
+    }
 }
 
 #[derive(Clone)]
 struct GuiState {
     octave: i8,
-    root_note: String,
-    scale_type: String,
+    note_range_policy: NoteRangePolicy,
+    scale: Scale,
+    vocabulary: SharedVocabulary,
+    chord_table: Arc<ChordTable>,
+    /// Why the built-in chord data was replaced by the computed fallback, if it was
+    chord_data_error: Option<String>,
 
     playing_chord: Option<ChordId>,
     inversion_chord: Option<ChordId>,
@@ -94,34 +277,291 @@ struct GuiState {
     playing_keys: HashSet<egui::Key>,
     view_mode: ViewMode,
     key_to_map: Option<egui::Key>,
+    /// A chord symbol typed in to map `key_to_map` by name
+    key_symbol: String,
+    key_symbol_error: Option<String>,
+    chromatic_grid: bool,
+    /// Label chords at the pitch they sound at rather than as written
+    show_sounding_pitch: bool,
+    /// Highlight the chords likely to follow the last one played
+    suggest_next: bool,
+    /// Mix the progression corpus's Markov model into the suggestions
+    learned_suggestions: bool,
+    last_chord: Option<ChordId>,
+    /// Suggested next chords, best first, as of the current frame
+    suggestions: Vec<ChordId>,
+    /// The transpose parameter as of the current frame
+    transpose: i32,
+    auditioning_note: Option<u8>,
+    fretboard: FretboardState,
+    presets: PresetBrowser,
+    mapping_file: MappingFileState,
+    custom_chord_form: CustomChordForm,
+    progression: ProgressionForm,
+    /// Play held chords with `comp_pattern` instead of sustaining them
+    comping: bool,
+    comp_pattern: CompPattern,
+    /// The step being edited in the rhythm view
+    selected_step: usize,
+    /// Pressure last sent for the chord held with the mouse
+    held_pressure: f32,
+    /// Announce the key and chord to plugins further down the chain
+    harmony_output: HarmonyOutput,
+    /// The host grid mouse and keyboard triggers wait for
+    quantize: Quantize,
+    min_note_length: bool,
+    tuning: TuningForm,
+    /// Why the OSC listener couldn't start on the chosen port
+    osc_error: Option<String>,
+    /// Why the last settings change didn't reach the audio thread
+    sync_error: Option<String>,
+}
+
+#[derive(Clone)]
+struct ProgressionForm {
+    text: String,
+    notation: ProgressionNotation,
+    beats_per_chord: u32,
+    generator: GeneratorOptions,
+    export_path: String,
+    message: Option<Result<String, String>>,
+}
+
+impl Default for ProgressionForm {
+    fn default() -> Self {
+        Self {
+            text: "I - V - vi - IV".to_string(),
+            notation: ProgressionNotation::RomanNumerals,
+            beats_per_chord: 4,
+            generator: GeneratorOptions::default(),
+            export_path: presets::default_progression_export_path()
+                .map(|path| path.display().to_string())
+                .unwrap_or_default(),
+            message: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ProgressionNotation {
+    RomanNumerals,
+    ChordSymbols,
+}
+
+impl ProgressionForm {
+    /// Parses the text in the chosen notation, with one message per token that couldn't be read.
+    /// Bass notes of slash chords aren't played.
+    fn parse(
+        &self,
+        scale: Scale,
+        vocabulary: &ChordVocabulary,
+    ) -> Result<Vec<ChordId>, Vec<String>> {
+        match self.notation {
+            ProgressionNotation::RomanNumerals => theory::parse_progression(&self.text, scale)
+                .map_err(|errors| errors.iter().map(ToString::to_string).collect()),
+            ProgressionNotation::ChordSymbols => theory::parse_chord_chart(&self.text, vocabulary)
+                .map(|symbols| symbols.iter().map(ChordSymbol::chord).collect())
+                .map_err(|errors| errors.iter().map(ToString::to_string).collect()),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+struct CustomChordForm {
+    key: String,
+    suffix: String,
+    intervals: String,
+    message: Option<Result<String, String>>,
+}
+
+#[derive(Clone)]
+struct MappingFileState {
+    path: String,
+    message: Option<Result<String, String>>,
+}
+
+impl Default for MappingFileState {
+    fn default() -> Self {
+        Self {
+            path: presets::default_mapping_path()
+                .map(|path| path.display().to_string())
+                .unwrap_or_default(),
+            message: None,
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+struct TuningForm {
+    scale_path: String,
+    /// Empty for Scala's default linear mapping
+    mapping_path: String,
+    /// The tuning sent to the audio thread, `None` for 12-TET
+    loaded: Option<Tuning>,
+    message: Option<Result<String, String>>,
+}
+
+#[derive(Clone, Default)]
+struct PresetBrowser {
+    entries: Vec<PresetEntry>,
+    current: Option<usize>,
+    pending: Option<PresetAction>,
+    name_input: String,
+    error: Option<String>,
+}
+
+/// A preset browser action waiting for a name or a confirmation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PresetAction {
+    SaveAs,
+    Rename,
+    Delete,
 }
 
-impl Default for GuiState {
+impl PresetBrowser {
+    /// Re-reads the preset list, keeping the current preset selected if it still exists.
+    fn refresh(&mut self) {
+        let current = self.current_entry().cloned();
+        self.entries = presets::list_presets();
+        self.current =
+            current.and_then(|current| self.entries.iter().position(|entry| *entry == current));
+    }
+
+    fn current_entry(&self) -> Option<&PresetEntry> {
+        self.current.and_then(|index| self.entries.get(index))
+    }
+}
+
+#[derive(Clone)]
+struct FretboardState {
+    chord: ChordId,
+    tuning: usize,
+    options: ShapeOptions,
+    shapes: Vec<ChordShape>,
+    shapes_for: Option<(ChordId, usize, ShapeOptions)>,
+    playing_shape: Option<ChordShape>,
+}
+
+impl Default for FretboardState {
     fn default() -> Self {
+        Self {
+            chord: ChordId::new(PitchClass::new(0), ChordType::Major),
+            tuning: 0,
+            options: ShapeOptions {
+                max_span: 4,
+                prefer_open: true,
+            },
+            shapes: Vec::new(),
+            shapes_for: None,
+            playing_shape: None,
+        }
+    }
+}
+
+impl GuiState {
+    /// Sends a settings change the editor has made to its own state, keeping the error to show
+    /// if the audio thread didn't get it.
+    fn send_setting(&mut self, sender: &Sender<MidiMessage>, message: MidiMessage) {
+        self.sync_error = send_update(sender, message).err();
+    }
+
+    fn new(vocabulary: SharedVocabulary) -> Self {
+        let (chord_table, chord_data_error) = theory::full_chord_table(&vocabulary.read());
+        if let Some(err) = &chord_data_error {
+            log::warn!("Falling back to computed chord data: {}", err);
+        }
+
         Self {
             octave: 3,
-            root_note: "C".to_string(),
-            scale_type: "Major".to_string(),
+            note_range_policy: NoteRangePolicy::default(),
+            scale: Scale::default(),
+            vocabulary,
+            chord_table: Arc::new(chord_table),
+            chord_data_error: chord_data_error.map(|err| err.to_string()),
             playing_chord: None,
             inversion_chord: None,
-            inversion_map: HashMap::new(),
-            key_mappings: generate_default_key_mappings(&get_scale_map(), "C Major".to_string()),
+            inversion_map: HashMap::with_capacity(INVERSION_MAP_CAPACITY),
+            key_mappings: generate_default_key_mappings(Scale::default()),
             playing_keys: HashSet::new(),
             view_mode: ViewMode::ChordGrid,
             key_to_map: None,
+            key_symbol: String::new(),
+            key_symbol_error: None,
+            chromatic_grid: false,
+            show_sounding_pitch: false,
+            suggest_next: true,
+            learned_suggestions: false,
+            last_chord: None,
+            suggestions: Vec::new(),
+            transpose: 0,
+            auditioning_note: None,
+            fretboard: FretboardState::default(),
+            presets: PresetBrowser::default(),
+            mapping_file: MappingFileState::default(),
+            custom_chord_form: CustomChordForm::default(),
+            progression: ProgressionForm::default(),
+            comping: false,
+            comp_pattern: CompPattern::default(),
+            selected_step: 0,
+            held_pressure: 0.0,
+            harmony_output: HarmonyOutput::default(),
+            quantize: Quantize::default(),
+            min_note_length: true,
+            tuning: TuningForm::default(),
+            osc_error: None,
+            sync_error: None,
         }
     }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CellStyle {
+    Diatonic,
+    Extended,
+    OutOfKey,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 enum ViewMode {
+    #[default]
     ChordGrid,
+    CircleOfFifths,
+    Fretboard,
     KeyMapping,
+    CustomChords,
+    Progression,
+    Rhythm,
+    Tuning,
 }
 
-impl Default for ViewMode {
-    fn default() -> Self {
-        ViewMode::ChordGrid
+/// A progression being played by the audio thread.
+struct ProgressionPlayer {
+    chords: Arc<[ChordId]>,
+    beats_per_chord: u32,
+    /// The chord that starts next
+    index: usize,
+    /// Samples from the start of the next buffer until the next chord starts
+    samples_to_next: f64,
+    notes: ChordNotes,
+}
+
+/// A chord played or released from the editor, which can be held back until the next grid point.
+#[derive(Debug, Clone, Copy)]
+enum Trigger {
+    ChordOn(ChordId),
+    ChordOff,
+    KeyChordOn(egui::Key),
+    KeyChordOff(egui::Key),
+    VoicingOn(ChordId, ChordNotes),
+}
+
+impl Trigger {
+    /// The key a trigger comes from, or `None` for the mouse.
+    fn source(self) -> Option<egui::Key> {
+        match self {
+            Trigger::KeyChordOn(key) | Trigger::KeyChordOff(key) => Some(key),
+            Trigger::ChordOn(_) | Trigger::ChordOff | Trigger::VoicingOn(..) => None,
+        }
     }
 }
 
@@ -129,9 +569,28 @@ pub struct PerfectChords {
     params: Arc<PerfectChordsParams>,
     midi_sender: Sender<MidiMessage>,
     midi_receiver: Receiver<MidiMessage>,
-    active_key_notes: HashMap<egui::Key, Vec<u8>>,
-    active_mouse_notes: Vec<u8>,
-    chord_table: ChordTable,
+    /// The OSC listener, which sends into `midi_sender` like the editor
+    osc: Arc<OscControl>,
+    active_key_notes: HashMap<egui::Key, ChordNotes>,
+    active_mouse_notes: ChordNotes,
+    audition_note: Option<u8>,
+    progression: Option<ProgressionPlayer>,
+    comp: CompPlayer,
+    voices: VoiceAllocator,
+    /// The harmony last announced, so it is only sent again when it changes
+    last_harmony: Option<HarmonyMessage>,
+    /// Triggers waiting for their grid point, by the sample they are due at
+    pending_triggers: Vec<(u64, Trigger)>,
+    /// Samples processed so far, which quantized triggers are scheduled against
+    sample_clock: u64,
+    /// When the mouse chord and each key's chord started, for the minimum note length
+    mouse_started_at: u64,
+    key_started_at: HashMap<egui::Key, u64>,
+    gui_event_sender: Sender<GuiEvent>,
+    gui_event_receiver: Receiver<GuiEvent>,
+    /// Retired values waiting for room in the audio-to-editor queue, allocated up front
+    retired: Vec<Retired>,
+    shared: Arc<SharedState>,
     scale_map: ScaleMap,
     state: GuiState,
 }
@@ -140,60 +599,1696 @@ pub struct PerfectChords {
 pub struct PerfectChordsParams {
     #[persist = "editor-state"]
     editor_state: Arc<EguiState>,
+
+    /// Shifts every played note after the voicing has been chosen, so the grid can stay in the
+    /// written key while the output sounds in another
+    #[id = "transpose"]
+    transpose: IntParam,
+
+    /// Sends every note on its own MIDI channel with a voice ID, for synths that support MPE
+    #[id = "mpe"]
+    mpe: BoolParam,
+
+    #[persist = "osc"]
+    osc: Mutex<OscSettings>,
 }
 
 impl Default for PerfectChords {
     fn default() -> Self {
-        let (sender, receiver) = crossbeam_channel::unbounded();
-        let chord_table: ChordTable =
-            serde_json::from_str(include_str!("../chords-builder/chords.json"))
-                .expect("Failed to parse chords.json");
+        let (sender, receiver) = crossbeam_channel::bounded(MIDI_QUEUE_CAPACITY);
+        let (gui_event_sender, gui_event_receiver) = crossbeam_channel::bounded(GUI_EVENT_CAPACITY);
+        // Custom chord types have to be registered before the chord table is built
+        let mut vocabulary = ChordVocabulary::new();
+        if let Err(err) = presets::load_custom_chord_types(&mut vocabulary) {
+            log::warn!("Could not load custom chord types: {}", err);
+        }
+        let vocabulary = SharedVocabulary::new(vocabulary);
 
         Self {
             params: Arc::new(PerfectChordsParams::default()),
+            osc: Arc::new(OscControl::new(sender.clone(), vocabulary.clone())),
             midi_sender: sender,
             midi_receiver: receiver,
-            active_key_notes: HashMap::new(),
-            active_mouse_notes: Vec::new(),
-            chord_table,
+            active_key_notes: HashMap::with_capacity(egui::Key::ALL.len()),
+            active_mouse_notes: ChordNotes::default(),
+            audition_note: None,
+            progression: None,
+            comp: CompPlayer::default(),
+            voices: VoiceAllocator::default(),
+            last_harmony: None,
+            pending_triggers: Vec::with_capacity(MIDI_QUEUE_CAPACITY),
+            sample_clock: 0,
+            mouse_started_at: 0,
+            key_started_at: HashMap::with_capacity(egui::Key::ALL.len()),
+            gui_event_sender,
+            gui_event_receiver,
+            retired: Vec::with_capacity(RETIRED_CAPACITY),
+            shared: Arc::new(SharedState::new()),
             scale_map: get_scale_map(),
-            state: GuiState::default(),
+            state: GuiState::new(vocabulary),
         }
     }
 }
 
-fn generate_default_key_mappings(scale_map: &ScaleMap, current_scale: String) -> HashMap<egui::Key, ChordId> {
-    let mut mappings = HashMap::new();
-    let default_keys = [
-        egui::Key::Z,
-        egui::Key::X,
-        egui::Key::C,
-        egui::Key::V,
-        egui::Key::B,
-        egui::Key::N,
-        egui::Key::M,
-    ];
+impl Drop for PerfectChords {
+    fn drop(&mut self) {
+        // An open editor shares the OSC control, so the listener could otherwise outlive the plugin
+        self.osc.stop();
+    }
+}
+
+/// Keys a parsed progression is mapped onto, in order: the default keys followed by the home row.
+const PROGRESSION_KEYS: [egui::Key; 16] = [
+    egui::Key::Z,
+    egui::Key::X,
+    egui::Key::C,
+    egui::Key::V,
+    egui::Key::B,
+    egui::Key::N,
+    egui::Key::M,
+    egui::Key::A,
+    egui::Key::S,
+    egui::Key::D,
+    egui::Key::F,
+    egui::Key::G,
+    egui::Key::H,
+    egui::Key::J,
+    egui::Key::K,
+    egui::Key::L,
+];
+
+const DEFAULT_MAPPING_KEYS: [egui::Key; 7] = [
+    egui::Key::Z,
+    egui::Key::X,
+    egui::Key::C,
+    egui::Key::V,
+    egui::Key::B,
+    egui::Key::N,
+    egui::Key::M,
+];
+
+fn generate_default_key_mappings(scale: Scale) -> HashMap<egui::Key, ChordId> {
+    let mut mappings = HashMap::with_capacity(egui::Key::ALL.len());
+    assign_default_key_mappings(&mut mappings, scale);
+    mappings
+}
+
+/// Maps the default keys to the scale's diatonic chords in place, so the audio thread can follow
+/// scale changes without allocating.
+fn assign_default_key_mappings(mappings: &mut HashMap<egui::Key, ChordId>, scale: Scale) {
+    mappings.clear();
+    for (key, chord_id) in DEFAULT_MAPPING_KEYS
+        .into_iter()
+        .zip(scale.diatonic_chords())
+    {
+        mappings.insert(key, chord_id);
+    }
+}
+
+fn apply_scale(state: &mut GuiState, sender: &Sender<MidiMessage>) {
+    state.key_mappings = generate_default_key_mappings(state.scale);
+    state.send_setting(sender, MidiMessage::UpdateScale(state.scale));
+}
+
+fn apply_gui_event(state: &mut GuiState, event: GuiEvent) {
+    match event {
+        GuiEvent::Octave(octave) => state.octave = octave,
+        GuiEvent::NoteRangePolicy(policy) => state.note_range_policy = policy,
+        GuiEvent::Scale(scale) => {
+            // Only regenerate on an actual change so mappings edited since are kept
+            if state.scale != scale {
+                state.scale = scale;
+                state.key_mappings = generate_default_key_mappings(scale);
+            }
+        }
+        GuiEvent::InversionChord(chord_id) => state.inversion_chord = Some(chord_id),
+        GuiEvent::Inversion(chord_id, inversion) => {
+            state.inversion_map.insert(chord_id, inversion);
+        }
+        GuiEvent::KeyMapping(key, chord_id) => {
+            state.key_mappings.insert(key, chord_id);
+        }
+        GuiEvent::Retired(retired) => match retired {
+            Retired::ChordTable(chord_table) => drop(chord_table),
+            Retired::Progression(chords) => drop(chords),
+            Retired::CompPattern(pattern) => drop(pattern),
+            Retired::Preset(settings) => drop(settings),
+            Retired::KeyMappings(mappings) => drop(mappings),
+            Retired::Tuning(table) => drop(table),
+        },
+    }
+}
+
+fn current_preset(state: &GuiState, mpe: bool) -> Preset {
+    let vocabulary = state.vocabulary.read();
+    let mut inversions: Vec<(ChordId, u8)> = state
+        .inversion_map
+        .iter()
+        .filter(|&(_, &inversion)| inversion != 0)
+        .map(|(&chord, &inversion)| (chord, inversion))
+        .collect();
+    inversions.sort_by_key(|&(chord, _)| chord);
+
+    Preset {
+        scale: state.scale,
+        octave: state.octave,
+        note_range_policy: state.note_range_policy,
+        key_mappings: state
+            .key_mappings
+            .iter()
+            .map(|(key, chord_id)| (key.name().to_string(), vocabulary.store(*chord_id)))
+            .collect(),
+        inversions: inversions
+            .into_iter()
+            .map(|(chord, inversion)| ChordInversion {
+                chord: vocabulary.store(chord),
+                inversion,
+            })
+            .collect(),
+        comping: PresetComping::new(state.comping, &state.comp_pattern),
+        quantize: state.quantize,
+        min_note_length: state.min_note_length,
+        mpe,
+        harmony_output: state.harmony_output,
+    }
+}
+
+/// Applies a preset to the editor state and sends it to the audio thread in one message. MPE is a
+/// host parameter and is set through `setter`.
+fn apply_preset(
+    state: &mut GuiState,
+    sender: &Sender<MidiMessage>,
+    setter: &ParamSetter,
+    params: &PerfectChordsParams,
+    preset: &Preset,
+) -> Result<(), String> {
+    state.scale = preset.scale;
+    state.octave = preset
+        .octave
+        .clamp(*OCTAVE_RANGE.start(), *OCTAVE_RANGE.end());
+    state.note_range_policy = preset.note_range_policy;
+    let vocabulary = state.vocabulary.read();
+    state.key_mappings.clear();
+    state.key_mappings.extend(preset.key_mappings(&vocabulary));
+    state.inversion_map.clear();
+    state.inversion_map.extend(preset.inversions(&vocabulary));
+    drop(vocabulary);
+    state.comping = preset.comping.enabled;
+    state.comp_pattern = preset.comping.pattern();
+    state.quantize = preset.quantize;
+    state.min_note_length = preset.min_note_length;
+    state.harmony_output = preset.harmony_output;
+    if params.mpe.value() != preset.mpe {
+        setter.begin_set_parameter(&params.mpe);
+        setter.set_parameter(&params.mpe, preset.mpe);
+        setter.end_set_parameter(&params.mpe);
+    }
+
+    let settings = PresetSettings {
+        scale: state.scale,
+        octave: state.octave,
+        note_range_policy: state.note_range_policy,
+        key_mappings: audio_key_mappings(&state.key_mappings),
+        inversion_map: {
+            let mut inversion_map = HashMap::with_capacity(INVERSION_MAP_CAPACITY);
+            inversion_map.extend(&state.inversion_map);
+            inversion_map
+        },
+        comp_pattern: state.comping.then_some(state.comp_pattern),
+        quantize: state.quantize,
+        min_note_length: state.min_note_length,
+        harmony_output: state.harmony_output,
+    };
+    send_update(sender, MidiMessage::ApplyPreset(Box::new(settings)))
+}
+
+/// Sends a change that the editor has already made to its own state, so losing it would leave the
+/// editor showing settings that don't play.
+fn send_update(sender: &Sender<MidiMessage>, message: MidiMessage) -> Result<(), String> {
+    sender.try_send(message).map_err(|_| {
+        "the audio thread isn't taking changes right now, so the editor is out of sync".to_string()
+    })
+}
+
+/// Replaces the key mappings in the editor and on the audio thread.
+fn replace_key_mappings(
+    state: &mut GuiState,
+    sender: &Sender<MidiMessage>,
+    mappings: impl IntoIterator<Item = (egui::Key, ChordId)>,
+) -> Result<(), String> {
+    state.key_mappings.clear();
+    state.key_mappings.extend(mappings);
+    send_update(
+        sender,
+        MidiMessage::UpdateKeyMappings(audio_key_mappings(&state.key_mappings)),
+    )
+}
+
+/// A copy of the key mappings with room for every key, so mapping keys on the audio thread later
+/// never reallocates.
+fn audio_key_mappings(mappings: &HashMap<egui::Key, ChordId>) -> HashMap<egui::Key, ChordId> {
+    let mut copy = HashMap::with_capacity(egui::Key::ALL.len());
+    copy.extend(mappings);
+    copy
+}
+
+fn load_preset_at(
+    state: &mut GuiState,
+    sender: &Sender<MidiMessage>,
+    setter: &ParamSetter,
+    params: &PerfectChordsParams,
+    index: usize,
+) {
+    let Some(entry) = state.presets.entries.get(index).cloned() else {
+        return;
+    };
+    match presets::load_preset(&entry) {
+        Ok(preset) => {
+            state.presets.error = apply_preset(state, sender, setter, params, &preset)
+                .err()
+                .map(|err| format!("Loaded `{}`, but {}", entry.name, err));
+            state.presets.current = Some(index);
+        }
+        Err(err) => state.presets.error = Some(format!("Could not load `{}`: {}", entry.name, err)),
+    }
+}
+
+/// Draws the preset browser: a picker with previous/next buttons, plus save-as, rename and delete.
+/// Factory presets can be loaded and saved under a new name but not renamed or deleted.
+fn preset_bar(
+    ui: &mut egui::Ui,
+    state: &mut GuiState,
+    sender: &Sender<MidiMessage>,
+    setter: &ParamSetter,
+    params: &PerfectChordsParams,
+) {
+    let mut load_index = None;
+
+    ui.horizontal(|ui| {
+        let browser = &mut state.presets;
+        let count = browser.entries.len();
+
+        ui.label("Preset:");
+        if ui.button("◀").clicked() && count > 0 {
+            load_index = Some(
+                browser
+                    .current
+                    .map_or(count - 1, |index| (index + count - 1) % count),
+            );
+        }
+        egui::ComboBox::from_id_salt("preset_picker")
+            .selected_text(
+                browser
+                    .current_entry()
+                    .map_or("Unsaved", |entry| entry.name.as_str()),
+            )
+            .width(200.0)
+            .show_ui(ui, |ui| {
+                for (index, entry) in browser.entries.iter().enumerate() {
+                    let label = if entry.factory {
+                        format!("{} (factory)", entry.name)
+                    } else {
+                        entry.name.clone()
+                    };
+                    if ui
+                        .selectable_label(browser.current == Some(index), label)
+                        .clicked()
+                    {
+                        load_index = Some(index);
+                    }
+                }
+            });
+        if ui.button("▶").clicked() && count > 0 {
+            load_index = Some(browser.current.map_or(0, |index| (index + 1) % count));
+        }
+
+        ui.add_space(10.0);
+        let current_name = browser.current_entry().map(|entry| entry.name.clone());
+        let is_user_preset = browser.current_entry().is_some_and(|entry| !entry.factory);
+        if ui.button("Save As…").clicked() {
+            browser.pending = Some(PresetAction::SaveAs);
+            browser.name_input = current_name.clone().unwrap_or_default();
+        }
+        if ui
+            .add_enabled(is_user_preset, egui::Button::new("Rename"))
+            .clicked()
+        {
+            browser.pending = Some(PresetAction::Rename);
+            browser.name_input = current_name.unwrap_or_default();
+        }
+        if ui
+            .add_enabled(is_user_preset, egui::Button::new("Delete"))
+            .clicked()
+        {
+            browser.pending = Some(PresetAction::Delete);
+        }
+        if ui
+            .button("⟳")
+            .on_hover_text("Rescan the preset folder")
+            .clicked()
+        {
+            browser.refresh();
+        }
+    });
+
+    if let Some(index) = load_index {
+        load_preset_at(state, sender, setter, params, index);
+    }
+
+    if let Some(action) = state.presets.pending {
+        let mut confirmed = false;
+        ui.horizontal(|ui| {
+            let browser = &mut state.presets;
+            match action {
+                PresetAction::SaveAs | PresetAction::Rename => {
+                    ui.label(if action == PresetAction::SaveAs {
+                        "Save as:"
+                    } else {
+                        "Rename to:"
+                    });
+                    let response = ui.text_edit_singleline(&mut browser.name_input);
+                    confirmed = ui.button("OK").clicked()
+                        || (response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)));
+                }
+                PresetAction::Delete => {
+                    let name = browser
+                        .current_entry()
+                        .map_or("", |entry| entry.name.as_str());
+                    ui.label(format!("Delete preset `{}`?", name));
+                    confirmed = ui.button("Delete").clicked();
+                }
+            }
+            if ui.button("Cancel").clicked() {
+                browser.pending = None;
+            }
+        });
+
+        if confirmed {
+            let preset = current_preset(state, params.mpe.value());
+            let browser = &mut state.presets;
+            let name = browser.name_input.clone();
+            let current_name = browser
+                .current_entry()
+                .map(|entry| entry.name.clone())
+                .unwrap_or_default();
+            let result = match action {
+                PresetAction::SaveAs => presets::save_preset(&name, &preset),
+                PresetAction::Rename => presets::rename_preset(&current_name, &name),
+                PresetAction::Delete => presets::delete_preset(&current_name),
+            };
+
+            match result {
+                Ok(()) => {
+                    browser.pending = None;
+                    browser.error = None;
+                    browser.refresh();
+                    if action != PresetAction::Delete {
+                        browser.current = browser
+                            .entries
+                            .iter()
+                            .position(|entry| !entry.factory && entry.name == name);
+                    }
+                }
+                Err(err) => browser.error = Some(err.to_string()),
+            }
+        }
+    }
+
+    if let Some(error) = &state.presets.error {
+        ui.colored_label(ui.visuals().error_fg_color, error);
+    }
+    if let Some(error) = &state.sync_error {
+        ui.colored_label(
+            ui.visuals().error_fg_color,
+            format!("A change didn't apply: {}", error),
+        );
+    }
+}
+
+/// Path field with buttons to export the key mappings to a JSON file or replace them with the
+/// contents of one. Imports are all-or-nothing so a bad file leaves the mappings untouched.
+fn mapping_file_controls(ui: &mut egui::Ui, state: &mut GuiState, sender: &Sender<MidiMessage>) {
+    ui.horizontal(|ui| {
+        ui.label("File:");
+        ui.add(egui::TextEdit::singleline(&mut state.mapping_file.path).desired_width(320.0));
+        let path = PathBuf::from(state.mapping_file.path.trim());
+
+        if ui.button("Export").clicked() {
+            let mappings = state
+                .key_mappings
+                .iter()
+                .map(|(&key, &chord_id)| (key, chord_id));
+            let vocabulary = state.vocabulary.read();
+            state.mapping_file.message = Some(
+                match presets::export_key_mappings(&path, mappings, &vocabulary) {
+                    Ok(()) => Ok(format!(
+                        "Exported {} mappings to {}",
+                        state.key_mappings.len(),
+                        path.display()
+                    )),
+                    Err(err) => Err(format!("Export failed: {}", err)),
+                },
+            );
+        }
+
+        if ui.button("Import").clicked() {
+            let imported = presets::import_key_mappings(&path, &state.vocabulary.read());
+            state.mapping_file.message = Some(match imported {
+                Ok(mappings) => {
+                    let count = mappings.len();
+                    match replace_key_mappings(state, sender, mappings) {
+                        Ok(()) => Ok(format!(
+                            "Imported {} mappings from {}",
+                            count,
+                            path.display()
+                        )),
+                        Err(err) => Err(format!("Imported {} mappings, but {}", count, err)),
+                    }
+                }
+                Err(err) => Err(format!("Import failed: {}", err)),
+            });
+        }
+    });
+
+    show_message(ui, &state.mapping_file.message);
+}
+
+/// Turns the OSC listener on or off and moves it to another port. The settings are saved with the
+/// plugin state even if the listener can't start, so a port that is busy now is tried again next
+/// time the plugin is activated.
+fn osc_controls(
+    ui: &mut egui::Ui,
+    state: &mut GuiState,
+    settings: &Mutex<OscSettings>,
+    osc: &OscControl,
+) {
+    ui.heading("OSC Control");
+    let mut settings = settings.lock().unwrap_or_else(|err| err.into_inner());
+    let apply = ui
+        .horizontal(|ui| {
+            let toggled = ui
+                .checkbox(&mut settings.enabled, "Listen on localhost port")
+                .changed();
+            let port = ui.add(egui::DragValue::new(&mut settings.port).range(1024..=65535));
+            // Rebinding on every step of a drag would hop across ports
+            toggled || (port.changed() && !port.dragged()) || port.drag_stopped()
+        })
+        .inner;
+    if apply {
+        state.osc_error = osc
+            .apply(*settings)
+            .err()
+            .map(|err| format!("Could not listen on port {}: {}", settings.port, err));
+    }
+
+    match (osc.port(), &state.osc_error) {
+        (Some(port), _) => {
+            ui.label(format!("Listening on 127.0.0.1:{}", port));
+        }
+        (None, Some(error)) => {
+            ui.colored_label(ui.visuals().error_fg_color, error);
+        }
+        (None, None) if settings.enabled => {
+            ui.colored_label(
+                ui.visuals().error_fg_color,
+                "Not listening; the port may be in use",
+            );
+        }
+        (None, None) => {}
+    }
+    ui.label(
+        "Addresses: /chord/on <root> <type>, /chord/off, /scale <root> <major|minor>, /octave <n>, \
+         /inversion <n> and /inversion <root> <type> <n>. Chord types use the keys from chords.json, \
+         such as maj, m7 or 7b9.",
+    );
+}
+
+/// Saves the custom chord types and hands a rebuilt chord table to the audio thread.
+fn commit_custom_chords(state: &mut GuiState, sender: &Sender<MidiMessage>) -> Result<(), String> {
+    let vocabulary = state.vocabulary.read();
+    let (chord_table, chord_data_error) = theory::full_chord_table(&vocabulary);
+    state.chord_table = Arc::new(chord_table);
+    state.chord_data_error = chord_data_error.map(|err| err.to_string());
+    let sent = send_update(
+        sender,
+        MidiMessage::UpdateChordTable(state.chord_table.clone()),
+    );
+    presets::save_custom_chord_types(&vocabulary)
+        .map_err(|err| format!("Could not save custom chord types: {}", err))?;
+    sent
+}
+
+fn parse_intervals(text: &str) -> Result<Vec<u8>, String> {
+    text.split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            part.parse::<u8>()
+                .map_err(|_| format!("`{}` is not an interval in semitones", part))
+        })
+        .collect()
+}
+
+/// Parses a progression typed in Roman numerals or chord symbols, or generates one, and plays it,
+/// loads it into the key mappings or exports it as a MIDI file.
+fn progression_view(
+    ui: &mut egui::Ui,
+    state: &mut GuiState,
+    sender: &Sender<MidiMessage>,
+    shared: &SharedState,
+    scale_map: &ScaleMap,
+) {
+    ui.heading("Progression");
+    ui.horizontal(|ui| {
+        let notation = &mut state.progression.notation;
+        ui.selectable_value(
+            notation,
+            ProgressionNotation::RomanNumerals,
+            "Roman numerals",
+        );
+        ui.selectable_value(notation, ProgressionNotation::ChordSymbols, "Chord symbols");
+    });
+    match state.progression.notation {
+        ProgressionNotation::RomanNumerals => ui.label(format!(
+            "Roman numerals in {}, such as `ii7 V7 Imaj7 vi7` or `I - V/vi - vi - IV`.",
+            state.scale
+        )),
+        ProgressionNotation::ChordSymbols => {
+            ui.label("A chord chart such as `| Cmaj7 | Am7 | Dm7 G7 |` or `Eb/G Fm7 Bb7#9`.")
+        }
+    };
+    ui.add_space(10.0);
+
+    generator_controls(ui, state, scale_map);
+    ui.add_space(10.0);
+
+    ui.add(egui::TextEdit::singleline(&mut state.progression.text).desired_width(f32::INFINITY));
+    let parsed = state
+        .progression
+        .parse(state.scale, &state.vocabulary.read());
+
+    ui.horizontal(|ui| {
+        ui.label("Beats per chord:");
+        ui.add(egui::DragValue::new(&mut state.progression.beats_per_chord).range(1..=16));
+
+        let chords = parsed.as_ref().ok().filter(|chords| !chords.is_empty());
+        if ui
+            .add_enabled(chords.is_some(), egui::Button::new("▶ Play"))
+            .clicked()
+            && let Some(chords) = chords
+        {
+            let _ = sender.try_send(MidiMessage::PlayProgression(
+                chords.as_slice().into(),
+                state.progression.beats_per_chord,
+            ));
+        }
+        if ui.button("■ Stop").clicked() {
+            let _ = sender.try_send(MidiMessage::StopProgression);
+        }
+        if ui
+            .add_enabled(
+                chords.is_some(),
+                egui::Button::new("Load into key mappings"),
+            )
+            .clicked()
+            && let Some(chords) = chords
+        {
+            state.progression.message = Some(load_progression_mappings(state, sender, chords));
+        }
+    });
+    ui.horizontal(|ui| {
+        ui.label("MIDI file:");
+        ui.add(egui::TextEdit::singleline(&mut state.progression.export_path).desired_width(300.0));
+        let chords = parsed.as_ref().ok().filter(|chords| !chords.is_empty());
+        if ui
+            .add_enabled(chords.is_some(), egui::Button::new("Export MIDI"))
+            .clicked()
+            && let Some(chords) = chords
+        {
+            state.progression.message = Some(export_progression(state, chords));
+        }
+    });
+    ui.add_space(10.0);
+
+    match &parsed {
+        Ok(chords) => {
+            ui.horizontal_wrapped(|ui| {
+                for &chord_id in chords {
+                    let text = egui::RichText::new(chord_label(state, chord_id));
+                    if shared.is_chord_sounding(chord_id) {
+                        ui.label(text.color(egui::Color32::from_rgb(100, 200, 100)).strong());
+                    } else {
+                        ui.label(text);
+                    }
+                }
+            });
+        }
+        Err(errors) => {
+            for error in errors {
+                ui.colored_label(ui.visuals().error_fg_color, error);
+            }
+        }
+    }
+
+    show_message(ui, &state.progression.message);
+}
+
+/// Settings for the random progression generator. Generating replaces the progression text with
+/// the result as chord symbols.
+fn generator_controls(ui: &mut egui::Ui, state: &mut GuiState, scale_map: &ScaleMap) {
+    egui::CollapsingHeader::new("Generate").show(ui, |ui| {
+        let options = &mut state.progression.generator;
+        ui.horizontal(|ui| {
+            ui.label("Bars:");
+            ui.add(egui::DragValue::new(&mut options.bars).range(1..=PROGRESSION_KEYS.len()));
+            ui.checkbox(&mut options.start_on_tonic, "Start on tonic");
+            ui.add_enabled(
+                options.cadence == Cadence::Free,
+                egui::Checkbox::new(&mut options.end_on_tonic, "End on tonic"),
+            );
+            ui.label("Cadence:");
+            egui::ComboBox::from_id_salt("generator_cadence")
+                .selected_text(options.cadence.name())
+                .show_ui(ui, |ui| {
+                    for cadence in Cadence::ALL {
+                        ui.selectable_value(&mut options.cadence, cadence, cadence.name());
+                    }
+                });
+        });
+        ui.horizontal_wrapped(|ui| {
+            ui.label("Chord types:");
+            for chord_type in [
+                ChordType::Major,
+                ChordType::Minor,
+                ChordType::Diminished,
+                ChordType::Major7,
+                ChordType::Minor7,
+                ChordType::Dominant7,
+                ChordType::HalfDiminished7,
+            ] {
+                let mut allowed = options.chord_types.contains(&chord_type);
+                if ui.checkbox(&mut allowed, chord_type.key()).changed() {
+                    if allowed {
+                        options.chord_types.push(chord_type);
+                    } else {
+                        options.chord_types.retain(|&other| other != chord_type);
+                    }
+                }
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("Borrowed chords:");
+            ui.add(
+                egui::Slider::new(&mut options.borrowed_probability, 0.0..=1.0).fixed_decimals(2),
+            )
+            .on_hover_text("Chance of each chord coming from the parallel key");
+            ui.label("Seed:");
+            ui.add(egui::DragValue::new(&mut options.seed));
+            if ui.button("🎲").on_hover_text("New seed").clicked() {
+                options.seed = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map(|elapsed| elapsed.as_nanos() as u64)
+                    .unwrap_or_default();
+            }
+            if ui.button("Generate").clicked() {
+                match theory::generate_progression(scale_map, state.scale, options) {
+                    Ok(chords) => {
+                        let vocabulary = state.vocabulary.read();
+                        let symbols: Vec<String> = chords
+                            .into_iter()
+                            .map(|chord| ChordSymbol::from(chord).display(&vocabulary).to_string())
+                            .collect();
+                        drop(vocabulary);
+                        state.progression.text = symbols.join(" | ");
+                        state.progression.notation = ProgressionNotation::ChordSymbols;
+                        state.progression.message = None;
+                    }
+                    Err(err) => {
+                        state.progression.message =
+                            Some(Err(format!("Could not generate: {}", err)))
+                    }
+                }
+            }
+        });
+    });
+}
+
+/// Writes a progression to the export path as a MIDI file, voiced and transposed the way the
+/// plugin would play it.
+fn export_progression(state: &GuiState, chords: &[ChordId]) -> Result<String, String> {
+    let path = state.progression.export_path.trim();
+    if path.is_empty() {
+        return Err("Enter a file to export to".to_string());
+    }
+
+    let voicings: Vec<ChordNotes> = chords
+        .iter()
+        .filter_map(|chord_id| {
+            let inversion = state.inversion_map.get(chord_id).copied().unwrap_or(0);
+            theory::voicing_notes(
+                &state.chord_table,
+                chord_id,
+                inversion,
+                state.octave,
+                state.note_range_policy,
+            )
+        })
+        .map(|notes| notes.transposed(state.transpose as i16, state.note_range_policy))
+        .collect();
+    let notes = midi_file::chord_sequence(&voicings, state.progression.beats_per_chord, 100)
+        .ok_or("The progression is too long for a MIDI file")?;
+    midi_file::write(Path::new(path), &notes, 120.0)
+        .map_err(|err| format!("Could not write {}: {}", path, err))?;
+    Ok(format!("Exported {} chords to {}", voicings.len(), path))
+}
 
-    if let Some(diatonics) = scale_map.get(&current_scale) {
-        for (i, key) in default_keys.iter().enumerate() {
-            if let Some(diatonic_chord) = diatonics.get(i) {
-                mappings.insert(
-                    *key,
-                    ChordId {
-                        root_note: diatonic_chord.root_note.clone(),
-                        chord_type: diatonic_chord.chord_type.clone(),
+/// Replaces the key mappings with a progression, one chord per key in [`PROGRESSION_KEYS`].
+fn load_progression_mappings(
+    state: &mut GuiState,
+    sender: &Sender<MidiMessage>,
+    chords: &[ChordId],
+) -> Result<String, String> {
+    if chords.len() > PROGRESSION_KEYS.len() {
+        return Err(format!(
+            "Only {} chords can be mapped to keys, this progression has {}",
+            PROGRESSION_KEYS.len(),
+            chords.len()
+        ));
+    }
+
+    replace_key_mappings(
+        state,
+        sender,
+        PROGRESSION_KEYS.into_iter().zip(chords.iter().copied()),
+    )?;
+    let keys: Vec<&str> = PROGRESSION_KEYS[..chords.len()]
+        .iter()
+        .map(|key| key.name())
+        .collect();
+    Ok(format!("Mapped to {}", keys.join(" ")))
+}
+
+/// Sets how triggers line up with the host's grid, and edits the comping pattern one step at a
+/// time. Any change is sent to the audio thread straight away, so a held chord picks it up on its
+/// next step.
+fn rhythm_view(ui: &mut egui::Ui, state: &mut GuiState, sender: &Sender<MidiMessage>) {
+    ui.heading("Timing");
+    ui.horizontal(|ui| {
+        ui.label("Quantize triggers:");
+        egui::ComboBox::from_id_salt("quantize_picker")
+            .selected_text(state.quantize.name())
+            .show_ui(ui, |ui| {
+                for quantize in Quantize::ALL {
+                    if ui
+                        .selectable_value(&mut state.quantize, quantize, quantize.name())
+                        .clicked()
+                    {
+                        state.send_setting(sender, MidiMessage::UpdateQuantize(quantize));
+                    }
+                }
+            })
+            .response
+            .on_hover_text("Chords wait for the next grid point while the host is playing");
+        if ui
+            .add_enabled(
+                state.quantize != Quantize::Off,
+                egui::Checkbox::new(
+                    &mut state.min_note_length,
+                    "Hold for at least one grid step",
+                ),
+            )
+            .changed()
+        {
+            state.send_setting(
+                sender,
+                MidiMessage::UpdateMinNoteLength(state.min_note_length),
+            );
+        }
+    });
+    ui.add_space(20.0);
+
+    ui.heading("Rhythm");
+    ui.label("Held chords play this pattern in sixteenth notes, following the host's bars while it plays.");
+    ui.add_space(10.0);
+
+    let before = (state.comping, state.comp_pattern);
+    ui.horizontal(|ui| {
+        ui.checkbox(&mut state.comping, "Comp held chords");
+        ui.add_space(20.0);
+        ui.label("Pattern:");
+        let matching = pattern_library()
+            .iter()
+            .find(|(_, pattern)| *pattern == state.comp_pattern);
+        egui::ComboBox::from_id_salt("comp_pattern_picker")
+            .selected_text(matching.map_or("Custom", |&(name, _)| name))
+            .show_ui(ui, |ui| {
+                for &(name, pattern) in pattern_library() {
+                    if ui
+                        .selectable_label(pattern == state.comp_pattern, name)
+                        .clicked()
+                    {
+                        state.comp_pattern = pattern;
+                    }
+                }
+            });
+        ui.label("Bars:");
+        ui.add(egui::DragValue::new(&mut state.comp_pattern.bars).range(1..=MAX_BARS as u8));
+    });
+    ui.add_space(10.0);
+
+    let step_count = state.comp_pattern.step_count();
+    state.selected_step = state.selected_step.min(step_count - 1);
+    for bar in 0..step_count / STEPS_PER_BAR {
+        ui.horizontal(|ui| {
+            ui.label(format!("{}", bar + 1));
+            for index in bar * STEPS_PER_BAR..(bar + 1) * STEPS_PER_BAR {
+                let step = state.comp_pattern.steps[index];
+                let text = egui::RichText::new(step.symbol().to_string()).monospace();
+                let button = egui::Button::new(text)
+                    .min_size(egui::vec2(22.0, 22.0))
+                    .selected(index == state.selected_step);
+                let response = ui
+                    .add(button)
+                    .on_hover_text("Click to select, right-click to switch on or off");
+                if response.clicked() {
+                    state.selected_step = index;
+                }
+                if response.secondary_clicked() {
+                    state.comp_pattern.steps[index].on = !step.on;
+                    state.selected_step = index;
+                }
+                if index % 4 == 3 {
+                    ui.add_space(6.0);
+                }
+            }
+        });
+    }
+    ui.add_space(10.0);
+
+    let selected = state.selected_step;
+    let step = &mut state.comp_pattern.steps[selected];
+    ui.horizontal(|ui| {
+        ui.label(format!(
+            "Step {}.{}:",
+            selected / STEPS_PER_BAR + 1,
+            selected % STEPS_PER_BAR + 1
+        ));
+        ui.checkbox(&mut step.on, "On");
+        ui.add_enabled_ui(step.on, |ui| {
+            ui.checkbox(&mut step.accent, "Accent");
+            egui::ComboBox::from_id_salt("comp_step_subset")
+                .selected_text(step.subset.name())
+                .show_ui(ui, |ui| {
+                    for subset in NoteSubset::ALL {
+                        ui.selectable_value(&mut step.subset, subset, subset.name());
+                    }
+                });
+            ui.label("Length:");
+            ui.add(
+                egui::DragValue::new(&mut step.length)
+                    .range(1..=STEPS_PER_BAR as u8)
+                    .suffix(" steps"),
+            );
+        });
+    });
+
+    if (state.comping, state.comp_pattern) != before {
+        let pattern = state.comping.then(|| Box::new(state.comp_pattern));
+        state.send_setting(sender, MidiMessage::UpdateComping(pattern));
+    }
+}
+
+fn load_tuning(scale_path: &str, mapping_path: &str) -> Result<Tuning, String> {
+    let read = |path: &str| {
+        std::fs::read_to_string(path).map_err(|err| format!("Could not read {}: {}", path, err))
+    };
+    let scale = ScalaScale::parse(&read(scale_path.trim())?)
+        .map_err(|err| format!("Invalid scale: {}", err))?;
+    let mapping = match mapping_path.trim() {
+        "" => KeyboardMapping::default(),
+        path => KeyboardMapping::parse(&read(path)?)
+            .map_err(|err| format!("Invalid keyboard mapping: {}", err))?,
+    };
+    Tuning::new(scale, mapping).map_err(|err| err.to_string())
+}
+
+/// Loads a Scala scale and keyboard mapping and hands the audio thread a table that retunes every
+/// note with pitch bend. Chords are still built on the keyboard, and each key then plays the scale
+/// degree the mapping gives it, so the grid's intervals become steps of the scale.
+fn tuning_view(ui: &mut egui::Ui, state: &mut GuiState, sender: &Sender<MidiMessage>) {
+    ui.heading("Tuning");
+    ui.label(
+        "Each note gets a channel of its own for its pitch bend. Without MPE the synth should use its \
+         default bend range of 2 semitones on every channel.",
+    );
+    ui.add_space(10.0);
+
+    egui::Grid::new("tuning_files")
+        .num_columns(2)
+        .show(ui, |ui| {
+            ui.label("Scale (.scl):");
+            ui.add(egui::TextEdit::singleline(&mut state.tuning.scale_path).desired_width(320.0));
+            ui.end_row();
+            ui.label("Mapping (.kbm):");
+            ui.add(
+                egui::TextEdit::singleline(&mut state.tuning.mapping_path)
+                    .hint_text("Tonic on middle C, A4 at 440 Hz")
+                    .desired_width(320.0),
+            );
+            ui.end_row();
+        });
+
+    ui.horizontal(|ui| {
+        if ui.button("Load").clicked() {
+            state.tuning.message = Some(
+                load_tuning(&state.tuning.scale_path, &state.tuning.mapping_path).and_then(
+                    |tuning| {
+                        let table = Box::new(tuning.retune_table());
+                        send_update(sender, MidiMessage::UpdateTuning(Some(table))).map_err(
+                            |err| format!("Loaded {}, but {}", tuning.scale.description, err),
+                        )?;
+                        let message = format!("Loaded {}", tuning.scale.description);
+                        state.tuning.loaded = Some(tuning);
+                        Ok(message)
                     },
+                ),
+            );
+        }
+        if ui
+            .add_enabled(state.tuning.loaded.is_some(), egui::Button::new("12-TET"))
+            .clicked()
+        {
+            state.tuning.message = Some(
+                send_update(sender, MidiMessage::UpdateTuning(None))
+                    .map(|()| {
+                        state.tuning.loaded = None;
+                        "Back to 12-tone equal temperament".to_string()
+                    })
+                    .map_err(|err| format!("Still retuned: {}", err)),
+            );
+        }
+    });
+
+    show_message(ui, &state.tuning.message);
+
+    let Some(tuning) = &state.tuning.loaded else {
+        return;
+    };
+    ui.add_space(10.0);
+    ui.label(format!(
+        "{} degrees per {:.2} cents",
+        tuning.scale.pitches.len(),
+        tuning.scale.period()
+    ));
+    egui::ScrollArea::vertical().show(ui, |ui| {
+        egui::Grid::new("tuning_degrees")
+            .num_columns(3)
+            .striped(true)
+            .show(ui, |ui| {
+                ui.strong("Degree");
+                ui.strong("Cents");
+                ui.strong("Nearest semitone");
+                ui.end_row();
+                for (degree, cents) in std::iter::once(0.0)
+                    .chain(tuning.scale.pitches.iter().copied())
+                    .enumerate()
+                {
+                    let semitones = (cents / 100.0).round();
+                    ui.label(degree.to_string());
+                    ui.label(format!("{:.2}", cents));
+                    ui.label(format!(
+                        "{} semitones {:+.1} cents",
+                        semitones,
+                        cents - semitones * 100.0
+                    ));
+                    ui.end_row();
+                }
+            });
+    });
+}
+
+fn custom_chords_view(ui: &mut egui::Ui, state: &mut GuiState, sender: &Sender<MidiMessage>) {
+    ui.heading("Custom Chord Types");
+    ui.label("Intervals are semitones above the root, for example `0 4 7 14` for an add9 chord.");
+    ui.add_space(10.0);
+
+    let mut removed = None;
+    egui::Grid::new("custom_chord_types")
+        .num_columns(5)
+        .spacing([20.0, 4.0])
+        .striped(true)
+        .show(ui, |ui| {
+            ui.strong("Key");
+            ui.strong("Suffix");
+            ui.strong("Intervals");
+            ui.end_row();
+
+            let vocabulary = state.vocabulary.clone();
+            for (chord_type, custom) in vocabulary.read().custom_chord_types() {
+                let intervals: Vec<String> = custom.intervals.iter().map(u8::to_string).collect();
+                ui.label(&custom.key);
+                ui.label(&custom.suffix);
+                ui.label(intervals.join(" "));
+                if ui.button("Edit").clicked() {
+                    state.custom_chord_form = CustomChordForm {
+                        key: custom.key.clone(),
+                        suffix: custom.suffix.clone(),
+                        intervals: intervals.join(" "),
+                        message: None,
+                    };
+                }
+                if ui.button("Remove").clicked() {
+                    removed = Some((chord_type, custom.key.clone()));
+                }
+                ui.end_row();
+            }
+        });
+
+    if let Some((chord_type, key)) = removed {
+        state.vocabulary.write().remove(chord_type);
+        state.custom_chord_form.message =
+            Some(commit_custom_chords(state, sender).map(|()| format!("Removed `{}`", key)));
+    }
+
+    ui.add_space(10.0);
+    ui.separator();
+
+    let form = &mut state.custom_chord_form;
+    let mut save = false;
+    egui::Grid::new("custom_chord_form")
+        .num_columns(2)
+        .show(ui, |ui| {
+            ui.label("Key:");
+            ui.text_edit_singleline(&mut form.key);
+            ui.end_row();
+            ui.label("Suffix:");
+            ui.text_edit_singleline(&mut form.suffix);
+            ui.end_row();
+            ui.label("Intervals:");
+            ui.text_edit_singleline(&mut form.intervals);
+            ui.end_row();
+            save = ui.button("Save").clicked();
+            ui.end_row();
+        });
+
+    if save {
+        let definition = parse_intervals(&form.intervals).map(|intervals| CustomChordType {
+            key: form.key.clone(),
+            suffix: form.suffix.clone(),
+            intervals,
+        });
+        let key = form.key.trim().to_string();
+        let result = definition
+            .and_then(|definition| state.vocabulary.write().register(definition))
+            .and_then(|_| commit_custom_chords(state, sender));
+        state.custom_chord_form.message = Some(result.map(|()| format!("Saved `{}`", key)));
+    }
+
+    show_message(ui, &state.custom_chord_form.message);
+}
+
+const CIRCLE_SEGMENT_STEPS: usize = 8;
+
+/// Draws the circle of fifths with major keys on the outer ring and their relative minors on the
+/// inner ring. Segment `i` holds the key `i` fifths above C, starting at the top and going
+/// clockwise.
+fn circle_of_fifths(
+    ui: &mut egui::Ui,
+    state: &mut GuiState,
+    sender: &Sender<MidiMessage>,
+    shared: &SharedState,
+) {
+    use std::f32::consts::{FRAC_PI_2, TAU};
+
+    let size = ui.available_size().min_elem().min(520.0);
+    let (response, painter) = ui.allocate_painter(
+        egui::vec2(ui.available_width(), size),
+        egui::Sense::click_and_drag(),
+    );
+    let center = response.rect.center();
+    let outer_radius = size / 2.0 - 4.0;
+    let middle_radius = outer_radius * 0.68;
+    let inner_radius = outer_radius * 0.36;
+    let segment_angle = TAU / 12.0;
+
+    let polar = |radius: f32, angle: f32| center + radius * egui::vec2(angle.cos(), angle.sin());
+    let segment_at = |pos: egui::Pos2| {
+        let offset = pos - center;
+        let distance = offset.length();
+        if distance < inner_radius || distance > outer_radius {
+            return None;
+        }
+        let angle = (offset.y.atan2(offset.x) + FRAC_PI_2 + segment_angle / 2.0).rem_euclid(TAU);
+        let index = (angle / segment_angle) as usize % 12;
+        Some((index, distance >= middle_radius))
+    };
+    let chord_at = |(index, outer): (usize, bool)| {
+        let major_root = PitchClass::new((index * 7 % 12) as u8);
+        if outer {
+            ChordId::new(major_root, ChordType::Major)
+        } else {
+            ChordId::new(major_root.transpose(9), ChordType::Minor)
+        }
+    };
+
+    let hovered = response.hover_pos().and_then(segment_at);
+    let stroke = ui.visuals().widgets.noninteractive.bg_stroke;
+
+    for index in 0..12 {
+        for outer in [true, false] {
+            let chord_id = chord_at((index, outer));
+            let scale_type = if outer {
+                ScaleType::Major
+            } else {
+                ScaleType::Minor
+            };
+            let is_current_key = state.scale == Scale::new(chord_id.root, scale_type);
+
+            let fill = if shared.is_chord_sounding(chord_id) {
+                egui::Color32::from_rgb(100, 200, 100)
+            } else if is_current_key {
+                egui::Color32::from_rgb(100, 150, 255)
+            } else if hovered == Some((index, outer)) {
+                ui.visuals().widgets.hovered.bg_fill
+            } else if outer {
+                ui.visuals().widgets.inactive.bg_fill
+            } else {
+                ui.visuals().widgets.noninteractive.bg_fill
+            };
+
+            let (r0, r1) = if outer {
+                (middle_radius, outer_radius)
+            } else {
+                (inner_radius, middle_radius)
+            };
+            let start = -FRAC_PI_2 + (index as f32 - 0.5) * segment_angle;
+            let step = segment_angle / CIRCLE_SEGMENT_STEPS as f32;
+
+            // Annular sectors aren't convex, so each one is built from thin convex slices
+            for i in 0..CIRCLE_SEGMENT_STEPS {
+                let a0 = start + i as f32 * step;
+                let a1 = a0 + step;
+                painter.add(egui::Shape::convex_polygon(
+                    vec![polar(r0, a0), polar(r1, a0), polar(r1, a1), polar(r0, a1)],
+                    fill,
+                    egui::Stroke::NONE,
+                ));
+            }
+            painter.line_segment([polar(r0, start), polar(r1, start)], stroke);
+
+            painter.text(
+                polar((r0 + r1) / 2.0, start + segment_angle / 2.0),
+                egui::Align2::CENTER_CENTER,
+                chord_label(state, chord_id),
+                egui::FontId::proportional(if outer { 16.0 } else { 13.0 }),
+                ui.visuals().text_color(),
+            );
+        }
+    }
+
+    for radius in [inner_radius, middle_radius, outer_radius] {
+        painter.circle_stroke(center, radius, stroke);
+    }
+
+    if response.is_pointer_button_down_on()
+        && ui.input(|i| i.pointer.primary_down())
+        && let Some(segment) = response.interact_pointer_pos().and_then(segment_at)
+    {
+        let chord_id = chord_at(segment);
+        if state.playing_chord != Some(chord_id) {
+            state.playing_chord = Some(chord_id);
+            state.last_chord = Some(chord_id);
+            let _ = sender.try_send(MidiMessage::ChordOn(chord_id));
+        }
+    }
+
+    if response.secondary_clicked()
+        && let Some((index, outer)) = response.interact_pointer_pos().and_then(segment_at)
+    {
+        let scale_type = if outer {
+            ScaleType::Major
+        } else {
+            ScaleType::Minor
+        };
+        state.scale = Scale::new(chord_at((index, outer)).root, scale_type);
+        apply_scale(state, sender);
+    }
+}
+
+const KEYBOARD_KEYS: u8 = 61;
+const WHITE_KEY_CLASSES: [u8; 7] = [0, 2, 4, 5, 7, 9, 11];
+
+/// Draws a five octave keyboard starting one octave below the current one, colouring sounding notes by
+/// their role in the chord. Pressing a key auditions that single note until the mouse is released.
+fn piano_keyboard(
+    ui: &mut egui::Ui,
+    state: &mut GuiState,
+    sender: &Sender<MidiMessage>,
+    sounding_notes: &SoundingNotes,
+) {
+    let first_note = (12 * state.octave as i32).clamp(0, 128 - KEYBOARD_KEYS as i32) as u8;
+    let is_white = |note: u8| WHITE_KEY_CLASSES.contains(&(note % 12));
+    let white_count = (first_note..first_note + KEYBOARD_KEYS)
+        .filter(|&note| is_white(note))
+        .count();
+
+    let (response, painter) = ui.allocate_painter(
+        egui::vec2(ui.available_width(), 72.0),
+        egui::Sense::click_and_drag(),
+    );
+    let rect = response.rect;
+    let white_width = rect.width() / white_count as f32;
+    let black_size = egui::vec2(white_width * 0.6, rect.height() * 0.6);
+
+    let mut white_keys = Vec::new();
+    let mut black_keys = Vec::new();
+    for note in first_note..first_note + KEYBOARD_KEYS {
+        let left = rect.left() + white_keys.len() as f32 * white_width;
+        if is_white(note) {
+            let key_rect = egui::Rect::from_min_size(
+                egui::pos2(left, rect.top()),
+                egui::vec2(white_width, rect.height()),
+            );
+            white_keys.push((note, key_rect));
+        } else {
+            let key_rect = egui::Rect::from_min_size(
+                egui::pos2(left - black_size.x / 2.0, rect.top()),
+                black_size,
+            );
+            black_keys.push((note, key_rect));
+        }
+    }
+
+    let stroke = egui::Stroke::new(1.0, egui::Color32::from_gray(80));
+    for &(note, key_rect) in &white_keys {
+        let fill = sounding_notes
+            .role(note)
+            .map(role_color)
+            .unwrap_or(egui::Color32::from_gray(235));
+        painter.rect_filled(key_rect, 0.0, fill);
+        painter.rect_stroke(key_rect, 0.0, stroke, egui::StrokeKind::Inside);
+        if note % 12 == 0 {
+            painter.text(
+                key_rect.center_bottom() - egui::vec2(0.0, 4.0),
+                egui::Align2::CENTER_BOTTOM,
+                format!("C{}", note as i32 / 12 - 1),
+                egui::FontId::proportional(10.0),
+                egui::Color32::from_gray(80),
+            );
+        }
+    }
+    for &(note, key_rect) in &black_keys {
+        let fill = sounding_notes
+            .role(note)
+            .map(role_color)
+            .unwrap_or(egui::Color32::from_gray(30));
+        painter.rect_filled(key_rect, 0.0, fill);
+        painter.rect_stroke(key_rect, 0.0, stroke, egui::StrokeKind::Inside);
+    }
+
+    let key_at = |pos: egui::Pos2| {
+        black_keys
+            .iter()
+            .chain(white_keys.iter())
+            .find(|(_, key_rect)| key_rect.contains(pos))
+            .map(|&(note, _)| note)
+    };
+
+    let pressed_note =
+        if response.is_pointer_button_down_on() && ui.input(|i| i.pointer.primary_down()) {
+            response.interact_pointer_pos().and_then(key_at)
+        } else {
+            None
+        };
+    if pressed_note != state.auditioning_note {
+        let _ = match pressed_note {
+            Some(note) => sender.try_send(MidiMessage::AuditionNoteOn(note)),
+            None => sender.try_send(MidiMessage::AuditionNoteOff),
+        };
+        state.auditioning_note = pressed_note;
+    }
+
+    // The audio thread only publishes note changes, so keep redrawing until they have settled
+    if !sounding_notes.is_empty() {
+        ui.ctx().request_repaint();
+    }
+}
+
+const SHAPE_LIMIT: usize = 12;
+
+/// Lists playable guitar shapes for the selected chord. Pressing a diagram sends exactly the
+/// pitches of that fingering rather than the chord table voicing.
+fn fretboard_view(
+    ui: &mut egui::Ui,
+    state: &mut GuiState,
+    sender: &Sender<MidiMessage>,
+    chord_table: &ChordTable,
+) {
+    let vocabulary = state.vocabulary.read();
+    let fretboard = &mut state.fretboard;
+
+    ui.horizontal(|ui| {
+        ui.label("Tuning:");
+        egui::ComboBox::from_id_salt("tuning_picker")
+            .selected_text(TUNINGS[fretboard.tuning].name)
+            .show_ui(ui, |ui| {
+                for (index, tuning) in TUNINGS.iter().enumerate() {
+                    ui.selectable_value(&mut fretboard.tuning, index, tuning.name);
+                }
+            });
+
+        ui.label("Chord:");
+        egui::ComboBox::from_id_salt("fretboard_root_picker")
+            .selected_text(fretboard.chord.root.name())
+            .show_ui(ui, |ui| {
+                for root in (0..12).map(PitchClass::new) {
+                    ui.selectable_value(&mut fretboard.chord.root, root, root.name());
+                }
+            });
+        egui::ComboBox::from_id_salt("fretboard_type_picker")
+            .selected_text(vocabulary.key(fretboard.chord.chord_type))
+            .show_ui(ui, |ui| {
+                for chord_type in vocabulary.grid_rows() {
+                    ui.selectable_value(
+                        &mut fretboard.chord.chord_type,
+                        chord_type,
+                        vocabulary.key(chord_type),
+                    );
+                }
+            });
+
+        ui.add_space(20.0);
+        ui.label("Max span:");
+        ui.add(egui::DragValue::new(&mut fretboard.options.max_span).range(2..=6));
+        ui.checkbox(&mut fretboard.options.prefer_open, "Prefer open strings");
+    });
+
+    ui.separator();
+
+    let shapes_for = (fretboard.chord, fretboard.tuning, fretboard.options);
+    if fretboard.shapes_for != Some(shapes_for) {
+        let root = fretboard.chord.root.semitones();
+        let pitch_classes: Vec<u8> = chord_table
+            .get(&fretboard.chord)
+            .and_then(|voicing| voicing.inversions.first())
+            .map(|notes| notes.iter().map(|note| note % 12).collect())
+            .unwrap_or_default();
+        fretboard.shapes = if pitch_classes.is_empty() {
+            Vec::new()
+        } else {
+            theory::guitar::find_shapes(
+                &TUNINGS[fretboard.tuning],
+                root,
+                &pitch_classes,
+                &fretboard.options,
+                SHAPE_LIMIT,
+            )
+        };
+        fretboard.shapes_for = Some(shapes_for);
+    }
+
+    if fretboard.shapes.is_empty() {
+        ui.label("No playable shapes for this chord with the current settings.");
+        return;
+    }
+
+    let mut pressed_shape = None;
+    egui::ScrollArea::vertical().show(ui, |ui| {
+        ui.horizontal_wrapped(|ui| {
+            for shape in &fretboard.shapes {
+                let is_playing = fretboard.playing_shape == Some(*shape);
+                let response = chord_shape_diagram(ui, shape, is_playing);
+                if response.is_pointer_button_down_on() && !is_playing {
+                    pressed_shape = Some(*shape);
+                }
+            }
+        });
+    });
+
+    if let Some(shape) = pressed_shape {
+        let notes = ChordNotes::from_slice(&shape.notes(&TUNINGS[fretboard.tuning]));
+        let chord_id = fretboard.chord;
+        fretboard.playing_shape = Some(shape);
+        state.playing_chord = Some(chord_id);
+        let _ = sender.try_send(MidiMessage::VoicingOn(chord_id, notes));
+    }
+}
+
+fn chord_shape_diagram(ui: &mut egui::Ui, shape: &ChordShape, is_playing: bool) -> egui::Response {
+    let (response, painter) =
+        ui.allocate_painter(egui::vec2(110.0, 140.0), egui::Sense::click_and_drag());
+    let rect = response.rect;
+    let fill = if is_playing {
+        egui::Color32::from_rgb(100, 200, 100)
+    } else if response.hovered() {
+        ui.visuals().widgets.hovered.bg_fill
+    } else {
+        ui.visuals().widgets.inactive.bg_fill
+    };
+    painter.rect_filled(rect, 4.0, fill);
+
+    let color = ui.visuals().text_color();
+    let stroke = egui::Stroke::new(1.0, color);
+    let base_fret = shape.base_fret();
+    let rows = shape
+        .frets
+        .iter()
+        .flatten()
+        .map(|&fret| fret.saturating_sub(base_fret) + 1)
+        .max()
+        .unwrap_or(1)
+        .max(5);
+
+    let left = rect.left() + 20.0;
+    let top = rect.top() + 24.0;
+    let string_gap = 14.0;
+    let fret_gap = 108.0 / rows as f32;
+    let string_x = |string: usize| left + string as f32 * string_gap;
+
+    for string in 0..6 {
+        let x = string_x(string);
+        painter.line_segment(
+            [
+                egui::pos2(x, top),
+                egui::pos2(x, top + rows as f32 * fret_gap),
+            ],
+            stroke,
+        );
+    }
+    for row in 0..=rows {
+        let y = top + row as f32 * fret_gap;
+        let width = if row == 0 && base_fret == 1 { 3.0 } else { 1.0 };
+        painter.line_segment(
+            [egui::pos2(left, y), egui::pos2(string_x(5), y)],
+            egui::Stroke::new(width, color),
+        );
+    }
+    if base_fret > 1 {
+        painter.text(
+            egui::pos2(left - 6.0, top + fret_gap / 2.0),
+            egui::Align2::RIGHT_CENTER,
+            base_fret.to_string(),
+            egui::FontId::proportional(11.0),
+            color,
+        );
+    }
+
+    for (string, fret) in shape.frets.iter().enumerate() {
+        let x = string_x(string);
+        match fret {
+            None => {
+                painter.text(
+                    egui::pos2(x, top - 10.0),
+                    egui::Align2::CENTER_CENTER,
+                    "×",
+                    egui::FontId::proportional(12.0),
+                    color,
                 );
             }
+            Some(0) => {
+                painter.circle_stroke(egui::pos2(x, top - 10.0), 4.0, stroke);
+            }
+            Some(fret) => {
+                let y = top + (fret - base_fret) as f32 * fret_gap + fret_gap / 2.0;
+                painter.circle_filled(egui::pos2(x, y), 5.0, color);
+            }
         }
     }
-    mappings
+
+    response
+}
+
+/// The name shown for a chord, moved to the pitch it sounds at when the editor is set to show
+/// sounding pitch.
+fn chord_label(state: &GuiState, chord_id: ChordId) -> String {
+    let chord_id = if state.show_sounding_pitch {
+        chord_id.transpose(state.transpose)
+    } else {
+        chord_id
+    };
+    chord_id.display(&state.vocabulary.read()).to_string()
+}
+
+/// How many suggested next chords are highlighted.
+const MAX_SUGGESTIONS: usize = 5;
+
+/// Cell colour for the suggestion at `rank`, fading from the best suggestion to the last.
+fn suggestion_color(rank: usize) -> egui::Color32 {
+    egui::Color32::from_rgb(230, 160, 40)
+        .gamma_multiply(1.0 - rank as f32 / MAX_SUGGESTIONS as f32 * 0.7)
+}
+
+fn refresh_suggestions(state: &mut GuiState) {
+    state.suggestions.clear();
+    if state.suggest_next
+        && let Some(last_chord) = state.last_chord
+    {
+        let model = state.learned_suggestions.then(theory::MarkovModel::builtin);
+        state.suggestions.extend(
+            theory::suggest_next_chords(last_chord, state.scale, model)
+                .into_iter()
+                .take(MAX_SUGGESTIONS)
+                .map(|suggestion| suggestion.chord),
+        );
+    }
+}
+
+fn chord_cell(
+    ui: &mut egui::Ui,
+    state: &mut GuiState,
+    sender: &Sender<MidiMessage>,
+    shared: &SharedState,
+    chord_id: ChordId,
+    width: f32,
+    style: CellStyle,
+) {
+    let is_inversion_target = state.inversion_chord == Some(chord_id);
+    let suggestion_rank = state
+        .suggestions
+        .iter()
+        .position(|&suggestion| suggestion == chord_id);
+
+    let button_color = if shared.is_chord_sounding(chord_id) {
+        egui::Color32::from_rgb(100, 200, 100)
+    } else if is_inversion_target {
+        egui::Color32::from_rgb(100, 150, 255)
+    } else if let Some(rank) = suggestion_rank {
+        suggestion_color(rank)
+    } else {
+        match style {
+            CellStyle::Diatonic => ui.visuals().widgets.inactive.bg_fill,
+            CellStyle::Extended => ui.visuals().widgets.noninteractive.bg_fill,
+            CellStyle::OutOfKey => ui
+                .visuals()
+                .widgets
+                .noninteractive
+                .bg_fill
+                .gamma_multiply(0.5),
+        }
+    };
+
+    let text = if style == CellStyle::OutOfKey {
+        egui::RichText::new(chord_label(state, chord_id)).weak()
+    } else {
+        egui::RichText::new(chord_label(state, chord_id))
+    };
+    let button = egui::Button::new(text)
+        .min_size(egui::vec2(width, 0.0))
+        .fill(button_color);
+    let mut response = ui.add(button);
+    if let Some(rank) = suggestion_rank {
+        response = response.on_hover_text(format!("Suggestion #{}", rank + 1));
+    }
+
+    if response.is_pointer_button_down_on() {
+        if ui.input(|i| i.modifiers.ctrl) {
+            state.inversion_chord = Some(chord_id);
+            state.send_setting(sender, MidiMessage::SetInversionChord(chord_id));
+        } else if state.playing_chord != Some(chord_id) {
+            state.playing_chord = Some(chord_id);
+            state.last_chord = Some(chord_id);
+            let _ = sender.try_send(MidiMessage::ChordOn(chord_id));
+        }
+    }
+}
+
+/// Pixels of vertical drag for full pressure.
+const PRESSURE_DRAG_RANGE: f32 = 150.0;
+
+/// While a chord is held with the mouse, dragging up from where it was pressed raises the
+/// pressure of its notes, and dragging back down lowers it again.
+fn update_drag_pressure(ctx: &egui::Context, state: &mut GuiState, sender: &Sender<MidiMessage>) {
+    let drag = ctx.input(|i| {
+        let (origin, position) = i.pointer.press_origin().zip(i.pointer.interact_pos())?;
+        i.pointer.primary_down().then_some(origin.y - position.y)
+    });
+    let pressure = match (state.playing_chord, drag) {
+        (Some(_), Some(drag)) => (drag / PRESSURE_DRAG_RANGE).clamp(0.0, 1.0),
+        _ => 0.0,
+    };
+    if pressure != state.held_pressure {
+        state.held_pressure = pressure;
+        if state.playing_chord.is_some() {
+            let _ = sender.try_send(MidiMessage::MousePressure(pressure));
+        }
+    }
+}
+
+fn mapping_cell(
+    ui: &mut egui::Ui,
+    state: &mut GuiState,
+    sender: &Sender<MidiMessage>,
+    key: egui::Key,
+    chord_id: ChordId,
+    width: f32,
+) {
+    let button = egui::Button::new(chord_label(state, chord_id)).min_size(egui::vec2(width, 0.0));
+
+    if ui.add(button).clicked() {
+        state.send_setting(sender, MidiMessage::UpdateKeyMapping(key, chord_id));
+        state.key_mappings.insert(key, chord_id);
+        state.key_to_map = None;
+    }
+}
+
+/// Lays out each chromatic section as a header row of Roman numerals above a row of chord cells.
+/// Chords missing from the chord table are left blank so the columns stay aligned.
+fn chord_sections_grid(
+    ui: &mut egui::Ui,
+    id_salt: &str,
+    sections: &[ChordSection],
+    chord_table: &ChordTable,
+    mut cell: impl FnMut(&mut egui::Ui, ChordId, f32),
+) {
+    for section in sections {
+        ui.add_space(10.0);
+        ui.strong(section.title);
+        egui::Grid::new((id_salt, section.title)).show(ui, |ui| {
+            for chord in &section.chords {
+                ui.label(&chord.degree);
+            }
+            ui.end_row();
+
+            for chord in &section.chords {
+                if chord_table.contains_key(&chord.chord) {
+                    let width = ui.available_width() / section.chords.len() as f32;
+                    cell(ui, chord.chord, width);
+                } else {
+                    ui.label("");
+                }
+            }
+            ui.end_row();
+        });
+    }
 }
 
 impl Default for PerfectChordsParams {
     fn default() -> Self {
         Self {
             editor_state: EguiState::from_size(800, 600),
+            transpose: IntParam::new("Transpose", 0, IntRange::Linear { min: -24, max: 24 })
+                .with_unit(" st"),
+            mpe: BoolParam::new("MPE Output", false),
+            osc: Mutex::new(OscSettings::default()),
         }
     }
 }
@@ -212,53 +2307,88 @@ impl Plugin for PerfectChords {
     }];
 
     const MIDI_INPUT: MidiConfig = MidiConfig::None;
-    const MIDI_OUTPUT: MidiConfig = MidiConfig::Basic;
+    const MIDI_OUTPUT: MidiConfig = MidiConfig::MidiCCs;
     const SAMPLE_ACCURATE_AUTOMATION: bool = true;
 
-    type SysExMessage = ();
+    type SysExMessage = HarmonyMessage;
     type BackgroundTask = ();
 
     fn params(&self) -> Arc<dyn Params> {
         self.params.clone()
     }
 
+    fn initialize(
+        &mut self,
+        _audio_io_layout: &AudioIOLayout,
+        _buffer_config: &BufferConfig,
+        _context: &mut impl InitContext<Self>,
+    ) -> bool {
+        // The OSC settings have been restored by now. A busy port shouldn't keep the plugin from
+        // loading, so failures are only logged and shown in the editor.
+        let settings = *self
+            .params
+            .osc
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        if let Err(err) = self.osc.apply(settings) {
+            log::warn!(
+                "Could not start the OSC listener on port {}: {}",
+                settings.port,
+                err
+            );
+        }
+        true
+    }
+
+    fn deactivate(&mut self) {
+        // Started again by `initialize` when the plugin is reactivated
+        self.osc.stop();
+        while self.gui_event_receiver.try_recv().is_ok() {}
+        self.retired.clear();
+    }
+
     fn editor(&mut self, _async_executor: AsyncExecutor<Self>) -> Option<Box<dyn Editor>> {
+        // Events queued while no editor was open are older than the state cloned below
+        while self.gui_event_receiver.try_recv().is_ok() {}
+        self.retired.clear();
+
         let sender = self.midi_sender.clone();
-        let initial_state = self.state.clone();
-        let chord_table = self.chord_table.clone();
+        let gui_events = self.gui_event_receiver.clone();
+        let mut initial_state = self.state.clone();
+        initial_state.presets.refresh();
         let scale_map = self.scale_map.clone();
+        let shared = self.shared.clone();
+        let params = self.params.clone();
+        let osc = self.osc.clone();
 
         create_egui_editor(
             self.params.editor_state.clone(),
             initial_state,
             |_, _| {},
-            move |egui_ctx, _setter, state| {
-                let grid_rows: Vec<(&str, &str)> = vec![
-                    ("maj", ""),
-                    ("m", "m"),
-                    ("5", "5"),
-                    ("sus2", "sus2"),
-                    ("sus4", "sus4"),
-                    ("6", "6"),
-                    ("m6", "m6"),
-                    ("7", "7"),
-                    ("m7", "m7"),
-                    ("maj7", "maj7"),
-                    ("dim", "dim"),
-                    ("aug", "aug"),
-                    ("9", "9"),
-                    ("m9", "m9"),
-                    ("maj9", "maj9"),
-                    ("flat5", "5-"),
-                    ("m7b5", "m7b5"),
-                    ("dim7", "dim7"),
-                ];
-
-                let scale = format!("{} {}", state.root_note, state.scale_type);
-                let diatonics = scale_map.get(&scale).cloned().unwrap_or_default();
+            move |egui_ctx, setter, state| {
+                let chord_table = state.chord_table.clone();
+                state.transpose = params.transpose.value();
+                let grid_rows = state.vocabulary.read().grid_rows();
+                while let Ok(event) = gui_events.try_recv() {
+                    apply_gui_event(state, event);
+                }
+
+                let columns: Vec<GridColumn> = if state.chromatic_grid {
+                    get_chromatic_columns(&scale_map, state.scale)
+                } else {
+                    get_diatonic_columns(&scale_map, state.scale)
+                };
+                let sections = get_extended_sections(&scale_map, state.scale);
+                refresh_suggestions(state);
 
+                // Typing into a text field shouldn't play chords
+                let typing = egui_ctx.wants_keyboard_input();
                 egui_ctx.input(|i| {
-                    let current_keys_down: HashSet<egui::Key> = i.keys_down.iter().copied().collect();
+                    let current_keys_down: HashSet<egui::Key> = if typing {
+                        HashSet::new()
+                    } else {
+                        i.keys_down.iter().copied().collect()
+                    };
 
                     // Keys that were just pressed
                     let newly_pressed_keys: HashSet<egui::Key> = current_keys_down
@@ -267,37 +2397,55 @@ impl Plugin for PerfectChords {
                         .collect();
 
                     // Keys that were just released
-                    let newly_released_keys: HashSet<egui::Key> = state.playing_keys
+                    let newly_released_keys: HashSet<egui::Key> = state
+                        .playing_keys
                         .difference(&current_keys_down)
                         .copied()
                         .collect();
 
                     for key in &newly_pressed_keys {
-                        info!("Editor: Sending KeyChordOn for key: {:?}", key);
-                        let _ = sender.send(MidiMessage::KeyChordOn(*key));
+                        let _ = sender.try_send(MidiMessage::KeyChordOn(*key));
+                        if let Some(&chord_id) = state.key_mappings.get(key) {
+                            state.last_chord = Some(chord_id);
+                        }
                     }
 
                     for key in &newly_released_keys {
-                        info!("Editor: Sending KeyChordOff for key: {:?}", key);
-                        let _ = sender.send(MidiMessage::KeyChordOff(*key));
+                        let _ = sender.try_send(MidiMessage::KeyChordOff(*key));
                     }
 
                     // Update the state.playing_keys to reflect the current keys down
                     state.playing_keys = current_keys_down;
+                });
 
-                    if !newly_pressed_keys.is_empty() || !newly_released_keys.is_empty() {
-                        info!("Editor: Sending UpdatePlayingKeys: {:?}", state.playing_keys);
-                        let _ = sender.send(MidiMessage::UpdatePlayingKeys(state.playing_keys.clone()));
-                    }
+                egui::TopBottomPanel::top("preset_bar").show(egui_ctx, |ui| {
+                    preset_bar(ui, state, &sender, setter, &params);
+                });
+
+                egui::TopBottomPanel::bottom("piano_keyboard").show(egui_ctx, |ui| {
+                    piano_keyboard(ui, state, &sender, &shared.sounding_notes);
                 });
 
                 egui::CentralPanel::default().show(egui_ctx, |ui| {
                     ui.style_mut().spacing.button_padding = egui::vec2(4.0, 4.0);
                     ui.style_mut().spacing.item_spacing = egui::vec2(2.0, 2.0);
 
+                    if let Some(error) = &state.chord_data_error {
+                        ui.colored_label(
+                            ui.visuals().error_fg_color,
+                            format!("Using computed chords because the built-in chord data is invalid: {}", error),
+                        );
+                    }
+
                     ui.horizontal(|ui| {
                         ui.selectable_value(&mut state.view_mode, ViewMode::ChordGrid, "Chord Grid");
+                        ui.selectable_value(&mut state.view_mode, ViewMode::CircleOfFifths, "Circle of Fifths");
+                        ui.selectable_value(&mut state.view_mode, ViewMode::Fretboard, "Fretboard");
                         ui.selectable_value(&mut state.view_mode, ViewMode::KeyMapping, "Key Mapping");
+                        ui.selectable_value(&mut state.view_mode, ViewMode::CustomChords, "Custom Chords");
+                        ui.selectable_value(&mut state.view_mode, ViewMode::Progression, "Progression");
+                        ui.selectable_value(&mut state.view_mode, ViewMode::Rhythm, "Rhythm");
+                        ui.selectable_value(&mut state.view_mode, ViewMode::Tuning, "Tuning");
                     });
 
                     ui.separator();
@@ -307,92 +2455,173 @@ impl Plugin for PerfectChords {
                             ui.horizontal(|ui| {
                                 ui.label("Root Note:");
                                 egui::ComboBox::from_id_salt("root_note_picker")
-                                    .selected_text(&state.root_note)
+                                    .selected_text(state.scale.root.name())
                                     .show_ui(ui, |ui| {
-                                        for note in ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"].iter() {
+                                        for root in (0..12).map(PitchClass::new) {
                                             if ui
-                                                .selectable_value(
-                                                    &mut state.root_note,
-                                                    note.to_string(),
-                                                    *note,
-                                                )
+                                                .selectable_value(&mut state.scale.root, root, root.name())
                                                 .clicked()
                                             {
-                                                let new_scale = format!("{} {}", state.root_note, state.scale_type);
-                                                state.key_mappings = generate_default_key_mappings(&scale_map, new_scale.clone());
-                                                let _ = sender.send(MidiMessage::UpdateScale(new_scale));
+                                                apply_scale(state, &sender);
                                             }
                                         }
                                     });
 
                                 ui.label("Scale Type:");
                                 egui::ComboBox::from_id_salt("scale_type_picker")
-                                    .selected_text(&state.scale_type)
+                                    .selected_text(state.scale.scale_type.name())
                                     .show_ui(ui, |ui| {
-                                        for scale_type in ["Major", "Minor"].iter() {
+                                        for scale_type in ScaleType::ALL {
                                             if ui
                                                 .selectable_value(
-                                                    &mut state.scale_type,
-                                                    scale_type.to_string(),
-                                                    *scale_type,
+                                                    &mut state.scale.scale_type,
+                                                    scale_type,
+                                                    scale_type.name(),
                                                 )
                                                 .clicked()
                                             {
-                                                let new_scale = format!("{} {}", state.root_note, state.scale_type);
-                                                state.key_mappings = generate_default_key_mappings(&scale_map, new_scale.clone());
-                                                let _ = sender.send(MidiMessage::UpdateScale(new_scale));
+                                                apply_scale(state, &sender);
                                             }
                                         }
                                     });
 
+                                ui.add_space(20.0);
+                                ui.checkbox(&mut state.chromatic_grid, "Chromatic");
+
                                 ui.add_space(20.0);
                                 ui.label("Octave:");
-                                if ui.button("◀").clicked() {
+                                if ui
+                                    .add_enabled(state.octave > *OCTAVE_RANGE.start(), egui::Button::new("◀"))
+                                    .clicked()
+                                {
                                     state.octave -= 1;
-                                    let _ = sender.send(MidiMessage::UpdateOctave(state.octave));
+                                    state.send_setting(&sender, MidiMessage::UpdateOctave(state.octave));
                                 }
-                                ui.label(format!("{}", state.octave));
-                                if ui.button("▶").clicked() {
+                                ui.label(format!("{}", state.octave)).on_hover_text(format!(
+                                    "Octaves {} to {}",
+                                    OCTAVE_RANGE.start(),
+                                    OCTAVE_RANGE.end()
+                                ));
+                                if ui
+                                    .add_enabled(state.octave < *OCTAVE_RANGE.end(), egui::Button::new("▶"))
+                                    .clicked()
+                                {
                                     state.octave += 1;
-                                    let _ = sender.send(MidiMessage::UpdateOctave(state.octave));
+                                    state.send_setting(&sender, MidiMessage::UpdateOctave(state.octave));
                                 }
 
+                                ui.label("Out of range:");
+                                egui::ComboBox::from_id_salt("note_range_policy_picker")
+                                    .selected_text(state.note_range_policy.name())
+                                    .show_ui(ui, |ui| {
+                                        for policy in NoteRangePolicy::ALL {
+                                            if ui
+                                                .selectable_value(&mut state.note_range_policy, policy, policy.name())
+                                                .clicked()
+                                            {
+                                                state.send_setting(&sender, MidiMessage::UpdateNoteRangePolicy(policy));
+                                            }
+                                        }
+                                    });
+
+                                ui.add_space(20.0);
+                                ui.label("Transpose:");
+                                let mut transpose = state.transpose;
+                                let response = ui.add(
+                                    egui::DragValue::new(&mut transpose)
+                                        .range(-24..=24)
+                                        .suffix(" st"),
+                                );
+                                if response.drag_started() {
+                                    setter.begin_set_parameter(&params.transpose);
+                                }
+                                if response.changed() {
+                                    if !response.dragged() {
+                                        setter.begin_set_parameter(&params.transpose);
+                                    }
+                                    setter.set_parameter(&params.transpose, transpose);
+                                    if !response.dragged() {
+                                        setter.end_set_parameter(&params.transpose);
+                                    }
+                                    state.transpose = transpose;
+                                }
+                                if response.drag_stopped() {
+                                    setter.end_set_parameter(&params.transpose);
+                                }
+                                ui.checkbox(&mut state.show_sounding_pitch, "Sounding pitch")
+                                    .on_hover_text("Label chords at the transposed pitch instead of as written");
+
+                                ui.add_space(20.0);
+                                let mut mpe = params.mpe.value();
+                                if ui
+                                    .checkbox(&mut mpe, "MPE")
+                                    .on_hover_text("Send each note on its own channel. Drag up while holding a chord for pressure.")
+                                    .changed()
+                                {
+                                    setter.begin_set_parameter(&params.mpe);
+                                    setter.set_parameter(&params.mpe, mpe);
+                                    setter.end_set_parameter(&params.mpe);
+                                }
+
+                                ui.label("Harmony out:");
+                                egui::ComboBox::from_id_salt("harmony_output_picker")
+                                    .selected_text(state.harmony_output.name())
+                                    .show_ui(ui, |ui| {
+                                        for output in HarmonyOutput::ALL {
+                                            if ui
+                                                .selectable_value(&mut state.harmony_output, output, output.name())
+                                                .on_hover_text("Tell plugins further down the chain the key and chord")
+                                                .clicked()
+                                            {
+                                                state.send_setting(&sender, MidiMessage::UpdateHarmonyOutput(output));
+                                            }
+                                        }
+                                    });
+
                                 ui.add_space(20.0);
                                 ui.label("Inversion:");
-                                let current_inversion = state.inversion_chord.as_ref()
-                                    .and_then(|chord_id| state.inversion_map.get(chord_id))
+                                let current_inversion = state.inversion_chord
+                                    .and_then(|chord_id| state.inversion_map.get(&chord_id))
                                     .copied()
                                     .unwrap_or(0);
 
-                                if ui.button("◀").clicked() {
-                                    if let Some(chord_id) = state.inversion_chord.clone() {
-                                        let voicing = chord_table
-                                            .get(&chord_id.root_note)
-                                            .and_then(|v| v.get(&chord_id.chord_type));
-                                        if let Some(voicing) = voicing {
-                                            let num_inversions = voicing.inversions.len() as u8;
-                                            if num_inversions > 0 {
-                                                let new_inversion = (current_inversion + num_inversions - 1) % num_inversions;
-                                                state.inversion_map.insert(chord_id.clone(), new_inversion);
-                                                let _ = sender.send(MidiMessage::UpdateInversion(chord_id, new_inversion));
-                                            }
-                                        }
+                                if ui.button("◀").clicked()
+                                    && let Some(chord_id) = state.inversion_chord
+                                {
+                                    let num_inversions = theory::inversion_count(&chord_table, &chord_id) as u8;
+                                    if num_inversions > 0 {
+                                        let new_inversion = (current_inversion + num_inversions - 1) % num_inversions;
+                                        state.inversion_map.insert(chord_id, new_inversion);
+                                        state.send_setting(&sender, MidiMessage::UpdateInversion(chord_id, new_inversion));
                                     }
                                 }
                                 ui.label(format!("{}", current_inversion));
-                                if ui.button("▶").clicked() {
-                                    if let Some(chord_id) = state.inversion_chord.clone() {
-                                        let voicing = chord_table
-                                            .get(&chord_id.root_note)
-                                            .and_then(|v| v.get(&chord_id.chord_type));
-                                        if let Some(voicing) = voicing {
-                                            let num_inversions = voicing.inversions.len() as u8;
-                                            if num_inversions > 0 {
-                                                let new_inversion = (current_inversion + 1) % num_inversions;
-                                                state.inversion_map.insert(chord_id.clone(), new_inversion);
-                                                let _ = sender.send(MidiMessage::UpdateInversion(chord_id, new_inversion));
-                                            }
-                                        }
+                                if ui.button("▶").clicked()
+                                    && let Some(chord_id) = state.inversion_chord
+                                {
+                                    let num_inversions = theory::inversion_count(&chord_table, &chord_id) as u8;
+                                    if num_inversions > 0 {
+                                        let new_inversion = (current_inversion + 1) % num_inversions;
+                                        state.inversion_map.insert(chord_id, new_inversion);
+                                        state.send_setting(&sender, MidiMessage::UpdateInversion(chord_id, new_inversion));
+                                    }
+                                }
+                            });
+
+                            ui.horizontal(|ui| {
+                                ui.checkbox(&mut state.suggest_next, "Suggest next chord");
+                                ui.add_enabled(
+                                    state.suggest_next,
+                                    egui::Checkbox::new(&mut state.learned_suggestions, "Learn from common progressions"),
+                                );
+                                if state.suggest_next
+                                    && let Some(last_chord) = state.last_chord
+                                {
+                                    ui.add_space(20.0);
+                                    ui.label(format!("After {}:", chord_label(state, last_chord)));
+                                    for rank in 0..state.suggestions.len() {
+                                        let label = chord_label(state, state.suggestions[rank]);
+                                        ui.label(egui::RichText::new(label).background_color(suggestion_color(rank)));
                                     }
                                 }
                             });
@@ -402,60 +2631,30 @@ impl Plugin for PerfectChords {
                             egui::ScrollArea::vertical().show(ui, |ui| {
                                 egui::Grid::new("chord_grid").show(ui, |ui| {
                                     ui.label("");
-                                    for d in &diatonics {
-                                        ui.strong(&d.degree);
+                                    for column in &columns {
+                                        if column.diatonic_type.is_some() {
+                                            ui.strong(&column.degree);
+                                        } else {
+                                            ui.weak(&column.degree);
+                                        }
                                     }
                                     ui.end_row();
 
-                                    for &(type_key, suffix) in &grid_rows {
+                                    for &chord_type in &grid_rows {
                                         ui.label("");
-                                        for d in &diatonics {
-                                            let root_note = &d.root_note;
-                                            let chord_id = ChordId {
-                                                root_note: root_note.clone(),
-                                                chord_type: type_key.to_string(),
-                                            };
-
-                                            if chord_table
-                                                .get(root_note)
-                                                .and_then(|vars| vars.get(type_key))
-                                                .is_some()
-                                            {
-                                                let label = format!("{}{}", root_note, suffix);
-                                                let is_playing_mouse =
-                                                    state.playing_chord.as_ref() == Some(&chord_id);
-                                                let is_inversion_target =
-                                                    state.inversion_chord.as_ref() == Some(&chord_id);
-                                                let is_diatonic = d.chord_type == type_key;
-                                                
-                                                let is_key_active = state.playing_keys.iter().any(|k| state.key_mappings.get(k) == Some(&chord_id));
-
-
-                                                let button_color = if is_playing_mouse || is_key_active {
-                                                    egui::Color32::from_rgb(100, 200, 100)
-                                                } else if is_inversion_target {
-                                                    egui::Color32::from_rgb(100, 150, 255)
-                                                } else if is_diatonic {
-                                                    ui.visuals().widgets.inactive.bg_fill
+                                        for column in &columns {
+                                            let chord_id = ChordId::new(column.root, chord_type);
+
+                                            if chord_table.contains_key(&chord_id) {
+                                                let width = ui.available_width() / columns.len() as f32;
+                                                let style = if column.diatonic_type.is_none() {
+                                                    CellStyle::OutOfKey
+                                                } else if column.diatonic_type == Some(chord_type) {
+                                                    CellStyle::Diatonic
                                                 } else {
-                                                    ui.visuals().widgets.noninteractive.bg_fill
+                                                    CellStyle::Extended
                                                 };
-
-                                                let button = egui::Button::new(label)
-                                                    .min_size(egui::vec2(ui.available_width() / diatonics.len() as f32, 0.0))
-                                                    .fill(button_color);
-                                                let response = ui.add(button);
-
-                                                if response.is_pointer_button_down_on() {
-                                                    if egui_ctx.input(|i| i.modifiers.ctrl) {
-                                                        state.inversion_chord = Some(chord_id.clone());
-                                                        let _ = sender
-                                                            .send(MidiMessage::SetInversionChord(chord_id.clone()));
-                                                    } else if state.playing_chord.as_ref() != Some(&chord_id) {
-                                                        state.playing_chord = Some(chord_id.clone());
-                                                        let _ = sender.send(MidiMessage::ChordOn(chord_id));
-                                                    }
-                                                }
+                                                chord_cell(ui, state, &sender, &shared, chord_id, width, style);
                                             } else {
                                                 ui.label("");
                                             }
@@ -463,83 +2662,117 @@ impl Plugin for PerfectChords {
                                         ui.end_row();
                                     }
                                 });
+
+                                chord_sections_grid(ui, "chord_grid", &sections, &chord_table, |ui, chord_id, width| {
+                                    chord_cell(ui, state, &sender, &shared, chord_id, width, CellStyle::Extended);
+                                });
                             });
 
+                            update_drag_pressure(egui_ctx, state, &sender);
+                            if state.playing_chord.is_some()
+                                && egui_ctx.input(|i| i.pointer.primary_released())
+                            {
+                                state.playing_chord = None;
+                                let _ = sender.try_send(MidiMessage::ChordOff);
+                            }
+                        }
+                        ViewMode::CircleOfFifths => {
+                            ui.label(format!(
+                                "Key: {} (click to play, right-click to change key)",
+                                state.scale
+                            ));
+                            ui.separator();
+
+                            circle_of_fifths(ui, state, &sender, &shared);
+
+                            update_drag_pressure(egui_ctx, state, &sender);
                             if state.playing_chord.is_some()
                                 && egui_ctx.input(|i| i.pointer.primary_released())
                             {
                                 state.playing_chord = None;
-                                let _ = sender.send(MidiMessage::ChordOff);
+                                let _ = sender.try_send(MidiMessage::ChordOff);
+                            }
+                        }
+                        ViewMode::Fretboard => {
+                            fretboard_view(ui, state, &sender, &chord_table);
+
+                            if state.fretboard.playing_shape.is_some()
+                                && egui_ctx.input(|i| i.pointer.primary_released())
+                            {
+                                state.fretboard.playing_shape = None;
+                                state.playing_chord = None;
+                                let _ = sender.try_send(MidiMessage::ChordOff);
                             }
                         }
                         ViewMode::KeyMapping => {
                             ui.heading("Key Mapping");
                             ui.add_space(10.0);
 
-                            let keys_to_map = [
-                                egui::Key::Z,
-                                egui::Key::X,
-                                egui::Key::C,
-                                egui::Key::V,
-                                egui::Key::B,
-                                egui::Key::N,
-                                egui::Key::M,
-                            ];
-
                             if let Some(key_to_map) = state.key_to_map {
                                 ui.horizontal(|ui| {
                                     ui.label(format!("Select a chord for key {:?}", key_to_map));
                                     if ui.button("Cancel").clicked() {
                                         state.key_to_map = None;
                                     }
+
+                                    ui.add_space(20.0);
+                                    ui.label("or by name:");
+                                    let response = ui.add(
+                                        egui::TextEdit::singleline(&mut state.key_symbol)
+                                            .hint_text("Cmaj7")
+                                            .desired_width(100.0),
+                                    );
+                                    let submitted = response.lost_focus()
+                                        && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                                    if ui.button("Map").clicked() || submitted {
+                                        let parsed = ChordSymbol::parse(&state.key_symbol, &state.vocabulary.read());
+                                        match parsed {
+                                            Ok(symbol) => {
+                                                let chord_id = symbol.chord();
+                                                state.send_setting(&sender, MidiMessage::UpdateKeyMapping(key_to_map, chord_id));
+                                                state.key_mappings.insert(key_to_map, chord_id);
+                                                state.key_to_map = None;
+                                                state.key_symbol.clear();
+                                                state.key_symbol_error = None;
+                                            }
+                                            Err(err) => state.key_symbol_error = Some(err.to_string()),
+                                        }
+                                    }
                                 });
+                                if let Some(error) = &state.key_symbol_error {
+                                    ui.colored_label(ui.visuals().error_fg_color, error);
+                                }
 
                                 egui::ScrollArea::vertical().show(ui, |ui| {
                                     egui::Grid::new("key_map_chord_selection_grid").show(
                                         ui,
                                         |ui| {
                                             ui.label("");
-                                            for d in &diatonics {
-                                                ui.strong(&d.degree);
+                                            for column in &columns {
+                                                if column.diatonic_type.is_some() {
+                                                    ui.strong(&column.degree);
+                                                } else {
+                                                    ui.weak(&column.degree);
+                                                }
                                             }
                                             ui.end_row();
 
-                                            for &(type_key, suffix) in &grid_rows {
+                                            for &chord_type in &grid_rows {
                                                 ui.label("");
-                                                for d in &diatonics {
-                                                    let root_note = &d.root_note;
-                                                    let chord_id = ChordId {
-                                                        root_note: root_note.clone(),
-                                                        chord_type: type_key.to_string(),
-                                                    };
-
-                                                    if chord_table
-                                                        .get(root_note)
-                                                        .and_then(|vars| vars.get(type_key))
-                                                        .is_some()
-                                                    {
-                                                        let label =
-                                                            format!("{}{}", root_note, suffix);
-                                                        let button = egui::Button::new(label)
-                                                            .min_size(egui::vec2(
-                                                                ui.available_width()
-                                                                    / diatonics.len() as f32,
-                                                                0.0,
-                                                            ));
-
-                                                        if ui.add(button).clicked() {
-                                                            let _ = sender.send(
-                                                                MidiMessage::UpdateKeyMapping(
-                                                                    key_to_map,
-                                                                    chord_id.clone(),
-                                                                ),
-                                                            );
-                                                            state.key_mappings.insert(
-                                                                key_to_map,
-                                                                chord_id.clone(),
-                                                            );
-                                                            state.key_to_map = None;
-                                                        }
+                                                for column in &columns {
+                                                    let chord_id = ChordId::new(column.root, chord_type);
+
+                                                    if chord_table.contains_key(&chord_id) {
+                                                        let width = ui.available_width()
+                                                            / columns.len() as f32;
+                                                        mapping_cell(
+                                                            ui,
+                                                            state,
+                                                            &sender,
+                                                            key_to_map,
+                                                            chord_id,
+                                                            width,
+                                                        );
                                                     } else {
                                                         ui.label("");
                                                     }
@@ -548,20 +2781,41 @@ impl Plugin for PerfectChords {
                                             }
                                         },
                                     );
+
+                                    chord_sections_grid(
+                                        ui,
+                                        "key_map_chord_selection_grid",
+                                        &sections,
+                                        &chord_table,
+                                        |ui, chord_id, width| {
+                                            mapping_cell(ui, state, &sender, key_to_map, chord_id, width);
+                                        },
+                                    );
                                 });
                             } else {
+                                // The default keys always get a row; imported mappings may use others
+                                let mut keys = DEFAULT_MAPPING_KEYS.to_vec();
+                                let mut other_keys: Vec<egui::Key> = state
+                                    .key_mappings
+                                    .keys()
+                                    .filter(|key| !DEFAULT_MAPPING_KEYS.contains(key))
+                                    .copied()
+                                    .collect();
+                                other_keys.sort_by_key(|key| key.name());
+                                keys.extend(other_keys);
+
                                 egui::Grid::new("key_mapping_grid")
                                     .num_columns(3)
                                     .spacing([40.0, 4.0])
                                     .striped(true)
                                     .show(ui, |ui| {
-                                        for key in keys_to_map.iter() {
+                                        for key in keys.iter() {
                                             ui.label(format!("{:?}", key));
 
                                             let mapped_chord_str = state
                                                 .key_mappings
                                                 .get(key)
-                                                .map(|c| format!("{}{}", c.root_note, c.chord_type))
+                                                .map(|c| chord_label(state, *c))
                                                 .unwrap_or_else(|| "None".to_string());
                                             ui.label(mapped_chord_str);
 
@@ -571,8 +2825,25 @@ impl Plugin for PerfectChords {
                                             ui.end_row();
                                         }
                                     });
+
+                                ui.add_space(10.0);
+                                mapping_file_controls(ui, state, &sender);
+                                ui.add_space(10.0);
+                                osc_controls(ui, state, &params.osc, &osc);
                             }
                         }
+                        ViewMode::CustomChords => {
+                            custom_chords_view(ui, state, &sender);
+                        }
+                        ViewMode::Progression => {
+                            progression_view(ui, state, &sender, &shared, &scale_map);
+                        }
+                        ViewMode::Rhythm => {
+                            rhythm_view(ui, state, &sender);
+                        }
+                        ViewMode::Tuning => {
+                            tuning_view(ui, state, &sender);
+                        }
                     }
                 });
             },
@@ -581,151 +2852,658 @@ impl Plugin for PerfectChords {
 
     fn process(
         &mut self,
-        _buffer: &mut Buffer,
+        buffer: &mut Buffer,
         _aux: &mut AuxiliaryBuffers,
         context: &mut impl ProcessContext<Self>,
     ) -> ProcessStatus {
-        while let Ok(message) = self.midi_receiver.try_recv() {
+        let transpose = self.params.transpose.value() as i16;
+        let mpe = self.params.mpe.value();
+        self.process_block(context, buffer.samples(), transpose, mpe);
+        ProcessStatus::Normal
+    }
+}
+
+impl PerfectChords {
+    /// Everything [`Plugin::process`] does for a buffer of `samples` samples, apart from reading
+    /// the parameters.
+    fn process_block(
+        &mut self,
+        context: &mut impl Host,
+        samples: usize,
+        transpose: i16,
+        mpe: bool,
+    ) {
+        if mpe != self.voices.mpe() {
+            for event in self.voices.set_mpe(mpe) {
+                context.send_event(event);
+            }
+        }
+        self.send_retired();
+        let mut received_messages = false;
+        // A message retires at most one value, and one place is left for the progression ending
+        while self.retired.capacity() - self.retired.len() > 1 {
+            let Ok(message) = self.midi_receiver.try_recv() else {
+                break;
+            };
+            received_messages = true;
             match message {
                 MidiMessage::ChordOn(chord_id) => {
-                    info!("Process: Received ChordOn for chord: {:?}", chord_id);
-                    for note in self.active_mouse_notes.drain(..) {
-                        context.send_event(NoteEvent::NoteOff {
-                            timing: 0,
-                            voice_id: None,
-                            channel: 0,
-                            note,
-                            velocity: 0.0,
-                        });
-                    }
-
-                    let current_inversion = self.state.inversion_map.get(&chord_id).copied().unwrap_or(0);
-                    if let Some(voicing) = self
-                        .chord_table
-                        .get(&chord_id.root_note)
-                        .and_then(|v| v.get(&chord_id.chord_type))
-                    {
-                        let num_inversions = voicing.inversions.len();
-                        if num_inversions > 0 {
-                            let inversion_idx = current_inversion as usize % num_inversions;
-                            let notes_to_play = &voicing.inversions[inversion_idx];
-                            let octave_offset = (self.state.octave - 3) * 12;
-
-                            for note in notes_to_play {
-                                let final_note = (*note as i16 + octave_offset as i16) as u8;
-                                context.send_event(NoteEvent::NoteOn {
-                                    timing: 0,
-                                    voice_id: None,
-                                    channel: 0,
-                                    note: final_note,
-                                    velocity: 0.8,
-                                });
-                                self.active_mouse_notes.push(final_note);
-                            }
-                        }
-                    }
-                    self.state.playing_chord = Some(chord_id);
+                    self.schedule_trigger(context, Trigger::ChordOn(chord_id), transpose);
                 }
                 MidiMessage::ChordOff => {
-                    info!("Process: Received ChordOff");
-                    for note in self.active_mouse_notes.drain(..) {
-                        context.send_event(NoteEvent::NoteOff {
-                            timing: 0,
-                            voice_id: None,
-                            channel: 0,
-                            note,
-                            velocity: 0.0,
-                        });
-                    }
-                    self.state.playing_chord = None;
+                    self.schedule_trigger(context, Trigger::ChordOff, transpose);
                 }
                 MidiMessage::SetInversionChord(chord_id) => {
-                    info!("Process: Received SetInversionChord for chord: {:?}", chord_id);
                     self.state.inversion_chord = Some(chord_id);
+                    self.notify_gui(GuiEvent::InversionChord(chord_id));
                 }
                 MidiMessage::UpdateOctave(octave) => {
-                    info!("Process: Received UpdateOctave: {}", octave);
-                    self.state.octave = octave;
+                    self.state.octave = octave.clamp(*OCTAVE_RANGE.start(), *OCTAVE_RANGE.end());
+                    self.notify_gui(GuiEvent::Octave(self.state.octave));
+                }
+                MidiMessage::UpdateNoteRangePolicy(policy) => {
+                    self.state.note_range_policy = policy;
+                    self.notify_gui(GuiEvent::NoteRangePolicy(policy));
                 }
                 MidiMessage::UpdateInversion(chord_id, inversion) => {
-                    info!("Process: Received UpdateInversion for chord: {:?}, inversion: {}", chord_id, inversion);
                     self.state.inversion_map.insert(chord_id, inversion);
+                    self.notify_gui(GuiEvent::Inversion(chord_id, inversion));
                 }
-                MidiMessage::UpdateScale(scale) => {
-                    info!("Process: Received UpdateScale: {}", scale);
-                    let parts: Vec<&str> = scale.split_whitespace().collect();
-                    if parts.len() == 2 {
-                        self.state.root_note = parts[0].to_string();
-                        self.state.scale_type = parts[1].to_string();
-                        self.state.key_mappings =
-                            generate_default_key_mappings(&self.scale_map, scale);
+                MidiMessage::SelectInversion(chord_id, inversion) => {
+                    let Some(chord_id) = chord_id.or(self.state.inversion_chord) else {
+                        continue;
+                    };
+                    let count = theory::inversion_count(&self.state.chord_table, &chord_id);
+                    if count > 0 {
+                        let inversion = (inversion as usize % count) as u8;
+                        self.state.inversion_map.insert(chord_id, inversion);
+                        self.notify_gui(GuiEvent::Inversion(chord_id, inversion));
                     }
                 }
+                MidiMessage::UpdateScale(scale) => {
+                    self.state.scale = scale;
+                    assign_default_key_mappings(&mut self.state.key_mappings, scale);
+                    self.notify_gui(GuiEvent::Scale(scale));
+                }
                 MidiMessage::UpdateKeyMapping(key, chord_id) => {
-                    info!("Process: Received UpdateKeyMapping for key: {:?}, chord: {:?}", key, chord_id);
                     self.state.key_mappings.insert(key, chord_id);
+                    self.notify_gui(GuiEvent::KeyMapping(key, chord_id));
+                }
+                MidiMessage::UpdateKeyMappings(mut mappings) => {
+                    std::mem::swap(&mut self.state.key_mappings, &mut mappings);
+                    self.retire(Retired::KeyMappings(mappings));
+                }
+                MidiMessage::ApplyPreset(mut settings) => {
+                    self.state.scale = settings.scale;
+                    self.state.octave = settings.octave;
+                    self.state.note_range_policy = settings.note_range_policy;
+                    std::mem::swap(&mut self.state.key_mappings, &mut settings.key_mappings);
+                    std::mem::swap(&mut self.state.inversion_map, &mut settings.inversion_map);
+                    self.state.quantize = settings.quantize;
+                    self.state.min_note_length = settings.min_note_length;
+                    self.state.harmony_output = settings.harmony_output;
+                    self.last_harmony = None;
+                    self.set_comping(context, settings.comp_pattern);
+                    self.retire(Retired::Preset(settings));
+                }
+                MidiMessage::UpdateChordTable(chord_table) => {
+                    let retired = std::mem::replace(&mut self.state.chord_table, chord_table);
+                    self.retire(Retired::ChordTable(retired));
                 }
                 MidiMessage::KeyChordOn(key) => {
-                    info!("Process: Received KeyChordOn for key: {:?}", key);
-                    if self.active_key_notes.contains_key(&key) {
-                        info!("Process: Key {:?} already active, skipping.", key);
-                        continue;
+                    self.schedule_trigger(context, Trigger::KeyChordOn(key), transpose);
+                }
+                MidiMessage::KeyChordOff(key) => {
+                    self.schedule_trigger(context, Trigger::KeyChordOff(key), transpose);
+                }
+                MidiMessage::AuditionNoteOn(note) => {
+                    if let Some(previous) = self.audition_note.take() {
+                        context.send_event(self.voices.note_off(0, previous));
                     }
-                    if let Some(chord_id) = self.state.key_mappings.get(&key).cloned() {
-                        let current_inversion =
-                            self.state.inversion_map.get(&chord_id).copied().unwrap_or(0);
-                        if let Some(voicing) = self
-                            .chord_table
-                            .get(&chord_id.root_note)
-                            .and_then(|v| v.get(&chord_id.chord_type))
-                        {
-                            let num_inversions = voicing.inversions.len();
-                            if num_inversions > 0 {
-                                let inversion_idx = current_inversion as usize % num_inversions;
-                                let notes_to_play = &voicing.inversions[inversion_idx];
-                                let octave_offset = (self.state.octave - 3) * 12;
-
-                                let mut played_notes = Vec::new();
-                                for note in notes_to_play {
-                                    let final_note = (*note as i16 + octave_offset as i16) as u8;
-                                    context.send_event(NoteEvent::NoteOn {
-                                        timing: 0,
-                                        voice_id: None,
-                                        channel: 0,
-                                        note: final_note,
-                                        velocity: 0.8,
-                                    });
-                                    played_notes.push(final_note);
-                                }
-                                self.active_key_notes.insert(key, played_notes);
-                                info!("Process: Active key notes after KeyChordOn: {:?}", self.active_key_notes);
-                            }
+                    let Some(note) = self.state.note_range_policy.apply(note as i16 + transpose)
+                    else {
+                        continue;
+                    };
+                    self.voices
+                        .note_on(0, note, 0.8, |event| context.send_event(event));
+                    self.audition_note = Some(note);
+                }
+                MidiMessage::VoicingOn(chord_id, notes) => {
+                    self.schedule_trigger(context, Trigger::VoicingOn(chord_id, notes), transpose);
+                }
+                MidiMessage::PlayProgression(chords, beats_per_chord) => {
+                    self.stop_progression(context);
+                    self.progression = Some(ProgressionPlayer {
+                        chords,
+                        beats_per_chord,
+                        index: 0,
+                        samples_to_next: 0.0,
+                        notes: ChordNotes::default(),
+                    });
+                }
+                MidiMessage::StopProgression => {
+                    self.stop_progression(context);
+                }
+                MidiMessage::UpdateQuantize(quantize) => {
+                    self.state.quantize = quantize;
+                }
+                MidiMessage::UpdateMinNoteLength(min_note_length) => {
+                    self.state.min_note_length = min_note_length;
+                }
+                MidiMessage::UpdateHarmonyOutput(output) => {
+                    self.state.harmony_output = output;
+                    self.last_harmony = None;
+                }
+                MidiMessage::MousePressure(pressure) => {
+                    for &note in self.active_mouse_notes.iter() {
+                        if let Some(event) = self.voices.pressure(0, note, pressure) {
+                            context.send_event(event);
                         }
                     }
                 }
-                MidiMessage::KeyChordOff(key) => {
-                    info!("Process: Received KeyChordOff for key: {:?}", key);
-                    if let Some(notes_to_stop) = self.active_key_notes.remove(&key) {
-                        for note in notes_to_stop {
-                            context.send_event(NoteEvent::NoteOff {
-                                timing: 0,
-                                voice_id: None,
-                                channel: 0,
-                                note,
-                                velocity: 0.0,
-                            });
-                        }
-                        info!("Process: Active key notes after KeyChordOff: {:?}", self.active_key_notes);
+                MidiMessage::UpdateComping(pattern) => {
+                    self.set_comping(context, pattern.as_deref().copied());
+                    if let Some(pattern) = pattern {
+                        self.retire(Retired::CompPattern(pattern));
                     }
                 }
-                MidiMessage::UpdatePlayingKeys(new_playing_keys) => {
-                    info!("Process: Received UpdatePlayingKeys: {:?}", new_playing_keys);
-                    self.state.playing_keys = new_playing_keys;
+                MidiMessage::UpdateTuning(table) => {
+                    if let Some(retired) = self
+                        .voices
+                        .set_tuning(table, |event| context.send_event(event))
+                    {
+                        self.retire(Retired::Tuning(retired));
+                    }
+                }
+                MidiMessage::AuditionNoteOff => {
+                    if let Some(note) = self.audition_note.take() {
+                        context.send_event(self.voices.note_off(0, note));
+                    }
                 }
             }
         }
 
-        ProcessStatus::Normal
+        let triggers_played = self.play_due_triggers(context, samples, transpose);
+        let progression_changed = self.advance_progression(context, samples, transpose);
+        self.advance_comping(context, samples);
+        self.send_harmony(context, transpose);
+        self.sample_clock += samples as u64;
+
+        if received_messages || triggers_played || progression_changed {
+            self.publish_shared_state(transpose as i32);
+        }
+    }
+}
+
+fn send_comp_event(context: &mut impl Host, voices: &mut VoiceAllocator, event: CompEvent) {
+    match event {
+        CompEvent::NoteOn {
+            timing,
+            note,
+            velocity,
+        } => voices.note_on(timing, note, velocity, |event| context.send_event(event)),
+        CompEvent::NoteOff { timing, note } => context.send_event(voices.note_off(timing, note)),
+    }
+}
+
+impl PerfectChords {
+    /// Sends a settings change to the editor. Dropped if the queue is full because no editor is
+    /// open; the editor starts from a fresh copy of the state when it opens.
+    fn notify_gui(&self, event: GuiEvent) {
+        let _ = self.gui_event_sender.try_send(event);
+    }
+
+    /// Sends a value to the editor thread to be freed, or keeps it in `retired` while the queue is
+    /// full. `process` only takes messages while there is room left, so this never allocates.
+    fn retire(&mut self, retired: Retired) {
+        if let Err(TrySendError::Full(GuiEvent::Retired(retired))) =
+            self.gui_event_sender.try_send(GuiEvent::Retired(retired))
+        {
+            self.retired.push(retired);
+        }
+    }
+
+    /// Sends the values `retire` kept back for as long as the queue has room.
+    fn send_retired(&mut self) {
+        while let Some(retired) = self.retired.pop() {
+            if let Err(err) = self.gui_event_sender.try_send(GuiEvent::Retired(retired)) {
+                if let GuiEvent::Retired(retired) = err.into_inner() {
+                    self.retired.push(retired);
+                }
+                break;
+            }
+        }
+    }
+
+    /// Plays a mouse or keyboard trigger straight away, or queues it for the next grid point while
+    /// quantizing to a running host transport. With a minimum note length, a chord is released no
+    /// sooner than one grid step after it started.
+    fn schedule_trigger(&mut self, context: &mut impl Host, trigger: Trigger, transpose: i16) {
+        let transport = context.transport();
+        let samples_per_beat = transport.samples_per_beat();
+        let beats_per_bar = transport.beats_per_bar();
+        let grid = transport
+            .pos_beats
+            .filter(|_| transport.playing)
+            .and_then(|pos_beats| {
+                let bar_start = transport.bar_start_pos_beats.unwrap_or(0.0);
+                let wait =
+                    self.state
+                        .quantize
+                        .beats_to_next(pos_beats, bar_start, beats_per_bar)?;
+                Some((wait, self.state.quantize.grid_beats(beats_per_bar)?))
+            });
+
+        let mut at = self.sample_clock;
+        if let Some((wait, grid)) = grid {
+            at += (wait * samples_per_beat).round() as u64;
+            if self.state.min_note_length {
+                let started = match trigger {
+                    Trigger::ChordOff => Some(self.mouse_started_at),
+                    Trigger::KeyChordOff(key) => self.key_started_at.get(&key).copied(),
+                    _ => None,
+                };
+                if let Some(started) = started {
+                    at = at.max(started + (grid * samples_per_beat).round() as u64);
+                }
+            }
+        }
+        // A trigger never overtakes an earlier one from the same mouse or key
+        let pending_at = self
+            .pending_triggers
+            .iter()
+            .rev()
+            .find(|(_, pending)| pending.source() == trigger.source())
+            .map(|&(pending_at, _)| pending_at);
+        if let Some(pending_at) = pending_at {
+            at = at.max(pending_at);
+        }
+
+        match trigger {
+            Trigger::ChordOn(_) | Trigger::VoicingOn(..) => self.mouse_started_at = at,
+            Trigger::KeyChordOn(key) => {
+                self.key_started_at.insert(key, at);
+            }
+            Trigger::ChordOff | Trigger::KeyChordOff(_) => {}
+        }
+
+        // Triggers at the same sample keep the order they arrived in
+        let wait = at > self.sample_clock || pending_at.is_some();
+        if wait && self.pending_triggers.len() == self.pending_triggers.capacity() {
+            // Make room by playing the oldest trigger from the same source early, so a release can't
+            // overtake the chord it releases
+            if let Some(index) = self
+                .pending_triggers
+                .iter()
+                .position(|(_, pending)| pending.source() == trigger.source())
+            {
+                let (_, oldest) = self.pending_triggers.remove(index);
+                self.apply_trigger(context, oldest, 0, transpose);
+            }
+        }
+        if wait && self.pending_triggers.len() < self.pending_triggers.capacity() {
+            let index = self
+                .pending_triggers
+                .partition_point(|&(pending_at, _)| pending_at <= at);
+            self.pending_triggers.insert(index, (at, trigger));
+        } else {
+            self.apply_trigger(context, trigger, 0, transpose);
+        }
+    }
+
+    /// Plays the queued triggers that fall in this buffer at their exact sample, or all of them at
+    /// once when the host has stopped. Returns whether any were played.
+    fn play_due_triggers(
+        &mut self,
+        context: &mut impl Host,
+        buffer_len: usize,
+        transpose: i16,
+    ) -> bool {
+        let flush = !context.transport().playing;
+        let buffer_end = self.sample_clock + buffer_len as u64;
+        let mut played = false;
+        while let Some(&(at, trigger)) = self.pending_triggers.first()
+            && (flush || at < buffer_end)
+        {
+            self.pending_triggers.remove(0);
+            let timing = if flush {
+                0
+            } else {
+                at.saturating_sub(self.sample_clock) as u32
+            };
+            self.apply_trigger(context, trigger, timing, transpose);
+            played = true;
+        }
+        played
+    }
+
+    fn apply_trigger(
+        &mut self,
+        context: &mut impl Host,
+        trigger: Trigger,
+        timing: u32,
+        transpose: i16,
+    ) {
+        match trigger {
+            Trigger::ChordOn(chord_id) => {
+                let released = std::mem::take(&mut self.active_mouse_notes);
+                self.stop_chord_notes(context, timing, &released);
+
+                let current_inversion = self
+                    .state
+                    .inversion_map
+                    .get(&chord_id)
+                    .copied()
+                    .unwrap_or(0);
+                if let Some(notes_to_play) = theory::voicing_notes(
+                    &self.state.chord_table,
+                    &chord_id,
+                    current_inversion,
+                    self.state.octave,
+                    self.state.note_range_policy,
+                ) {
+                    let notes_to_play =
+                        notes_to_play.transposed(transpose, self.state.note_range_policy);
+                    self.start_chord_notes(context, timing, &notes_to_play);
+                    self.active_mouse_notes = notes_to_play;
+                    self.state.playing_chord = Some(chord_id);
+                } else {
+                    self.state.playing_chord = None;
+                }
+            }
+            Trigger::ChordOff => {
+                let released = std::mem::take(&mut self.active_mouse_notes);
+                self.stop_chord_notes(context, timing, &released);
+                self.state.playing_chord = None;
+            }
+            Trigger::KeyChordOn(key) => {
+                if self.active_key_notes.contains_key(&key) {
+                    return;
+                }
+                if let Some(&chord_id) = self.state.key_mappings.get(&key) {
+                    let current_inversion = self
+                        .state
+                        .inversion_map
+                        .get(&chord_id)
+                        .copied()
+                        .unwrap_or(0);
+                    if let Some(notes_to_play) = theory::voicing_notes(
+                        &self.state.chord_table,
+                        &chord_id,
+                        current_inversion,
+                        self.state.octave,
+                        self.state.note_range_policy,
+                    ) {
+                        let notes_to_play =
+                            notes_to_play.transposed(transpose, self.state.note_range_policy);
+                        self.start_chord_notes(context, timing, &notes_to_play);
+                        self.active_key_notes.insert(key, notes_to_play);
+                    }
+                }
+            }
+            Trigger::KeyChordOff(key) => {
+                if let Some(notes_to_stop) = self.active_key_notes.remove(&key) {
+                    self.stop_chord_notes(context, timing, &notes_to_stop);
+                }
+            }
+            Trigger::VoicingOn(chord_id, notes) => {
+                let released = std::mem::take(&mut self.active_mouse_notes);
+                self.stop_chord_notes(context, timing, &released);
+                let notes = notes.transposed(transpose, self.state.note_range_policy);
+                self.start_chord_notes(context, timing, &notes);
+                self.active_mouse_notes = notes;
+                self.state.playing_chord = Some(chord_id);
+            }
+        }
+    }
+
+    /// Starts a held chord's notes. While comping they stay silent and the pattern plays them.
+    fn start_chord_notes(&mut self, context: &mut impl Host, timing: u32, notes: &[u8]) {
+        if self.state.comping {
+            return;
+        }
+        for &note in notes {
+            self.voices
+                .note_on(timing, note, 0.8, |event| context.send_event(event));
+        }
+    }
+
+    /// Releases a held chord's notes, unless comping is on and they were never started.
+    fn stop_chord_notes(&mut self, context: &mut impl Host, timing: u32, notes: &[u8]) {
+        if self.state.comping {
+            return;
+        }
+        for &note in notes {
+            context.send_event(self.voices.note_off(timing, note));
+        }
+    }
+
+    /// Every note of the chords held by the mouse, the keyboard and the progression player.
+    fn held_notes(&self) -> [bool; 128] {
+        let mut held = [false; 128];
+        let progression_notes = self.progression.iter().map(|player| &player.notes);
+        for notes in std::iter::once(&self.active_mouse_notes)
+            .chain(self.active_key_notes.values())
+            .chain(progression_notes)
+        {
+            for &note in notes.iter() {
+                held[note as usize & 127] = true;
+            }
+        }
+        held
+    }
+
+    /// Switches between sustained chords and comping, handing the held notes over so nothing is
+    /// left hanging or cut off.
+    fn set_comping(&mut self, context: &mut impl Host, pattern: Option<CompPattern>) {
+        let held = self.held_notes();
+        let was_comping = self.state.comping;
+        self.state.comping = pattern.is_some();
+        if let Some(pattern) = pattern {
+            self.state.comp_pattern = pattern;
+        }
+
+        match (was_comping, self.state.comping) {
+            (false, true) => {
+                for note in (0..128u8).filter(|&note| held[note as usize]) {
+                    context.send_event(self.voices.note_off(0, note));
+                }
+            }
+            (true, false) => {
+                let voices = &mut self.voices;
+                self.comp
+                    .release_all(0, |event| send_comp_event(context, voices, event));
+                for note in (0..128u8).filter(|&note| held[note as usize]) {
+                    self.voices
+                        .note_on(0, note, 0.8, |event| context.send_event(event));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Plays this buffer's steps of the comping pattern over the held chords, on the host's bars
+    /// when its transport is running. The pattern's steps are sixteenth notes, and each of the
+    /// host's bars starts a bar of the pattern whatever its time signature.
+    fn advance_comping(&mut self, context: &mut impl Host, buffer_len: usize) {
+        if !self.state.comping {
+            return;
+        }
+        let held = self.held_notes();
+        let transport = context.transport();
+        let timing = CompTiming {
+            samples_per_step: transport.samples_per_beat() / 4.0,
+            steps_per_bar: transport.beats_per_bar() * 4.0,
+        };
+        // Counted from the start of the current bar, as if every earlier bar had been in this time
+        // signature, so the pattern restarts on the host's bar lines
+        let host_position = transport
+            .pos_beats
+            .filter(|_| transport.playing)
+            .map(|pos_beats| {
+                let bar_start = transport.bar_start_pos_beats.unwrap_or(0.0);
+                let bar = transport.bar_number.map_or_else(
+                    || (bar_start / transport.beats_per_bar()).round(),
+                    |bar| bar as f64,
+                );
+                bar * timing.steps_per_bar + (pos_beats - bar_start) * 4.0
+            });
+        let voices = &mut self.voices;
+        self.comp.process(
+            &self.state.comp_pattern,
+            &held,
+            host_position,
+            timing,
+            buffer_len,
+            |event| send_comp_event(context, voices, event),
+        );
+    }
+
+    /// The chord announced to other plugins: the one held with the mouse, else the progression's,
+    /// else one held on the keyboard.
+    fn current_chord(&self) -> Option<ChordId> {
+        let progression_chord = self
+            .progression
+            .as_ref()
+            .and_then(|player| player.chords.get(player.index.checked_sub(1)?).copied());
+        self.state.playing_chord.or(progression_chord).or_else(|| {
+            self.active_key_notes
+                .keys()
+                .find_map(|key| self.state.key_mappings.get(key).copied())
+        })
+    }
+
+    /// Announces the key and chord at their sounding pitch when either has changed since the last
+    /// announcement.
+    fn send_harmony(&mut self, context: &mut impl Host, transpose: i16) {
+        if self.state.harmony_output == HarmonyOutput::Off {
+            return;
+        }
+        let chord = self.current_chord();
+        let chord_tones = chord
+            .and_then(|chord_id| self.state.chord_table.get(&chord_id)?.inversions.first())
+            .map_or(0, |notes| {
+                harmony::pitch_class_mask(notes.iter().map(|&note| note as i32 + transpose as i32))
+            });
+        let scale = self.state.scale;
+        let message = HarmonyMessage {
+            scale: Scale::new(scale.root.transpose(transpose as i32), scale.scale_type),
+            chord: chord.map(|chord_id| chord_id.transpose(transpose as i32)),
+            chord_tones,
+        };
+        if self.last_harmony == Some(message) {
+            return;
+        }
+        self.last_harmony = Some(message);
+
+        match self.state.harmony_output {
+            HarmonyOutput::Off => {}
+            HarmonyOutput::ControlChange => {
+                for event in message.control_changes(0, mpe::MASTER_CHANNEL) {
+                    context.send_event(event);
+                }
+            }
+            HarmonyOutput::SysEx => context.send_event(NoteEvent::MidiSysEx { timing: 0, message }),
+        }
+    }
+
+    /// Releases the progression's notes and hands it back to the editor thread.
+    fn stop_progression(&mut self, context: &mut impl Host) {
+        if let Some(player) = self.progression.take() {
+            self.stop_chord_notes(context, 0, &player.notes);
+            self.retire(Retired::Progression(player.chords));
+        }
+    }
+
+    /// Moves the progression through this buffer, switching chords at the sample where each one
+    /// starts. The length of a beat follows the host tempo, or 120 BPM when there is none. Returns
+    /// whether the sounding chord changed.
+    fn advance_progression(
+        &mut self,
+        context: &mut impl Host,
+        buffer_len: usize,
+        transpose: i16,
+    ) -> bool {
+        let Some(mut player) = self.progression.take() else {
+            return false;
+        };
+        let samples_per_beat = context.transport().samples_per_beat();
+        let chord_len = (player.beats_per_chord as f64 * samples_per_beat).max(1.0);
+
+        let mut changed = false;
+        while player.samples_to_next < buffer_len as f64 {
+            let timing = player.samples_to_next as u32;
+            self.stop_chord_notes(context, timing, &std::mem::take(&mut player.notes));
+            changed = true;
+
+            let Some(chord_id) = player.chords.get(player.index) else {
+                self.retire(Retired::Progression(player.chords));
+                return true;
+            };
+            let inversion = self.state.inversion_map.get(chord_id).copied().unwrap_or(0);
+            if let Some(notes) = theory::voicing_notes(
+                &self.state.chord_table,
+                chord_id,
+                inversion,
+                self.state.octave,
+                self.state.note_range_policy,
+            ) {
+                player.notes = notes.transposed(transpose, self.state.note_range_policy);
+                self.start_chord_notes(context, timing, &player.notes);
+            }
+            player.index += 1;
+            player.samples_to_next += chord_len;
+        }
+        player.samples_to_next -= buffer_len as f64;
+        self.progression = Some(player);
+        changed
+    }
+
+    /// Rebuilds the sounding snapshot from the held mouse chord, the held key chords and the
+    /// auditioned note. Chords are labelled first so that a shared note keeps its chord role, with
+    /// roles judged against the transposed root since the notes have already been transposed.
+    fn publish_shared_state(&self, transpose: i32) {
+        let mut roles = [None; 128];
+
+        if let Some(note) = self.audition_note {
+            roles[note as usize & 127] = Some(NoteRole::Other);
+        }
+        for (key, notes) in &self.active_key_notes {
+            if let Some(chord_id) = self.state.key_mappings.get(key) {
+                for &note in notes.iter() {
+                    roles[note as usize & 127] =
+                        Some(NoteRole::of(&chord_id.transpose(transpose), note));
+                }
+            }
+        }
+        if let Some(chord_id) = &self.state.playing_chord {
+            for &note in self.active_mouse_notes.iter() {
+                roles[note as usize & 127] =
+                    Some(NoteRole::of(&chord_id.transpose(transpose), note));
+            }
+        }
+        let progression_chord = self.progression.as_ref().and_then(|player| {
+            let chord_id = player.chords.get(player.index.checked_sub(1)?)?;
+            for &note in player.notes.iter() {
+                roles[note as usize & 127] =
+                    Some(NoteRole::of(&chord_id.transpose(transpose), note));
+            }
+            Some(*chord_id)
+        });
+
+        self.shared.sounding_notes.publish(&roles);
+
+        let key_chords = self
+            .active_key_notes
+            .keys()
+            .filter_map(|key| self.state.key_mappings.get(key).copied());
+        self.shared.publish_chords(
+            self.state
+                .playing_chord
+                .into_iter()
+                .chain(progression_chord)
+                .chain(key_chords),
+        );
     }
 }
 
@@ -749,4 +3527,415 @@ impl Vst3Plugin for PerfectChords {
 }
 
 nih_export_clap!(PerfectChords);
-nih_export_vst3!(PerfectChords);
\ No newline at end of file
+nih_export_vst3!(PerfectChords);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use host::TransportInfo;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use theory::RetunedNote;
+
+    /// Counts the allocations made on each thread, so tests running in parallel don't see each
+    /// other's.
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    fn count_allocation() {
+        // Fails while the thread is being torn down, when there's nothing left to count
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            count_allocation();
+            unsafe { System.alloc(layout) }
+        }
+
+        unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+            count_allocation();
+            unsafe { System.alloc_zeroed(layout) }
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            count_allocation();
+            unsafe { System.realloc(ptr, layout, new_size) }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            unsafe { System.dealloc(ptr, layout) }
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    const BLOCK_LEN: usize = 1024;
+
+    /// A host with a running transport that keeps the events it is sent in a preallocated list.
+    struct TestHost {
+        transport: TransportInfo,
+        events: Vec<NoteEvent<HarmonyMessage>>,
+    }
+
+    impl Host for TestHost {
+        fn transport(&self) -> TransportInfo {
+            self.transport
+        }
+
+        fn send_event(&mut self, event: NoteEvent<HarmonyMessage>) {
+            assert!(
+                self.events.len() < self.events.capacity(),
+                "too many events in one block"
+            );
+            self.events.push(event);
+        }
+    }
+
+    impl TestHost {
+        fn new() -> Self {
+            Self {
+                transport: TransportInfo {
+                    playing: true,
+                    sample_rate: 48000.0,
+                    tempo: Some(120.0),
+                    time_sig_numerator: Some(3),
+                    time_sig_denominator: Some(4),
+                    pos_beats: Some(0.0),
+                    bar_start_pos_beats: Some(0.0),
+                    bar_number: Some(0),
+                },
+                events: Vec::with_capacity(MIDI_QUEUE_CAPACITY),
+            }
+        }
+
+        /// Moves the transport on by one block.
+        fn advance(&mut self) {
+            let transport = &mut self.transport;
+            let pos_beats =
+                transport.pos_beats.unwrap() + BLOCK_LEN as f64 / transport.samples_per_beat();
+            let bar = (pos_beats / transport.beats_per_bar()).floor();
+            transport.pos_beats = Some(pos_beats);
+            transport.bar_start_pos_beats = Some(bar * transport.beats_per_bar());
+            transport.bar_number = Some(bar as i32);
+            self.events.clear();
+        }
+    }
+
+    /// The allocations made while processing `blocks` blocks.
+    fn allocations_while_playing(
+        plugin: &mut PerfectChords,
+        host: &mut TestHost,
+        blocks: usize,
+        mpe: bool,
+    ) -> usize {
+        let before = ALLOCATIONS.with(Cell::get);
+        for _ in 0..blocks {
+            plugin.process_block(host, BLOCK_LEN, 2, mpe);
+            host.advance();
+        }
+        ALLOCATIONS.with(Cell::get) - before
+    }
+
+    /// A session's worth of editor messages, each of which is played for a while before the next.
+    fn session() -> Vec<MidiMessage> {
+        let c_major = ChordId::new(PitchClass::new(0), ChordType::Major);
+        let progression: Arc<[ChordId]> = [
+            c_major,
+            ChordId::new(PitchClass::new(9), ChordType::Minor),
+            ChordId::new(PitchClass::new(7), ChordType::Dominant7),
+        ]
+        .into();
+        let detuned: RetuneTable = std::array::from_fn(|note| {
+            Some(RetunedNote {
+                note: note as u8,
+                bend: 0.25,
+            })
+        });
+        vec![
+            MidiMessage::UpdateHarmonyOutput(HarmonyOutput::SysEx),
+            MidiMessage::UpdateTuning(Some(Box::new(detuned))),
+            MidiMessage::ChordOn(c_major),
+            MidiMessage::ChordOff,
+            MidiMessage::UpdateQuantize(Quantize::Quarter),
+            MidiMessage::KeyChordOn(egui::Key::Z),
+            MidiMessage::KeyChordOff(egui::Key::Z),
+            MidiMessage::UpdateQuantize(Quantize::Off),
+            MidiMessage::UpdateComping(Some(Box::new(CompPattern::default()))),
+            MidiMessage::ChordOn(c_major),
+            MidiMessage::ChordOff,
+            MidiMessage::PlayProgression(progression, 1),
+            MidiMessage::StopProgression,
+            MidiMessage::UpdateComping(None),
+            MidiMessage::UpdateTuning(None),
+            MidiMessage::UpdateHarmonyOutput(HarmonyOutput::ControlChange),
+        ]
+    }
+
+    #[test]
+    fn processing_never_allocates() {
+        let mut plugin = PerfectChords::default();
+        let mut host = TestHost::new();
+        // Everything the editor sends is allocated on its thread, before processing starts
+        let sessions = [(false, session()), (true, session())];
+        allocations_while_playing(&mut plugin, &mut host, 1, false);
+
+        for (mpe, session) in sessions {
+            for (index, message) in session.into_iter().enumerate() {
+                plugin.midi_sender.try_send(message).ok().unwrap();
+                let allocations = allocations_while_playing(&mut plugin, &mut host, 40, mpe);
+                assert_eq!(
+                    allocations, 0,
+                    "allocated after message {index} with MPE {mpe}"
+                );
+                while plugin.gui_event_receiver.try_recv().is_ok() {}
+            }
+        }
+    }
+
+    /// A note starting (`true`) or stopping at a sample counted from the plugin's creation.
+    type Played = (u64, bool, u8);
+
+    /// The notes started and stopped over `blocks` blocks.
+    fn play(
+        plugin: &mut PerfectChords,
+        host: &mut TestHost,
+        blocks: usize,
+        transpose: i16,
+    ) -> Vec<Played> {
+        let mut played = Vec::new();
+        for _ in 0..blocks {
+            let block_start = plugin.sample_clock;
+            plugin.process_block(host, BLOCK_LEN, transpose, false);
+            played.extend(host.events.iter().filter_map(|event| match *event {
+                NoteEvent::NoteOn { timing, note, .. } => {
+                    Some((block_start + timing as u64, true, note))
+                }
+                NoteEvent::NoteOff { timing, note, .. } => {
+                    Some((block_start + timing as u64, false, note))
+                }
+                _ => None,
+            }));
+            host.advance();
+        }
+        played
+    }
+
+    fn send(plugin: &PerfectChords, message: MidiMessage) {
+        plugin.midi_sender.try_send(message).ok().unwrap();
+    }
+
+    /// A host at 120 BPM and 48 kHz, so a beat is 24000 samples, in 4/4 from the second half of the
+    /// first beat.
+    fn half_a_beat_in() -> TestHost {
+        let mut host = TestHost::new();
+        host.transport.time_sig_numerator = Some(4);
+        host.transport.pos_beats = Some(0.5);
+        host
+    }
+
+    fn quantized(quantize: Quantize, min_note_length: bool) -> PerfectChords {
+        let plugin = PerfectChords::default();
+        send(&plugin, MidiMessage::UpdateQuantize(quantize));
+        send(&plugin, MidiMessage::UpdateMinNoteLength(min_note_length));
+        plugin
+    }
+
+    /// The samples the chord's notes start and stop on, checking that every note agrees.
+    fn starts_and_stops(played: &[Played]) -> (Vec<u64>, Vec<u64>) {
+        let mut starts: Vec<u64> = played
+            .iter()
+            .filter(|note| note.1)
+            .map(|note| note.0)
+            .collect();
+        let mut stops: Vec<u64> = played
+            .iter()
+            .filter(|note| !note.1)
+            .map(|note| note.0)
+            .collect();
+        assert!(!starts.is_empty());
+        assert_eq!(starts.len(), stops.len(), "{played:?}");
+        starts.dedup();
+        stops.dedup();
+        (starts, stops)
+    }
+
+    fn c_major() -> ChordId {
+        ChordId::new(PitchClass::new(0), ChordType::Major)
+    }
+
+    #[test]
+    fn quantized_chords_start_and_stop_on_the_grid() {
+        let mut plugin = quantized(Quantize::Quarter, false);
+        let mut host = half_a_beat_in();
+        send(&plugin, MidiMessage::ChordOn(c_major()));
+        let mut played = play(&mut plugin, &mut host, 15, 0);
+        // Released a little after the second beat, so held until the third
+        send(&plugin, MidiMessage::ChordOff);
+        played.extend(play(&mut plugin, &mut host, 30, 0));
+        assert_eq!(starts_and_stops(&played), (vec![12000], vec![36000]));
+
+        // Bars of 3/4 from beat 0: a bar-quantized chord waits for beat 3
+        let mut plugin = quantized(Quantize::Bar, false);
+        let mut host = TestHost::new();
+        host.transport.pos_beats = Some(1.0);
+        send(&plugin, MidiMessage::ChordOn(c_major()));
+        let played = play(&mut plugin, &mut host, 60, 0);
+        assert_eq!(played.iter().map(|note| note.0).min(), Some(48000));
+    }
+
+    #[test]
+    fn minimum_note_length_holds_quick_chords_for_a_grid_step() {
+        for (min_note_length, stop) in [(false, 12000), (true, 36000)] {
+            let mut plugin = quantized(Quantize::Quarter, min_note_length);
+            let mut host = half_a_beat_in();
+            send(&plugin, MidiMessage::ChordOn(c_major()));
+            send(&plugin, MidiMessage::ChordOff);
+            let played = play(&mut plugin, &mut host, 40, 0);
+            assert_eq!(starts_and_stops(&played), (vec![12000], vec![stop]));
+        }
+    }
+
+    #[test]
+    fn stopping_the_transport_plays_queued_chords_at_once() {
+        let mut plugin = quantized(Quantize::Bar, false);
+        let mut host = half_a_beat_in();
+        send(&plugin, MidiMessage::ChordOn(c_major()));
+        assert_eq!(play(&mut plugin, &mut host, 2, 0), []);
+        host.transport.playing = false;
+        let played = play(&mut plugin, &mut host, 1, 0);
+        assert!(!played.is_empty());
+        assert!(
+            played
+                .iter()
+                .all(|&(at, on, _)| on && at == 2 * BLOCK_LEN as u64)
+        );
+        // Nothing waits for the grid while stopped
+        send(&plugin, MidiMessage::ChordOff);
+        let played = play(&mut plugin, &mut host, 1, 0);
+        assert!(!played.is_empty());
+        assert!(
+            played
+                .iter()
+                .all(|&(at, on, _)| !on && at == 3 * BLOCK_LEN as u64)
+        );
+    }
+
+    #[test]
+    fn a_full_queue_never_lets_a_release_overtake_its_chord() {
+        let mut plugin = quantized(Quantize::Bar, false);
+        plugin.pending_triggers = Vec::with_capacity(2);
+        let mut host = half_a_beat_in();
+        let d_minor = ChordId::new(PitchClass::new(2), ChordType::Minor);
+        send(&plugin, MidiMessage::ChordOn(d_minor));
+        send(&plugin, MidiMessage::KeyChordOn(egui::Key::Z));
+        // The queue is full, so the mouse chord plays now to make room for its release
+        send(&plugin, MidiMessage::ChordOff);
+        send(&plugin, MidiMessage::KeyChordOff(egui::Key::Z));
+        let played = play(&mut plugin, &mut host, 100, 0);
+
+        let mut sounding = [0i32; 128];
+        for &(_, on, note) in &played {
+            sounding[note as usize] += if on { 1 } else { -1 };
+            assert!(
+                sounding[note as usize] >= 0,
+                "{note} stopped before it started"
+            );
+        }
+        assert_eq!(sounding, [0; 128], "{played:?}");
+        assert!(played.iter().any(|&(at, on, _)| on && at == 0));
+        assert!(played.iter().any(|&(at, on, _)| !on && at == 84000));
+    }
+
+    /// The notes started and the notes stopped, each sorted.
+    fn started_and_stopped(played: &[Played]) -> (Vec<u8>, Vec<u8>) {
+        let mut started: Vec<u8> = played
+            .iter()
+            .filter(|note| note.1)
+            .map(|note| note.2)
+            .collect();
+        let mut stopped: Vec<u8> = played
+            .iter()
+            .filter(|note| !note.1)
+            .map(|note| note.2)
+            .collect();
+        started.sort_unstable();
+        stopped.sort_unstable();
+        (started, stopped)
+    }
+
+    #[test]
+    fn transpose_shifts_every_way_of_playing() {
+        // The grid, the keyboard, the piano's audition and the fretboard
+        let ways = || -> [(MidiMessage, MidiMessage, &[u8]); 4] {
+            [
+                (
+                    MidiMessage::ChordOn(c_major()),
+                    MidiMessage::ChordOff,
+                    &[48, 52, 55],
+                ),
+                (
+                    MidiMessage::KeyChordOn(egui::Key::Z),
+                    MidiMessage::KeyChordOff(egui::Key::Z),
+                    &[48, 52, 55],
+                ),
+                (
+                    MidiMessage::AuditionNoteOn(60),
+                    MidiMessage::AuditionNoteOff,
+                    &[60],
+                ),
+                (
+                    MidiMessage::VoicingOn(c_major(), ChordNotes::from_slice(&[48, 55, 64])),
+                    MidiMessage::ChordOff,
+                    &[48, 55, 64],
+                ),
+            ]
+        };
+        for transpose in [2, -12] {
+            for (on, off, notes) in ways() {
+                let mut plugin = PerfectChords::default();
+                let mut host = TestHost::new();
+                send(&plugin, on);
+                let mut played = play(&mut plugin, &mut host, 2, transpose);
+                send(&plugin, off);
+                played.extend(play(&mut plugin, &mut host, 2, transpose));
+
+                let expected: Vec<u8> = notes
+                    .iter()
+                    .map(|&note| (note as i16 + transpose) as u8)
+                    .collect();
+                assert_eq!(
+                    started_and_stopped(&played),
+                    (expected.clone(), expected),
+                    "transposed by {transpose}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn held_chords_stop_the_notes_they_started_when_the_transpose_changes() {
+        let mut plugin = PerfectChords::default();
+        let mut host = TestHost::new();
+        send(&plugin, MidiMessage::ChordOn(c_major()));
+        send(&plugin, MidiMessage::KeyChordOn(egui::Key::Z));
+        let held = play(&mut plugin, &mut host, 2, 2);
+        assert_eq!(started_and_stopped(&held).0, [50, 50, 54, 54, 57, 57]);
+
+        // Moving to another chord and letting go both stop the notes as they were transposed
+        send(&plugin, MidiMessage::ChordOn(c_major()));
+        send(&plugin, MidiMessage::KeyChordOff(egui::Key::Z));
+        let moved = play(&mut plugin, &mut host, 2, -12);
+        assert_eq!(
+            started_and_stopped(&moved),
+            (vec![36, 40, 43], vec![50, 50, 54, 54, 57, 57])
+        );
+
+        send(&plugin, MidiMessage::ChordOff);
+        let released = play(&mut plugin, &mut host, 2, 2);
+        assert_eq!(started_and_stopped(&released), (vec![], vec![36, 40, 43]));
+    }
+}
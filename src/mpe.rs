@@ -0,0 +1,410 @@
+//! MIDI Polyphonic Expression output: every sounding note gets a member channel of its own, so a
+//! synth can bend, press and shape each note of a chord separately. The same per-note channels
+//! carry the pitch bends that retune notes to a microtonal tuning.
+
+use nih_plug::prelude::NoteEvent;
+
+use crate::theory::RetuneTable;
+
+/// The zone's master channel, counting from 0. Member channels follow it.
+pub const MASTER_CHANNEL: u8 = 0;
+/// Member channels of the lower zone: MIDI channels 2 to 16.
+pub const MEMBER_CHANNELS: u8 = 15;
+const CHANNELS: u8 = 16;
+
+/// Pitch bend ranges in semitones that receivers assume without being told otherwise: 48 on MPE
+/// member channels and 2 everywhere else.
+const MPE_BEND_RANGE: f32 = 48.0;
+const BEND_RANGE: f32 = 2.0;
+
+/// Where a sounding note was sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Voice {
+    channel: u8,
+    voice_id: Option<i32>,
+    /// The note sent, which differs from the note played when retuning
+    note: u8,
+    /// Whether the note has its channel to itself, as far as there are channels to go round
+    allocated: bool,
+}
+
+/// Hands out channels and voice IDs to notes and remembers them, so a note is released,
+/// pressed and bent on the channel it started on even if MPE or the tuning was switched in
+/// between.
+#[derive(Debug, Clone)]
+pub struct VoiceAllocator {
+    mpe: bool,
+    tuning: Option<Box<RetuneTable>>,
+    voices: [Option<Voice>; 128],
+    /// Notes sounding on each channel
+    channel_notes: [u8; CHANNELS as usize],
+    /// The channel to try first, so channels are reused as late as possible
+    next_channel: u8,
+    next_voice_id: i32,
+}
+
+impl Default for VoiceAllocator {
+    fn default() -> Self {
+        Self {
+            mpe: false,
+            tuning: None,
+            voices: [None; 128],
+            channel_notes: [0; CHANNELS as usize],
+            next_channel: 0,
+            next_voice_id: 0,
+        }
+    }
+}
+
+impl VoiceAllocator {
+    pub fn mpe(&self) -> bool {
+        self.mpe
+    }
+
+    /// Switches MPE on or off for notes started from now on, returning the MPE Configuration
+    /// Message that tells the receiver how many member channels to expect.
+    pub fn set_mpe<S>(&mut self, mpe: bool) -> [NoteEvent<S>; 3] {
+        self.mpe = mpe;
+        let member_channels = if mpe { MEMBER_CHANNELS } else { 0 };
+        // RPN 6 on the master channel, with the number of member channels as data entry
+        [(101, 0.0), (100, 6.0), (6, member_channels as f32)].map(|(cc, value)| NoteEvent::MidiCC {
+            timing: 0,
+            channel: MASTER_CHANNEL,
+            cc,
+            value: value / 127.0,
+        })
+    }
+
+    /// Retunes notes started from now on, or goes back to twelve-tone equal temperament with
+    /// `None`. Returns the previous table so it can be dropped off the audio thread.
+    pub fn set_tuning<S>(
+        &mut self,
+        tuning: Option<Box<RetuneTable>>,
+        mut emit: impl FnMut(NoteEvent<S>),
+    ) -> Option<Box<RetuneTable>> {
+        if tuning.is_none() && self.tuning.is_some() {
+            for channel in 0..CHANNELS {
+                emit(NoteEvent::MidiPitchBend {
+                    timing: 0,
+                    channel,
+                    value: 0.5,
+                });
+            }
+        }
+        std::mem::replace(&mut self.tuning, tuning)
+    }
+
+    /// Picks the first idle channel after the last one used, or the least busy one when every
+    /// channel is sounding. MPE uses the member channels, retuning without MPE all sixteen.
+    fn allocate_channel(&mut self) -> u8 {
+        let first = if self.mpe { MASTER_CHANNEL + 1 } else { 0 };
+        let count = CHANNELS - first;
+        let start = self.next_channel.clamp(first, CHANNELS - 1) - first;
+        let channel = (0..count)
+            .map(|offset| first + (start + offset) % count)
+            .min_by_key(|&channel| self.channel_notes[channel as usize])
+            .unwrap_or(first);
+        self.next_channel = first + (channel - first + 1) % count;
+        self.channel_notes[channel as usize] += 1;
+        channel
+    }
+
+    /// Starts a note. A note that is already sounding is retriggered where it is. Under a tuning
+    /// the note goes out where the tuning puts it, after a pitch bend on its channel, and notes the
+    /// tuning leaves unmapped are not played at all.
+    pub fn note_on<S>(
+        &mut self,
+        timing: u32,
+        note: u8,
+        velocity: f32,
+        mut emit: impl FnMut(NoteEvent<S>),
+    ) {
+        let voice = match self.voices[note as usize & 127] {
+            Some(voice) => voice,
+            None => {
+                let retuned = match &self.tuning {
+                    Some(table) => match table[note as usize & 127] {
+                        Some(retuned) => Some(retuned),
+                        None => return,
+                    },
+                    None => None,
+                };
+                let allocated = self.mpe || retuned.is_some();
+                let voice_id = self.mpe.then_some(self.next_voice_id);
+                if self.mpe {
+                    self.next_voice_id = self.next_voice_id.wrapping_add(1);
+                }
+                let voice = Voice {
+                    channel: if allocated {
+                        self.allocate_channel()
+                    } else {
+                        0
+                    },
+                    voice_id,
+                    note: retuned.map_or(note, |retuned| retuned.note),
+                    allocated,
+                };
+                if let Some(retuned) = retuned {
+                    let range = if self.mpe { MPE_BEND_RANGE } else { BEND_RANGE };
+                    emit(NoteEvent::MidiPitchBend {
+                        timing,
+                        channel: voice.channel,
+                        value: (0.5 + retuned.bend / (2.0 * range)).clamp(0.0, 1.0),
+                    });
+                }
+                voice
+            }
+        };
+        self.voices[note as usize & 127] = Some(voice);
+
+        emit(NoteEvent::NoteOn {
+            timing,
+            voice_id: voice.voice_id,
+            channel: voice.channel,
+            note: voice.note,
+            velocity,
+        });
+    }
+
+    /// Releases a note on the channel it was started on, freeing that channel.
+    pub fn note_off<S>(&mut self, timing: u32, note: u8) -> NoteEvent<S> {
+        let voice = self.voices[note as usize & 127].take();
+        if let Some(Voice {
+            channel,
+            allocated: true,
+            ..
+        }) = voice
+        {
+            let count = &mut self.channel_notes[channel as usize];
+            *count = count.saturating_sub(1);
+        }
+
+        NoteEvent::NoteOff {
+            timing,
+            voice_id: voice.and_then(|voice| voice.voice_id),
+            channel: voice.map_or(0, |voice| voice.channel),
+            note: voice.map_or(note, |voice| voice.note),
+            velocity: 0.0,
+        }
+    }
+
+    /// Per-note pressure for a sounding note: channel pressure on its member channel under MPE,
+    /// otherwise polyphonic aftertouch.
+    pub fn pressure<S>(&self, timing: u32, note: u8, pressure: f32) -> Option<NoteEvent<S>> {
+        let voice = self.voices[note as usize & 127]?;
+        Some(match voice.voice_id {
+            Some(_) => NoteEvent::MidiChannelPressure {
+                timing,
+                channel: voice.channel,
+                pressure,
+            },
+            None => NoteEvent::PolyPressure {
+                timing,
+                voice_id: None,
+                channel: voice.channel,
+                note: voice.note,
+                pressure,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::theory::RetunedNote;
+
+    type Event = NoteEvent<()>;
+
+    fn note_on(voices: &mut VoiceAllocator, note: u8) -> Vec<Event> {
+        let mut events = Vec::new();
+        voices.note_on(0, note, 0.8, |event| events.push(event));
+        events
+    }
+
+    /// The channel and voice ID a note started on.
+    fn started_on(voices: &mut VoiceAllocator, note: u8) -> (u8, Option<i32>) {
+        match *note_on(voices, note).last().unwrap() {
+            NoteEvent::NoteOn {
+                channel, voice_id, ..
+            } => (channel, voice_id),
+            event => panic!("expected a note on, got {event:?}"),
+        }
+    }
+
+    fn released_on(voices: &mut VoiceAllocator, note: u8) -> (u8, Option<i32>) {
+        match voices.note_off::<()>(0, note) {
+            NoteEvent::NoteOff {
+                channel, voice_id, ..
+            } => (channel, voice_id),
+            event => panic!("expected a note off, got {event:?}"),
+        }
+    }
+
+    fn mpe() -> VoiceAllocator {
+        let mut voices = VoiceAllocator::default();
+        voices.set_mpe::<()>(true);
+        voices
+    }
+
+    #[test]
+    fn announces_the_member_channels() {
+        let rpn = |member_channels: f32| -> [Event; 3] {
+            [(101, 0.0), (100, 6.0), (6, member_channels)].map(|(cc, value)| NoteEvent::MidiCC {
+                timing: 0,
+                channel: 0,
+                cc,
+                value: value / 127.0,
+            })
+        };
+        let mut voices = VoiceAllocator::default();
+        assert_eq!(voices.set_mpe(true), rpn(15.0));
+        assert!(voices.mpe());
+        assert_eq!(voices.set_mpe(false), rpn(0.0));
+        assert!(!voices.mpe());
+    }
+
+    #[test]
+    fn gives_every_note_a_member_channel_of_its_own() {
+        let mut voices = mpe();
+        let started: Vec<(u8, Option<i32>)> =
+            (60..75).map(|note| started_on(&mut voices, note)).collect();
+        // MIDI channels 2 to 16, in order, each note with its own voice ID
+        assert_eq!(
+            started
+                .iter()
+                .map(|&(channel, _)| channel)
+                .collect::<Vec<_>>(),
+            (1..16).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            started
+                .iter()
+                .map(|&(_, voice_id)| voice_id)
+                .collect::<Vec<_>>(),
+            (0..15).map(Some).collect::<Vec<_>>()
+        );
+        // A sixteenth note has to share, starting again from the first member channel
+        assert_eq!(started_on(&mut voices, 75).0, 1);
+    }
+
+    #[test]
+    fn reuses_channels_as_late_as_possible() {
+        let mut voices = mpe();
+        assert_eq!(started_on(&mut voices, 60).0, 1);
+        released_on(&mut voices, 60);
+        // The released channel may still be ringing out, so the next note moves on
+        assert_eq!(started_on(&mut voices, 62).0, 2);
+
+        // With every channel busy but one, the idle channel is taken wherever it is
+        let mut voices = mpe();
+        for note in 60..75 {
+            started_on(&mut voices, note);
+        }
+        assert_eq!(released_on(&mut voices, 64).0, 5);
+        assert_eq!(started_on(&mut voices, 80).0, 5);
+        // And a note that is already sounding is retriggered on its own channel
+        assert_eq!(started_on(&mut voices, 80).0, 5);
+    }
+
+    #[test]
+    fn plain_notes_share_the_first_channel_without_voice_ids() {
+        let mut voices = VoiceAllocator::default();
+        assert_eq!(started_on(&mut voices, 60), (0, None));
+        assert_eq!(started_on(&mut voices, 64), (0, None));
+        assert_eq!(released_on(&mut voices, 60), (0, None));
+        assert_eq!(
+            voices.pressure::<()>(0, 64, 0.5).map(|event| matches!(
+                event,
+                NoteEvent::PolyPressure {
+                    channel: 0,
+                    note: 64,
+                    ..
+                }
+            )),
+            Some(true)
+        );
+        assert_eq!(voices.pressure::<()>(0, 60, 0.5), None);
+    }
+
+    #[test]
+    fn notes_end_where_they_started_when_mpe_is_toggled() {
+        let mut voices = mpe();
+        let under_mpe = started_on(&mut voices, 60);
+        assert_eq!(under_mpe, (1, Some(0)));
+        voices.set_mpe::<()>(false);
+        let plain = started_on(&mut voices, 64);
+        assert_eq!(plain, (0, None));
+
+        assert_eq!(
+            voices.pressure(0, 60, 0.5),
+            Some(NoteEvent::<()>::MidiChannelPressure {
+                timing: 0,
+                channel: 1,
+                pressure: 0.5
+            })
+        );
+        assert_eq!(released_on(&mut voices, 60), under_mpe);
+        voices.set_mpe::<()>(true);
+        assert_eq!(released_on(&mut voices, 64), plain);
+    }
+
+    #[test]
+    fn retuned_notes_end_where_they_started_when_the_tuning_changes() {
+        // Every note a quarter tone sharp of the note below
+        let table: RetuneTable = std::array::from_fn(|note| {
+            (note > 0).then(|| RetunedNote {
+                note: note as u8 - 1,
+                bend: 0.5,
+            })
+        });
+        let mut voices = VoiceAllocator::default();
+        assert_eq!(voices.set_tuning::<()>(Some(Box::new(table)), |_| {}), None);
+        assert_eq!(
+            note_on(&mut voices, 60),
+            [
+                NoteEvent::MidiPitchBend {
+                    timing: 0,
+                    channel: 0,
+                    value: 0.5 + 0.5 / 4.0,
+                },
+                NoteEvent::NoteOn {
+                    timing: 0,
+                    voice_id: None,
+                    channel: 0,
+                    note: 59,
+                    velocity: 0.8,
+                },
+            ]
+        );
+        // Without MPE, retuned notes still get a channel each to bend, from all sixteen
+        assert_eq!(started_on(&mut voices, 62).0, 1);
+        // Unmapped notes aren't played
+        assert_eq!(note_on(&mut voices, 0), []);
+
+        let mut resets = Vec::new();
+        assert!(
+            voices
+                .set_tuning(None, |event: Event| resets.push(event))
+                .is_some()
+        );
+        assert_eq!(resets.len(), 16);
+        assert!(
+            resets.iter().all(
+                |event| matches!(event, NoteEvent::MidiPitchBend { value, .. } if *value == 0.5)
+            )
+        );
+        assert_eq!(
+            voices.note_off::<()>(0, 60),
+            NoteEvent::NoteOff {
+                timing: 0,
+                voice_id: None,
+                channel: 0,
+                note: 59,
+                velocity: 0.0,
+            }
+        );
+        assert_eq!(released_on(&mut voices, 62).0, 1);
+        assert_eq!(started_on(&mut voices, 60), (0, None));
+    }
+}
@@ -0,0 +1,513 @@
+//! Remote control over Open Sound Control: a UDP listener on localhost that turns OSC messages
+//! into the same [`MidiMessage`]s the editor sends, so control surfaces and show software can
+//! play chords and change settings.
+//!
+//! # Addresses
+//!
+//! | Address | Arguments | Effect |
+//! |---------|-----------|--------|
+//! | `/chord/on` | root, chord type | Plays a chord, like clicking it in the grid |
+//! | `/chord/off` | | Releases it |
+//! | `/scale` | root, scale type | Changes the key |
+//! | `/octave` | octave | Changes the octave chords are played in |
+//! | `/inversion` | inversion | Inverts the chord last selected for inversions |
+//! | `/inversion` | root, chord type, inversion | Inverts the given chord |
+//!
+//! Roots are note names such as `C`, `F#` or `Bb`, or pitch classes from 0 for C to 11 for B.
+//! Chord types are the keys used in `chords.json`, such as `maj`, `m7` or `7b9`, or positions in
+//! [`ChordType::ALL`] as in the harmony SysEx format. Scale types are `major` or `0` and `minor` or
+//! `1`. Numbers may be sent as ints or floats, since many control surfaces only send floats.
+//! Bundles are unpacked and their messages applied straight away, ignoring time tags.
+
+use crossbeam_channel::Sender;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::theory::{ChordId, ChordType, ChordVocabulary, PitchClass, Scale, ScaleType};
+use crate::{MidiMessage, SharedVocabulary};
+
+pub const DEFAULT_PORT: u16 = 9000;
+
+/// The largest UDP payload over IPv4.
+const MAX_PACKET_LEN: usize = 65507;
+/// How often the listener checks whether it should stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Whether to listen for OSC and on which port, saved with the plugin state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OscSettings {
+    pub enabled: bool,
+    pub port: u16,
+}
+
+impl Default for OscSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: DEFAULT_PORT,
+        }
+    }
+}
+
+/// Why a packet was ignored.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OscError {
+    /// The packet ended in the middle of a string, argument or bundle element
+    Truncated,
+    /// A string isn't valid UTF-8
+    InvalidString,
+    /// An argument type other than `i`, `f`, `s`, `T` or `F`
+    UnsupportedType(char),
+    UnknownAddress(String),
+    /// A message to a known address with the wrong arguments
+    InvalidArguments(String),
+}
+
+impl fmt::Display for OscError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OscError::Truncated => write!(f, "the packet is truncated"),
+            OscError::InvalidString => write!(f, "a string is not valid UTF-8"),
+            OscError::UnsupportedType(tag) => write!(f, "unsupported argument type `{}`", tag),
+            OscError::UnknownAddress(address) => write!(f, "unknown address {}", address),
+            OscError::InvalidArguments(address) => write!(f, "invalid arguments for {}", address),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OscArgument<'a> {
+    Int(i32),
+    Float(f32),
+    Str(&'a str),
+    Bool(bool),
+}
+
+impl OscArgument<'_> {
+    /// The argument as a whole number. Floats are rounded.
+    fn as_int(self) -> Option<i32> {
+        match self {
+            OscArgument::Int(value) => Some(value),
+            OscArgument::Float(value) if value.is_finite() => Some(value.round() as i32),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OscMessage<'a> {
+    pub address: &'a str,
+    pub arguments: Vec<OscArgument<'a>>,
+}
+
+/// Reads OSC's big-endian, four-byte-aligned values off the front of a packet.
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], OscError> {
+        if self.bytes.len() < len {
+            return Err(OscError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn word(&mut self) -> Result<[u8; 4], OscError> {
+        Ok(self.take(4)?.try_into().unwrap_or_default())
+    }
+
+    /// A string ends with at least one NUL and is padded to a multiple of four bytes.
+    fn string(&mut self) -> Result<&'a str, OscError> {
+        let len = self
+            .bytes
+            .iter()
+            .position(|&byte| byte == 0)
+            .ok_or(OscError::Truncated)?;
+        let bytes = self.take((len + 4) & !3)?;
+        std::str::from_utf8(&bytes[..len]).map_err(|_| OscError::InvalidString)
+    }
+}
+
+/// Parses an OSC packet, handing every message in it to `handle`, including those nested in
+/// bundles. A malformed packet may still have handed over the messages before the fault.
+pub fn parse_packet<'a>(
+    packet: &'a [u8],
+    handle: &mut impl FnMut(OscMessage<'a>),
+) -> Result<(), OscError> {
+    let mut reader = Reader { bytes: packet };
+    if let Some(elements) = packet.strip_prefix(b"#bundle\0") {
+        reader.bytes = elements;
+        // The time tag
+        reader.take(8)?;
+        while !reader.bytes.is_empty() {
+            let len = u32::from_be_bytes(reader.word()?) as usize;
+            parse_packet(reader.take(len)?, handle)?;
+        }
+        return Ok(());
+    }
+
+    let address = reader.string()?;
+    // Type tags are optional in old OSC implementations, which then send no arguments
+    let tags = if reader.bytes.first() == Some(&b',') {
+        reader.string()?
+    } else {
+        ","
+    };
+    let mut arguments = Vec::with_capacity(tags.len() - 1);
+    for tag in tags.chars().skip(1) {
+        arguments.push(match tag {
+            'i' => OscArgument::Int(i32::from_be_bytes(reader.word()?)),
+            'f' => OscArgument::Float(f32::from_be_bytes(reader.word()?)),
+            's' | 'S' => OscArgument::Str(reader.string()?),
+            'T' => OscArgument::Bool(true),
+            'F' => OscArgument::Bool(false),
+            tag => return Err(OscError::UnsupportedType(tag)),
+        });
+    }
+    handle(OscMessage { address, arguments });
+    Ok(())
+}
+
+fn parse_root(argument: OscArgument) -> Option<PitchClass> {
+    match argument {
+        OscArgument::Str(name) => {
+            let mut chars = name.chars();
+            let name: String = chars.next()?.to_uppercase().chain(chars).collect();
+            PitchClass::from_name(&name).or_else(|| {
+                let natural = PitchClass::from_name(name.strip_suffix('b')?)?;
+                Some(natural.transpose(-1))
+            })
+        }
+        argument => Some(PitchClass::new(argument.as_int()?.rem_euclid(12) as u8)),
+    }
+}
+
+fn parse_chord_type(argument: OscArgument, vocabulary: &ChordVocabulary) -> Option<ChordType> {
+    match argument {
+        OscArgument::Str(key) => vocabulary.from_key(key),
+        argument => ChordType::ALL
+            .get(usize::try_from(argument.as_int()?).ok()?)
+            .copied(),
+    }
+}
+
+fn parse_scale_type(argument: OscArgument) -> Option<ScaleType> {
+    match argument {
+        OscArgument::Str(name) if name.eq_ignore_ascii_case("major") => Some(ScaleType::Major),
+        OscArgument::Str(name) if name.eq_ignore_ascii_case("minor") => Some(ScaleType::Minor),
+        argument => match argument.as_int()? {
+            0 => Some(ScaleType::Major),
+            1 => Some(ScaleType::Minor),
+            _ => None,
+        },
+    }
+}
+
+/// The editor message an OSC message stands for, as listed in the [module docs](self).
+pub(crate) fn translate(
+    message: &OscMessage,
+    vocabulary: &ChordVocabulary,
+) -> Result<MidiMessage, OscError> {
+    let invalid = || OscError::InvalidArguments(message.address.to_string());
+    let chord = |root: OscArgument, chord_type: OscArgument| {
+        Some(ChordId::new(
+            parse_root(root)?,
+            parse_chord_type(chord_type, vocabulary)?,
+        ))
+    };
+
+    let translated = match (message.address, message.arguments.as_slice()) {
+        ("/chord/on", &[root, chord_type]) => chord(root, chord_type).map(MidiMessage::ChordOn),
+        ("/chord/off", _) => Some(MidiMessage::ChordOff),
+        ("/scale", &[root, scale_type]) => parse_root(root)
+            .zip(parse_scale_type(scale_type))
+            .map(|(root, scale_type)| MidiMessage::UpdateScale(Scale::new(root, scale_type))),
+        ("/octave", &[octave]) => octave.as_int().map(|octave| {
+            MidiMessage::UpdateOctave(octave.clamp(i8::MIN as i32, i8::MAX as i32) as i8)
+        }),
+        ("/inversion", &[inversion]) => inversion
+            .as_int()
+            .and_then(|inversion| u8::try_from(inversion).ok())
+            .map(|inversion| MidiMessage::SelectInversion(None, inversion)),
+        ("/inversion", &[root, chord_type, inversion]) => chord(root, chord_type)
+            .zip(
+                inversion
+                    .as_int()
+                    .and_then(|inversion| u8::try_from(inversion).ok()),
+            )
+            .map(|(chord, inversion)| MidiMessage::SelectInversion(Some(chord), inversion)),
+        ("/chord/on" | "/scale" | "/octave" | "/inversion", _) => None,
+        (address, _) => return Err(OscError::UnknownAddress(address.to_string())),
+    };
+    translated.ok_or_else(invalid)
+}
+
+/// A listener thread bound to a port on localhost. Dropping it stops the thread.
+pub struct OscServer {
+    port: u16,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl OscServer {
+    /// Starts listening on `port`, or on a free port chosen by the system with 0.
+    pub fn start(
+        port: u16,
+        sender: Sender<MidiMessage>,
+        vocabulary: SharedVocabulary,
+    ) -> io::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, port))?;
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+        let port = socket.local_addr()?.port();
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = thread::Builder::new()
+            .name("Perfect Chords OSC".to_string())
+            .spawn({
+                let stop = stop.clone();
+                move || serve(&socket, &sender, &vocabulary, &stop)
+            })?;
+
+        Ok(Self {
+            port,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl Drop for OscServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn serve(
+    socket: &UdpSocket,
+    sender: &Sender<MidiMessage>,
+    vocabulary: &SharedVocabulary,
+    stop: &AtomicBool,
+) {
+    let mut buffer = vec![0; MAX_PACKET_LEN];
+    while !stop.load(Ordering::Relaxed) {
+        let len = match socket.recv(&mut buffer) {
+            Ok(len) => len,
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                continue;
+            }
+            Err(err) => {
+                log::warn!("OSC receive failed: {}", err);
+                continue;
+            }
+        };
+
+        let result = parse_packet(&buffer[..len], &mut |message| match translate(
+            &message,
+            &vocabulary.read(),
+        ) {
+            // Dropped like the editor's messages if the audio thread has stopped draining the queue
+            Ok(message) => {
+                let _ = sender.try_send(message);
+            }
+            Err(err) => log::warn!("Ignoring OSC message: {}", err),
+        });
+        if let Err(err) = result {
+            log::warn!("Ignoring OSC packet: {}", err);
+        }
+    }
+}
+
+/// Starts, stops and moves the listener as the settings change. Shared by the plugin, which
+/// starts it when activated, and the editor, which changes the settings.
+pub struct OscControl {
+    sender: Sender<MidiMessage>,
+    vocabulary: SharedVocabulary,
+    server: Mutex<Option<OscServer>>,
+}
+
+impl OscControl {
+    pub fn new(sender: Sender<MidiMessage>, vocabulary: SharedVocabulary) -> Self {
+        Self {
+            sender,
+            vocabulary,
+            server: Mutex::new(None),
+        }
+    }
+
+    /// Makes the listener match `settings`. A listener already on the right port keeps running.
+    pub fn apply(&self, settings: OscSettings) -> io::Result<()> {
+        let mut server = self.server.lock().unwrap_or_else(|err| err.into_inner());
+        let port = server.as_ref().map(OscServer::port);
+        if settings.enabled && port == Some(settings.port) {
+            return Ok(());
+        }
+        // The old socket has to be closed before the new one can take over its port
+        *server = None;
+        if settings.enabled {
+            *server = Some(OscServer::start(
+                settings.port,
+                self.sender.clone(),
+                self.vocabulary.clone(),
+            )?);
+        }
+        Ok(())
+    }
+
+    /// Stops the listener and waits for its thread to finish.
+    pub fn stop(&self) {
+        let mut server = self.server.lock().unwrap_or_else(|err| err.into_inner());
+        *server = None;
+    }
+
+    /// The port being listened on, if any.
+    pub fn port(&self) -> Option<u16> {
+        let server = self.server.lock().unwrap_or_else(|err| err.into_inner());
+        server.as_ref().map(OscServer::port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encodes a message with the given type tags and already encoded arguments.
+    fn packet(address: &str, tags: &str, arguments: &[u8]) -> Vec<u8> {
+        let tags = if tags.is_empty() {
+            Vec::new()
+        } else {
+            string(tags)
+        };
+        [string(address), tags, arguments.to_vec()].concat()
+    }
+
+    fn string(value: &str) -> Vec<u8> {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.resize((value.len() + 4) & !3, 0);
+        bytes
+    }
+
+    fn int(value: i32) -> Vec<u8> {
+        value.to_be_bytes().to_vec()
+    }
+
+    #[test]
+    fn parses_arguments_and_bundles() {
+        let message = packet(
+            "/chord/on",
+            ",sf",
+            &[string("Bb"), 9.0f32.to_be_bytes().to_vec()].concat(),
+        );
+        let mut bundle = b"#bundle\0".to_vec();
+        bundle.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+        bundle.extend_from_slice(&int(message.len() as i32));
+        bundle.extend_from_slice(&message);
+
+        let mut messages = Vec::new();
+        assert_eq!(
+            parse_packet(&bundle, &mut |message| messages.push(message)),
+            Ok(())
+        );
+        assert_eq!(
+            messages,
+            [OscMessage {
+                address: "/chord/on",
+                arguments: vec![OscArgument::Str("Bb"), OscArgument::Float(9.0)],
+            }]
+        );
+
+        let ignore = &mut |_| {};
+        assert_eq!(
+            parse_packet(&message[..message.len() - 2], ignore),
+            Err(OscError::Truncated)
+        );
+        assert_eq!(
+            parse_packet(&bundle[..bundle.len() - 4], ignore),
+            Err(OscError::Truncated)
+        );
+        assert_eq!(parse_packet(b"/octave", ignore), Err(OscError::Truncated));
+        assert_eq!(
+            parse_packet(&packet("/octave", ",d", &[0; 8]), ignore),
+            Err(OscError::UnsupportedType('d'))
+        );
+    }
+
+    #[test]
+    fn listener_applies_valid_messages_only() {
+        let (sender, receiver) = crossbeam_channel::bounded(16);
+        let osc = OscControl::new(sender, SharedVocabulary::new(ChordVocabulary::new()));
+        osc.apply(OscSettings {
+            enabled: true,
+            port: 0,
+        })
+        .unwrap();
+        let port = osc.port().unwrap();
+        assert_ne!(port, 0);
+
+        let packets = [
+            packet("/chord/on", ",ss", &[string("f#"), string("m7")].concat()),
+            // Wrong type tags: a bool for the octave, and a type OSC clients may send but we don't read
+            packet("/octave", ",T", &[]),
+            packet("/octave", ",h", &[0; 8]),
+            // Truncated: the tag promises an int that never comes
+            packet("/octave", ",i", &[]),
+            packet("/unknown", ",i", &int(1)),
+            packet("/chord/on", ",s", &string("C")),
+            packet("/scale", ",ii", &[int(7), int(1)].concat()),
+            packet("/octave", ",f", &(-1.6f32).to_be_bytes()),
+            packet("/inversion", ",i", &int(2)),
+            packet("/chord/off", "", &[]),
+        ];
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        for packet in &packets {
+            socket.send_to(packet, (Ipv4Addr::LOCALHOST, port)).unwrap();
+        }
+
+        let mut received = Vec::new();
+        while let Ok(message) = receiver.recv_timeout(Duration::from_secs(2)) {
+            let done = matches!(message, MidiMessage::ChordOff);
+            received.push(message);
+            if done {
+                break;
+            }
+        }
+        let [
+            MidiMessage::ChordOn(chord),
+            MidiMessage::UpdateScale(scale),
+            MidiMessage::UpdateOctave(-2),
+            MidiMessage::SelectInversion(None, 2),
+            MidiMessage::ChordOff,
+        ] = received.as_slice()
+        else {
+            panic!("unexpected messages: {} received", received.len());
+        };
+        assert_eq!(*chord, ChordId::new(PitchClass::new(6), ChordType::Minor7));
+        assert_eq!(*scale, Scale::new(PitchClass::new(7), ScaleType::Minor));
+
+        osc.stop();
+        assert_eq!(osc.port(), None);
+        socket
+            .send_to(&packets[0], (Ipv4Addr::LOCALHOST, port))
+            .unwrap();
+        assert!(receiver.recv_timeout(POLL_INTERVAL * 3).is_err());
+    }
+}
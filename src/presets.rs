@@ -0,0 +1,608 @@
+//! Named presets bundling the scale, key mappings, inversions and octave along with the comping,
+//! quantize, MPE and harmony output settings. User presets are JSON files in the user's data
+//! directory; factory presets are built in and read-only. Key mappings can also be exported and
+//! imported on their own, and custom chord types are kept in a file next to the presets.
+
+use nih_plug_egui::egui;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::comping::{CompPattern, MAX_BARS, MAX_STEPS, Step};
+use crate::harmony::HarmonyOutput;
+use crate::quantize::Quantize;
+use crate::theory::{
+    ChordId, ChordType, ChordVocabulary, CustomChordType, NoteRangePolicy, PitchClass, Scale,
+    ScaleType, StoredChord,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Preset {
+    pub scale: Scale,
+    pub octave: i8,
+    #[serde(default)]
+    pub note_range_policy: NoteRangePolicy,
+    /// Chords keyed by key name, as returned by [`egui::Key::name`].
+    #[serde(default)]
+    pub key_mappings: BTreeMap<String, StoredChord>,
+    #[serde(default)]
+    pub inversions: Vec<ChordInversion>,
+    #[serde(default)]
+    pub comping: PresetComping,
+    #[serde(default)]
+    pub quantize: Quantize,
+    #[serde(default)]
+    pub min_note_length: bool,
+    #[serde(default)]
+    pub mpe: bool,
+    #[serde(default)]
+    pub harmony_output: HarmonyOutput,
+}
+
+/// The comping settings of a preset. The pattern's steps are stored as a list, since serde can't
+/// handle arrays as long as [`CompPattern::steps`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PresetComping {
+    pub enabled: bool,
+    pub bars: u8,
+    pub steps: Vec<Step>,
+}
+
+impl PresetComping {
+    pub fn new(enabled: bool, pattern: &CompPattern) -> Self {
+        Self {
+            enabled,
+            bars: pattern.bars,
+            steps: pattern.steps[..pattern.step_count()].to_vec(),
+        }
+    }
+
+    /// The pattern, with missing steps filled in with rests.
+    pub fn pattern(&self) -> CompPattern {
+        let mut steps = [Step::REST; MAX_STEPS];
+        for (step, stored) in steps.iter_mut().zip(&self.steps) {
+            *step = *stored;
+        }
+        CompPattern {
+            bars: self.bars.clamp(1, MAX_BARS as u8),
+            steps,
+        }
+    }
+}
+
+impl Default for PresetComping {
+    fn default() -> Self {
+        Self::new(false, &CompPattern::default())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChordInversion {
+    pub chord: StoredChord,
+    pub inversion: u8,
+}
+
+impl Preset {
+    /// The key mappings whose key names are known to egui and whose chords `vocabulary` defines.
+    pub fn key_mappings<'a>(
+        &'a self,
+        vocabulary: &'a ChordVocabulary,
+    ) -> impl Iterator<Item = (egui::Key, ChordId)> + 'a {
+        self.key_mappings.iter().filter_map(|(name, chord)| {
+            Some((egui::Key::from_name(name)?, vocabulary.resolve(chord)?))
+        })
+    }
+
+    /// The chord inversions whose chords `vocabulary` defines.
+    pub fn inversions<'a>(
+        &'a self,
+        vocabulary: &'a ChordVocabulary,
+    ) -> impl Iterator<Item = (ChordId, u8)> + 'a {
+        self.inversions.iter().filter_map(|inversion| {
+            Some((vocabulary.resolve(&inversion.chord)?, inversion.inversion))
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum PresetError {
+    Io(io::Error),
+    Parse(serde_json::Error),
+    /// An imported key-mapping file isn't valid JSON of the right shape
+    ParseMappings(serde_json::Error),
+    /// The custom chord types file isn't valid JSON of the right shape
+    ParseCustomChords(serde_json::Error),
+    InvalidName(String),
+    AlreadyExists(String),
+    ReadOnly(String),
+    NoPresetDirectory,
+    InvalidMappings(Vec<UnknownMappingName>),
+}
+
+/// Why an entry of an imported key-mapping file was rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UnknownMappingName {
+    Key(String),
+    Root { key: String, root: String },
+    ChordType { key: String, chord_type: String },
+}
+
+impl fmt::Display for UnknownMappingName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnknownMappingName::Key(key) => write!(f, "unknown key `{}`", key),
+            UnknownMappingName::Root { key, root } => {
+                write!(f, "key `{}`: unknown root note `{}`", key, root)
+            }
+            UnknownMappingName::ChordType { key, chord_type } => {
+                write!(f, "key `{}`: unknown chord type `{}`", key, chord_type)
+            }
+        }
+    }
+}
+
+impl fmt::Display for PresetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PresetError::Io(err) => write!(f, "{}", err),
+            PresetError::Parse(err) => write!(f, "invalid preset file: {}", err),
+            PresetError::ParseMappings(err) => write!(f, "invalid key-mapping file: {}", err),
+            PresetError::ParseCustomChords(err) => write!(f, "invalid custom chord file: {}", err),
+            PresetError::InvalidName(name) => write!(f, "`{}` is not a valid preset name", name),
+            PresetError::AlreadyExists(name) => {
+                write!(f, "a preset named `{}` already exists", name)
+            }
+            PresetError::ReadOnly(name) => {
+                write!(f, "`{}` is a factory preset and can't be changed", name)
+            }
+            PresetError::NoPresetDirectory => write!(f, "could not find a user data directory"),
+            PresetError::InvalidMappings(problems) => {
+                write!(f, "invalid key mappings: ")?;
+                for (index, problem) in problems.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, "; ")?;
+                    }
+                    write!(f, "{}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl From<io::Error> for PresetError {
+    fn from(err: io::Error) -> Self {
+        PresetError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for PresetError {
+    fn from(err: serde_json::Error) -> Self {
+        PresetError::Parse(err)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PresetEntry {
+    pub name: String,
+    pub factory: bool,
+}
+
+/// Where user presets are stored: `%APPDATA%` on Windows, `~/Library/Application Support` on
+/// macOS and `$XDG_DATA_HOME` (or `~/.local/share`) elsewhere.
+pub fn preset_directory() -> Option<PathBuf> {
+    let env_path = |name: &str| {
+        std::env::var_os(name)
+            .filter(|value| !value.is_empty())
+            .map(PathBuf::from)
+    };
+    let data_dir = if cfg!(target_os = "windows") {
+        env_path("APPDATA")
+    } else if cfg!(target_os = "macos") {
+        env_path("HOME").map(|home| home.join("Library").join("Application Support"))
+    } else {
+        env_path("XDG_DATA_HOME")
+            .or_else(|| env_path("HOME").map(|home| home.join(".local").join("share")))
+    }?;
+    Some(data_dir.join("Perfect Chords").join("Presets"))
+}
+
+/// The suggested location for exported key mappings, next to the preset folder.
+pub fn default_mapping_path() -> Option<PathBuf> {
+    Some(preset_directory()?.parent()?.join("key-mappings.json"))
+}
+
+/// The suggested location for progressions exported as MIDI files, next to the preset folder.
+pub fn default_progression_export_path() -> Option<PathBuf> {
+    Some(preset_directory()?.parent()?.join("progression.mid"))
+}
+
+pub fn custom_chords_path() -> Option<PathBuf> {
+    Some(preset_directory()?.parent()?.join("custom-chords.json"))
+}
+
+/// Registers the custom chord types saved by [`save_custom_chord_types`]. A missing file just
+/// means none have been defined. Invalid definitions are skipped and logged.
+pub fn load_custom_chord_types(vocabulary: &mut ChordVocabulary) -> Result<(), PresetError> {
+    let path = custom_chords_path().ok_or(PresetError::NoPresetDirectory)?;
+    let json = match fs::read_to_string(path) {
+        Ok(json) => json,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let definitions: Vec<CustomChordType> =
+        serde_json::from_str(&json).map_err(PresetError::ParseCustomChords)?;
+    for definition in definitions {
+        let key = definition.key.clone();
+        if let Err(err) = vocabulary.register(definition) {
+            log::warn!("Skipping custom chord type `{}`: {}", key, err);
+        }
+    }
+    Ok(())
+}
+
+pub fn save_custom_chord_types(vocabulary: &ChordVocabulary) -> Result<(), PresetError> {
+    let path = custom_chords_path().ok_or(PresetError::NoPresetDirectory)?;
+    let definitions: Vec<&CustomChordType> = vocabulary
+        .custom_chord_types()
+        .map(|(_, definition)| definition)
+        .collect();
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(&definitions)?)?;
+    Ok(())
+}
+
+fn preset_path(name: &str) -> Result<PathBuf, PresetError> {
+    if name.is_empty()
+        || name.trim() != name
+        || name.starts_with('.')
+        || name.contains(['/', '\\', ':'])
+    {
+        return Err(PresetError::InvalidName(name.to_string()));
+    }
+    if is_factory_preset(name) {
+        return Err(PresetError::ReadOnly(name.to_string()));
+    }
+    let directory = preset_directory().ok_or(PresetError::NoPresetDirectory)?;
+    Ok(directory.join(format!("{}.json", name)))
+}
+
+/// Lists the factory presets followed by the user presets in alphabetical order. A missing preset
+/// directory just means there are no user presets yet.
+pub fn list_presets() -> Vec<PresetEntry> {
+    let mut entries: Vec<PresetEntry> = FACTORY_PRESETS
+        .iter()
+        .map(|factory| PresetEntry {
+            name: factory.name.to_string(),
+            factory: true,
+        })
+        .collect();
+
+    let mut user_names: Vec<String> = preset_directory()
+        .and_then(|directory| fs::read_dir(directory).ok())
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            if path.extension()? != "json" {
+                return None;
+            }
+            Some(path.file_stem()?.to_str()?.to_string())
+        })
+        .filter(|name| !is_factory_preset(name))
+        .collect();
+    user_names.sort_by_key(|name| name.to_lowercase());
+
+    entries.extend(user_names.into_iter().map(|name| PresetEntry {
+        name,
+        factory: false,
+    }));
+    entries
+}
+
+pub fn load_preset(entry: &PresetEntry) -> Result<Preset, PresetError> {
+    if entry.factory {
+        return factory_preset(&entry.name)
+            .ok_or_else(|| PresetError::InvalidName(entry.name.clone()));
+    }
+    let json = fs::read_to_string(preset_path(&entry.name)?)?;
+    Ok(serde_json::from_str(&json)?)
+}
+
+pub fn save_preset(name: &str, preset: &Preset) -> Result<(), PresetError> {
+    let path = preset_path(name)?;
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(preset)?)?;
+    Ok(())
+}
+
+pub fn rename_preset(old_name: &str, new_name: &str) -> Result<(), PresetError> {
+    let old_path = preset_path(old_name)?;
+    let new_path = preset_path(new_name)?;
+    if new_path.exists() {
+        return Err(PresetError::AlreadyExists(new_name.to_string()));
+    }
+    fs::rename(old_path, new_path)?;
+    Ok(())
+}
+
+pub fn delete_preset(name: &str) -> Result<(), PresetError> {
+    fs::remove_file(preset_path(name)?)?;
+    Ok(())
+}
+
+/// The on-disk form of an exported key-mapping set. Chords are kept as plain strings when reading
+/// so every bad entry can be reported instead of failing on the first one.
+#[derive(Serialize, Deserialize)]
+struct KeyMappingFile<C> {
+    key_mappings: BTreeMap<String, C>,
+}
+
+#[derive(Deserialize)]
+struct RawChordId {
+    root: String,
+    chord_type: String,
+}
+
+pub fn export_key_mappings(
+    path: &Path,
+    mappings: impl Iterator<Item = (egui::Key, ChordId)>,
+    vocabulary: &ChordVocabulary,
+) -> Result<(), PresetError> {
+    let file = KeyMappingFile {
+        key_mappings: mappings
+            .map(|(key, chord_id)| (key.name().to_string(), vocabulary.store(chord_id)))
+            .collect(),
+    };
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(&file)?)?;
+    Ok(())
+}
+
+/// Reads a key-mapping set written by [`export_key_mappings`]. Nothing is returned unless every
+/// entry is valid.
+pub fn import_key_mappings(
+    path: &Path,
+    vocabulary: &ChordVocabulary,
+) -> Result<Vec<(egui::Key, ChordId)>, PresetError> {
+    let file: KeyMappingFile<RawChordId> =
+        serde_json::from_str(&fs::read_to_string(path)?).map_err(PresetError::ParseMappings)?;
+
+    let mut mappings = Vec::new();
+    let mut problems = Vec::new();
+    for (key_name, raw) in file.key_mappings {
+        let key = egui::Key::from_name(&key_name);
+        let root = PitchClass::from_name(&raw.root);
+        let chord_type = vocabulary.from_key(&raw.chord_type);
+
+        if key.is_none() {
+            problems.push(UnknownMappingName::Key(key_name.clone()));
+        }
+        if root.is_none() {
+            problems.push(UnknownMappingName::Root {
+                key: key_name.clone(),
+                root: raw.root,
+            });
+        }
+        if chord_type.is_none() {
+            problems.push(UnknownMappingName::ChordType {
+                key: key_name,
+                chord_type: raw.chord_type,
+            });
+        }
+        if let (Some(key), Some(root), Some(chord_type)) = (key, root, chord_type) {
+            mappings.push((key, ChordId::new(root, chord_type)));
+        }
+    }
+
+    if problems.is_empty() {
+        Ok(mappings)
+    } else {
+        Err(PresetError::InvalidMappings(problems))
+    }
+}
+
+struct FactoryPreset {
+    name: &'static str,
+    scale: (&'static str, ScaleType),
+    octave: i8,
+    /// Key name, root and chord type for each mapped key
+    mappings: &'static [(&'static str, &'static str, ChordType)],
+}
+
+const FACTORY_PRESETS: [FactoryPreset; 5] = [
+    FactoryPreset {
+        name: "Pop - Four Chords",
+        scale: ("C", ScaleType::Major),
+        octave: 3,
+        mappings: &[
+            ("Z", "C", ChordType::Major),
+            ("X", "G", ChordType::Major),
+            ("C", "A", ChordType::Minor),
+            ("V", "F", ChordType::Major),
+            ("B", "D", ChordType::Minor),
+            ("N", "E", ChordType::Minor),
+            ("M", "G", ChordType::Sus4),
+        ],
+    },
+    FactoryPreset {
+        name: "Jazz - ii V I",
+        scale: ("A#", ScaleType::Major),
+        octave: 3,
+        mappings: &[
+            ("Z", "C", ChordType::Minor7),
+            ("X", "F", ChordType::Dominant7),
+            ("C", "A#", ChordType::Major7),
+            ("V", "G", ChordType::Minor7),
+            ("B", "D#", ChordType::Major9),
+            ("N", "A", ChordType::HalfDiminished7),
+            ("M", "D", ChordType::Dominant7),
+        ],
+    },
+    FactoryPreset {
+        name: "Lo-Fi - Minor Sevenths",
+        scale: ("A", ScaleType::Minor),
+        octave: 3,
+        mappings: &[
+            ("Z", "A", ChordType::Minor9),
+            ("X", "D", ChordType::Minor7),
+            ("C", "F", ChordType::Major7),
+            ("V", "E", ChordType::Minor7),
+            ("B", "C", ChordType::Major9),
+            ("N", "G", ChordType::Dominant9),
+            ("M", "E", ChordType::Dominant7),
+        ],
+    },
+    FactoryPreset {
+        name: "Cinematic - Dark Minor",
+        scale: ("D", ScaleType::Minor),
+        octave: 2,
+        mappings: &[
+            ("Z", "D", ChordType::Minor),
+            ("X", "A#", ChordType::Major),
+            ("C", "F", ChordType::Major),
+            ("V", "C", ChordType::Major),
+            ("B", "G", ChordType::Minor),
+            ("N", "A", ChordType::Major),
+            ("M", "C#", ChordType::Diminished7),
+        ],
+    },
+    FactoryPreset {
+        name: "EDM - Epic Minor",
+        scale: ("F", ScaleType::Minor),
+        octave: 3,
+        mappings: &[
+            ("Z", "F", ChordType::Minor),
+            ("X", "C#", ChordType::Major),
+            ("C", "G#", ChordType::Major),
+            ("V", "D#", ChordType::Major),
+            ("B", "A#", ChordType::Minor),
+            ("N", "C", ChordType::Sus4),
+            ("M", "C", ChordType::Major),
+        ],
+    },
+];
+
+fn is_factory_preset(name: &str) -> bool {
+    FACTORY_PRESETS.iter().any(|factory| factory.name == name)
+}
+
+fn factory_preset(name: &str) -> Option<Preset> {
+    let factory = FACTORY_PRESETS
+        .iter()
+        .find(|factory| factory.name == name)?;
+    let (root, scale_type) = factory.scale;
+    let key_mappings = factory
+        .mappings
+        .iter()
+        .filter_map(|&(key, root, chord_type)| {
+            let chord = StoredChord {
+                root: PitchClass::from_name(root)?,
+                chord_type: chord_type.key().to_string(),
+            };
+            Some((key.to_string(), chord))
+        })
+        .collect();
+
+    Some(Preset {
+        scale: Scale::new(PitchClass::from_name(root)?, scale_type),
+        octave: factory.octave,
+        note_range_policy: NoteRangePolicy::default(),
+        key_mappings,
+        inversions: Vec::new(),
+        comping: PresetComping::default(),
+        quantize: Quantize::default(),
+        min_note_length: false,
+        mpe: false,
+        harmony_output: HarmonyOutput::default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comping::STEPS_PER_BAR;
+
+    #[test]
+    fn older_presets_load_with_default_settings() {
+        let json = r#"{"scale": {"root": "G", "scale_type": "Major"}, "octave": 3}"#;
+        let preset: Preset = serde_json::from_str(json).unwrap();
+        assert!(!preset.comping.enabled && !preset.mpe && !preset.min_note_length);
+        assert_eq!(preset.comping.pattern(), CompPattern::default());
+        assert_eq!(
+            (preset.quantize, preset.harmony_output),
+            (Quantize::Off, HarmonyOutput::Off)
+        );
+    }
+
+    #[test]
+    fn settings_round_trip() {
+        let mut preset = factory_preset("Pop - Four Chords").unwrap();
+        let pattern = CompPattern::parse("X==u==u=b=u==u== | B===--u=b=u=----").unwrap();
+        preset.comping = PresetComping::new(true, &pattern);
+        preset.quantize = Quantize::Eighth;
+        preset.min_note_length = true;
+        preset.mpe = true;
+        preset.harmony_output = HarmonyOutput::SysEx;
+
+        let json = serde_json::to_string(&preset).unwrap();
+        let loaded: Preset = serde_json::from_str(&json).unwrap();
+        assert!(loaded.comping.enabled);
+        assert_eq!(loaded.comping.steps.len(), 2 * STEPS_PER_BAR);
+        assert_eq!(loaded.comping.pattern(), pattern);
+        assert_eq!(
+            (loaded.quantize, loaded.min_note_length, loaded.mpe),
+            (Quantize::Eighth, true, true)
+        );
+        assert_eq!(loaded.harmony_output, HarmonyOutput::SysEx);
+        assert_eq!(loaded.key_mappings, preset.key_mappings);
+    }
+
+    #[test]
+    fn import_errors_name_the_file_kind() {
+        let path =
+            std::env::temp_dir().join(format!("perfect-chords-import-{}.json", std::process::id()));
+        let vocabulary = ChordVocabulary::new();
+
+        fs::write(&path, r#"{"key_mappings": {"Z": "C major"}}"#).unwrap();
+        let err = import_key_mappings(&path, &vocabulary).unwrap_err();
+        assert!(matches!(err, PresetError::ParseMappings(_)));
+        assert!(
+            err.to_string().starts_with("invalid key-mapping file: "),
+            "{}",
+            err
+        );
+
+        fs::write(&path, r#"{"key_mappings": {"Z": {"root": "H", "chord_type": "maj"}, "Ü": {"root": "C", "chord_type": "maj"}}}"#).unwrap();
+        let err = import_key_mappings(&path, &vocabulary).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid key mappings: key `Z`: unknown root note `H`; unknown key `Ü`"
+        );
+
+        fs::write(
+            &path,
+            r#"{"key_mappings": {"Z": {"root": "A", "chord_type": "m7"}}}"#,
+        )
+        .unwrap();
+        let mappings = import_key_mappings(&path, &vocabulary).unwrap();
+        assert_eq!(
+            mappings,
+            [(
+                egui::Key::Z,
+                ChordId::new(PitchClass::from_name("A").unwrap(), ChordType::Minor7)
+            )]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
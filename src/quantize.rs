@@ -0,0 +1,116 @@
+//! Holding chord triggers back until the next bar or subdivision of the host's grid.
+
+use serde::{Deserialize, Serialize};
+
+/// The grid triggers are quantized to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Quantize {
+    #[default]
+    Off,
+    Bar,
+    Quarter,
+    Eighth,
+    Sixteenth,
+}
+
+impl Quantize {
+    pub const ALL: [Quantize; 5] = [
+        Quantize::Off,
+        Quantize::Bar,
+        Quantize::Quarter,
+        Quantize::Eighth,
+        Quantize::Sixteenth,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Quantize::Off => "Off",
+            Quantize::Bar => "Bar",
+            Quantize::Quarter => "1/4",
+            Quantize::Eighth => "1/8",
+            Quantize::Sixteenth => "1/16",
+        }
+    }
+
+    /// The distance between grid points in quarter notes.
+    pub fn grid_beats(self, beats_per_bar: f64) -> Option<f64> {
+        match self {
+            Quantize::Off => None,
+            Quantize::Bar => Some(beats_per_bar),
+            Quantize::Quarter => Some(1.0),
+            Quantize::Eighth => Some(0.5),
+            Quantize::Sixteenth => Some(0.25),
+        }
+    }
+
+    /// Quarter notes from `pos_beats` until the next grid point, counting the grid from the start
+    /// of the current bar. A position right on a grid point waits for nothing.
+    pub fn beats_to_next(
+        self,
+        pos_beats: f64,
+        bar_start_beats: f64,
+        beats_per_bar: f64,
+    ) -> Option<f64> {
+        let grid = self.grid_beats(beats_per_bar).filter(|&grid| grid > 0.0)?;
+        let since_grid_point = (pos_beats - bar_start_beats).rem_euclid(grid);
+        // Rounding in the host's position shouldn't push a trigger on the grid point a whole step late
+        if since_grid_point < 1e-6 || grid - since_grid_point < 1e-6 {
+            Some(0.0)
+        } else {
+            Some(grid - since_grid_point)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grids_divide_the_bar() {
+        assert_eq!(Quantize::Off.grid_beats(4.0), None);
+        assert_eq!(Quantize::Bar.grid_beats(4.0), Some(4.0));
+        assert_eq!(Quantize::Bar.grid_beats(3.0), Some(3.0));
+        assert_eq!(Quantize::Quarter.grid_beats(3.0), Some(1.0));
+        assert_eq!(Quantize::Eighth.grid_beats(4.0), Some(0.5));
+        assert_eq!(Quantize::Sixteenth.grid_beats(4.0), Some(0.25));
+    }
+
+    #[test]
+    fn waits_for_the_next_grid_point() {
+        let wait = |quantize: Quantize, pos_beats| quantize.beats_to_next(pos_beats, 4.0, 4.0);
+        assert_eq!(wait(Quantize::Off, 5.3), None);
+        assert_eq!(wait(Quantize::Bar, 5.5), Some(2.5));
+        assert_eq!(wait(Quantize::Quarter, 5.25), Some(0.75));
+        assert_eq!(wait(Quantize::Eighth, 5.125), Some(0.375));
+        assert_eq!(wait(Quantize::Sixteenth, 5.125), Some(0.125));
+        assert_eq!(wait(Quantize::Sixteenth, 5.0625), Some(0.1875));
+    }
+
+    #[test]
+    fn counts_the_grid_from_the_bar_line() {
+        // A bar of 3/4 that starts on beat 6: the next bar line is on beat 9, not 8
+        assert_eq!(Quantize::Bar.beats_to_next(7.0, 6.0, 3.0), Some(2.0));
+        assert_eq!(Quantize::Bar.beats_to_next(8.5, 6.0, 3.0), Some(0.5));
+        // Bars of 6/8 that don't start on a whole beat shift the quarter-note grid with them
+        assert_eq!(Quantize::Quarter.beats_to_next(1.75, 1.5, 3.0), Some(0.75));
+        assert_eq!(Quantize::Quarter.beats_to_next(2.5, 1.5, 3.0), Some(0.0));
+    }
+
+    #[test]
+    fn positions_on_the_grid_play_at_once() {
+        assert_eq!(Quantize::Bar.beats_to_next(8.0, 8.0, 4.0), Some(0.0));
+        assert_eq!(
+            Quantize::Quarter.beats_to_next(5.0 + 1e-7, 4.0, 4.0),
+            Some(0.0)
+        );
+        assert_eq!(
+            Quantize::Quarter.beats_to_next(5.0 - 1e-7, 4.0, 4.0),
+            Some(0.0)
+        );
+        let just_after = Quantize::Quarter
+            .beats_to_next(5.0 + 1e-5, 4.0, 4.0)
+            .unwrap();
+        assert!((just_after - (1.0 - 1e-5)).abs() < 1e-9);
+    }
+}
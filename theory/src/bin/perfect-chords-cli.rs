@@ -0,0 +1,618 @@
+//! Command-line access to the chord engine for scripts and the asset pipeline. Every command
+//! prints plain text by default and JSON with `--json`.
+
+use perfect_chords_theory::{
+    self as theory, BASE_OCTAVE, ChordId, ChordSymbol, ChordTable, ChordType, ChordVocabulary,
+    NoteRangePolicy, OCTAVE_RANGE, Scale, SpelledNote, StoredChord, chord_voicing, identify_chords,
+    midi_file, parse_key, spell_chord,
+};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+const USAGE: &str = "\
+Usage: perfect-chords-cli [--json] <command> [arguments]
+
+Commands:
+  scale <root> <major|minor>          List the diatonic chords of a scale
+  chord <chord> [--octave N]          Print a chord's MIDI notes in every inversion
+  spell <chord>                       Spell a chord, e.g. `spell Ebmaj7` or `spell Eb maj7`
+  identify <note>...                  Name the chords made of the given MIDI note numbers
+  render <progression> -o <file.mid>  Write a progression such as \"I vi IV V in G major\"
+      [--octave N] [--beats N] [--bpm N]
+  chord-data                          Print chord data in the format of chords.json
+
+A chord is a lead-sheet symbol such as `F#m7b5` or `Bb7#9`, or a root and one of the chord
+type keys used in chords.json: maj, m, 5, sus2, sus4, 6, m6, 7, m7, maj7, dim, aug, 9, m9,
+maj9, flat5, m7b5, dim7, it6, fr6, ger6, add9, sus4add9, 7sus4, 7b9, 7#9, 13.";
+
+/// The tool only knows the built-in chord types.
+static VOCABULARY: ChordVocabulary = ChordVocabulary::new();
+
+/// A chord in JSON output: its display name next to the root and type it is stored under.
+#[derive(Serialize)]
+struct ChordOutput {
+    name: String,
+    #[serde(flatten)]
+    chord: StoredChord,
+}
+
+impl From<ChordId> for ChordOutput {
+    fn from(chord: ChordId) -> Self {
+        Self {
+            name: chord.display(&VOCABULARY).to_string(),
+            chord: VOCABULARY.store(chord),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct DegreeOutput {
+    degree: &'static str,
+    #[serde(flatten)]
+    chord: ChordOutput,
+}
+
+#[derive(Serialize)]
+struct ScaleOutput {
+    scale: String,
+    chords: Vec<DegreeOutput>,
+}
+
+#[derive(Serialize)]
+struct VoicingOutput {
+    #[serde(flatten)]
+    chord: ChordOutput,
+    octave: i8,
+    inversions: Vec<Vec<u8>>,
+}
+
+#[derive(Serialize)]
+struct SpellingOutput {
+    name: String,
+    notes: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct IdentifiedOutput {
+    /// The chord name, in slash notation when the lowest note isn't the root
+    name: String,
+    #[serde(flatten)]
+    chord: StoredChord,
+    bass: String,
+}
+
+#[derive(Serialize)]
+struct RenderOutput {
+    file: PathBuf,
+    scale: String,
+    chords: Vec<ChordOutput>,
+}
+
+/// A chord type's voicings in the shape `chords-builder` writes them.
+#[derive(Serialize)]
+struct ChordDataOutput {
+    root: Vec<u8>,
+    inversions: Vec<Vec<u8>>,
+}
+
+fn main() -> ExitCode {
+    match run(std::env::args().skip(1).collect()) {
+        Ok(output) => {
+            println!("{}", output);
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("error: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Runs a command line, returning what to print.
+fn run(mut args: Vec<String>) -> Result<String, String> {
+    let json = take_flag(&mut args, "--json");
+
+    if args.is_empty() || take_flag(&mut args, "--help") || take_flag(&mut args, "-h") {
+        return Ok(USAGE.to_string());
+    }
+
+    let command = args.remove(0);
+    match command.as_str() {
+        "scale" => scale(args, json),
+        "chord" => chord(args, json),
+        "spell" => spell(args, json),
+        "identify" => identify(args, json),
+        "render" => render(args, json),
+        "chord-data" => chord_data(args),
+        other => Err(format!("unknown command `{}`\n\n{}", other, USAGE)),
+    }
+}
+
+fn take_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    let len = args.len();
+    args.retain(|arg| arg != flag);
+    args.len() != len
+}
+
+/// Removes an option and its value from the arguments, parsing the value.
+fn take_option<T: std::str::FromStr>(
+    args: &mut Vec<String>,
+    names: &[&str],
+) -> Result<Option<T>, String> {
+    let Some(index) = args.iter().position(|arg| names.contains(&arg.as_str())) else {
+        return Ok(None);
+    };
+    let name = args.remove(index);
+    if index >= args.len() {
+        return Err(format!("`{}` needs a value", name));
+    }
+    let value = args.remove(index);
+    value
+        .parse()
+        .map(Some)
+        .map_err(|_| format!("invalid value `{}` for `{}`", value, name))
+}
+
+fn expect_args(args: &[String], count: usize, usage: &str) -> Result<(), String> {
+    if args.len() == count {
+        Ok(())
+    } else {
+        Err(format!("usage: perfect-chords-cli {}", usage))
+    }
+}
+
+/// Reads a chord given either as one symbol or as a root followed by a chord type key.
+fn parse_chord(args: &[String], usage: &str) -> Result<ChordSymbol, String> {
+    match args {
+        [symbol] => ChordSymbol::parse(symbol, &VOCABULARY).map_err(|err| err.to_string()),
+        [root, key] => Ok(ChordSymbol {
+            root: SpelledNote::parse(root)
+                .ok_or_else(|| format!("unknown note name `{}`", root))?,
+            chord_type: VOCABULARY
+                .from_key(key)
+                .ok_or_else(|| format!("unknown chord type `{}`", key))?,
+            bass: None,
+        }),
+        _ => Err(format!("usage: perfect-chords-cli {}", usage)),
+    }
+}
+
+fn parse_octave(args: &mut Vec<String>) -> Result<i8, String> {
+    let octave = take_option(args, &["--octave"])?.unwrap_or(BASE_OCTAVE);
+    if OCTAVE_RANGE.contains(&octave) {
+        Ok(octave)
+    } else {
+        Err(format!(
+            "octave {} is outside the range {} to {}",
+            octave,
+            OCTAVE_RANGE.start(),
+            OCTAVE_RANGE.end()
+        ))
+    }
+}
+
+/// Formats a command's output as pretty JSON, or as text.
+fn format_output<T: Serialize>(
+    json: bool,
+    value: &T,
+    text: impl FnOnce(&T) -> String,
+) -> Result<String, String> {
+    if json {
+        serde_json::to_string_pretty(value).map_err(|err| err.to_string())
+    } else {
+        Ok(text(value))
+    }
+}
+
+fn chord_table() -> ChordTable {
+    let (chord_table, error) = theory::full_chord_table(&VOCABULARY);
+    if let Some(error) = error {
+        eprintln!(
+            "warning: using computed chords because the built-in chord data is invalid: {}",
+            error
+        );
+    }
+    chord_table
+}
+
+fn scale(args: Vec<String>, json: bool) -> Result<String, String> {
+    expect_args(&args, 2, "scale <root> <major|minor>")?;
+    let key = args.join(" ");
+    let scale = parse_key(&key).ok_or_else(|| format!("unknown scale `{}`", key))?;
+
+    let output = ScaleOutput {
+        scale: scale.to_string(),
+        chords: scale
+            .diatonic_chords()
+            .into_iter()
+            .zip(scale.scale_type.degrees())
+            .map(|(chord, degree)| DegreeOutput {
+                degree,
+                chord: chord.into(),
+            })
+            .collect(),
+    };
+    format_output(json, &output, |output| {
+        let mut text = output.scale.clone();
+        for degree in &output.chords {
+            text.push_str(&format!("\n  {:<5} {}", degree.degree, degree.chord.name));
+        }
+        text
+    })
+}
+
+fn chord(mut args: Vec<String>, json: bool) -> Result<String, String> {
+    let octave = parse_octave(&mut args)?;
+    let chord = parse_chord(&args, "chord <chord> [--octave N]")?.chord();
+
+    let chord_table = chord_table();
+    let inversions = (0..theory::inversion_count(&chord_table, &chord))
+        .filter_map(|inversion| {
+            theory::voicing_notes(
+                &chord_table,
+                &chord,
+                inversion as u8,
+                octave,
+                NoteRangePolicy::default(),
+            )
+        })
+        .map(|notes| notes.to_vec())
+        .collect();
+
+    let output = VoicingOutput {
+        chord: chord.into(),
+        octave,
+        inversions,
+    };
+    format_output(json, &output, |output| {
+        let mut text = format!("{} (octave {})", output.chord.name, output.octave);
+        for (inversion, notes) in output.inversions.iter().enumerate() {
+            let notes: Vec<String> = notes.iter().map(u8::to_string).collect();
+            text.push_str(&format!(
+                "\n  {:<15} {}",
+                inversion_name(inversion),
+                notes.join(" ")
+            ));
+        }
+        text
+    })
+}
+
+fn inversion_name(inversion: usize) -> String {
+    match inversion {
+        0 => "root position".to_string(),
+        1 => "1st inversion".to_string(),
+        2 => "2nd inversion".to_string(),
+        3 => "3rd inversion".to_string(),
+        n => format!("{}th inversion", n),
+    }
+}
+
+fn spell(args: Vec<String>, json: bool) -> Result<String, String> {
+    let symbol = parse_chord(&args, "spell <chord>")?;
+
+    let output = SpellingOutput {
+        name: symbol.display(&VOCABULARY).to_string(),
+        notes: spell_chord(&VOCABULARY, symbol.root, symbol.chord_type)
+            .iter()
+            .map(ToString::to_string)
+            .collect(),
+    };
+    format_output(json, &output, |output| {
+        format!("{}: {}", output.name, output.notes.join(" "))
+    })
+}
+
+fn identify(args: Vec<String>, json: bool) -> Result<String, String> {
+    if args.is_empty() {
+        return Err("usage: perfect-chords-cli identify <note>...".to_string());
+    }
+    let notes = args
+        .iter()
+        .map(|arg| match arg.parse::<u8>() {
+            Ok(note) if note <= 127 => Ok(note),
+            _ => Err(format!("`{}` is not a MIDI note number (0-127)", arg)),
+        })
+        .collect::<Result<Vec<u8>, String>>()?;
+    let bass = theory::PitchClass::of_note(notes.iter().copied().min().unwrap_or_default());
+
+    let output: Vec<IdentifiedOutput> = identify_chords(&VOCABULARY, &notes)
+        .into_iter()
+        .map(|chord| IdentifiedOutput {
+            name: if chord.root == bass {
+                chord.display(&VOCABULARY).to_string()
+            } else {
+                format!("{}/{}", chord.display(&VOCABULARY), bass.name())
+            },
+            chord: VOCABULARY.store(chord),
+            bass: bass.name().to_string(),
+        })
+        .collect();
+    format_output(json, &output, |output| {
+        if output.is_empty() {
+            "No matching chord".to_string()
+        } else {
+            output
+                .iter()
+                .map(|chord| chord.name.as_str())
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+    })
+}
+
+/// Splits `"I vi IV V in G major"` into its numerals and key. Without a key the progression is
+/// read in C major.
+fn parse_progression(progression: &str) -> Result<(Scale, Vec<ChordId>), String> {
+    let (numerals, key) = match progression.rsplit_once(" in ") {
+        Some((numerals, key)) => (
+            numerals,
+            parse_key(key).ok_or_else(|| format!("unknown key `{}`", key.trim()))?,
+        ),
+        None => (progression, Scale::default()),
+    };
+
+    let chords = theory::parse_progression(numerals, key).map_err(|errors| {
+        let errors: Vec<String> = errors.iter().map(ToString::to_string).collect();
+        errors.join("\n")
+    })?;
+    if chords.is_empty() {
+        return Err("the progression has no chords".to_string());
+    }
+    Ok((key, chords))
+}
+
+fn render(mut args: Vec<String>, json: bool) -> Result<String, String> {
+    let file: PathBuf = take_option(&mut args, &["-o", "--output"])?
+        .ok_or("render needs an output file, e.g. `-o progression.mid`")?;
+    let octave = parse_octave(&mut args)?;
+    let beats = take_option(&mut args, &["--beats"])?.unwrap_or(4u32).max(1);
+    let bpm = take_option(&mut args, &["--bpm"])?.unwrap_or(120.0f64);
+    if args.is_empty() {
+        return Err("usage: perfect-chords-cli render <progression> -o <file.mid>".to_string());
+    }
+    let (scale, chords) = parse_progression(&args.join(" "))?;
+
+    let chord_table = chord_table();
+    let voicings = chords
+        .iter()
+        .map(|chord| {
+            theory::voicing_notes(&chord_table, chord, 0, octave, NoteRangePolicy::default())
+                .ok_or_else(|| format!("no voicing for {}", chord.display(&VOCABULARY)))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let notes = midi_file::chord_sequence(&voicings, beats, 100).ok_or_else(|| {
+        format!(
+            "--beats {} makes the progression too long for a MIDI file",
+            beats
+        )
+    })?;
+    midi_file::write(&file, &notes, bpm)
+        .map_err(|err| format!("could not write {}: {}", file.display(), err))?;
+
+    let output = RenderOutput {
+        file,
+        scale: scale.to_string(),
+        chords: chords.into_iter().map(ChordOutput::from).collect(),
+    };
+    format_output(json, &output, |output| {
+        let names: Vec<&str> = output
+            .chords
+            .iter()
+            .map(|chord| chord.name.as_str())
+            .collect();
+        format!(
+            "Wrote {} in {} to {}",
+            names.join(" "),
+            output.scale,
+            output.file.display()
+        )
+    })
+}
+
+/// Prints the chord data `chords-builder` generates, computed from the chord formulas.
+fn chord_data(args: Vec<String>) -> Result<String, String> {
+    expect_args(&args, 0, "chord-data")?;
+    let mut data: BTreeMap<&str, BTreeMap<String, ChordDataOutput>> = BTreeMap::new();
+    for root in (0..12).map(theory::PitchClass::new) {
+        let voicings = data.entry(root.name()).or_default();
+        for chord_type in ChordType::ALL {
+            let voicing = chord_voicing(root, chord_type.formula());
+            voicings.insert(
+                chord_type.key().to_string(),
+                ChordDataOutput {
+                    root: voicing.inversions[0].clone(),
+                    inversions: voicing.inversions,
+                },
+            );
+        }
+    }
+    format_output(true, &data, |_| String::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use perfect_chords_theory::ChordNotes;
+    use serde_json::{Value, json};
+
+    fn run_with(args: &[&str]) -> Result<String, String> {
+        run(args.iter().map(ToString::to_string).collect())
+    }
+
+    fn run_json(args: &[&str]) -> Value {
+        let output = run_with(&[&["--json"], args].concat()).unwrap();
+        serde_json::from_str(&output).unwrap()
+    }
+
+    /// A file in the temporary directory that is removed when the test is done with it.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            Self(std::env::temp_dir().join(format!(
+                "perfect-chords-cli-{}-{}",
+                std::process::id(),
+                name
+            )))
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn options_are_taken_with_their_values() {
+        let mut args: Vec<String> = ["I", "--beats", "3", "vi", "--json"]
+            .map(String::from)
+            .to_vec();
+        assert!(take_flag(&mut args, "--json"));
+        assert!(!take_flag(&mut args, "--json"));
+        assert_eq!(take_option(&mut args, &["--beats"]), Ok(Some(3u32)));
+        assert_eq!(take_option::<u32>(&mut args, &["--beats"]), Ok(None));
+        assert_eq!(args, ["I", "vi"]);
+
+        let mut args: Vec<String> = ["--bpm", "fast"].map(String::from).to_vec();
+        assert_eq!(
+            take_option::<f64>(&mut args, &["--bpm"]),
+            Err("invalid value `fast` for `--bpm`".to_string())
+        );
+        let mut args: Vec<String> = vec!["-o".to_string()];
+        assert_eq!(
+            take_option::<PathBuf>(&mut args, &["-o", "--output"]),
+            Err("`-o` needs a value".to_string())
+        );
+    }
+
+    #[test]
+    fn usage_errors_say_what_was_expected() {
+        assert_eq!(run_with(&[]), Ok(USAGE.to_string()));
+        assert_eq!(run_with(&["scale", "--help"]), Ok(USAGE.to_string()));
+        assert!(
+            run_with(&["transpose"])
+                .unwrap_err()
+                .starts_with("unknown command `transpose`")
+        );
+        assert_eq!(
+            run_with(&["scale", "G"]),
+            Err("usage: perfect-chords-cli scale <root> <major|minor>".to_string())
+        );
+        assert_eq!(
+            run_with(&["scale", "H", "major"]),
+            Err("unknown scale `H major`".to_string())
+        );
+        assert!(
+            run_with(&["chord", "C", "--octave", "12"])
+                .unwrap_err()
+                .starts_with("octave 12 is outside")
+        );
+        assert_eq!(
+            run_with(&["identify", "60", "128"]),
+            Err("`128` is not a MIDI note number (0-127)".to_string())
+        );
+    }
+
+    #[test]
+    fn prints_text_and_json() {
+        assert_eq!(
+            run_with(&["scale", "G", "major"]).unwrap(),
+            "G Major\n  I     G\n  ii    Am\n  iii   Bm\n  IV    C\n  V     D\n  vi    Em\n  vii°  F#dim"
+        );
+        let scale = run_json(&["scale", "a", "minor"]);
+        assert_eq!(scale["scale"], "A Minor");
+        assert_eq!(
+            scale["chords"][1],
+            json!({"degree": "ii°", "name": "Bdim", "root": "B", "chord_type": "dim"})
+        );
+
+        let chord = run_json(&["chord", "Cmaj7", "--octave", "4"]);
+        assert_eq!(chord["name"], "Cmaj7");
+        assert_eq!(chord["chord_type"], "maj7");
+        assert_eq!(chord["inversions"][0], json!([60, 64, 67, 71]));
+        assert_eq!(chord["inversions"][3], json!([71, 72, 76, 79]));
+        assert_eq!(
+            run_with(&["chord", "C"]).unwrap(),
+            "C (octave 3)\n  root position   48 52 55\n  1st inversion   52 55 60\n  2nd inversion   55 60 64"
+        );
+
+        assert_eq!(
+            run_json(&["spell", "Eb", "maj7"]),
+            json!({"name": "Ebmaj7", "notes": ["Eb", "G", "Bb", "D"]})
+        );
+        assert_eq!(
+            run_json(&["identify", "64", "67", "72"]),
+            json!([{"name": "C/E", "root": "C", "chord_type": "maj", "bass": "E"}])
+        );
+        assert_eq!(
+            run_with(&["identify", "60", "61"]).unwrap(),
+            "No matching chord"
+        );
+
+        let data = run_json(&["chord-data"]);
+        assert_eq!(data.as_object().unwrap().len(), 12);
+        assert_eq!(data["C"]["maj"]["root"], json!([48, 52, 55]));
+        assert_eq!(data["C"]["maj"]["inversions"][1], json!([52, 55, 60]));
+    }
+
+    #[test]
+    fn renders_progressions_to_midi_files() {
+        let file = TempFile::new("render.mid");
+        assert_eq!(
+            run_with(&[
+                "render",
+                "I",
+                "vi",
+                "-o",
+                file.path(),
+                "--beats",
+                "2",
+                "--bpm",
+                "90",
+                "--octave",
+                "4"
+            ]),
+            Ok(format!("Wrote C Am in C Major to {}", file.path()))
+        );
+        let chords = [
+            ChordNotes::from_slice(&[60, 64, 67]),
+            ChordNotes::from_slice(&[69, 72, 76]),
+        ];
+        let expected =
+            midi_file::encode(&midi_file::chord_sequence(&chords, 2, 100).unwrap(), 90.0);
+        assert_eq!(std::fs::read(&file.0).unwrap(), expected);
+
+        let output = run_json(&["render", "ii7 V7 I in Bb major", "--output", file.path()]);
+        assert_eq!(output["file"], file.path());
+        assert_eq!(output["scale"], "A# Major");
+        let names: Vec<&str> = output["chords"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|chord| chord["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, ["Cm7", "F7", "A#"]);
+
+        assert_eq!(
+            run_with(&["render", "I", "-o", file.path(), "--beats", "4294967295"]),
+            Err("--beats 4294967295 makes the progression too long for a MIDI file".to_string())
+        );
+        assert_eq!(
+            run_with(&["render", "I", "vi"]),
+            Err("render needs an output file, e.g. `-o progression.mid`".to_string())
+        );
+        assert!(
+            run_with(&["render", "I", "X", "-o", file.path()])
+                .unwrap_err()
+                .starts_with("chord 2 `X`")
+        );
+    }
+}
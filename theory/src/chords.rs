@@ -0,0 +1,1187 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::{Deref, RangeInclusive};
+
+use super::notes::{PitchClass, SpelledNote};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ChordType {
+    Major,
+    Minor,
+    Power,
+    Sus2,
+    Sus4,
+    Sixth,
+    MinorSixth,
+    Dominant7,
+    Minor7,
+    Major7,
+    Diminished,
+    Augmented,
+    Dominant9,
+    Minor9,
+    Major9,
+    Flat5,
+    HalfDiminished7,
+    Diminished7,
+    Italian6,
+    French6,
+    German6,
+    Add9,
+    Sus4Add9,
+    Dominant7Sus4,
+    Dominant7Flat9,
+    Dominant7Sharp9,
+    Dominant13,
+    /// A user-defined chord type, indexing the custom types of a [`ChordVocabulary`].
+    Custom(u8),
+}
+
+impl ChordType {
+    /// The chord types shown as grid rows, in display order.
+    pub const GRID: [ChordType; 18] = [
+        ChordType::Major,
+        ChordType::Minor,
+        ChordType::Power,
+        ChordType::Sus2,
+        ChordType::Sus4,
+        ChordType::Sixth,
+        ChordType::MinorSixth,
+        ChordType::Dominant7,
+        ChordType::Minor7,
+        ChordType::Major7,
+        ChordType::Diminished,
+        ChordType::Augmented,
+        ChordType::Dominant9,
+        ChordType::Minor9,
+        ChordType::Major9,
+        ChordType::Flat5,
+        ChordType::HalfDiminished7,
+        ChordType::Diminished7,
+    ];
+
+    /// Augmented sixth chords, named from the lowered sixth degree they are built on.
+    pub const AUGMENTED_SIXTHS: [ChordType; 3] =
+        [ChordType::Italian6, ChordType::French6, ChordType::German6];
+
+    /// Every built-in chord type. The ones after the grid rows and augmented sixths are extended
+    /// chords that lead-sheet symbols need but that have no row of their own.
+    pub const ALL: [ChordType; 27] = [
+        ChordType::Major,
+        ChordType::Minor,
+        ChordType::Power,
+        ChordType::Sus2,
+        ChordType::Sus4,
+        ChordType::Sixth,
+        ChordType::MinorSixth,
+        ChordType::Dominant7,
+        ChordType::Minor7,
+        ChordType::Major7,
+        ChordType::Diminished,
+        ChordType::Augmented,
+        ChordType::Dominant9,
+        ChordType::Minor9,
+        ChordType::Major9,
+        ChordType::Flat5,
+        ChordType::HalfDiminished7,
+        ChordType::Diminished7,
+        ChordType::Italian6,
+        ChordType::French6,
+        ChordType::German6,
+        ChordType::Add9,
+        ChordType::Sus4Add9,
+        ChordType::Dominant7Sus4,
+        ChordType::Dominant7Flat9,
+        ChordType::Dominant7Sharp9,
+        ChordType::Dominant13,
+    ];
+
+    /// The key this chord type is stored under in `chords.json`. Empty for custom chord types,
+    /// whose keys live in a [`ChordVocabulary`].
+    pub fn key(self) -> &'static str {
+        match self {
+            ChordType::Major => "maj",
+            ChordType::Minor => "m",
+            ChordType::Power => "5",
+            ChordType::Sus2 => "sus2",
+            ChordType::Sus4 => "sus4",
+            ChordType::Sixth => "6",
+            ChordType::MinorSixth => "m6",
+            ChordType::Dominant7 => "7",
+            ChordType::Minor7 => "m7",
+            ChordType::Major7 => "maj7",
+            ChordType::Diminished => "dim",
+            ChordType::Augmented => "aug",
+            ChordType::Dominant9 => "9",
+            ChordType::Minor9 => "m9",
+            ChordType::Major9 => "maj9",
+            ChordType::Flat5 => "flat5",
+            ChordType::HalfDiminished7 => "m7b5",
+            ChordType::Diminished7 => "dim7",
+            ChordType::Italian6 => "it6",
+            ChordType::French6 => "fr6",
+            ChordType::German6 => "ger6",
+            ChordType::Add9 => "add9",
+            ChordType::Sus4Add9 => "sus4add9",
+            ChordType::Dominant7Sus4 => "7sus4",
+            ChordType::Dominant7Flat9 => "7b9",
+            ChordType::Dominant7Sharp9 => "7#9",
+            ChordType::Dominant13 => "13",
+            ChordType::Custom(_) => "",
+        }
+    }
+
+    /// Intervals above the root, matching `CHORD_FORMULAS` in `chords-builder`. Empty for custom
+    /// chord types, whose intervals live in a [`ChordVocabulary`].
+    pub fn formula(self) -> &'static [u8] {
+        match self {
+            ChordType::Major => &[0, 4, 7],
+            ChordType::Minor => &[0, 3, 7],
+            ChordType::Power => &[0, 7],
+            ChordType::Sus2 => &[0, 2, 7],
+            ChordType::Sus4 => &[0, 5, 7],
+            ChordType::Sixth => &[0, 4, 7, 9],
+            ChordType::MinorSixth => &[0, 3, 7, 9],
+            ChordType::Dominant7 => &[0, 4, 7, 10],
+            ChordType::Minor7 => &[0, 3, 7, 10],
+            ChordType::Major7 => &[0, 4, 7, 11],
+            ChordType::Diminished => &[0, 3, 6],
+            ChordType::Augmented => &[0, 4, 8],
+            ChordType::Dominant9 => &[0, 4, 7, 10, 14],
+            ChordType::Minor9 => &[0, 3, 7, 10, 14],
+            ChordType::Major9 => &[0, 4, 7, 11, 14],
+            ChordType::Flat5 => &[0, 4, 6],
+            ChordType::HalfDiminished7 => &[0, 3, 6, 10],
+            ChordType::Diminished7 => &[0, 3, 6, 9],
+            ChordType::Italian6 => &[0, 4, 10],
+            ChordType::French6 => &[0, 4, 6, 10],
+            ChordType::German6 => &[0, 4, 7, 10],
+            ChordType::Add9 => &[0, 4, 7, 14],
+            ChordType::Sus4Add9 => &[0, 5, 7, 14],
+            ChordType::Dominant7Sus4 => &[0, 5, 7, 10],
+            ChordType::Dominant7Flat9 => &[0, 4, 7, 10, 13],
+            ChordType::Dominant7Sharp9 => &[0, 4, 7, 10, 15],
+            ChordType::Dominant13 => &[0, 4, 7, 10, 14, 21],
+            ChordType::Custom(_) => &[],
+        }
+    }
+
+    /// The suffix appended to the root note when naming a chord of this type. Empty for custom
+    /// chord types, whose suffixes live in a [`ChordVocabulary`].
+    pub fn suffix(self) -> &'static str {
+        match self {
+            ChordType::Major => "",
+            ChordType::Flat5 => "5-",
+            ChordType::Italian6 => "It+6",
+            ChordType::French6 => "Fr+6",
+            ChordType::German6 => "Ger+6",
+            ChordType::Custom(_) => "",
+            other => other.key(),
+        }
+    }
+
+    /// The built-in chord type stored under `key`. Custom keys are looked up with
+    /// [`ChordVocabulary::from_key`].
+    pub fn from_key(key: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|chord_type| chord_type.key() == key)
+    }
+}
+
+pub const MAX_CUSTOM_CHORD_TYPES: usize = 32;
+
+/// A chord type defined by the user as intervals above the root.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CustomChordType {
+    pub key: String,
+    pub suffix: String,
+    pub intervals: Vec<u8>,
+}
+
+impl CustomChordType {
+    /// Sorts and deduplicates the intervals, then checks that the chord can be stored and voiced.
+    pub fn normalized(mut self) -> Result<Self, String> {
+        self.key = self.key.trim().to_string();
+        self.intervals.sort_unstable();
+        self.intervals.dedup();
+
+        if self.key.is_empty() || self.key.contains(char::is_whitespace) {
+            return Err(format!("`{}` is not a valid chord type key", self.key));
+        }
+        if ChordType::ALL
+            .iter()
+            .any(|chord_type| chord_type.key() == self.key)
+        {
+            return Err(format!("`{}` is already a built-in chord type", self.key));
+        }
+        if self.intervals.first() != Some(&0) {
+            return Err("intervals must include the root (0)".to_string());
+        }
+        if self.intervals.len() > MAX_CHORD_NOTES {
+            return Err(format!(
+                "a chord can have at most {} notes",
+                MAX_CHORD_NOTES
+            ));
+        }
+        if self.intervals.iter().any(|&interval| interval > 36) {
+            return Err("intervals can span at most three octaves (36)".to_string());
+        }
+        Ok(self)
+    }
+}
+
+/// The chord types one plugin instance knows: the built-in ones plus the custom types the user
+/// defined. [`ChordType::Custom`] indexes into it, so chords with custom types only mean something
+/// alongside the vocabulary they came from.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChordVocabulary {
+    /// Removed types leave a `None` behind so the indices of the others stay valid.
+    custom: Vec<Option<CustomChordType>>,
+}
+
+impl ChordVocabulary {
+    /// A vocabulary of just the built-in chord types.
+    pub const fn new() -> Self {
+        Self { custom: Vec::new() }
+    }
+
+    fn custom(&self, chord_type: ChordType) -> Option<&CustomChordType> {
+        match chord_type {
+            ChordType::Custom(index) => self.custom.get(index as usize)?.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// The key a chord type is stored under: its `chords.json` key or the user-chosen key of a
+    /// custom chord type.
+    pub fn key(&self, chord_type: ChordType) -> &str {
+        match self.custom(chord_type) {
+            Some(custom) => &custom.key,
+            None => chord_type.key(),
+        }
+    }
+
+    /// The suffix appended to the root note when naming a chord of this type.
+    pub fn suffix(&self, chord_type: ChordType) -> &str {
+        match self.custom(chord_type) {
+            Some(custom) => &custom.suffix,
+            None => chord_type.suffix(),
+        }
+    }
+
+    /// The intervals above the root: the [`formula`](ChordType::formula) of a built-in chord type
+    /// or the registered intervals of a custom one.
+    pub fn intervals(&self, chord_type: ChordType) -> &[u8] {
+        match self.custom(chord_type) {
+            Some(custom) => &custom.intervals,
+            None => chord_type.formula(),
+        }
+    }
+
+    pub fn from_key(&self, key: &str) -> Option<ChordType> {
+        ChordType::from_key(key).or_else(|| {
+            self.custom_chord_types()
+                .find(|(_, custom)| custom.key == key)
+                .map(|(chord_type, _)| chord_type)
+        })
+    }
+
+    /// All currently defined custom chord types, in the order they were registered.
+    pub fn custom_chord_types(&self) -> impl Iterator<Item = (ChordType, &CustomChordType)> {
+        self.custom
+            .iter()
+            .enumerate()
+            .filter_map(|(index, custom)| Some((ChordType::Custom(index as u8), custom.as_ref()?)))
+    }
+
+    /// Every built-in chord type followed by the custom ones.
+    pub fn chord_types(&self) -> impl Iterator<Item = ChordType> + '_ {
+        ChordType::ALL
+            .into_iter()
+            .chain(self.custom_chord_types().map(|(chord_type, _)| chord_type))
+    }
+
+    /// The built-in grid rows followed by the custom chord types.
+    pub fn grid_rows(&self) -> Vec<ChordType> {
+        let mut rows = ChordType::GRID.to_vec();
+        rows.extend(self.custom_chord_types().map(|(chord_type, _)| chord_type));
+        rows
+    }
+
+    /// Adds a custom chord type, or replaces the definition of the one with the same key.
+    pub fn register(&mut self, custom: CustomChordType) -> Result<ChordType, String> {
+        let custom = custom.normalized()?;
+
+        if let Some(index) = self.custom.iter().position(|existing| {
+            existing
+                .as_ref()
+                .is_some_and(|existing| existing.key == custom.key)
+        }) {
+            self.custom[index] = Some(custom);
+            return Ok(ChordType::Custom(index as u8));
+        }
+
+        // Slots of removed types are never reused, so a chord still referring to one can't turn
+        // into a different chord. They are reclaimed when the vocabulary is loaded again.
+        if self.custom.len() >= MAX_CUSTOM_CHORD_TYPES {
+            return Err(format!(
+                "at most {} custom chord types can be defined, including removed ones until the plugin is reloaded",
+                MAX_CUSTOM_CHORD_TYPES
+            ));
+        }
+        self.custom.push(Some(custom));
+        Ok(ChordType::Custom(self.custom.len() as u8 - 1))
+    }
+
+    pub fn remove(&mut self, chord_type: ChordType) {
+        if let ChordType::Custom(index) = chord_type
+            && let Some(slot) = self.custom.get_mut(index as usize)
+        {
+            *slot = None;
+        }
+    }
+
+    /// Writes a chord the way files store it, with the chord type by key.
+    pub fn store(&self, chord: ChordId) -> StoredChord {
+        StoredChord {
+            root: chord.root,
+            chord_type: self.key(chord.chord_type).to_string(),
+        }
+    }
+
+    /// Looks up a stored chord. `None` if its chord type isn't defined here.
+    pub fn resolve(&self, chord: &StoredChord) -> Option<ChordId> {
+        Some(ChordId::new(chord.root, self.from_key(&chord.chord_type)?))
+    }
+}
+
+/// A chord as saved in presets and other files. The chord type is kept by key rather than as a
+/// [`ChordType`], since custom chord type indices only hold within one [`ChordVocabulary`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct StoredChord {
+    pub root: PitchClass,
+    pub chord_type: String,
+}
+
+/// Identifies a chord by root and type. It is `Copy` so it can travel to the audio thread and be
+/// used as a map key there without allocating.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Hash, PartialOrd, Ord)]
+pub struct ChordId {
+    pub root: PitchClass,
+    pub chord_type: ChordType,
+}
+
+impl ChordId {
+    pub fn new(root: PitchClass, chord_type: ChordType) -> Self {
+        Self { root, chord_type }
+    }
+
+    pub fn transpose(self, semitones: i32) -> Self {
+        Self::new(self.root.transpose(semitones), self.chord_type)
+    }
+
+    /// The chord's name, such as `Cmaj7`, with custom chord types named by `vocabulary`.
+    pub fn display(self, vocabulary: &ChordVocabulary) -> ChordName<'_> {
+        ChordName {
+            chord: self,
+            vocabulary,
+        }
+    }
+}
+
+/// Displays a chord's name. Returned by [`ChordId::display`].
+#[derive(Debug, Clone, Copy)]
+pub struct ChordName<'a> {
+    chord: ChordId,
+    vocabulary: &'a ChordVocabulary,
+}
+
+impl fmt::Display for ChordName<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{}",
+            self.chord.root.name(),
+            self.vocabulary.suffix(self.chord.chord_type)
+        )
+    }
+}
+
+pub const MAX_CHORD_NOTES: usize = 8;
+
+/// A fixed-capacity list of MIDI notes so voicings can be built and stored on the audio thread
+/// without allocating. Notes beyond [`MAX_CHORD_NOTES`] are dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ChordNotes {
+    notes: [u8; MAX_CHORD_NOTES],
+    len: u8,
+}
+
+impl ChordNotes {
+    pub fn from_slice(notes: &[u8]) -> Self {
+        let mut chord_notes = Self::default();
+        for &note in notes {
+            chord_notes.push(note);
+        }
+        chord_notes
+    }
+
+    pub fn push(&mut self, note: u8) {
+        if (self.len as usize) < MAX_CHORD_NOTES {
+            self.notes[self.len as usize] = note;
+            self.len += 1;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Shifts every note by `semitones`. Notes pushed out of the MIDI range are handled by
+    /// `policy`, and any that end up doubled are only kept once.
+    pub fn transposed(&self, semitones: i16, policy: NoteRangePolicy) -> Self {
+        let mut chord_notes = Self::default();
+        for &note in self.iter() {
+            if let Some(note) = policy.apply(note as i16 + semitones)
+                && !chord_notes.contains(&note)
+            {
+                chord_notes.push(note);
+            }
+        }
+        chord_notes
+    }
+}
+
+impl Deref for ChordNotes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.notes[..self.len as usize]
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ChordVoicing {
+    pub inversions: Vec<Vec<u8>>,
+}
+
+pub type ChordTable = HashMap<ChordId, ChordVoicing>;
+
+/// The octave the chord data is generated in. Octave arguments are absolute and get shifted
+/// relative to this one.
+pub const BASE_OCTAVE: i8 = 3;
+
+/// Voices a chord from its intervals the way `chords-builder` does: root position in
+/// [`BASE_OCTAVE`], then each inversion moves the lowest note up an octave.
+pub fn chord_voicing(root: PitchClass, intervals: &[u8]) -> ChordVoicing {
+    let base_note = 12 + BASE_OCTAVE as u8 * 12 + root.semitones();
+    let mut notes: Vec<u8> = intervals
+        .iter()
+        .map(|interval| base_note + interval)
+        .collect();
+
+    let mut inversions = Vec::with_capacity(notes.len());
+    for _ in 0..notes.len() {
+        let mut sorted = notes.clone();
+        sorted.sort_unstable();
+        inversions.push(sorted);
+        let lowest = notes.remove(0);
+        notes.push(lowest + 12);
+    }
+    ChordVoicing { inversions }
+}
+
+/// The built-in chords plus every custom chord type of `vocabulary` on all twelve roots. See
+/// [`builtin_chord_table`] for the error.
+pub fn full_chord_table(vocabulary: &ChordVocabulary) -> (ChordTable, Option<ChordDataError>) {
+    let (mut chord_table, error) = builtin_chord_table();
+    for (chord_type, custom) in vocabulary.custom_chord_types() {
+        for root in (0..12).map(PitchClass::new) {
+            chord_table.insert(
+                ChordId::new(root, chord_type),
+                chord_voicing(root, &custom.intervals),
+            );
+        }
+    }
+    (chord_table, error)
+}
+
+/// Why chord data was rejected.
+#[derive(Debug)]
+pub enum ChordDataError {
+    Parse(serde_json::Error),
+    UnknownRoot(String),
+    UnknownChordType(String),
+    EmptyInversions(ChordId),
+    TooManyNotes(ChordId),
+    NoteOutOfRange { chord: ChordId, note: u8 },
+}
+
+impl fmt::Display for ChordDataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Chord data only holds built-in chord types
+        let vocabulary = ChordVocabulary::new();
+        match self {
+            ChordDataError::Parse(err) => write!(f, "invalid chord data: {}", err),
+            ChordDataError::UnknownRoot(root) => write!(f, "unknown root note `{}`", root),
+            ChordDataError::UnknownChordType(key) => write!(f, "unknown chord type `{}`", key),
+            ChordDataError::EmptyInversions(chord) => {
+                write!(f, "{} has no inversions", chord.display(&vocabulary))
+            }
+            ChordDataError::TooManyNotes(chord) => {
+                write!(
+                    f,
+                    "{} has more than {} notes",
+                    chord.display(&vocabulary),
+                    MAX_CHORD_NOTES
+                )
+            }
+            ChordDataError::NoteOutOfRange { chord, note } => {
+                write!(
+                    f,
+                    "{} contains note {}, outside the MIDI range 0-127",
+                    chord.display(&vocabulary),
+                    note
+                )
+            }
+        }
+    }
+}
+
+impl From<serde_json::Error> for ChordDataError {
+    fn from(err: serde_json::Error) -> Self {
+        ChordDataError::Parse(err)
+    }
+}
+
+/// Parses and validates chord data in the format `chords-builder` writes. Anything wrong, including
+/// a chord type that isn't built in, rejects the whole file.
+pub fn parse_chord_table(json: &str) -> Result<ChordTable, ChordDataError> {
+    let raw: HashMap<String, HashMap<String, ChordVoicing>> = serde_json::from_str(json)?;
+
+    let mut chord_table = ChordTable::new();
+    for (root_note, voicings) in raw {
+        let root =
+            PitchClass::from_name(&root_note).ok_or(ChordDataError::UnknownRoot(root_note))?;
+        for (type_key, voicing) in voicings {
+            let chord_type =
+                ChordType::from_key(&type_key).ok_or(ChordDataError::UnknownChordType(type_key))?;
+            let chord = ChordId::new(root, chord_type);
+            if voicing.inversions.is_empty() || voicing.inversions.iter().any(Vec::is_empty) {
+                return Err(ChordDataError::EmptyInversions(chord));
+            }
+            if voicing
+                .inversions
+                .iter()
+                .any(|notes| notes.len() > MAX_CHORD_NOTES)
+            {
+                return Err(ChordDataError::TooManyNotes(chord));
+            }
+            if let Some(&note) = voicing
+                .inversions
+                .iter()
+                .flatten()
+                .find(|&&note| note > 127)
+            {
+                return Err(ChordDataError::NoteOutOfRange { chord, note });
+            }
+            chord_table.insert(chord, voicing);
+        }
+    }
+    Ok(chord_table)
+}
+
+/// Voices every built-in chord type from its [`ChordType::formula`]. This is what `chords-builder`
+/// generates, so it stands in for `chords.json` when that can't be used.
+pub fn computed_chord_table() -> ChordTable {
+    let mut chord_table = ChordTable::new();
+    for chord_type in ChordType::ALL {
+        for root in (0..12).map(PitchClass::new) {
+            chord_table.insert(
+                ChordId::new(root, chord_type),
+                chord_voicing(root, chord_type.formula()),
+            );
+        }
+    }
+    chord_table
+}
+
+/// Loads chord data, using the computed table instead if it is malformed. The error is returned
+/// alongside it so it can be reported.
+pub fn load_chord_table(json: &str) -> (ChordTable, Option<ChordDataError>) {
+    match parse_chord_table(json) {
+        Ok(chord_table) => (chord_table, None),
+        Err(err) => (computed_chord_table(), Some(err)),
+    }
+}
+
+/// Loads the chord data generated by `chords-builder`. See [`load_chord_table`].
+pub fn builtin_chord_table() -> (ChordTable, Option<ChordDataError>) {
+    load_chord_table(include_str!("../data/chords.json"))
+}
+
+pub fn inversion_count(chord_table: &ChordTable, chord_id: &ChordId) -> usize {
+    chord_table
+        .get(chord_id)
+        .map(|voicing| voicing.inversions.len())
+        .unwrap_or(0)
+}
+
+/// The octaves a chord can be played in. The root of the lowest is MIDI note 0 and the root of
+/// the highest is C9 (120), so upper chord tones may still need a [`NoteRangePolicy`].
+pub const OCTAVE_RANGE: RangeInclusive<i8> = -1..=9;
+
+/// What happens to chord tones that fall outside the MIDI note range after shifting octaves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum NoteRangePolicy {
+    /// Pins the note to 0 or 127.
+    Clamp,
+    /// Moves the note by whole octaves until it fits, keeping its pitch class.
+    #[default]
+    Fold,
+    /// Leaves the note out.
+    Drop,
+}
+
+impl NoteRangePolicy {
+    pub const ALL: [NoteRangePolicy; 3] = [
+        NoteRangePolicy::Clamp,
+        NoteRangePolicy::Fold,
+        NoteRangePolicy::Drop,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            NoteRangePolicy::Clamp => "Clamp",
+            NoteRangePolicy::Fold => "Fold",
+            NoteRangePolicy::Drop => "Drop",
+        }
+    }
+
+    pub fn apply(self, note: i16) -> Option<u8> {
+        match self {
+            NoteRangePolicy::Clamp => Some(note.clamp(0, 127) as u8),
+            NoteRangePolicy::Fold => {
+                let mut note = note;
+                while note < 0 {
+                    note += 12;
+                }
+                while note > 127 {
+                    note -= 12;
+                }
+                Some(note as u8)
+            }
+            NoteRangePolicy::Drop => (0..=127).contains(&note).then_some(note as u8),
+        }
+    }
+}
+
+/// Looks up the MIDI notes of a chord in the given inversion and octave. Inversion indices wrap
+/// around so callers can cycle through them freely. Notes pushed out of the MIDI range are handled
+/// by `policy`, and any that end up doubled are only played once.
+pub fn voicing_notes(
+    chord_table: &ChordTable,
+    chord_id: &ChordId,
+    inversion: u8,
+    octave: i8,
+    policy: NoteRangePolicy,
+) -> Option<ChordNotes> {
+    let voicing = chord_table.get(chord_id)?;
+    if voicing.inversions.is_empty() {
+        return None;
+    }
+
+    let notes = &voicing.inversions[inversion as usize % voicing.inversions.len()];
+    let octave_offset = (octave as i16 - BASE_OCTAVE as i16) * 12;
+    let mut chord_notes = ChordNotes::default();
+    for &note in notes {
+        if let Some(note) = policy.apply(note as i16 + octave_offset)
+            && !chord_notes.contains(&note)
+        {
+            chord_notes.push(note);
+        }
+    }
+    Some(chord_notes)
+}
+
+/// How many letters above the root each chord tone is written. Augmented sixth chords are spelled
+/// with their raised sixth and, for the French sixth, raised fourth. Otherwise an altered fifth or
+/// sixth is spelled in place unless the chord also has a perfect fifth, which makes it a raised
+/// eleventh or lowered thirteenth, and a minor third next to a major third is a raised ninth.
+fn letter_steps(chord_type: ChordType, intervals: &[u8]) -> Vec<u8> {
+    match chord_type {
+        ChordType::Italian6 => return vec![0, 2, 5],
+        ChordType::French6 => return vec![0, 2, 3, 5],
+        ChordType::German6 => return vec![0, 2, 4, 5],
+        _ => {}
+    }
+    let has_third = intervals.iter().any(|&interval| interval % 12 == 4);
+    let has_fifth = intervals.iter().any(|&interval| interval % 12 == 7);
+    intervals
+        .iter()
+        .map(|&interval| match interval % 12 {
+            0 => 0,
+            1 | 2 => 1,
+            3 if has_third => 1,
+            3 | 4 => 2,
+            5 => 3,
+            6 if has_fifth => 3,
+            6 | 7 => 4,
+            8 if has_fifth => 5,
+            8 => 4,
+            9 if chord_type == ChordType::Diminished7 => 6,
+            9 => 5,
+            _ => 6,
+        })
+        .collect()
+}
+
+/// Spells a chord from a written root, one letter per chord tone: `Eb` major is `Eb G Bb` rather
+/// than `D# G A#`.
+pub fn spell_chord(
+    vocabulary: &ChordVocabulary,
+    root: SpelledNote,
+    chord_type: ChordType,
+) -> Vec<SpelledNote> {
+    let intervals = vocabulary.intervals(chord_type);
+    letter_steps(chord_type, intervals)
+        .into_iter()
+        .zip(intervals.iter())
+        .map(|(steps, &interval)| root.above(steps, interval))
+        .collect()
+}
+
+/// Names the chords made up of exactly the pitch classes of `notes`, ignoring octaves and
+/// doublings. Chords rooted on the lowest note come first, so root position wins over inversions.
+pub fn identify_chords(vocabulary: &ChordVocabulary, notes: &[u8]) -> Vec<ChordId> {
+    let Some(&bass) = notes.iter().min() else {
+        return Vec::new();
+    };
+    let pitch_classes = |root: u8, intervals: &[u8]| {
+        intervals
+            .iter()
+            .fold(0u16, |mask, &interval| mask | 1 << ((root + interval) % 12))
+    };
+    let target = notes
+        .iter()
+        .fold(0u16, |mask, &note| mask | 1 << (note % 12));
+
+    let mut matches: Vec<ChordId> = vocabulary
+        .chord_types()
+        .flat_map(|chord_type| {
+            let intervals = vocabulary.intervals(chord_type);
+            (0..12)
+                .filter(|&root| !intervals.is_empty() && pitch_classes(root, intervals) == target)
+                .map(|root| ChordId::new(PitchClass::new(root), chord_type))
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    matches.sort_by_key(|chord| chord.root != PitchClass::of_note(bass));
+    matches
+}
+
+/// The function of a note within a chord, judged by its interval above the root.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoteRole {
+    Root,
+    Third,
+    Fifth,
+    Other,
+}
+
+impl NoteRole {
+    pub fn of(chord_id: &ChordId, note: u8) -> Self {
+        match (note + 12 - chord_id.root.semitones()) % 12 {
+            0 => NoteRole::Root,
+            3 | 4 => NoteRole::Third,
+            6..=8 => NoteRole::Fifth,
+            _ => NoteRole::Other,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn custom(key: &str, intervals: &[u8]) -> CustomChordType {
+        CustomChordType {
+            key: key.to_string(),
+            suffix: key.to_string(),
+            intervals: intervals.to_vec(),
+        }
+    }
+
+    fn spelled(chord: &str) -> String {
+        let symbol = crate::ChordSymbol::parse(chord, &ChordVocabulary::new()).unwrap();
+        let notes: Vec<String> =
+            spell_chord(&ChordVocabulary::new(), symbol.root, symbol.chord_type)
+                .iter()
+                .map(ToString::to_string)
+                .collect();
+        notes.join(" ")
+    }
+
+    #[test]
+    fn spells_one_letter_per_chord_tone() {
+        assert_eq!(spelled("C"), "C E G");
+        assert_eq!(spelled("Ebm7"), "Eb Gb Bb Db");
+        assert_eq!(spelled("F#maj7"), "F# A# C# E#");
+        assert_eq!(spelled("Bdim7"), "B D F Ab");
+        assert_eq!(spelled("Dbaug"), "Db F A");
+        assert_eq!(spelled("Bm7b5"), "B D F A");
+        assert_eq!(spelled("G7#9"), "G B D F A#");
+        assert_eq!(spelled("C7b9"), "C E G Bb Db");
+    }
+
+    #[test]
+    fn spells_augmented_sixths_with_the_raised_sixth() {
+        let vocabulary = ChordVocabulary::new();
+        let ab = SpelledNote::parse("Ab").unwrap();
+        let spell = |chord_type| {
+            let notes: Vec<String> = spell_chord(&vocabulary, ab, chord_type)
+                .iter()
+                .map(ToString::to_string)
+                .collect();
+            notes.join(" ")
+        };
+        assert_eq!(spell(ChordType::Italian6), "Ab C F#");
+        assert_eq!(spell(ChordType::French6), "Ab C D F#");
+        assert_eq!(spell(ChordType::German6), "Ab C Eb F#");
+    }
+
+    #[test]
+    fn chord_data_matches_the_formulas() {
+        let (chord_table, error) = builtin_chord_table();
+        assert!(error.is_none(), "{:?}", error);
+        let computed = computed_chord_table();
+        assert_eq!(chord_table.len(), computed.len());
+        for (chord, voicing) in &computed {
+            assert_eq!(
+                chord_table[chord].inversions, voicing.inversions,
+                "{:?}",
+                chord
+            );
+        }
+    }
+
+    fn chord_data_error(json: &str) -> ChordDataError {
+        parse_chord_table(json).expect_err(json)
+    }
+
+    #[test]
+    fn rejects_malformed_chord_data() {
+        let c_major = ChordId::new(PitchClass::new(0), ChordType::Major);
+        assert!(matches!(
+            chord_data_error(r#"{"C": {"maj": {"root": [48, 52, 55], "inversions": [[48, 52"#),
+            ChordDataError::Parse(_)
+        ));
+        assert!(matches!(
+            chord_data_error(r#"{"C": {"maj": {}}}"#),
+            ChordDataError::Parse(_)
+        ));
+        assert!(matches!(
+            chord_data_error(r#"{"H": {"maj": {"inversions": [[48, 52, 55]]}}}"#),
+            ChordDataError::UnknownRoot(root) if root == "H"
+        ));
+        assert!(matches!(
+            chord_data_error(r#"{"C": {"maj13#11": {"inversions": [[48, 52, 55]]}}}"#),
+            ChordDataError::UnknownChordType(key) if key == "maj13#11"
+        ));
+        assert!(matches!(
+            chord_data_error(r#"{"C": {"maj": {"inversions": []}}}"#),
+            ChordDataError::EmptyInversions(chord) if chord == c_major
+        ));
+        assert!(matches!(
+            chord_data_error(r#"{"C": {"maj": {"inversions": [[48, 52, 55], []]}}}"#),
+            ChordDataError::EmptyInversions(chord) if chord == c_major
+        ));
+        assert!(matches!(
+            chord_data_error(r#"{"C": {"maj": {"inversions": [[48, 52, 55, 60, 64, 67, 72, 76, 79]]}}}"#),
+            ChordDataError::TooManyNotes(chord) if chord == c_major
+        ));
+        assert!(matches!(
+            chord_data_error(r#"{"C": {"maj": {"inversions": [[120, 124, 128]]}}}"#),
+            ChordDataError::NoteOutOfRange { chord, note: 128 } if chord == c_major
+        ));
+        // Larger than a byte fails to parse before it can be range checked
+        assert!(matches!(
+            chord_data_error(r#"{"C": {"maj": {"inversions": [[48, 52, 300]]}}}"#),
+            ChordDataError::Parse(_)
+        ));
+    }
+
+    #[test]
+    fn malformed_chord_data_falls_back_to_computed_chords() {
+        let (chord_table, error) = load_chord_table(r#"{"C": {"maj": {"inversions": []}}}"#);
+        assert!(matches!(error, Some(ChordDataError::EmptyInversions(_))));
+        let computed = computed_chord_table();
+        assert_eq!(chord_table.len(), computed.len());
+        for (chord, voicing) in &computed {
+            assert_eq!(chord_table[chord].inversions, voicing.inversions);
+        }
+
+        let (chord_table, error) =
+            load_chord_table(r#"{"D": {"m": {"inversions": [[50, 53, 57]]}}}"#);
+        assert!(error.is_none());
+        assert_eq!(chord_table.len(), 1);
+    }
+
+    #[test]
+    fn inversions_move_the_lowest_note_up_an_octave() {
+        let voicing = chord_voicing(PitchClass::new(0), ChordType::Major7.formula());
+        assert_eq!(
+            voicing.inversions,
+            vec![
+                vec![48, 52, 55, 59],
+                vec![52, 55, 59, 60],
+                vec![55, 59, 60, 64],
+                vec![59, 60, 64, 67]
+            ]
+        );
+
+        let chord_table = computed_chord_table();
+        for chord_type in ChordType::ALL {
+            let chord = ChordId::new(PitchClass::new(0), chord_type);
+            assert_eq!(
+                inversion_count(&chord_table, &chord),
+                chord_type.formula().len()
+            );
+        }
+        assert_eq!(
+            inversion_count(
+                &chord_table,
+                &ChordId::new(PitchClass::new(0), ChordType::Custom(0))
+            ),
+            0
+        );
+    }
+
+    #[test]
+    fn voicing_notes_shifts_octaves_and_wraps_inversions() {
+        let chord_table = computed_chord_table();
+        let chord = ChordId::new(PitchClass::from_name("D").unwrap(), ChordType::Minor);
+        let notes = |inversion, octave| {
+            voicing_notes(
+                &chord_table,
+                &chord,
+                inversion,
+                octave,
+                NoteRangePolicy::Fold,
+            )
+            .unwrap()
+            .to_vec()
+        };
+        assert_eq!(notes(0, BASE_OCTAVE), [50, 53, 57]);
+        assert_eq!(notes(1, BASE_OCTAVE), [53, 57, 62]);
+        assert_eq!(notes(0, BASE_OCTAVE + 1), [62, 65, 69]);
+        assert_eq!(notes(3, BASE_OCTAVE), notes(0, BASE_OCTAVE));
+        assert_eq!(notes(4, BASE_OCTAVE - 2), notes(1, BASE_OCTAVE - 2));
+
+        let missing = ChordId::new(PitchClass::new(0), ChordType::Custom(0));
+        assert!(
+            voicing_notes(
+                &chord_table,
+                &missing,
+                0,
+                BASE_OCTAVE,
+                NoteRangePolicy::Fold
+            )
+            .is_none()
+        );
+    }
+
+    #[test]
+    fn every_octave_stays_in_the_midi_range() {
+        let chord_table = computed_chord_table();
+        for octave in OCTAVE_RANGE {
+            for root in (0..12).map(PitchClass::new) {
+                for chord_type in ChordType::ALL {
+                    let chord = ChordId::new(root, chord_type);
+                    for inversion in 0..inversion_count(&chord_table, &chord) as u8 {
+                        let notes = |policy| {
+                            voicing_notes(&chord_table, &chord, inversion, octave, policy).unwrap()
+                        };
+                        let context =
+                            format!("{:?} inversion {} octave {}", chord, inversion, octave);
+                        for policy in NoteRangePolicy::ALL {
+                            assert!(
+                                notes(policy).iter().all(|&note| note <= 127),
+                                "{} {:?}",
+                                context,
+                                policy
+                            );
+                        }
+
+                        // Drop leaves out exactly the notes that don't fit
+                        let offset = (octave as i16 - BASE_OCTAVE as i16) * 12;
+                        let in_range: Vec<u8> = chord_table[&chord].inversions[inversion as usize]
+                            .iter()
+                            .map(|&note| note as i16 + offset)
+                            .filter(|note| (0..=127).contains(note))
+                            .map(|note| note as u8)
+                            .collect();
+                        assert_eq!(
+                            notes(NoteRangePolicy::Drop).to_vec(),
+                            in_range,
+                            "{}",
+                            context
+                        );
+
+                        // Fold keeps every pitch class
+                        let pitch_classes = |notes: &[u8]| {
+                            notes
+                                .iter()
+                                .fold(0u16, |mask, note| mask | 1 << (note % 12))
+                        };
+                        assert_eq!(
+                            pitch_classes(&notes(NoteRangePolicy::Fold)),
+                            pitch_classes(&chord_table[&chord].inversions[0]),
+                            "{}",
+                            context
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn root_stays_in_range_at_the_octave_limits() {
+        let chord_table = computed_chord_table();
+        let lowest = ChordId::new(PitchClass::new(0), ChordType::Major);
+        let notes = voicing_notes(
+            &chord_table,
+            &lowest,
+            0,
+            *OCTAVE_RANGE.start(),
+            NoteRangePolicy::Drop,
+        )
+        .unwrap();
+        assert_eq!(notes[0], 0);
+        let highest = ChordId::new(PitchClass::new(0), ChordType::Dominant13);
+        let notes = voicing_notes(
+            &chord_table,
+            &highest,
+            0,
+            *OCTAVE_RANGE.end(),
+            NoteRangePolicy::Drop,
+        )
+        .unwrap();
+        assert_eq!(notes.to_vec(), [120, 124, 127]);
+        let notes = voicing_notes(
+            &chord_table,
+            &highest,
+            0,
+            *OCTAVE_RANGE.end(),
+            NoteRangePolicy::Clamp,
+        )
+        .unwrap();
+        assert_eq!(notes.to_vec(), [120, 124, 127]);
+    }
+
+    #[test]
+    fn identifies_root_position_before_inversions() {
+        let vocabulary = ChordVocabulary::new();
+        let c = PitchClass::new(0);
+        let a = PitchClass::from_name("A").unwrap();
+        // C6 and Am7 share their notes, so the bass decides which comes first
+        assert_eq!(
+            identify_chords(&vocabulary, &[48, 52, 55, 57]),
+            [
+                ChordId::new(c, ChordType::Sixth),
+                ChordId::new(a, ChordType::Minor7)
+            ]
+        );
+        assert_eq!(
+            identify_chords(&vocabulary, &[45, 48, 52, 55]),
+            [
+                ChordId::new(a, ChordType::Minor7),
+                ChordId::new(c, ChordType::Sixth)
+            ]
+        );
+        assert!(identify_chords(&vocabulary, &[]).is_empty());
+        assert!(identify_chords(&vocabulary, &[60, 61, 62]).is_empty());
+    }
+
+    #[test]
+    fn identifies_custom_chord_types() {
+        let mut vocabulary = ChordVocabulary::new();
+        let chord_type = vocabulary.register(custom("cluster", &[0, 1, 2])).unwrap();
+        assert!(identify_chords(&ChordVocabulary::new(), &[60, 61, 62]).is_empty());
+        assert_eq!(
+            identify_chords(&vocabulary, &[60, 61, 62]),
+            [ChordId::new(PitchClass::new(0), chord_type)]
+        );
+    }
+
+    #[test]
+    fn every_voicing_identifies_as_its_chord() {
+        let vocabulary = ChordVocabulary::new();
+        let chord_table = computed_chord_table();
+        for root in (0..12).map(PitchClass::new) {
+            for chord_type in ChordType::ALL {
+                let chord = ChordId::new(root, chord_type);
+                for inversion in 0..inversion_count(&chord_table, &chord) as u8 {
+                    let notes = voicing_notes(
+                        &chord_table,
+                        &chord,
+                        inversion,
+                        BASE_OCTAVE,
+                        NoteRangePolicy::Fold,
+                    )
+                    .unwrap();
+                    let identified = identify_chords(&vocabulary, &notes);
+                    assert!(
+                        identified.contains(&chord),
+                        "{:?} inversion {} gave {:?}",
+                        chord,
+                        inversion,
+                        identified
+                    );
+                    if inversion == 0 {
+                        assert_eq!(identified[0].root, root, "{:?}", chord);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn removed_custom_slots_are_not_reused() {
+        let mut vocabulary = ChordVocabulary::new();
+        let first = vocabulary.register(custom("first", &[0, 4, 7])).unwrap();
+        vocabulary.remove(first);
+        let second = vocabulary.register(custom("second", &[0, 3, 7])).unwrap();
+
+        assert_ne!(first, second);
+        assert_eq!(vocabulary.key(first), "");
+        assert!(vocabulary.intervals(first).is_empty());
+        assert_eq!(vocabulary.key(second), "second");
+    }
+
+    #[test]
+    fn reregistering_a_key_keeps_its_slot() {
+        let mut vocabulary = ChordVocabulary::new();
+        let chord_type = vocabulary.register(custom("wide", &[0, 7, 16])).unwrap();
+        assert_eq!(
+            vocabulary.register(custom("wide", &[0, 7, 15])).unwrap(),
+            chord_type
+        );
+        assert_eq!(vocabulary.intervals(chord_type), &[0, 7, 15]);
+    }
+
+    #[test]
+    fn removed_slots_count_towards_the_limit() {
+        let mut vocabulary = ChordVocabulary::new();
+        for index in 0..MAX_CUSTOM_CHORD_TYPES {
+            let chord_type = vocabulary
+                .register(custom(&format!("c{}", index), &[0, 7]))
+                .unwrap();
+            vocabulary.remove(chord_type);
+        }
+        assert!(vocabulary.register(custom("one-more", &[0, 7])).is_err());
+    }
+}
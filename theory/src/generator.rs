@@ -0,0 +1,482 @@
+use std::fmt;
+
+use super::chords::{ChordId, ChordType};
+use super::scales::{Scale, ScaleMap};
+use super::suggestions::functional_suggestions;
+
+/// How a generated progression ends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Cadence {
+    /// No required ending
+    #[default]
+    Free,
+    /// V to I
+    Authentic,
+    /// IV to I
+    Plagal,
+    /// Ends on V
+    Half,
+    /// V to vi
+    Deceptive,
+}
+
+impl Cadence {
+    pub const ALL: [Cadence; 5] = [
+        Cadence::Free,
+        Cadence::Authentic,
+        Cadence::Plagal,
+        Cadence::Half,
+        Cadence::Deceptive,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Cadence::Free => "Any",
+            Cadence::Authentic => "Authentic (V–I)",
+            Cadence::Plagal => "Plagal (IV–I)",
+            Cadence::Half => "Half (–V)",
+            Cadence::Deceptive => "Deceptive (V–vi)",
+        }
+    }
+
+    /// The scale degrees of the final chords.
+    fn degrees(self) -> &'static [usize] {
+        match self {
+            Cadence::Free => &[],
+            Cadence::Authentic => &[4, 0],
+            Cadence::Plagal => &[3, 0],
+            Cadence::Half => &[4],
+            Cadence::Deceptive => &[4, 5],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeneratorOptions {
+    /// Number of chords, one per bar
+    pub bars: usize,
+    pub start_on_tonic: bool,
+    /// Only applies without a cadence, since the cadence decides the last chord
+    pub end_on_tonic: bool,
+    pub cadence: Cadence,
+    /// Chord types the progression may use. Each degree is played as its diatonic triad or the
+    /// matching seventh chord, whichever are allowed.
+    pub chord_types: Vec<ChordType>,
+    /// Chance of each free chord being borrowed from the parallel key, from 0 to 1
+    pub borrowed_probability: f32,
+    pub seed: u64,
+}
+
+impl Default for GeneratorOptions {
+    fn default() -> Self {
+        Self {
+            bars: 4,
+            start_on_tonic: true,
+            end_on_tonic: true,
+            cadence: Cadence::Free,
+            chord_types: vec![ChordType::Major, ChordType::Minor, ChordType::Diminished],
+            borrowed_probability: 0.0,
+            seed: 1,
+        }
+    }
+}
+
+/// Why a progression couldn't be generated with the given options.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GeneratorError {
+    NoBars,
+    /// The cadence needs more bars than the progression has
+    TooShortForCadence {
+        bars: usize,
+        needed: usize,
+    },
+    /// None of the allowed chord types fit this degree of the scale
+    DegreeNotAllowed(String),
+}
+
+impl fmt::Display for GeneratorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GeneratorError::NoBars => write!(f, "the progression needs at least one bar"),
+            GeneratorError::TooShortForCadence { bars, needed } => {
+                write!(
+                    f,
+                    "the cadence needs {} bars but the progression has {}",
+                    needed, bars
+                )
+            }
+            GeneratorError::DegreeNotAllowed(degree) => {
+                write!(
+                    f,
+                    "none of the allowed chord types can be played on {}",
+                    degree
+                )
+            }
+        }
+    }
+}
+
+/// A small SplitMix64 generator, so the same seed gives the same progression everywhere.
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0.0..1.0`.
+    fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// Picks an item with probability proportional to its weight.
+    fn choose_weighted<T: Copy>(&mut self, items: &[(T, f32)]) -> Option<T> {
+        let total: f32 = items.iter().map(|&(_, weight)| weight).sum();
+        if total <= 0.0 {
+            return None;
+        }
+        let mut target = self.next_f32() * total;
+        for &(item, weight) in items {
+            if target < weight {
+                return Some(item);
+            }
+            target -= weight;
+        }
+        items.last().map(|&(item, _)| item)
+    }
+}
+
+/// The seventh chord that extends a triad of the given type.
+fn seventh_of(chord_type: ChordType, degree: usize) -> Option<ChordType> {
+    match chord_type {
+        ChordType::Major if degree == 4 => Some(ChordType::Dominant7),
+        ChordType::Major => Some(ChordType::Major7),
+        ChordType::Minor => Some(ChordType::Minor7),
+        ChordType::Diminished => Some(ChordType::HalfDiminished7),
+        _ => None,
+    }
+}
+
+/// The allowed forms of each diatonic chord: the triad and its seventh chord.
+fn degree_variants(scale_map: &ScaleMap, scale: Scale, allowed: &[ChordType]) -> [Vec<ChordId>; 7] {
+    let diatonic = scale_map.get(&scale).cloned().unwrap_or_default();
+    std::array::from_fn(|degree| {
+        let Some(triad) = diatonic.get(degree).map(|d| d.chord) else {
+            return Vec::new();
+        };
+        std::iter::once(triad.chord_type)
+            .chain(seventh_of(triad.chord_type, degree))
+            .filter(|chord_type| allowed.contains(chord_type))
+            .map(|chord_type| ChordId::new(triad.root, chord_type))
+            .collect()
+    })
+}
+
+/// Generates a progression in `scale` from the diatonic chords in `scale_map`. Each free chord
+/// follows the one before it by the weights of [`functional_suggestions`], or is borrowed from
+/// the parallel key with the given probability.
+pub fn generate_progression(
+    scale_map: &ScaleMap,
+    scale: Scale,
+    options: &GeneratorOptions,
+) -> Result<Vec<ChordId>, GeneratorError> {
+    if options.bars == 0 {
+        return Err(GeneratorError::NoBars);
+    }
+    let cadence = options.cadence.degrees();
+    if cadence.len() > options.bars {
+        return Err(GeneratorError::TooShortForCadence {
+            bars: options.bars,
+            needed: cadence.len(),
+        });
+    }
+
+    let mut rng = Rng(options.seed);
+    let variants = degree_variants(scale_map, scale, &options.chord_types);
+    let degree_names = scale.scale_type.degrees();
+    let pick_degree = |rng: &mut Rng, degree: usize| {
+        let chords = &variants[degree];
+        if chords.is_empty() {
+            return Err(GeneratorError::DegreeNotAllowed(
+                degree_names[degree].to_string(),
+            ));
+        }
+        Ok(chords[(rng.next_u64() % chords.len() as u64) as usize])
+    };
+
+    let diatonic: Vec<ChordId> = variants.iter().flatten().copied().collect();
+    let parallel = Scale::new(scale.root, scale.scale_type.parallel());
+    let borrowed: Vec<ChordId> = degree_variants(scale_map, parallel, &options.chord_types)
+        .into_iter()
+        .flatten()
+        .filter(|chord| !diatonic.contains(chord))
+        .collect();
+    if diatonic.is_empty() {
+        return Err(GeneratorError::DegreeNotAllowed(
+            degree_names[0].to_string(),
+        ));
+    }
+
+    let mut fixed_end: Vec<ChordId> = Vec::with_capacity(cadence.len());
+    for &degree in cadence {
+        fixed_end.push(pick_degree(&mut rng, degree)?);
+    }
+    if cadence.is_empty() && options.end_on_tonic {
+        fixed_end.push(pick_degree(&mut rng, 0)?);
+    }
+
+    let mut chords: Vec<ChordId> = Vec::with_capacity(options.bars);
+    if options.start_on_tonic && options.bars > fixed_end.len() {
+        chords.push(pick_degree(&mut rng, 0)?);
+    }
+    while chords.len() + fixed_end.len() < options.bars {
+        if !borrowed.is_empty() && rng.next_f32() < options.borrowed_probability {
+            chords.push(borrowed[(rng.next_u64() % borrowed.len() as u64) as usize]);
+            continue;
+        }
+
+        // Weight each allowed chord by how well it follows the previous one, with a little
+        // weight on everything so that unusual moves stay possible
+        let candidates: Vec<(ChordId, f32)> = match chords.last() {
+            Some(&previous) => {
+                let suggestions = functional_suggestions(previous, scale);
+                diatonic
+                    .iter()
+                    .filter(|&&chord| chord != previous)
+                    .map(|&chord| {
+                        let score = suggestions
+                            .iter()
+                            .filter(|suggestion| suggestion.chord.root == chord.root)
+                            .map(|suggestion| suggestion.score)
+                            .fold(0.0, f32::max);
+                        (chord, score + 0.05)
+                    })
+                    .collect()
+            }
+            None => diatonic.iter().map(|&chord| (chord, 1.0)).collect(),
+        };
+        match rng.choose_weighted(&candidates) {
+            Some(chord) => chords.push(chord),
+            None => chords.push(diatonic[0]),
+        }
+    }
+
+    chords.extend(fixed_end);
+    Ok(chords)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ScaleType, get_scale_map, parse_key};
+
+    fn generate(key: &str, options: &GeneratorOptions) -> Result<Vec<ChordId>, GeneratorError> {
+        generate_progression(&get_scale_map(), parse_key(key).unwrap(), options)
+    }
+
+    /// The same options with each of the first hundred seeds.
+    fn seeds(options: GeneratorOptions) -> impl Iterator<Item = GeneratorOptions> {
+        (0..100).map(move |seed| GeneratorOptions {
+            seed,
+            ..options.clone()
+        })
+    }
+
+    fn diatonic(key: &str, degree: usize) -> ChordId {
+        parse_key(key).unwrap().diatonic_chords()[degree]
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_progression() {
+        let options = GeneratorOptions {
+            bars: 8,
+            borrowed_probability: 0.3,
+            ..GeneratorOptions::default()
+        };
+        let first = generate("D major", &options).unwrap();
+        assert_eq!(first.len(), 8);
+        assert_eq!(generate("D major", &options).unwrap(), first);
+        let progressions: Vec<_> = seeds(options)
+            .map(|options| generate("D major", &options).unwrap())
+            .collect();
+        assert!(progressions.iter().any(|progression| *progression != first));
+    }
+
+    #[test]
+    fn starts_and_ends_on_the_tonic_when_asked() {
+        for key in ["C major", "A minor"] {
+            for options in seeds(GeneratorOptions::default()) {
+                let chords = generate(key, &options).unwrap();
+                assert_eq!(chords[0], diatonic(key, 0));
+                assert_eq!(chords[3], diatonic(key, 0));
+            }
+            let loose = GeneratorOptions {
+                start_on_tonic: false,
+                end_on_tonic: false,
+                ..GeneratorOptions::default()
+            };
+            assert!(seeds(loose).any(|options| {
+                let chords = generate(key, &options).unwrap();
+                chords[0] != diatonic(key, 0) && chords[3] != diatonic(key, 0)
+            }));
+        }
+        // A single bar is the tonic at both ends
+        let one_bar = GeneratorOptions {
+            bars: 1,
+            ..GeneratorOptions::default()
+        };
+        assert_eq!(
+            generate("C major", &one_bar),
+            Ok(vec![diatonic("C major", 0)])
+        );
+    }
+
+    #[test]
+    fn each_cadence_ends_on_its_degrees() {
+        for (cadence, degrees) in [
+            (Cadence::Authentic, [4, 0].as_slice()),
+            (Cadence::Plagal, &[3, 0]),
+            (Cadence::Half, &[4]),
+            (Cadence::Deceptive, &[4, 5]),
+        ] {
+            for key in ["C major", "A minor"] {
+                let triads = GeneratorOptions {
+                    bars: 5,
+                    cadence,
+                    ..GeneratorOptions::default()
+                };
+                for options in seeds(triads) {
+                    let chords = generate(key, &options).unwrap();
+                    let ending: Vec<ChordId> = degrees
+                        .iter()
+                        .map(|&degree| diatonic(key, degree))
+                        .collect();
+                    assert_eq!(chords[5 - degrees.len()..], ending, "{cadence:?} in {key}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn only_allowed_chord_types_appear() {
+        let sevenths = GeneratorOptions {
+            bars: 8,
+            cadence: Cadence::Authentic,
+            chord_types: vec![
+                ChordType::Major7,
+                ChordType::Minor7,
+                ChordType::Dominant7,
+                ChordType::HalfDiminished7,
+            ],
+            borrowed_probability: 0.5,
+            ..GeneratorOptions::default()
+        };
+        for options in seeds(sevenths) {
+            for key in ["E major", "F# minor"] {
+                let chords = generate(key, &options).unwrap();
+                assert!(
+                    chords
+                        .iter()
+                        .all(|chord| options.chord_types.contains(&chord.chord_type)),
+                    "{chords:?}"
+                );
+            }
+        }
+        let no_diminished = GeneratorOptions {
+            bars: 8,
+            start_on_tonic: false,
+            end_on_tonic: false,
+            chord_types: vec![ChordType::Major, ChordType::Minor],
+            ..GeneratorOptions::default()
+        };
+        for options in seeds(no_diminished) {
+            let chords = generate("C major", &options).unwrap();
+            assert!(
+                chords
+                    .iter()
+                    .all(|chord| chord.chord_type != ChordType::Diminished)
+            );
+        }
+    }
+
+    #[test]
+    fn borrowed_chords_follow_the_probability() {
+        let scale = parse_key("C major").unwrap();
+        let in_key: Vec<ChordId> = degree_variants(
+            &get_scale_map(),
+            scale,
+            &GeneratorOptions::default().chord_types,
+        )
+        .into_iter()
+        .flatten()
+        .collect();
+        let parallel = Scale::new(scale.root, ScaleType::Minor).diatonic_chords();
+        let free = |borrowed_probability| GeneratorOptions {
+            bars: 6,
+            borrowed_probability,
+            ..GeneratorOptions::default()
+        };
+
+        for options in seeds(free(0.0)) {
+            let chords = generate("C major", &options).unwrap();
+            assert!(
+                chords.iter().all(|chord| in_key.contains(chord)),
+                "{chords:?}"
+            );
+        }
+        for options in seeds(free(1.0)) {
+            let chords = generate("C major", &options).unwrap();
+            // The tonic at either end isn't free
+            for chord in &chords[1..5] {
+                assert!(
+                    !in_key.contains(chord) && parallel.contains(chord),
+                    "{chords:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn reports_options_it_cannot_meet() {
+        let options = |bars, cadence, chord_types| GeneratorOptions {
+            bars,
+            cadence,
+            chord_types,
+            ..GeneratorOptions::default()
+        };
+        let triads = GeneratorOptions::default().chord_types;
+        assert_eq!(
+            generate("C major", &options(0, Cadence::Free, triads.clone())),
+            Err(GeneratorError::NoBars)
+        );
+        assert_eq!(
+            generate("C major", &options(1, Cadence::Authentic, triads.clone())),
+            Err(GeneratorError::TooShortForCadence { bars: 1, needed: 2 })
+        );
+        assert_eq!(
+            generate("C major", &options(2, Cadence::Authentic, triads)).map(|chords| chords.len()),
+            Ok(2)
+        );
+        assert_eq!(
+            generate(
+                "A minor",
+                &options(4, Cadence::Free, vec![ChordType::Major])
+            ),
+            Err(GeneratorError::DegreeNotAllowed("i".to_string()))
+        );
+        let error = generate(
+            "C major",
+            &options(4, Cadence::Half, vec![ChordType::Minor]),
+        )
+        .unwrap_err();
+        assert_eq!(error, GeneratorError::DegreeNotAllowed("V".to_string()));
+        assert_eq!(
+            error.to_string(),
+            "none of the allowed chord types can be played on V"
+        );
+    }
+}
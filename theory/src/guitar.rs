@@ -0,0 +1,350 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tuning {
+    pub name: &'static str,
+    /// Open string pitches as MIDI notes, from the low E string up.
+    pub strings: [u8; 6],
+}
+
+pub const TUNINGS: [Tuning; 6] = [
+    Tuning {
+        name: "Standard",
+        strings: [40, 45, 50, 55, 59, 64],
+    },
+    Tuning {
+        name: "Half Step Down",
+        strings: [39, 44, 49, 54, 58, 63],
+    },
+    Tuning {
+        name: "Drop D",
+        strings: [38, 45, 50, 55, 59, 64],
+    },
+    Tuning {
+        name: "DADGAD",
+        strings: [38, 45, 50, 55, 57, 62],
+    },
+    Tuning {
+        name: "Open G",
+        strings: [38, 43, 50, 55, 59, 62],
+    },
+    Tuning {
+        name: "Open D",
+        strings: [38, 45, 50, 54, 57, 62],
+    },
+];
+
+const MAX_FRET: u8 = 15;
+const MAX_FINGERS: usize = 4;
+const MIN_SOUNDING_STRINGS: usize = 3;
+
+/// A fingering with one entry per string, `None` for a muted string and `Some(0)` for an open one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ChordShape {
+    pub frets: [Option<u8>; 6],
+}
+
+impl ChordShape {
+    pub fn notes(&self, tuning: &Tuning) -> Vec<u8> {
+        self.frets
+            .iter()
+            .zip(tuning.strings)
+            .filter_map(|(fret, open)| fret.map(|fret| open + fret))
+            .collect()
+    }
+
+    /// The lowest fretted position, or 1 when the shape fits in the first five frets and can be
+    /// drawn against the nut.
+    pub fn base_fret(&self) -> u8 {
+        let fretted = self.frets.iter().flatten().filter(|&&fret| fret > 0);
+        let min = fretted.clone().min().copied().unwrap_or(1);
+        let max = fretted.max().copied().unwrap_or(1);
+        if max <= 5 { 1 } else { min }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShapeOptions {
+    /// The number of frets the fretting hand may cover, e.g. 4 for frets 2 to 5.
+    pub max_span: u8,
+    pub prefer_open: bool,
+}
+
+/// Finds the most playable shapes for a chord, best first. The chord is given as its root pitch
+/// class and the pitch classes it contains. Shapes must have the root in the bass, no muted
+/// strings between sounding ones and at most four fretting fingers, counting a barre as one.
+/// The fifth may be left out of chords with four or more notes.
+pub fn find_shapes(
+    tuning: &Tuning,
+    root: u8,
+    pitch_classes: &[u8],
+    options: &ShapeOptions,
+    limit: usize,
+) -> Vec<ChordShape> {
+    let mut search = ShapeSearch {
+        tuning,
+        root,
+        pitch_classes,
+        options,
+        scored: Vec::new(),
+    };
+    let span = options.max_span.clamp(1, MAX_FRET);
+    for position in 1..=MAX_FRET - span + 1 {
+        search.visit(position..position + span, 0, &mut [None; 6]);
+    }
+
+    // Overlapping fret windows find the same shapes more than once
+    let mut scored = search.scored;
+    scored.sort();
+    scored.dedup_by(|a, b| a.1 == b.1);
+    scored
+        .into_iter()
+        .take(limit)
+        .map(|(_, shape)| shape)
+        .collect()
+}
+
+struct ShapeSearch<'a> {
+    tuning: &'a Tuning,
+    root: u8,
+    pitch_classes: &'a [u8],
+    options: &'a ShapeOptions,
+    scored: Vec<(i32, ChordShape)>,
+}
+
+impl ShapeSearch<'_> {
+    fn visit(&mut self, window: std::ops::Range<u8>, string: usize, frets: &mut [Option<u8>; 6]) {
+        if string == frets.len() {
+            let shape = ChordShape { frets: *frets };
+            if let Some(score) = score_shape(
+                self.tuning,
+                self.root,
+                self.pitch_classes,
+                self.options,
+                &shape,
+            ) {
+                self.scored.push((score, shape));
+            }
+            return;
+        }
+
+        let open = self.tuning.strings[string];
+        frets[string] = None;
+        self.visit(window.clone(), string + 1, frets);
+        for fret in std::iter::once(0).chain(window.clone()) {
+            if self.pitch_classes.contains(&((open + fret) % 12)) {
+                frets[string] = Some(fret);
+                self.visit(window.clone(), string + 1, frets);
+            }
+        }
+        frets[string] = None;
+    }
+}
+
+/// Returns `None` for unplayable shapes, otherwise a score where lower is better.
+fn score_shape(
+    tuning: &Tuning,
+    root: u8,
+    pitch_classes: &[u8],
+    options: &ShapeOptions,
+    shape: &ChordShape,
+) -> Option<i32> {
+    let lowest = shape.frets.iter().position(Option::is_some)?;
+    let sounding = &shape.frets[lowest..];
+    if sounding.len() < MIN_SOUNDING_STRINGS || sounding.iter().any(Option::is_none) {
+        return None;
+    }
+
+    let notes = shape.notes(tuning);
+    if notes[0] % 12 != root {
+        return None;
+    }
+    let fifth = (root + 7) % 12;
+    let covered = pitch_classes.iter().all(|pitch_class| {
+        notes.iter().any(|note| note % 12 == *pitch_class)
+            || (*pitch_class == fifth && pitch_classes.len() >= 4)
+    });
+    if !covered {
+        return None;
+    }
+
+    let fretted: Vec<u8> = sounding
+        .iter()
+        .flatten()
+        .copied()
+        .filter(|&fret| fret > 0)
+        .collect();
+    let min_fret = fretted.iter().min().copied().unwrap_or(0);
+    let max_fret = fretted.iter().max().copied().unwrap_or(0);
+    if fretted.len() > MAX_FINGERS {
+        // A barre frets every string above the lowest one it covers, so none of those can ring open
+        let first_barre = sounding.iter().position(|&fret| fret == Some(min_fret))?;
+        if sounding[first_barre..].contains(&Some(0)) {
+            return None;
+        }
+        let barre_strings = fretted.iter().filter(|&&fret| fret == min_fret).count();
+        if fretted.len() - barre_strings + 1 > MAX_FINGERS {
+            return None;
+        }
+    }
+
+    let open_strings = sounding.iter().filter(|&&fret| fret == Some(0)).count() as i32;
+    let open_bonus = if options.prefer_open {
+        open_strings * 4
+    } else {
+        0
+    };
+    Some(
+        min_fret as i32 * 3 + (max_fret - min_fret) as i32 * 2
+            - sounding.len() as i32 * 4
+            - open_bonus,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STANDARD: Tuning = TUNINGS[0];
+    const DADGAD: Tuning = TUNINGS[3];
+
+    const OPEN: ShapeOptions = ShapeOptions {
+        max_span: 4,
+        prefer_open: true,
+    };
+
+    const C_MAJOR: [u8; 3] = [0, 4, 7];
+    const F_MAJOR: [u8; 3] = [5, 9, 0];
+    const G_MAJOR: [u8; 3] = [7, 11, 2];
+    const D_MAJOR: [u8; 3] = [2, 6, 9];
+
+    /// Shapes written as tab from the low string up, `x` for muted strings.
+    fn tabs(shapes: &[ChordShape]) -> Vec<String> {
+        shapes
+            .iter()
+            .map(|shape| {
+                shape
+                    .frets
+                    .iter()
+                    .map(|fret| fret.map_or("x".to_string(), |fret| fret.to_string()))
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn finds_the_familiar_shapes_first() {
+        assert_eq!(
+            tabs(&find_shapes(&STANDARD, 0, &C_MAJOR, &OPEN, 3)),
+            ["x32010", "x32013", "x32050"]
+        );
+        // The E-shape barre, then the same chord without the barre
+        assert_eq!(
+            tabs(&find_shapes(&STANDARD, 5, &F_MAJOR, &OPEN, 2)),
+            ["133211", "xx3211"]
+        );
+        assert_eq!(
+            tabs(&find_shapes(&STANDARD, 7, &G_MAJOR, &OPEN, 2)),
+            ["320003", "320033"]
+        );
+        // G7 may leave out its fifth
+        assert_eq!(
+            tabs(&find_shapes(&STANDARD, 7, &[7, 11, 2, 5], &OPEN, 1)),
+            ["320001"]
+        );
+    }
+
+    #[test]
+    fn alternate_tunings_change_the_shapes() {
+        assert_eq!(
+            tabs(&find_shapes(&DADGAD, 2, &D_MAJOR, &OPEN, 2)),
+            ["000204", "004200"]
+        );
+        assert_eq!(
+            tabs(&find_shapes(&DADGAD, 7, &G_MAJOR, &OPEN, 1)),
+            ["520020"]
+        );
+        let open_g = TUNINGS
+            .iter()
+            .find(|tuning| tuning.name == "Open G")
+            .unwrap();
+        assert_eq!(
+            tabs(&find_shapes(open_g, 7, &G_MAJOR, &OPEN, 1)),
+            ["x00000"]
+        );
+    }
+
+    #[test]
+    fn open_strings_are_preferred_when_asked() {
+        let closed = ShapeOptions {
+            prefer_open: false,
+            ..OPEN
+        };
+        // Two open strings outrank a shape with a fretted note on the fourth string only when
+        // open strings are preferred
+        assert_eq!(
+            tabs(&find_shapes(&STANDARD, 7, &G_MAJOR, &OPEN, 4))[2..],
+            ["350003", "320403"]
+        );
+        assert_eq!(
+            tabs(&find_shapes(&STANDARD, 7, &G_MAJOR, &closed, 4))[2..],
+            ["320403", "350003"]
+        );
+    }
+
+    #[test]
+    fn shapes_fit_the_hand_and_play_the_chord() {
+        let chords: [&[u8]; 3] = [&[0, 4, 7], &[0, 3, 7], &[0, 4, 7, 10]];
+        for tuning in &TUNINGS {
+            for max_span in 2..=5 {
+                for chord in chords {
+                    for root in 0..12 {
+                        let pitch_classes: Vec<u8> = chord
+                            .iter()
+                            .map(|interval| (root + interval) % 12)
+                            .collect();
+                        let options = ShapeOptions {
+                            max_span,
+                            prefer_open: true,
+                        };
+                        let shapes = find_shapes(tuning, root, &pitch_classes, &options, 10);
+                        // Any chord can be played within four frets, but not always within fewer
+                        if max_span >= 4 {
+                            assert!(!shapes.is_empty(), "{} {root} {chord:?}", tuning.name);
+                        }
+                        for shape in &shapes {
+                            let fretted = shape.frets.iter().flatten().filter(|&&fret| fret > 0);
+                            let span =
+                                fretted.clone().max().unwrap_or(&0) - fretted.min().unwrap_or(&0);
+                            assert!(span < max_span, "{:?} spans {span}", tabs(&[*shape]));
+
+                            let notes = shape.notes(tuning);
+                            assert_eq!(notes[0] % 12, root);
+                            assert!(
+                                notes
+                                    .iter()
+                                    .all(|note| pitch_classes.contains(&(note % 12)))
+                            );
+                            let fifth = (root + 7) % 12;
+                            assert!(
+                                pitch_classes
+                                    .iter()
+                                    .filter(|&&pitch_class| pitch_class != fifth || chord.len() < 4)
+                                    .all(|pitch_class| notes
+                                        .iter()
+                                        .any(|note| note % 12 == *pitch_class))
+                            );
+                        }
+                    }
+                }
+            }
+        }
+        // The open C shape covers three frets
+        let narrow = ShapeOptions {
+            max_span: 2,
+            prefer_open: true,
+        };
+        assert!(
+            !tabs(&find_shapes(&STANDARD, 0, &C_MAJOR, &narrow, 20))
+                .contains(&"x32010".to_string())
+        );
+    }
+}
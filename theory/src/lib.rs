@@ -0,0 +1,23 @@
+//! Music theory independent of the plugin and its editor: note names, scales, the chord
+//! vocabulary and voicing lookup, Roman numeral progressions and lead-sheet symbols, next-chord
+//! suggestions and random progressions, guitar chord shapes, and Scala microtonal tunings.
+
+mod chords;
+mod generator;
+pub mod guitar;
+pub mod midi_file;
+mod notes;
+mod progression;
+mod scales;
+mod suggestions;
+mod symbols;
+mod tuning;
+
+pub use chords::*;
+pub use generator::*;
+pub use notes::*;
+pub use progression::*;
+pub use scales::*;
+pub use suggestions::*;
+pub use symbols::*;
+pub use tuning::*;
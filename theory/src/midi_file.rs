@@ -0,0 +1,253 @@
+//! Writing chords to Standard MIDI Files so progressions can be dragged into a DAW.
+
+use std::io;
+use std::path::Path;
+
+use crate::ChordNotes;
+
+/// Ticks per quarter note in the files written here.
+pub const TICKS_PER_QUARTER: u32 = 480;
+
+/// A note placed in time, measured in ticks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimedNote {
+    pub start: u32,
+    pub length: u32,
+    pub note: u8,
+    pub velocity: u8,
+}
+
+/// Lays chords out back to back, each held for `beats` quarter notes. Returns `None` if the
+/// sequence would run past the last tick a MIDI file can count to.
+pub fn chord_sequence(chords: &[ChordNotes], beats: u32, velocity: u8) -> Option<Vec<TimedNote>> {
+    let length = beats.checked_mul(TICKS_PER_QUARTER)?;
+    u32::try_from(chords.len()).ok()?.checked_mul(length)?;
+    Some(
+        chords
+            .iter()
+            .enumerate()
+            .flat_map(|(index, notes)| {
+                notes.iter().map(move |&note| TimedNote {
+                    start: index as u32 * length,
+                    length,
+                    note,
+                    velocity,
+                })
+            })
+            .collect(),
+    )
+}
+
+fn write_variable_length(bytes: &mut Vec<u8>, mut value: u32) {
+    let mut buffer = [0u8; 4];
+    let mut len = 0;
+    loop {
+        buffer[len] = (value & 0x7f) as u8;
+        len += 1;
+        value >>= 7;
+        if value == 0 {
+            break;
+        }
+    }
+    for i in (0..len).rev() {
+        bytes.push(if i > 0 { buffer[i] | 0x80 } else { buffer[i] });
+    }
+}
+
+/// Encodes notes as a single-track (format 0) file in 4/4 at a fixed tempo, on channel 1. Notes
+/// ending on the same tick another one starts are released first, so repeated notes retrigger.
+pub fn encode(notes: &[TimedNote], tempo_bpm: f64) -> Vec<u8> {
+    // (tick, is note on, note, velocity); sorting puts note offs before note ons on the same tick
+    let mut events: Vec<(u32, bool, u8, u8)> = notes
+        .iter()
+        .flat_map(|note| {
+            [
+                (
+                    note.start,
+                    true,
+                    note.note & 0x7f,
+                    note.velocity.clamp(1, 127),
+                ),
+                (note.start + note.length, false, note.note & 0x7f, 0),
+            ]
+        })
+        .collect();
+    events.sort();
+
+    let mut track = Vec::new();
+    // The tempo has three bytes, which is enough for anything faster than about 3.6 BPM
+    let microseconds_per_quarter =
+        ((60_000_000.0 / tempo_bpm.max(1.0)).round() as u32).min(0xff_ffff);
+    track.extend_from_slice(&[0x00, 0xff, 0x51, 0x03]);
+    track.extend_from_slice(&microseconds_per_quarter.to_be_bytes()[1..]);
+    track.extend_from_slice(&[0x00, 0xff, 0x58, 0x04, 0x04, 0x02, 0x18, 0x08]);
+
+    let mut last_tick = 0;
+    for (tick, note_on, note, velocity) in events {
+        write_variable_length(&mut track, tick - last_tick);
+        last_tick = tick;
+        track.extend_from_slice(&[if note_on { 0x90 } else { 0x80 }, note, velocity]);
+    }
+    track.extend_from_slice(&[0x00, 0xff, 0x2f, 0x00]);
+
+    let mut bytes = Vec::with_capacity(22 + track.len());
+    bytes.extend_from_slice(b"MThd");
+    bytes.extend_from_slice(&6u32.to_be_bytes());
+    bytes.extend_from_slice(&0u16.to_be_bytes());
+    bytes.extend_from_slice(&1u16.to_be_bytes());
+    bytes.extend_from_slice(&(TICKS_PER_QUARTER as u16).to_be_bytes());
+    bytes.extend_from_slice(b"MTrk");
+    bytes.extend_from_slice(&(track.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(&track);
+    bytes
+}
+
+pub fn write(path: &Path, notes: &[TimedNote], tempo_bpm: f64) -> io::Result<()> {
+    std::fs::write(path, encode(notes, tempo_bpm))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A note event read back from a file: (tick, status, note, velocity).
+    type Event = (u32, u8, u8, u8);
+
+    fn read_variable_length(bytes: &[u8], at: &mut usize) -> u32 {
+        let mut value = 0;
+        loop {
+            let byte = bytes[*at];
+            *at += 1;
+            value = (value << 7) | (byte & 0x7f) as u32;
+            if byte & 0x80 == 0 {
+                return value;
+            }
+        }
+    }
+
+    /// Reads back a file written by [`encode`], checking its chunk headers and lengths. Returns
+    /// the tempo in microseconds per quarter note and the note events at their absolute ticks.
+    fn decode(bytes: &[u8]) -> (u32, Vec<Event>) {
+        assert_eq!(&bytes[..8], b"MThd\0\0\0\x06");
+        // Format 0, one track, 480 ticks per quarter note
+        assert_eq!(&bytes[8..14], &[0, 0, 0, 1, 0x01, 0xe0]);
+        assert_eq!(&bytes[14..18], b"MTrk");
+        let track_len = u32::from_be_bytes(bytes[18..22].try_into().unwrap()) as usize;
+        let track = &bytes[22..];
+        assert_eq!(track.len(), track_len);
+
+        let mut at = 0;
+        let mut tick = 0;
+        let mut tempo = 0;
+        let mut events = Vec::new();
+        loop {
+            tick += read_variable_length(track, &mut at);
+            match track[at] {
+                0xff => {
+                    let (kind, len) = (track[at + 1], track[at + 2] as usize);
+                    let data = &track[at + 3..at + 3 + len];
+                    at += 3 + len;
+                    match kind {
+                        0x51 => tempo = u32::from_be_bytes([0, data[0], data[1], data[2]]),
+                        0x58 => assert_eq!(data, [4, 2, 24, 8]),
+                        0x2f => {
+                            assert_eq!(at, track.len());
+                            return (tempo, events);
+                        }
+                        kind => panic!("unexpected meta event {kind:#x}"),
+                    }
+                }
+                status => {
+                    events.push((tick, status, track[at + 1], track[at + 2]));
+                    at += 3;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn writes_delta_times_in_as_few_bytes_as_they_need() {
+        for (value, expected) in [
+            (0, vec![0x00]),
+            (0x7f, vec![0x7f]),
+            (0x80, vec![0x81, 0x00]),
+            (480, vec![0x83, 0x60]),
+            (0x3fff, vec![0xff, 0x7f]),
+            (0x4000, vec![0x81, 0x80, 0x00]),
+            (0x0fff_ffff, vec![0xff, 0xff, 0xff, 0x7f]),
+        ] {
+            let mut bytes = Vec::new();
+            write_variable_length(&mut bytes, value);
+            assert_eq!(bytes, expected, "{value:#x}");
+            assert_eq!(read_variable_length(&bytes, &mut 0), value);
+        }
+    }
+
+    #[test]
+    fn chords_round_trip_through_a_file() {
+        let chords = [
+            ChordNotes::from_slice(&[60, 64, 67]),
+            ChordNotes::from_slice(&[60, 65, 69]),
+        ];
+        let notes = chord_sequence(&chords, 4, 100).unwrap();
+        let (tempo, events) = decode(&encode(&notes, 90.0));
+        assert_eq!(tempo, 666_667);
+        assert_eq!(
+            events,
+            [
+                (0, 0x90, 60, 100),
+                (0, 0x90, 64, 100),
+                (0, 0x90, 67, 100),
+                // The repeated C is released before it starts again
+                (1920, 0x80, 60, 0),
+                (1920, 0x80, 64, 0),
+                (1920, 0x80, 67, 0),
+                (1920, 0x90, 60, 100),
+                (1920, 0x90, 65, 100),
+                (1920, 0x90, 69, 100),
+                (3840, 0x80, 60, 0),
+                (3840, 0x80, 65, 0),
+                (3840, 0x80, 69, 0),
+            ]
+        );
+
+        // Velocities stay in range, and a file with no notes still has its tempo and end
+        let silent = [TimedNote {
+            start: 0,
+            length: 1,
+            note: 200,
+            velocity: 0,
+        }];
+        assert_eq!(
+            decode(&encode(&silent, 120.0)),
+            (500_000, vec![(0, 0x90, 72, 1), (1, 0x80, 72, 0)])
+        );
+        assert_eq!(decode(&encode(&[], 0.0)), (0xff_ffff, vec![]));
+        assert_eq!(decode(&encode(&[], 1.0)).0, 0xff_ffff);
+    }
+
+    #[test]
+    fn sequences_too_long_for_a_file_are_refused() {
+        let chords = [ChordNotes::from_slice(&[60, 64, 67]); 3];
+        let sequence = chord_sequence(&chords, 2, 90).unwrap();
+        assert_eq!(sequence.len(), 9);
+        assert_eq!(
+            sequence[8],
+            TimedNote {
+                start: 1920,
+                length: 960,
+                note: 67,
+                velocity: 90
+            }
+        );
+
+        assert_eq!(chord_sequence(&chords, u32::MAX, 90), None);
+        // Each chord fits, but not all three one after another
+        let beats = u32::MAX / TICKS_PER_QUARTER / 2;
+        assert_eq!(
+            chord_sequence(&chords[..2], beats, 90).map(|notes| notes.len()),
+            Some(6)
+        );
+        assert_eq!(chord_sequence(&chords, beats, 90), None);
+    }
+}
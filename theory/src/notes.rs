@@ -0,0 +1,125 @@
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+
+pub const NOTES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+/// A note name without an octave, stored as semitones above C.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PitchClass(u8);
+
+impl PitchClass {
+    pub fn new(semitones: u8) -> Self {
+        Self(semitones % 12)
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        NOTES
+            .iter()
+            .position(|n| *n == name)
+            .map(|index| Self(index as u8))
+    }
+
+    /// The pitch class of a MIDI note number.
+    pub fn of_note(note: u8) -> Self {
+        Self(note % 12)
+    }
+
+    pub fn semitones(self) -> u8 {
+        self.0
+    }
+
+    pub fn name(self) -> &'static str {
+        NOTES[self.0 as usize]
+    }
+
+    pub fn transpose(self, semitones: i32) -> Self {
+        Self((self.0 as i32 + semitones).rem_euclid(12) as u8)
+    }
+}
+
+impl Serialize for PitchClass {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for PitchClass {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        Self::from_name(&name)
+            .ok_or_else(|| D::Error::custom(format!("unknown note name `{}`", name)))
+    }
+}
+
+const LETTERS: [char; 7] = ['C', 'D', 'E', 'F', 'G', 'A', 'B'];
+const LETTER_SEMITONES: [u8; 7] = [0, 2, 4, 5, 7, 9, 11];
+
+/// A note name as written. Unlike [`PitchClass`] it keeps the letter, so `A#` and `Bb` stay
+/// distinct and chords can be spelled with one letter per chord tone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpelledNote {
+    letter: u8,
+    accidental: i8,
+}
+
+impl SpelledNote {
+    /// Parses a letter followed by any number of sharps or flats, such as `"Bb"`, `"F##"` or
+    /// `"E♭"`.
+    pub fn parse(name: &str) -> Option<Self> {
+        let mut chars = name.chars();
+        let letter_char = chars.next()?.to_ascii_uppercase();
+        let letter = LETTERS.iter().position(|&l| l == letter_char)? as u8;
+        let mut accidental = 0i8;
+        for c in chars {
+            accidental += match c {
+                '#' | '♯' => 1,
+                'b' | '♭' => -1,
+                _ => return None,
+            };
+        }
+        Some(Self { letter, accidental })
+    }
+
+    pub fn pitch_class(self) -> PitchClass {
+        PitchClass::new(
+            (LETTER_SEMITONES[self.letter as usize] as i32 + self.accidental as i32).rem_euclid(12)
+                as u8,
+        )
+    }
+
+    /// The note `steps` letters and `semitones` above this one. A major third, for example, is
+    /// two letters and four semitones up.
+    pub fn above(self, steps: u8, semitones: u8) -> Self {
+        let letter = (self.letter + steps) % 7;
+        let target = self.pitch_class().semitones() as i8 + (semitones % 12) as i8;
+        let mut accidental = (target - LETTER_SEMITONES[letter as usize] as i8).rem_euclid(12);
+        if accidental > 6 {
+            accidental -= 12;
+        }
+        Self { letter, accidental }
+    }
+}
+
+impl From<PitchClass> for SpelledNote {
+    /// Spells a pitch class the way [`NOTES`] does, with sharps.
+    fn from(pitch_class: PitchClass) -> Self {
+        Self::parse(pitch_class.name()).unwrap_or(Self {
+            letter: 0,
+            accidental: 0,
+        })
+    }
+}
+
+impl fmt::Display for SpelledNote {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", LETTERS[self.letter as usize])?;
+        let accidental = if self.accidental < 0 { "b" } else { "#" };
+        for _ in 0..self.accidental.unsigned_abs() {
+            f.write_str(accidental)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,295 @@
+use std::fmt;
+
+use super::chords::{ChordId, ChordType};
+use super::notes::SpelledNote;
+use super::scales::{Scale, ScaleType};
+
+const NUMERALS: [&str; 7] = ["I", "II", "III", "IV", "V", "VI", "VII"];
+
+/// Why a Roman numeral couldn't be read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NumeralError {
+    /// The token doesn't start with a numeral from I to VII.
+    NotANumeral,
+    /// The numeral mixes upper and lower case, such as `Vi`.
+    MixedCase,
+    /// The chord quality after the numeral isn't known for a numeral of this case.
+    UnknownQuality(String),
+    /// The numeral after `/` in a secondary chord is invalid.
+    InvalidTarget(Box<NumeralError>),
+}
+
+impl fmt::Display for NumeralError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NumeralError::NotANumeral => write!(f, "expected a Roman numeral from I to VII"),
+            NumeralError::MixedCase => {
+                write!(
+                    f,
+                    "use upper case for major numerals and lower case for minor ones"
+                )
+            }
+            NumeralError::UnknownQuality(quality) => {
+                write!(f, "unknown chord quality `{}`", quality)
+            }
+            NumeralError::InvalidTarget(err) => write!(f, "invalid secondary target: {}", err),
+        }
+    }
+}
+
+/// A token of a progression that couldn't be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgressionError {
+    /// Position of the token among the progression's chords, starting at 0
+    pub index: usize,
+    pub token: String,
+    pub error: NumeralError,
+}
+
+impl fmt::Display for ProgressionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "chord {} `{}`: {}",
+            self.index + 1,
+            self.token,
+            self.error
+        )
+    }
+}
+
+/// The chord type a numeral's quality suffix stands for. Upper case numerals take the major
+/// qualities and lower case ones the minor and diminished qualities.
+fn numeral_quality(quality: &str, upper: bool) -> Option<ChordType> {
+    let chord_type = if upper {
+        match quality {
+            "" => ChordType::Major,
+            "7" => ChordType::Dominant7,
+            "9" => ChordType::Dominant9,
+            "maj7" | "M7" | "Δ7" | "Δ" => ChordType::Major7,
+            "maj9" | "M9" | "Δ9" => ChordType::Major9,
+            "6" => ChordType::Sixth,
+            "5" => ChordType::Power,
+            "sus2" => ChordType::Sus2,
+            "sus4" | "sus" => ChordType::Sus4,
+            "+" | "aug" => ChordType::Augmented,
+            _ => return None,
+        }
+    } else {
+        match quality {
+            "" => ChordType::Minor,
+            "7" => ChordType::Minor7,
+            "9" => ChordType::Minor9,
+            "6" => ChordType::MinorSixth,
+            "°" | "o" | "dim" => ChordType::Diminished,
+            "°7" | "o7" | "dim7" => ChordType::Diminished7,
+            "ø" | "ø7" | "m7b5" => ChordType::HalfDiminished7,
+            _ => return None,
+        }
+    };
+    Some(chord_type)
+}
+
+/// Parses a Roman numeral such as `"vi"`, `"V7"`, `"vii°7"`, `"bVII"` or `"V7/vi"` against a
+/// scale. The numeral picks the scale degree the chord is built on, and a leading `b` or `#`
+/// lowers or raises it. Upper case is major and lower case minor, with suffixes such as `7`,
+/// `maj7`, `°`, `ø` and `+` for other qualities. After a `/` comes the chord the first one
+/// belongs to, so `V/vi` is the dominant of the sixth degree, read in that chord's major key.
+pub fn parse_roman_numeral(numeral: &str, scale: Scale) -> Result<ChordId, NumeralError> {
+    if let Some((chord, target)) = numeral.split_once('/') {
+        let target = parse_roman_numeral(target, scale)
+            .map_err(|err| NumeralError::InvalidTarget(Box::new(err)))?;
+        return parse_roman_numeral(chord, Scale::new(target.root, ScaleType::Major));
+    }
+
+    let mut accidental = 0;
+    let mut rest = numeral;
+    while let Some(c) = rest.chars().next() {
+        match c {
+            'b' | '♭' => accidental -= 1,
+            '#' | '♯' => accidental += 1,
+            _ => break,
+        }
+        rest = &rest[c.len_utf8()..];
+    }
+
+    let numeral_len = rest
+        .find(|c: char| !matches!(c, 'I' | 'V' | 'i' | 'v'))
+        .unwrap_or(rest.len());
+    let (degree_name, quality) = rest.split_at(numeral_len);
+    let degree = NUMERALS
+        .iter()
+        .position(|name| name.eq_ignore_ascii_case(degree_name))
+        .ok_or(NumeralError::NotANumeral)?;
+
+    let upper = degree_name.chars().all(|c| c.is_ascii_uppercase());
+    if !upper && !degree_name.chars().all(|c| c.is_ascii_lowercase()) {
+        return Err(NumeralError::MixedCase);
+    }
+    let chord_type = numeral_quality(quality, upper)
+        .ok_or_else(|| NumeralError::UnknownQuality(quality.to_string()))?;
+
+    let root = scale.diatonic_chords()[degree].root.transpose(accidental);
+    Ok(ChordId::new(root, chord_type))
+}
+
+/// Parses a whole progression such as `"ii7 V7 Imaj7 vi7"` or `"I - V/vi - vi - IV"`. Chords are
+/// separated by spaces, `-`, `|` or `,`. Every token that can't be read is reported, not just the
+/// first.
+pub fn parse_progression(
+    progression: &str,
+    scale: Scale,
+) -> Result<Vec<ChordId>, Vec<ProgressionError>> {
+    let mut chords = Vec::new();
+    let mut errors = Vec::new();
+    let tokens = progression
+        .split(|c: char| c.is_whitespace() || matches!(c, '-' | '|' | ','))
+        .filter(|token| !token.is_empty());
+    for (index, token) in tokens.enumerate() {
+        match parse_roman_numeral(token, scale) {
+            Ok(chord) => chords.push(chord),
+            Err(error) => errors.push(ProgressionError {
+                index,
+                token: token.to_string(),
+                error,
+            }),
+        }
+    }
+    if errors.is_empty() {
+        Ok(chords)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Parses a key name such as `"G major"`, `"Bb minor"` or `"f# Minor"`. More forgiving than
+/// [`Scale::parse`]: flats are accepted and the scale type can be in any case.
+pub fn parse_key(key: &str) -> Option<Scale> {
+    let mut words = key.split_whitespace();
+    let root = SpelledNote::parse(words.next()?)?.pitch_class();
+    let scale_type = words.next()?;
+    let scale_type = ScaleType::ALL
+        .into_iter()
+        .find(|scale_type_option| scale_type_option.name().eq_ignore_ascii_case(scale_type))?;
+    words
+        .next()
+        .is_none()
+        .then_some(Scale::new(root, scale_type))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ChordVocabulary;
+
+    fn names(progression: &str, key: &str) -> Vec<String> {
+        let vocabulary = ChordVocabulary::new();
+        parse_progression(progression, parse_key(key).unwrap())
+            .unwrap()
+            .iter()
+            .map(|chord| chord.display(&vocabulary).to_string())
+            .collect()
+    }
+
+    #[test]
+    fn case_picks_the_quality() {
+        assert_eq!(
+            names("I ii II iii III IV iv V v vi VI", "C major"),
+            ["C", "Dm", "D", "Em", "E", "F", "Fm", "G", "Gm", "Am", "A"]
+        );
+        assert_eq!(
+            names("vii° vii°7 viiø7 viiø viio7 viim7b5", "C major"),
+            ["Bdim", "Bdim7", "Bm7b5", "Bm7b5", "Bdim7", "Bm7b5"]
+        );
+        assert_eq!(
+            names("Imaj7 IΔ7 ii7 V7 V9 vi9 IV6 iv6 Vsus4 I+ I5", "C major"),
+            [
+                "Cmaj7", "Cmaj7", "Dm7", "G7", "G9", "Am9", "F6", "Fm6", "Gsus4", "Caug", "C5"
+            ]
+        );
+    }
+
+    #[test]
+    fn accidentals_move_the_degree() {
+        assert_eq!(
+            names("bVI bVII bIII #iv° ♭II #IV", "C major"),
+            ["G#", "A#", "D#", "F#dim", "C#", "F#"]
+        );
+        assert_eq!(names("bbVII ##I", "C major"), ["A", "D"]);
+    }
+
+    #[test]
+    fn secondary_chords_are_read_in_the_target_key() {
+        assert_eq!(
+            names("V7/V V/vi vii°7/V V7/IV", "C major"),
+            ["D7", "E", "F#dim7", "C7"]
+        );
+        assert_eq!(names("V7/V/V", "C major"), ["A7"]);
+        assert_eq!(names("V7/III", "A minor"), ["G7"]);
+    }
+
+    #[test]
+    fn numerals_follow_the_key() {
+        assert_eq!(names("I - V - vi - IV", "G major"), ["G", "D", "Em", "C"]);
+        assert_eq!(
+            names("i ii° III iv v VI VII", "A minor"),
+            ["Am", "Bdim", "C", "Dm", "Em", "F", "G"]
+        );
+        assert_eq!(names("i iv V7", "Bb minor"), ["A#m", "D#m", "F7"]);
+        assert_eq!(names("I|IV,V", "E major"), ["E", "A", "B"]);
+    }
+
+    #[test]
+    fn reports_every_bad_token_with_its_position() {
+        let errors = parse_progression("I Vi X  V7/Q ii+ vi", Scale::default()).unwrap_err();
+        let found: Vec<(usize, &str, &NumeralError)> = errors
+            .iter()
+            .map(|error| (error.index, error.token.as_str(), &error.error))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (1, "Vi", &NumeralError::MixedCase),
+                (2, "X", &NumeralError::NotANumeral),
+                (
+                    3,
+                    "V7/Q",
+                    &NumeralError::InvalidTarget(Box::new(NumeralError::NotANumeral))
+                ),
+                (4, "ii+", &NumeralError::UnknownQuality("+".to_string())),
+            ]
+        );
+        assert_eq!(
+            errors[0].to_string(),
+            "chord 2 `Vi`: use upper case for major numerals and lower case for minor ones"
+        );
+        assert_eq!(
+            parse_roman_numeral("Vmaj7", Scale::default()),
+            Ok(ChordId::new(
+                crate::PitchClass::from_name("G").unwrap(),
+                ChordType::Major7
+            ))
+        );
+        assert_eq!(
+            parse_roman_numeral("vmaj7", Scale::default()),
+            Err(NumeralError::UnknownQuality("maj7".to_string()))
+        );
+        assert_eq!(parse_progression("", Scale::default()), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn parses_keys_leniently() {
+        let scale = |root: &str, scale_type| {
+            Some(Scale::new(
+                crate::PitchClass::from_name(root).unwrap(),
+                scale_type,
+            ))
+        };
+        assert_eq!(parse_key("G major"), scale("G", ScaleType::Major));
+        assert_eq!(parse_key("Bb MINOR"), scale("A#", ScaleType::Minor));
+        assert_eq!(parse_key("f# Minor"), scale("F#", ScaleType::Minor));
+        assert_eq!(parse_key("G"), None);
+        assert_eq!(parse_key("G major scale"), None);
+        assert_eq!(parse_key("H major"), None);
+    }
+}
@@ -0,0 +1,507 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+
+use super::chords::{ChordId, ChordType};
+use super::notes::PitchClass;
+
+#[derive(Debug, Clone)]
+pub struct DiatonicChord {
+    pub chord: ChordId,
+    pub degree: String,
+}
+
+/// Diatonic chords for every key, in degree order.
+pub type ScaleMap = HashMap<Scale, Vec<DiatonicChord>>;
+
+#[derive(Debug, Clone)]
+pub struct ChordSection {
+    pub title: &'static str,
+    pub chords: Vec<DiatonicChord>,
+}
+
+/// A column of the chord grid. `diatonic_type` is the chord the scale builds on this root, or
+/// `None` when the root is outside the scale.
+#[derive(Debug, Clone)]
+pub struct GridColumn {
+    pub root: PitchClass,
+    pub degree: String,
+    pub diatonic_type: Option<ChordType>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ScaleType {
+    Major,
+    Minor,
+}
+
+impl ScaleType {
+    pub const ALL: [ScaleType; 2] = [ScaleType::Major, ScaleType::Minor];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ScaleType::Major => "Major",
+            ScaleType::Minor => "Minor",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|scale_type| scale_type.name() == name)
+    }
+
+    pub fn parallel(self) -> Self {
+        match self {
+            ScaleType::Major => ScaleType::Minor,
+            ScaleType::Minor => ScaleType::Major,
+        }
+    }
+
+    /// Semitones above the tonic for each scale degree.
+    pub fn pattern(self) -> [u8; 7] {
+        match self {
+            ScaleType::Major => [0, 2, 4, 5, 7, 9, 11],
+            ScaleType::Minor => [0, 2, 3, 5, 7, 8, 10],
+        }
+    }
+
+    pub fn chord_types(self) -> [ChordType; 7] {
+        use ChordType::{Diminished, Major, Minor};
+        match self {
+            ScaleType::Major => [Major, Minor, Minor, Major, Major, Minor, Diminished],
+            ScaleType::Minor => [Minor, Diminished, Major, Minor, Minor, Major, Major],
+        }
+    }
+
+    pub fn degrees(self) -> [&'static str; 7] {
+        match self {
+            ScaleType::Major => ["I", "ii", "iii", "IV", "V", "vi", "vii°"],
+            ScaleType::Minor => ["i", "ii°", "III", "iv", "v", "VI", "VII"],
+        }
+    }
+
+    /// A label for each root by semitones above the tonic. Roots outside the scale are named by
+    /// how they alter its degrees, so in minor the raised third, sixth and seventh are sharpened
+    /// rather than clashing with the scale's own III, VI and VII.
+    pub fn chromatic_degrees(self) -> [&'static str; 12] {
+        match self {
+            ScaleType::Major => [
+                "I", "♭II", "II", "♭III", "III", "IV", "♯IV", "V", "♭VI", "VI", "♭VII", "VII",
+            ],
+            ScaleType::Minor => [
+                "I", "♭II", "II", "III", "♯III", "IV", "♯IV", "V", "VI", "♯VI", "VII", "♯VII",
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Scale {
+    pub root: PitchClass,
+    pub scale_type: ScaleType,
+}
+
+impl Scale {
+    pub fn new(root: PitchClass, scale_type: ScaleType) -> Self {
+        Self { root, scale_type }
+    }
+
+    /// Parses a key name such as `"F# Minor"`.
+    pub fn parse(key: &str) -> Option<Self> {
+        let (root, scale_type) = key.split_once(' ')?;
+        Some(Self {
+            root: PitchClass::from_name(root)?,
+            scale_type: ScaleType::from_name(scale_type)?,
+        })
+    }
+
+    /// The triads built on each scale degree. Computed directly rather than through a
+    /// [`ScaleMap`] so the audio thread can use it without allocating.
+    pub fn diatonic_chords(&self) -> [ChordId; 7] {
+        let pattern = self.scale_type.pattern();
+        let chord_types = self.scale_type.chord_types();
+        std::array::from_fn(|degree| {
+            ChordId::new(
+                self.root.transpose(pattern[degree] as i32),
+                chord_types[degree],
+            )
+        })
+    }
+}
+
+impl Default for Scale {
+    fn default() -> Self {
+        Self::new(PitchClass::new(0), ScaleType::Major)
+    }
+}
+
+impl fmt::Display for Scale {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.root.name(), self.scale_type.name())
+    }
+}
+
+pub fn get_scale_map() -> ScaleMap {
+    let mut scales = HashMap::new();
+
+    for semitones in 0..12 {
+        for scale_type in ScaleType::ALL {
+            let scale = Scale::new(PitchClass::new(semitones), scale_type);
+            let diatonics = scale
+                .diatonic_chords()
+                .into_iter()
+                .zip(scale_type.degrees())
+                .map(|(chord, degree)| DiatonicChord {
+                    chord,
+                    degree: degree.to_string(),
+                })
+                .collect();
+            scales.insert(scale, diatonics);
+        }
+    }
+
+    scales
+}
+
+/// Returns the scale's seven degrees as grid columns.
+pub fn get_diatonic_columns(scale_map: &ScaleMap, scale: Scale) -> Vec<GridColumn> {
+    scale_map
+        .get(&scale)
+        .map(|diatonics| {
+            diatonics
+                .iter()
+                .map(|d| GridColumn {
+                    root: d.chord.root,
+                    degree: d.degree.clone(),
+                    diatonic_type: Some(d.chord.chord_type),
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Returns all twelve roots of the scale as grid columns. In-key columns keep their diatonic
+/// chord and degree; the others are labelled relative to the tonic.
+pub fn get_chromatic_columns(scale_map: &ScaleMap, scale: Scale) -> Vec<GridColumn> {
+    let diatonics = scale_map.get(&scale).cloned().unwrap_or_default();
+
+    (0..12)
+        .map(|semitones| {
+            let root = scale.root.transpose(semitones as i32);
+            match diatonics.iter().find(|d| d.chord.root == root) {
+                Some(d) => GridColumn {
+                    root,
+                    degree: d.degree.clone(),
+                    diatonic_type: Some(d.chord.chord_type),
+                },
+                None => GridColumn {
+                    root,
+                    degree: scale.scale_type.chromatic_degrees()[semitones].to_string(),
+                    diatonic_type: None,
+                },
+            }
+        })
+        .collect()
+}
+
+/// Builds the chromatic chord sections shown below the diatonic grid: secondary dominants and
+/// leading-tone chords for every non-diminished degree, chords borrowed from the parallel
+/// major/minor, and the Neapolitan and augmented sixth chords built on the lowered sixth.
+pub fn get_extended_sections(scale_map: &ScaleMap, scale: Scale) -> Vec<ChordSection> {
+    let diatonics = scale_map.get(&scale).cloned().unwrap_or_default();
+    let parallel_type = scale.scale_type.parallel();
+    let borrowed_title = match parallel_type {
+        ScaleType::Minor => "Borrowed from Parallel Minor",
+        ScaleType::Major => "Borrowed from Parallel Major",
+    };
+    let parallel = scale_map
+        .get(&Scale::new(scale.root, parallel_type))
+        .cloned()
+        .unwrap_or_default();
+
+    let targets: Vec<&DiatonicChord> = diatonics
+        .iter()
+        .skip(1)
+        .filter(|d| d.chord.chord_type != ChordType::Diminished)
+        .collect();
+
+    let secondary_dominants = targets
+        .iter()
+        .map(|d| DiatonicChord {
+            chord: ChordId::new(d.chord.root.transpose(7), ChordType::Dominant7),
+            degree: format!("V7/{}", d.degree),
+        })
+        .collect();
+
+    let secondary_leading_tones = targets
+        .iter()
+        .map(|d| DiatonicChord {
+            chord: ChordId::new(d.chord.root.transpose(-1), ChordType::Diminished7),
+            degree: format!("vii°7/{}", d.degree),
+        })
+        .collect();
+
+    let borrowed = parallel
+        .iter()
+        .zip(diatonics.iter())
+        .filter(|(p, d)| p.chord != d.chord)
+        .map(|(p, d)| {
+            let accidental = match (p.chord.root.semitones() + 12 - d.chord.root.semitones()) % 12 {
+                0 => "",
+                11 => "♭",
+                _ => "♯",
+            };
+            DiatonicChord {
+                chord: p.chord,
+                degree: format!("{}{}", accidental, p.degree),
+            }
+        })
+        .collect();
+
+    let mut chromatic = vec![DiatonicChord {
+        chord: ChordId::new(scale.root.transpose(1), ChordType::Major),
+        degree: "♭II (N)".to_string(),
+    }];
+    for chord_type in ChordType::AUGMENTED_SIXTHS {
+        chromatic.push(DiatonicChord {
+            chord: ChordId::new(scale.root.transpose(8), chord_type),
+            degree: chord_type.suffix().to_string(),
+        });
+    }
+
+    vec![
+        ChordSection {
+            title: "Secondary Dominants",
+            chords: secondary_dominants,
+        },
+        ChordSection {
+            title: "Secondary Leading-Tone Chords",
+            chords: secondary_leading_tones,
+        },
+        ChordSection {
+            title: borrowed_title,
+            chords: borrowed,
+        },
+        ChordSection {
+            title: "Neapolitan & Augmented Sixths",
+            chords: chromatic,
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scale_map_holds_every_key() {
+        let scale_map = get_scale_map();
+        assert_eq!(scale_map.len(), 24);
+        for (scale, diatonics) in &scale_map {
+            assert_eq!(diatonics.len(), 7);
+            for ((diatonic, chord), degree) in diatonics
+                .iter()
+                .zip(scale.diatonic_chords())
+                .zip(scale.scale_type.degrees())
+            {
+                assert_eq!(diatonic.chord, chord);
+                assert_eq!(diatonic.degree, degree);
+            }
+        }
+    }
+
+    #[test]
+    fn diatonic_chords_follow_the_scale() {
+        let names = |key: &str| -> Vec<String> {
+            let vocabulary = crate::ChordVocabulary::new();
+            Scale::parse(key)
+                .unwrap()
+                .diatonic_chords()
+                .iter()
+                .map(|chord| chord.display(&vocabulary).to_string())
+                .collect()
+        };
+        assert_eq!(names("G Major"), ["G", "Am", "Bm", "C", "D", "Em", "F#dim"]);
+        assert_eq!(names("A Minor"), ["Am", "Bdim", "C", "Dm", "Em", "F", "G"]);
+    }
+
+    #[test]
+    fn chromatic_columns_keep_the_diatonic_degrees() {
+        let scale_map = get_scale_map();
+        let scale = Scale::parse("D Major").unwrap();
+        let columns = get_chromatic_columns(&scale_map, scale);
+        assert_eq!(columns.len(), 12);
+        assert_eq!(
+            columns
+                .iter()
+                .filter(|column| column.diatonic_type.is_some())
+                .count(),
+            7
+        );
+        assert_eq!(columns[0].degree, "I");
+        assert_eq!(columns[1].degree, "♭II");
+        assert_eq!(columns[1].root, PitchClass::from_name("D#").unwrap());
+        assert_eq!(columns[11].degree, "vii°");
+        assert_eq!(get_diatonic_columns(&scale_map, scale).len(), 7);
+    }
+
+    #[test]
+    fn chromatic_columns_are_labelled_apart_in_minor() {
+        let scale_map = get_scale_map();
+        let columns = get_chromatic_columns(&scale_map, Scale::parse("A Minor").unwrap());
+        let labels: Vec<&str> = columns
+            .iter()
+            .map(|column| column.degree.as_str())
+            .collect();
+        assert_eq!(
+            labels,
+            [
+                "i", "♭II", "ii°", "III", "♯III", "iv", "♯IV", "v", "VI", "♯VI", "VII", "♯VII"
+            ]
+        );
+        for (index, label) in labels.iter().enumerate() {
+            assert!(
+                !labels[index + 1..].contains(label),
+                "{label} is used twice"
+            );
+        }
+        assert_eq!(columns[4].root, PitchClass::from_name("C#").unwrap());
+        assert_eq!(columns[4].diatonic_type, None);
+    }
+
+    /// A chord as its root's name, its type and its label.
+    type Labelled = (String, ChordType, String);
+
+    fn sections(key: &str) -> Vec<(&'static str, Vec<Labelled>)> {
+        get_extended_sections(&get_scale_map(), Scale::parse(key).unwrap())
+            .into_iter()
+            .map(|section| {
+                let chords = section
+                    .chords
+                    .into_iter()
+                    .map(|d| {
+                        (
+                            d.chord.root.name().to_string(),
+                            d.chord.chord_type,
+                            d.degree,
+                        )
+                    })
+                    .collect();
+                (section.title, chords)
+            })
+            .collect()
+    }
+
+    fn expected(chords: &[(&str, ChordType, &str)]) -> Vec<Labelled> {
+        chords
+            .iter()
+            .map(|&(root, chord_type, degree)| (root.to_string(), chord_type, degree.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn extended_sections_in_a_major_key() {
+        use ChordType::*;
+        let sections = sections("C Major");
+        let titles: Vec<&str> = sections.iter().map(|(title, _)| *title).collect();
+        assert_eq!(
+            titles,
+            [
+                "Secondary Dominants",
+                "Secondary Leading-Tone Chords",
+                "Borrowed from Parallel Minor",
+                "Neapolitan & Augmented Sixths",
+            ]
+        );
+        assert_eq!(
+            sections[0].1,
+            expected(&[
+                ("A", Dominant7, "V7/ii"),
+                ("B", Dominant7, "V7/iii"),
+                ("C", Dominant7, "V7/IV"),
+                ("D", Dominant7, "V7/V"),
+                ("E", Dominant7, "V7/vi"),
+            ])
+        );
+        assert_eq!(
+            sections[1].1,
+            expected(&[
+                ("C#", Diminished7, "vii°7/ii"),
+                ("D#", Diminished7, "vii°7/iii"),
+                ("E", Diminished7, "vii°7/IV"),
+                ("F#", Diminished7, "vii°7/V"),
+                ("G#", Diminished7, "vii°7/vi"),
+            ])
+        );
+        assert_eq!(
+            sections[2].1,
+            expected(&[
+                ("C", Minor, "i"),
+                ("D", Diminished, "ii°"),
+                ("D#", Major, "♭III"),
+                ("F", Minor, "iv"),
+                ("G", Minor, "v"),
+                ("G#", Major, "♭VI"),
+                ("A#", Major, "♭VII"),
+            ])
+        );
+        assert_eq!(
+            sections[3].1,
+            expected(&[
+                ("C#", Major, "♭II (N)"),
+                ("G#", Italian6, "It+6"),
+                ("G#", French6, "Fr+6"),
+                ("G#", German6, "Ger+6"),
+            ])
+        );
+    }
+
+    #[test]
+    fn extended_sections_in_a_minor_key() {
+        use ChordType::*;
+        let sections = sections("A Minor");
+        assert_eq!(sections[2].0, "Borrowed from Parallel Major");
+        assert_eq!(
+            sections[0].1,
+            expected(&[
+                ("G", Dominant7, "V7/III"),
+                ("A", Dominant7, "V7/iv"),
+                ("B", Dominant7, "V7/v"),
+                ("C", Dominant7, "V7/VI"),
+                ("D", Dominant7, "V7/VII"),
+            ])
+        );
+        assert_eq!(
+            sections[1].1,
+            expected(&[
+                ("B", Diminished7, "vii°7/III"),
+                ("C#", Diminished7, "vii°7/iv"),
+                ("D#", Diminished7, "vii°7/v"),
+                ("E", Diminished7, "vii°7/VI"),
+                ("F#", Diminished7, "vii°7/VII"),
+            ])
+        );
+        assert_eq!(
+            sections[2].1,
+            expected(&[
+                ("A", Major, "I"),
+                ("B", Minor, "ii"),
+                ("C#", Minor, "♯iii"),
+                ("D", Major, "IV"),
+                ("E", Major, "V"),
+                ("F#", Minor, "♯vi"),
+                ("G#", Diminished, "♯vii°"),
+            ])
+        );
+        assert_eq!(
+            sections[3].1,
+            expected(&[
+                ("A#", Major, "♭II (N)"),
+                ("F", Italian6, "It+6"),
+                ("F", French6, "Fr+6"),
+                ("F", German6, "Ger+6"),
+            ])
+        );
+    }
+}
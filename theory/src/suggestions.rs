@@ -0,0 +1,388 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use super::chords::{ChordId, ChordType};
+use super::notes::PitchClass;
+use super::progression::parse_progression;
+use super::scales::{Scale, ScaleType};
+
+/// Usual root progressions from each scale degree, after Piston's table: the degrees a chord
+/// usually moves to, sometimes moves to and less often moves to.
+const DEGREE_PROGRESSIONS: [(&[usize], &[usize], &[usize]); 7] = [
+    (&[3, 4], &[5], &[1, 2]),
+    (&[4], &[3, 5], &[0, 2]),
+    (&[5], &[3], &[0, 1, 4]),
+    (&[4], &[0, 1], &[2, 5]),
+    (&[0], &[3, 5], &[1, 2]),
+    (&[1, 4], &[2, 3], &[0]),
+    (&[0], &[2], &[5]),
+];
+
+/// Progressions the Markov model learns from, written in Roman numerals.
+const PROGRESSION_CORPUS: [(&str, ScaleType); 24] = [
+    ("I V vi IV I", ScaleType::Major),
+    ("I vi IV V I", ScaleType::Major),
+    ("vi IV I V vi", ScaleType::Major),
+    ("I IV vi V I", ScaleType::Major),
+    ("I IV V I", ScaleType::Major),
+    ("I IV I V I", ScaleType::Major),
+    ("ii7 V7 Imaj7", ScaleType::Major),
+    ("Imaj7 vi7 ii7 V7 Imaj7", ScaleType::Major),
+    ("iii7 vi7 ii7 V7 Imaj7", ScaleType::Major),
+    ("IV V iii vi", ScaleType::Major),
+    ("I V vi iii IV I IV V I", ScaleType::Major),
+    ("I iii IV V I", ScaleType::Major),
+    ("I bVII IV I", ScaleType::Major),
+    ("I V/vi vi IV V I", ScaleType::Major),
+    ("I V/V V I", ScaleType::Major),
+    ("I V7/IV IV iv I", ScaleType::Major),
+    ("ii V I vi ii V I", ScaleType::Major),
+    ("I7 IV7 I7 V7 IV7 I7", ScaleType::Major),
+    ("i VI III VII i", ScaleType::Minor),
+    ("i iv V i", ScaleType::Minor),
+    ("i VII VI V i", ScaleType::Minor),
+    ("iiø7 V7 i", ScaleType::Minor),
+    ("i iv VII III VI iiø7 V7 i", ScaleType::Minor),
+    ("i VI iv V i", ScaleType::Minor),
+];
+
+/// Chord types that pull towards the chord a fifth below.
+fn is_dominant(chord_type: ChordType) -> bool {
+    matches!(
+        chord_type,
+        ChordType::Major
+            | ChordType::Dominant7
+            | ChordType::Dominant9
+            | ChordType::Dominant7Flat9
+            | ChordType::Dominant7Sharp9
+            | ChordType::Dominant13
+            | ChordType::Dominant7Sus4
+    )
+}
+
+/// A chord suggested to follow the previous one. Scores range from 0 to 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Suggestion {
+    pub chord: ChordId,
+    pub score: f32,
+}
+
+fn add_suggestion(suggestions: &mut Vec<Suggestion>, chord: ChordId, score: f32) {
+    match suggestions
+        .iter_mut()
+        .find(|suggestion| suggestion.chord == chord)
+    {
+        Some(suggestion) => suggestion.score = suggestion.score.max(score),
+        None => suggestions.push(Suggestion { chord, score }),
+    }
+}
+
+/// Scores the chords that could follow `previous` by functional harmony: the usual root movement
+/// between scale degrees, with the dominant also offered as a seventh chord, and the resolution
+/// of dominants, leading-tone chords, the Neapolitan and augmented sixths that lead somewhere in
+/// the key.
+pub fn functional_suggestions(previous: ChordId, scale: Scale) -> Vec<Suggestion> {
+    let diatonic = scale.diatonic_chords();
+    let on_root = |root: PitchClass| diatonic.iter().copied().find(|chord| chord.root == root);
+    let dominant = diatonic[4];
+    let mut suggestions = Vec::new();
+
+    // Chords are placed by their root, so `ii7` and the secondary dominant `V/V` both count as ii
+    if let Some(degree) = diatonic
+        .iter()
+        .position(|chord| chord.root == previous.root)
+    {
+        let (usually, sometimes, less_often) = DEGREE_PROGRESSIONS[degree];
+        for (targets, score) in [(usually, 1.0), (sometimes, 0.6), (less_often, 0.3)] {
+            for &target in targets {
+                add_suggestion(&mut suggestions, diatonic[target], score);
+                if target == 4 {
+                    let seventh = ChordId::new(dominant.root, ChordType::Dominant7);
+                    add_suggestion(&mut suggestions, seventh, score * 0.9);
+                }
+            }
+        }
+    }
+
+    match previous.chord_type {
+        ChordType::Italian6 | ChordType::French6 | ChordType::German6 => {
+            add_suggestion(&mut suggestions, dominant, 1.0);
+        }
+        ChordType::Major if previous.root == scale.root.transpose(1) => {
+            add_suggestion(&mut suggestions, dominant, 1.0);
+            add_suggestion(
+                &mut suggestions,
+                ChordId::new(dominant.root, ChordType::Dominant7),
+                0.9,
+            );
+        }
+        ChordType::Diminished | ChordType::Diminished7 | ChordType::HalfDiminished7 => {
+            if let Some(target) = on_root(previous.root.transpose(1)) {
+                add_suggestion(&mut suggestions, target, 1.0);
+            }
+        }
+        chord_type if is_dominant(chord_type) => {
+            if let Some(target) = on_root(previous.root.transpose(5)) {
+                add_suggestion(&mut suggestions, target, 1.0);
+            }
+        }
+        _ => {}
+    }
+
+    // A chord from outside the key with no resolution of its own heads home
+    if suggestions.is_empty() {
+        add_suggestion(&mut suggestions, diatonic[0], 0.6);
+        add_suggestion(&mut suggestions, dominant, 0.4);
+    }
+
+    suggestions.retain(|suggestion| suggestion.chord != previous);
+    suggestions
+}
+
+/// A chord relative to the key it is in: semitones of the root above the tonic, and the type.
+type RelativeChord = (u8, ChordType);
+
+fn relative_chord(chord: ChordId, scale: Scale) -> RelativeChord {
+    (
+        (chord.root.semitones() + 12 - scale.root.semitones()) % 12,
+        chord.chord_type,
+    )
+}
+
+/// Chord-to-chord transition counts learned from example progressions, kept relative to the key
+/// so one model serves every scale of the same type.
+#[derive(Debug, Clone, Default)]
+pub struct MarkovModel {
+    transitions: HashMap<(ScaleType, RelativeChord), HashMap<RelativeChord, u32>>,
+}
+
+impl MarkovModel {
+    /// Counts the chord changes in the given Roman numeral progressions. Progressions that don't
+    /// parse are skipped.
+    pub fn train(progressions: &[(&str, ScaleType)]) -> Self {
+        let mut model = Self::default();
+        for &(progression, scale_type) in progressions {
+            let scale = Scale::new(PitchClass::new(0), scale_type);
+            let Ok(chords) = parse_progression(progression, scale) else {
+                continue;
+            };
+            for pair in chords.windows(2) {
+                let from = relative_chord(pair[0], scale);
+                let to = relative_chord(pair[1], scale);
+                *model
+                    .transitions
+                    .entry((scale_type, from))
+                    .or_default()
+                    .entry(to)
+                    .or_default() += 1;
+            }
+        }
+        model
+    }
+
+    /// The model trained on the built-in corpus of common progressions.
+    pub fn builtin() -> &'static Self {
+        static MODEL: OnceLock<MarkovModel> = OnceLock::new();
+        MODEL.get_or_init(|| Self::train(&PROGRESSION_CORPUS))
+    }
+
+    /// How likely each chord is to follow `previous` in `scale`, from 0 to 1. When the exact chord
+    /// was never seen, the transitions of every chord on the same root are pooled.
+    pub fn next_chords(&self, previous: ChordId, scale: Scale) -> Vec<Suggestion> {
+        let from = relative_chord(previous, scale);
+        let mut counts: HashMap<RelativeChord, u32> = HashMap::new();
+        match self.transitions.get(&(scale.scale_type, from)) {
+            Some(transitions) => counts.clone_from(transitions),
+            None => {
+                let same_root = self
+                    .transitions
+                    .iter()
+                    .filter(|((scale_type, (root, _)), _)| {
+                        *scale_type == scale.scale_type && *root == from.0
+                    });
+                for (_, transitions) in same_root {
+                    for (&to, &count) in transitions {
+                        *counts.entry(to).or_default() += count;
+                    }
+                }
+            }
+        }
+
+        let total: u32 = counts.values().sum();
+        counts
+            .into_iter()
+            .map(|((semitones, chord_type), count)| Suggestion {
+                chord: ChordId::new(scale.root.transpose(semitones as i32), chord_type),
+                score: count as f32 / total as f32,
+            })
+            .collect()
+    }
+}
+
+/// Ranks the chords likely to follow `previous`, best first. With a Markov model that knows the
+/// chord, the functional score and the learned probability count equally, the latter scaled so its
+/// best guess scores 1.
+pub fn suggest_next_chords(
+    previous: ChordId,
+    scale: Scale,
+    model: Option<&MarkovModel>,
+) -> Vec<Suggestion> {
+    let mut suggestions = functional_suggestions(previous, scale);
+
+    if let Some(learned) = model
+        .map(|model| model.next_chords(previous, scale))
+        .filter(|learned| !learned.is_empty())
+    {
+        let best = learned
+            .iter()
+            .map(|suggestion| suggestion.score)
+            .fold(0.0, f32::max);
+        for suggestion in &mut suggestions {
+            suggestion.score *= 0.5;
+        }
+        for learned in learned
+            .into_iter()
+            .filter(|learned| learned.chord != previous)
+        {
+            let score = 0.5 * learned.score / best;
+            match suggestions
+                .iter_mut()
+                .find(|suggestion| suggestion.chord == learned.chord)
+            {
+                Some(suggestion) => suggestion.score += score,
+                None => suggestions.push(Suggestion {
+                    chord: learned.chord,
+                    score,
+                }),
+            }
+        }
+    }
+
+    suggestions.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.chord.cmp(&b.chord)));
+    suggestions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ChordVocabulary, parse_key, parse_roman_numeral};
+
+    /// The suggestions after a numeral, best first, as chord names with their scores rounded to
+    /// two places.
+    fn ranked(previous: &str, key: &str, model: Option<&MarkovModel>) -> Vec<(String, f32)> {
+        let vocabulary = ChordVocabulary::new();
+        let scale = parse_key(key).unwrap();
+        let previous = parse_roman_numeral(previous, scale).unwrap();
+        suggest_next_chords(previous, scale, model)
+            .iter()
+            .map(|suggestion| {
+                (
+                    suggestion.chord.display(&vocabulary).to_string(),
+                    (suggestion.score * 100.0).round() / 100.0,
+                )
+            })
+            .collect()
+    }
+
+    fn names(suggestions: &[(String, f32)]) -> Vec<&str> {
+        suggestions.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    #[test]
+    fn degrees_move_as_usual_in_major() {
+        assert_eq!(
+            ranked("ii", "C major", None),
+            [
+                ("G".to_string(), 1.0),
+                ("G7".to_string(), 0.9),
+                ("F".to_string(), 0.6),
+                ("Am".to_string(), 0.6),
+                ("C".to_string(), 0.3),
+                ("Em".to_string(), 0.3),
+            ]
+        );
+        // The perfect cadence: the dominant goes home before anywhere else
+        assert_eq!(
+            names(&ranked("V7", "C major", None)),
+            ["C", "F", "Am", "Dm", "Em"]
+        );
+        assert_eq!(ranked("V7", "C major", None)[0], ("C".to_string(), 1.0));
+    }
+
+    #[test]
+    fn degrees_move_as_usual_in_minor() {
+        assert_eq!(
+            names(&ranked("iv", "A minor", None)),
+            ["Em", "E7", "Am", "Bdim", "C", "F"]
+        );
+        assert_eq!(
+            names(&ranked("V7", "A minor", None)),
+            ["Am", "Dm", "F", "C", "Bdim"]
+        );
+        assert_eq!(ranked("V7", "A minor", None)[0], ("Am".to_string(), 1.0));
+    }
+
+    #[test]
+    fn chords_from_outside_the_key_resolve() {
+        // Secondary dominants and leading-tone chords go to the chord they tonicize
+        assert_eq!(ranked("V7/vi", "C major", None)[0], ("Am".to_string(), 1.0));
+        assert_eq!(ranked("V7/IV", "C major", None)[0], ("F".to_string(), 1.0));
+        assert_eq!(names(&ranked("vii°7/V", "C major", None)), ["G"]);
+        assert_eq!(ranked("V7/III", "A minor", None)[0], ("C".to_string(), 1.0));
+        // The Neapolitan and augmented sixths lead to the dominant
+        assert_eq!(names(&ranked("bII", "C major", None)), ["G", "G7"]);
+        let german = ChordId::new(PitchClass::new(8), ChordType::German6);
+        let suggestions = suggest_next_chords(german, Scale::default(), None);
+        assert_eq!(suggestions[0].chord, Scale::default().diatonic_chords()[4]);
+        // Anything else heads home
+        assert_eq!(
+            ranked("bVI", "C major", None),
+            [("C".to_string(), 0.6), ("G".to_string(), 0.4)]
+        );
+    }
+
+    #[test]
+    fn the_corpus_ranks_what_it_has_seen() {
+        let model = MarkovModel::builtin();
+        // ii7 only ever goes to V7 in the corpus, which lifts it above the plain triad
+        assert_eq!(
+            ranked("ii7", "C major", Some(model)),
+            [
+                ("G7".to_string(), 0.95),
+                ("G".to_string(), 0.5),
+                ("F".to_string(), 0.3),
+                ("Am".to_string(), 0.3),
+                ("C".to_string(), 0.15),
+                ("Em".to_string(), 0.15),
+            ]
+        );
+        assert_eq!(
+            names(&ranked("IV", "C major", Some(model))),
+            ["G", "C", "G7", "Dm", "Am", "Em", "Fm"]
+        );
+        // Ties are broken by chord, so the major chord comes first
+        assert_eq!(
+            names(&ranked("iv", "A minor", Some(model))),
+            ["E", "Em", "E7", "Am", "Bdim", "G", "C", "F"]
+        );
+    }
+
+    #[test]
+    fn rankings_are_ordered_and_repeatable() {
+        // A model trained separately iterates its maps in a different order
+        let retrained = MarkovModel::train(&PROGRESSION_CORPUS);
+        for (previous, key) in [
+            ("IV", "C major"),
+            ("vi", "G major"),
+            ("iv", "A minor"),
+            ("VI", "E minor"),
+        ] {
+            let ranking = ranked(previous, key, Some(MarkovModel::builtin()));
+            assert_eq!(ranking, ranked(previous, key, Some(&retrained)));
+            assert!(
+                ranking.windows(2).all(|pair| pair[0].1 >= pair[1].1),
+                "{ranking:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,349 @@
+use std::fmt;
+
+use super::chords::{ChordId, ChordType, ChordVocabulary};
+use super::notes::{PitchClass, SpelledNote};
+
+/// Alternative spellings of chord qualities, after parentheses are removed and `♭`/`♯` replaced by
+/// `b`/`#`. The chord type keys and display suffixes are accepted as well, so these only list the
+/// other common forms.
+const QUALITY_ALIASES: &[(&str, ChordType)] = &[
+    ("M", ChordType::Major),
+    ("maj", ChordType::Major),
+    ("min", ChordType::Minor),
+    ("-", ChordType::Minor),
+    ("sus", ChordType::Sus4),
+    ("M6", ChordType::Sixth),
+    ("maj6", ChordType::Sixth),
+    ("min6", ChordType::MinorSixth),
+    ("-6", ChordType::MinorSixth),
+    ("dom7", ChordType::Dominant7),
+    ("min7", ChordType::Minor7),
+    ("-7", ChordType::Minor7),
+    ("M7", ChordType::Major7),
+    ("ma7", ChordType::Major7),
+    ("Δ", ChordType::Major7),
+    ("Δ7", ChordType::Major7),
+    ("°", ChordType::Diminished),
+    ("o", ChordType::Diminished),
+    ("+", ChordType::Augmented),
+    ("min9", ChordType::Minor9),
+    ("-9", ChordType::Minor9),
+    ("M9", ChordType::Major9),
+    ("Δ9", ChordType::Major9),
+    ("b5", ChordType::Flat5),
+    ("ø", ChordType::HalfDiminished7),
+    ("ø7", ChordType::HalfDiminished7),
+    ("min7b5", ChordType::HalfDiminished7),
+    ("-7b5", ChordType::HalfDiminished7),
+    ("°7", ChordType::Diminished7),
+    ("o7", ChordType::Diminished7),
+    ("add2", ChordType::Add9),
+    ("susadd9", ChordType::Sus4Add9),
+    ("7sus", ChordType::Dominant7Sus4),
+    ("dom7b9", ChordType::Dominant7Flat9),
+    ("dom7#9", ChordType::Dominant7Sharp9),
+    ("majadd9", ChordType::Add9),
+];
+
+/// Why a chord symbol couldn't be read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChordSymbolError {
+    Empty,
+    UnknownRoot(String),
+    UnknownQuality(String),
+    UnknownBass(String),
+}
+
+impl fmt::Display for ChordSymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChordSymbolError::Empty => write!(f, "empty chord symbol"),
+            ChordSymbolError::UnknownRoot(root) => write!(f, "unknown root note `{}`", root),
+            ChordSymbolError::UnknownQuality(quality) => {
+                write!(f, "unknown chord quality `{}`", quality)
+            }
+            ChordSymbolError::UnknownBass(bass) => write!(f, "unknown bass note `{}`", bass),
+        }
+    }
+}
+
+/// A chord as written on a lead sheet, such as `Cmaj7`, `F#m7b5` or `Eb/G`. The root and bass keep
+/// their spelling so a symbol renders back the way it was written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChordSymbol {
+    pub root: SpelledNote,
+    pub chord_type: ChordType,
+    /// The bass note of a slash chord
+    pub bass: Option<SpelledNote>,
+}
+
+impl ChordSymbol {
+    /// The chord this symbol stands for. The bass note of a slash chord isn't part of it.
+    pub fn chord(&self) -> ChordId {
+        ChordId::new(self.root.pitch_class(), self.chord_type)
+    }
+
+    pub fn bass_pitch_class(&self) -> Option<PitchClass> {
+        self.bass.map(SpelledNote::pitch_class)
+    }
+
+    /// Reads a symbol, recognizing the suffixes and keys of `vocabulary`'s custom chord types too.
+    pub fn parse(symbol: &str, vocabulary: &ChordVocabulary) -> Result<Self, ChordSymbolError> {
+        let symbol = symbol.trim();
+        if symbol.is_empty() {
+            return Err(ChordSymbolError::Empty);
+        }
+
+        // `C6/9` has a slash too, so only a note name after it makes a slash chord
+        let (chord, bass) = match symbol.rsplit_once('/') {
+            Some((chord, bass)) if bass.starts_with(|c: char| c.is_ascii_alphabetic()) => {
+                let bass = SpelledNote::parse(bass.trim())
+                    .ok_or_else(|| ChordSymbolError::UnknownBass(bass.to_string()))?;
+                (chord, Some(bass))
+            }
+            _ => (symbol, None),
+        };
+
+        let (root_name, quality) = split_note(chord);
+        let root = SpelledNote::parse(root_name)
+            .ok_or_else(|| ChordSymbolError::UnknownRoot(root_name.to_string()))?;
+        let chord_type = quality_chord_type(quality, vocabulary)
+            .ok_or_else(|| ChordSymbolError::UnknownQuality(quality.to_string()))?;
+
+        Ok(Self {
+            root,
+            chord_type,
+            bass,
+        })
+    }
+
+    /// The canonical symbol: the root, the chord type's display suffix and the bass note.
+    pub fn display<'a>(&'a self, vocabulary: &'a ChordVocabulary) -> SymbolDisplay<'a> {
+        SymbolDisplay {
+            symbol: self,
+            vocabulary,
+        }
+    }
+}
+
+/// Displays a chord symbol. Returned by [`ChordSymbol::display`].
+#[derive(Debug, Clone, Copy)]
+pub struct SymbolDisplay<'a> {
+    symbol: &'a ChordSymbol,
+    vocabulary: &'a ChordVocabulary,
+}
+
+impl fmt::Display for SymbolDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{}",
+            self.symbol.root,
+            self.vocabulary.suffix(self.symbol.chord_type)
+        )?;
+        if let Some(bass) = self.symbol.bass {
+            write!(f, "/{}", bass)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<ChordId> for ChordSymbol {
+    fn from(chord: ChordId) -> Self {
+        Self {
+            root: chord.root.into(),
+            chord_type: chord.chord_type,
+            bass: None,
+        }
+    }
+}
+
+/// Splits a leading note name off a symbol: a letter and the sharps and flats after it. A `b`
+/// straight after the letter is always read as a flat, so `Cb5` is a C flat power chord.
+fn split_note(symbol: &str) -> (&str, &str) {
+    let len = symbol
+        .char_indices()
+        .find(|&(index, c)| index > 0 && !matches!(c, '#' | 'b' | '♯' | '♭'))
+        .map(|(index, _)| index)
+        .unwrap_or(symbol.len());
+    symbol.split_at(len)
+}
+
+fn quality_chord_type(quality: &str, vocabulary: &ChordVocabulary) -> Option<ChordType> {
+    let quality: String = quality
+        .trim()
+        .chars()
+        .filter(|c| !matches!(c, '(' | ')' | ' '))
+        .map(|c| match c {
+            '♭' => 'b',
+            '♯' => '#',
+            c => c,
+        })
+        .collect();
+
+    ChordType::ALL
+        .into_iter()
+        .find(|chord_type| chord_type.suffix() == quality || chord_type.key() == quality)
+        .or_else(|| {
+            QUALITY_ALIASES
+                .iter()
+                .find(|(alias, _)| *alias == quality)
+                .map(|&(_, chord_type)| chord_type)
+        })
+        .or_else(|| {
+            vocabulary
+                .custom_chord_types()
+                .find(|(_, custom)| custom.suffix.trim() == quality || custom.key == quality)
+                .map(|(chord_type, _)| chord_type)
+        })
+}
+
+/// A symbol in a chord chart that couldn't be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChartError {
+    /// Position of the symbol among the chart's chords, starting at 0
+    pub index: usize,
+    pub token: String,
+    pub error: ChordSymbolError,
+}
+
+impl fmt::Display for ChartError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "chord {} `{}`: {}",
+            self.index + 1,
+            self.token,
+            self.error
+        )
+    }
+}
+
+/// Parses a pasted chord chart such as `"| Cmaj7 | Am7 | Dm7 G7 |"`. Symbols are separated by
+/// spaces, bar lines or commas, and every one that can't be read is reported.
+pub fn parse_chord_chart(
+    chart: &str,
+    vocabulary: &ChordVocabulary,
+) -> Result<Vec<ChordSymbol>, Vec<ChartError>> {
+    let mut symbols = Vec::new();
+    let mut errors = Vec::new();
+    let tokens = chart
+        .split(|c: char| c.is_whitespace() || matches!(c, '|' | ','))
+        .filter(|token| !token.is_empty());
+    for (index, token) in tokens.enumerate() {
+        match ChordSymbol::parse(token, vocabulary) {
+            Ok(symbol) => symbols.push(symbol),
+            Err(error) => errors.push(ChartError {
+                index,
+                token: token.to_string(),
+                error,
+            }),
+        }
+    }
+    if errors.is_empty() {
+        Ok(symbols)
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chords::CustomChordType;
+
+    fn every_chord(vocabulary: &ChordVocabulary) -> Vec<ChordId> {
+        (0..12)
+            .flat_map(|root| {
+                vocabulary
+                    .chord_types()
+                    .map(move |chord_type| ChordId::new(PitchClass::new(root), chord_type))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn every_symbol_round_trips() {
+        let mut vocabulary = ChordVocabulary::new();
+        vocabulary
+            .register(CustomChordType {
+                key: "cluster".to_string(),
+                suffix: "cl".to_string(),
+                intervals: vec![0, 1, 2],
+            })
+            .unwrap();
+
+        for chord in every_chord(&vocabulary) {
+            let symbol = ChordSymbol::from(chord);
+            let text = symbol.display(&vocabulary).to_string();
+            assert_eq!(
+                ChordSymbol::parse(&text, &vocabulary),
+                Ok(symbol),
+                "{}",
+                text
+            );
+
+            let slash = ChordSymbol {
+                bass: Some(SpelledNote::parse("Bb").unwrap()),
+                ..symbol
+            };
+            let text = slash.display(&vocabulary).to_string();
+            assert_eq!(
+                ChordSymbol::parse(&text, &vocabulary),
+                Ok(slash),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn every_chart_round_trips() {
+        let vocabulary = ChordVocabulary::new();
+        let symbols: Vec<ChordSymbol> = every_chord(&vocabulary)
+            .into_iter()
+            .map(ChordSymbol::from)
+            .collect();
+        let chart: Vec<String> = symbols
+            .iter()
+            .map(|symbol| symbol.display(&vocabulary).to_string())
+            .collect();
+        assert_eq!(
+            parse_chord_chart(&chart.join(" | "), &vocabulary),
+            Ok(symbols)
+        );
+    }
+
+    #[test]
+    fn augmented_sixths_are_single_tokens() {
+        let vocabulary = ChordVocabulary::new();
+        let chart = parse_chord_chart("AbIt+6 Ab(Fr+6) | AbGer+6", &vocabulary).unwrap();
+        let chord_types: Vec<ChordType> = chart.iter().map(|symbol| symbol.chord_type).collect();
+        assert_eq!(
+            chord_types,
+            [ChordType::Italian6, ChordType::French6, ChordType::German6]
+        );
+        assert_eq!(chart[0].display(&vocabulary).to_string(), "AbIt+6");
+    }
+
+    #[test]
+    fn reports_every_bad_symbol_with_its_position() {
+        let errors = parse_chord_chart("C Hm7 | Cxyz, G/Q", &ChordVocabulary::new()).unwrap_err();
+        let found: Vec<(usize, &str, &ChordSymbolError)> = errors
+            .iter()
+            .map(|error| (error.index, error.token.as_str(), &error.error))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (1, "Hm7", &ChordSymbolError::UnknownRoot("H".to_string())),
+                (
+                    2,
+                    "Cxyz",
+                    &ChordSymbolError::UnknownQuality("xyz".to_string())
+                ),
+                (3, "G/Q", &ChordSymbolError::UnknownBass("Q".to_string())),
+            ]
+        );
+    }
+}
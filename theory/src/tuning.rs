@@ -0,0 +1,597 @@
+use std::fmt;
+
+/// Why a Scala scale or keyboard mapping couldn't be read.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TuningError {
+    /// The file ended before this field
+    Missing(&'static str),
+    /// The line with this field doesn't hold a valid value. Lines count from 1.
+    Invalid {
+        line: usize,
+        field: &'static str,
+        text: String,
+    },
+    /// The scale has no notes besides the tonic
+    EmptyScale,
+    /// The mapping's reference note has no scale degree, so nothing can be tuned from it
+    UnmappedReference(u8),
+}
+
+impl fmt::Display for TuningError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TuningError::Missing(field) => write!(f, "the file ends before the {}", field),
+            TuningError::Invalid { line, field, text } => {
+                write!(f, "line {}: `{}` is not a valid {}", line, text, field)
+            }
+            TuningError::EmptyScale => write!(f, "the scale has no notes"),
+            TuningError::UnmappedReference(note) => {
+                write!(f, "the reference note {} is not mapped", note)
+            }
+        }
+    }
+}
+
+/// The lines of a Scala file that aren't comments, with their line numbers.
+fn data_lines(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.starts_with('!'))
+}
+
+/// Reads the value a line starts with. Anything after it is a comment.
+fn parse_field<T: std::str::FromStr>(
+    line: Option<(usize, &str)>,
+    field: &'static str,
+) -> Result<T, TuningError> {
+    let (line, text) = line.ok_or(TuningError::Missing(field))?;
+    let value = text.split_whitespace().next().unwrap_or_default();
+    value.parse().map_err(|_| TuningError::Invalid {
+        line,
+        field,
+        text: text.to_string(),
+    })
+}
+
+/// Reads a pitch: cents when it has a decimal point, otherwise a ratio such as `3/2` or `2`.
+fn parse_pitch(value: &str) -> Option<f64> {
+    if value.contains('.') {
+        return value.parse().ok();
+    }
+    let (numerator, denominator) = value.split_once('/').unwrap_or((value, "1"));
+    let numerator: f64 = numerator.parse::<u64>().ok()? as f64;
+    let denominator: f64 = denominator.parse::<u64>().ok()? as f64;
+    (numerator > 0.0 && denominator > 0.0).then(|| 1200.0 * (numerator / denominator).log2())
+}
+
+/// A scale from a Scala `.scl` file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScalaScale {
+    pub description: String,
+    /// Cents above the tonic of each degree after it. The last one is the interval the scale
+    /// repeats at, usually the octave.
+    pub pitches: Vec<f64>,
+}
+
+impl ScalaScale {
+    pub fn parse(text: &str) -> Result<Self, TuningError> {
+        let mut lines = data_lines(text);
+        let (_, description) = lines.next().ok_or(TuningError::Missing("description"))?;
+        let count: usize = parse_field(lines.next(), "note count")?;
+        if count == 0 {
+            return Err(TuningError::EmptyScale);
+        }
+
+        // The count comes from the file, so it can't be trusted to size an allocation
+        let mut pitches = Vec::new();
+        for _ in 0..count {
+            let (line, text) = lines.next().ok_or(TuningError::Missing("pitch"))?;
+            let pitch = text
+                .split_whitespace()
+                .next()
+                .and_then(parse_pitch)
+                .ok_or_else(|| TuningError::Invalid {
+                    line,
+                    field: "pitch",
+                    text: text.to_string(),
+                })?;
+            pitches.push(pitch);
+        }
+
+        Ok(Self {
+            description: description.to_string(),
+            pitches,
+        })
+    }
+
+    pub fn period(&self) -> f64 {
+        self.pitches.last().copied().unwrap_or(1200.0)
+    }
+
+    /// Cents above the tonic of a scale degree, which may lie beyond the first period.
+    fn degree_cents(&self, degree: i64) -> f64 {
+        let len = self.pitches.len() as i64;
+        let periods = degree.div_euclid(len);
+        let step = degree.rem_euclid(len);
+        let within = if step == 0 {
+            0.0
+        } else {
+            self.pitches[step as usize - 1]
+        };
+        periods as f64 * self.period() + within
+    }
+}
+
+/// How MIDI notes map onto scale degrees, from a Scala `.kbm` file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyboardMapping {
+    pub first_note: u8,
+    pub last_note: u8,
+    /// The note that plays the scale's tonic
+    pub middle_note: u8,
+    /// The note tuned to `reference_frequency`
+    pub reference_note: u8,
+    pub reference_frequency: f64,
+    /// The degree the mapping repeats at, or 0 to use the scale's period
+    pub octave_degree: usize,
+    /// The degree each key of the repeating pattern plays, `None` for unmapped keys. Empty for
+    /// a linear mapping, where consecutive notes play consecutive degrees.
+    pub degrees: Vec<Option<usize>>,
+}
+
+impl Default for KeyboardMapping {
+    /// The linear mapping Scala uses without a `.kbm` file: the tonic on middle C, with A above it
+    /// tuned to 440 Hz.
+    fn default() -> Self {
+        Self {
+            first_note: 0,
+            last_note: 127,
+            middle_note: 60,
+            reference_note: 69,
+            reference_frequency: 440.0,
+            octave_degree: 0,
+            degrees: Vec::new(),
+        }
+    }
+}
+
+impl KeyboardMapping {
+    pub fn parse(text: &str) -> Result<Self, TuningError> {
+        let mut lines = data_lines(text);
+        let size_line = lines.next();
+        let size: usize = parse_field(size_line, "map size")?;
+        // A map repeats across the keyboard, so it never needs more entries than there are keys
+        if let Some((line, text)) = size_line.filter(|_| size > 128) {
+            return Err(TuningError::Invalid {
+                line,
+                field: "map size",
+                text: text.to_string(),
+            });
+        }
+        let first_note = parse_field(lines.next(), "first note")?;
+        let last_note = parse_field(lines.next(), "last note")?;
+        let middle_note = parse_field(lines.next(), "middle note")?;
+        let reference_note = parse_field(lines.next(), "reference note")?;
+        let reference_frequency = parse_field(lines.next(), "reference frequency")?;
+        let octave_degree = parse_field(lines.next(), "octave degree")?;
+
+        // Keys missing from the end of the map are unmapped
+        let mut degrees = vec![None; size];
+        for (degree, (line, text)) in degrees.iter_mut().zip(lines) {
+            let value = text.split_whitespace().next().unwrap_or_default();
+            *degree = match value {
+                "x" | "X" => None,
+                value => Some(value.parse().map_err(|_| TuningError::Invalid {
+                    line,
+                    field: "scale degree",
+                    text: text.to_string(),
+                })?),
+            };
+        }
+
+        Ok(Self {
+            first_note,
+            last_note,
+            middle_note,
+            reference_note,
+            reference_frequency,
+            octave_degree,
+            degrees,
+        })
+    }
+}
+
+/// Where a note of twelve-tone equal temperament sounds in another tuning: a MIDI note and the
+/// pitch bend from it in semitones.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetunedNote {
+    pub note: u8,
+    pub bend: f32,
+}
+
+/// A retuning of every MIDI note, indexed by note number.
+pub type RetuneTable = [Option<RetunedNote>; 128];
+
+/// A Scala scale laid out on the keyboard.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tuning {
+    pub scale: ScalaScale,
+    pub mapping: KeyboardMapping,
+    /// Cents above the middle note of the reference note
+    reference_cents: f64,
+}
+
+impl Tuning {
+    pub fn new(scale: ScalaScale, mapping: KeyboardMapping) -> Result<Self, TuningError> {
+        let mut tuning = Self {
+            scale,
+            mapping,
+            reference_cents: 0.0,
+        };
+        tuning.reference_cents =
+            tuning
+                .cents(tuning.mapping.reference_note)
+                .ok_or(TuningError::UnmappedReference(
+                    tuning.mapping.reference_note,
+                ))?;
+        Ok(tuning)
+    }
+
+    /// Cents of a key above the middle note, or `None` if the key plays nothing.
+    fn cents(&self, note: u8) -> Option<f64> {
+        let mapping = &self.mapping;
+        if !(mapping.first_note..=mapping.last_note).contains(&note) {
+            return None;
+        }
+        let offset = note as i64 - mapping.middle_note as i64;
+        if mapping.degrees.is_empty() {
+            return Some(self.scale.degree_cents(offset));
+        }
+
+        let size = mapping.degrees.len() as i64;
+        let degree = mapping.degrees[offset.rem_euclid(size) as usize]?;
+        let period = match mapping.octave_degree {
+            0 => self.scale.period(),
+            octave_degree => self.scale.degree_cents(octave_degree as i64),
+        };
+        Some(offset.div_euclid(size) as f64 * period + self.scale.degree_cents(degree as i64))
+    }
+
+    /// The frequency a key plays in Hz, or `None` if the key plays nothing.
+    pub fn frequency(&self, note: u8) -> Option<f64> {
+        let cents = self.cents(note)? - self.reference_cents;
+        Some(self.mapping.reference_frequency * (cents / 1200.0).exp2())
+    }
+
+    /// Where each key sounds in the tuning: the key plays the scale degree its mapping gives it, so
+    /// chords built in semitones are reinterpreted as scale steps. With a `.kbm` file that maps the
+    /// white keys to a seven-note scale, a major third on the keyboard becomes the scale's third,
+    /// and under a linear mapping a major third is four steps of the scale, whatever their size.
+    /// Keys the mapping leaves out, and keys that would sound outside the MIDI range, are `None`.
+    pub fn retune_table(&self) -> RetuneTable {
+        std::array::from_fn(|note| {
+            // The pitch as a fractional MIDI note in twelve-tone equal temperament, A at 440 Hz
+            let pitch = 69.0 + 12.0 * (self.frequency(note as u8)? / 440.0).log2();
+            let nearest = pitch.round();
+            (0.0..=127.0).contains(&nearest).then_some(RetunedNote {
+                note: nearest as u8,
+                bend: (pitch - nearest) as f32,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The example scale from the Scala file format documentation.
+    const MEANQUAR: &str = "! meanquar.scl
+!
+1/4-comma meantone scale. Pietro Aaron's temperament (1523)
+ 12
+!
+ 76.04900
+ 193.15686
+ 310.26471
+ 5/4
+ 503.42157
+ 579.47057
+ 696.57843
+ 25/16
+ 889.73529
+ 1006.84314
+ 1082.89214
+ 2/1
+";
+
+    /// Ptolemy's intense diatonic scale, with its 1/1 left implicit as Scala files do.
+    const PTOLEMY: &str = "! ptolemy.scl
+!
+Ptolemy's Intense Diatonic Systonon, also just major
+ 7
+!
+ 9/8
+ 5/4
+ 4/3
+ 3/2   fifth, the rest of the line is a comment
+ 5/3
+ 15/8
+ 2/1
+";
+
+    /// The white keys mapped to a seven-note scale, as in the Scala documentation's template.
+    const WHITE_KEYS: &str = "! whitekeys.kbm
+! Size of map:
+12
+! First MIDI note number to retune:
+0
+! Last MIDI note number to retune:
+127
+! Middle note where the first entry of the mapping is mapped to:
+60
+! Reference note for which frequency is given:
+60
+! Frequency to tune the above note to
+261.6255653
+! Scale degree to consider as formal octave:
+7
+! Mapping.
+0
+x
+1
+x
+2
+3
+x
+4
+x
+5
+x
+6
+";
+
+    fn tuning(scale: &str, mapping: Option<&str>) -> Tuning {
+        let mapping = mapping.map_or_else(KeyboardMapping::default, |mapping| {
+            KeyboardMapping::parse(mapping).unwrap()
+        });
+        Tuning::new(ScalaScale::parse(scale).unwrap(), mapping).unwrap()
+    }
+
+    fn cents(ratio: f64) -> f64 {
+        1200.0 * ratio.log2()
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-4,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    /// A retuned note as a fractional MIDI note.
+    fn pitch(table: &RetuneTable, note: u8) -> Option<f64> {
+        table[note as usize].map(|retuned| retuned.note as f64 + retuned.bend as f64)
+    }
+
+    #[test]
+    fn parses_the_documented_example() {
+        let scale = ScalaScale::parse(MEANQUAR).unwrap();
+        assert_eq!(
+            scale.description,
+            "1/4-comma meantone scale. Pietro Aaron's temperament (1523)"
+        );
+        assert_eq!(scale.pitches.len(), 12);
+        assert_close(scale.pitches[0], 76.049);
+        assert_close(scale.pitches[3], cents(5.0 / 4.0));
+        assert_close(scale.pitches[7], cents(25.0 / 16.0));
+        assert_close(scale.period(), 1200.0);
+    }
+
+    #[test]
+    fn skips_comments_and_trailing_text() {
+        let scale = ScalaScale::parse(PTOLEMY).unwrap();
+        assert_eq!(scale.pitches.len(), 7);
+        assert_close(scale.pitches[3], cents(1.5));
+
+        let mapping = KeyboardMapping::parse(WHITE_KEYS).unwrap();
+        assert_eq!(
+            (
+                mapping.middle_note,
+                mapping.reference_note,
+                mapping.octave_degree
+            ),
+            (60, 60, 7)
+        );
+        assert_eq!(mapping.degrees.len(), 12);
+    }
+
+    #[test]
+    fn reads_cents_only_with_a_decimal_point() {
+        let scale = ScalaScale::parse("\n 4\n 2\n 2.\n 2.0\n 3/2\n").unwrap();
+        assert_close(scale.pitches[0], 1200.0);
+        assert_close(scale.pitches[1], 2.0);
+        assert_close(scale.pitches[2], 2.0);
+        assert_close(scale.pitches[3], cents(1.5));
+        assert_close(scale.period(), cents(1.5));
+    }
+
+    #[test]
+    fn the_tonic_is_implicit() {
+        let tuning = tuning(PTOLEMY, None);
+        assert_close(tuning.scale.degree_cents(0), 0.0);
+        assert_close(tuning.scale.degree_cents(7), 1200.0);
+        assert_close(tuning.scale.degree_cents(-1), cents(15.0 / 8.0) - 1200.0);
+        // The default mapping is linear, so A4 is nine steps above the tonic on middle C
+        assert_close(tuning.frequency(69).unwrap(), 440.0);
+        assert_close(tuning.frequency(60).unwrap(), 440.0 / 2.5);
+        assert_close(tuning.frequency(61).unwrap(), 440.0 / 2.5 * 9.0 / 8.0);
+    }
+
+    #[test]
+    fn unmapped_keys_play_nothing() {
+        let tuning = tuning(PTOLEMY, Some(WHITE_KEYS));
+        let table = tuning.retune_table();
+        for note in [61, 63, 66, 68, 70, 1, 126] {
+            assert_eq!(tuning.frequency(note), None, "{}", note);
+            assert_eq!(table[note as usize], None, "{}", note);
+        }
+        assert!(table[60].is_some() && table[72].is_some());
+
+        // Keys missing from the end of the map are unmapped too
+        let mapping = KeyboardMapping::parse("3\n0\n127\n60\n60\n440.0\n0\n0\n1\n").unwrap();
+        assert_eq!(mapping.degrees, [Some(0), Some(1), None]);
+        let narrow = KeyboardMapping::parse("0\n48\n72\n60\n69\n440.0\n0\n").unwrap();
+        let tuning = Tuning::new(ScalaScale::parse(MEANQUAR).unwrap(), narrow).unwrap();
+        assert_eq!((tuning.frequency(47), tuning.frequency(73)), (None, None));
+        assert!(tuning.frequency(48).is_some());
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        assert_eq!(
+            ScalaScale::parse("! only a comment\n"),
+            Err(TuningError::Missing("description"))
+        );
+        assert_eq!(
+            ScalaScale::parse("name\n"),
+            Err(TuningError::Missing("note count"))
+        );
+        assert_eq!(
+            ScalaScale::parse("name\n 0\n"),
+            Err(TuningError::EmptyScale)
+        );
+        assert_eq!(
+            ScalaScale::parse("name\n 2\n 3/2\n"),
+            Err(TuningError::Missing("pitch"))
+        );
+        for pitch in ["abc", "3/0", "-3/2", "0/1", "3/2/1", "1.2.3"] {
+            let error = ScalaScale::parse(&format!("name\n!\n 2\n 3/2\n {}\n", pitch)).unwrap_err();
+            assert_eq!(
+                error,
+                TuningError::Invalid {
+                    line: 5,
+                    field: "pitch",
+                    text: pitch.to_string(),
+                }
+            );
+        }
+        assert_eq!(
+            ScalaScale::parse("name\n twelve\n")
+                .unwrap_err()
+                .to_string(),
+            "line 2: `twelve` is not a valid note count"
+        );
+
+        assert_eq!(
+            KeyboardMapping::parse("12\n0\n127\n60\n"),
+            Err(TuningError::Missing("reference note"))
+        );
+        assert_eq!(
+            KeyboardMapping::parse("! huge.kbm\n999999999999\n0\n127\n60\n69\n440.0\n0\n"),
+            Err(TuningError::Invalid {
+                line: 2,
+                field: "map size",
+                text: "999999999999".to_string(),
+            })
+        );
+        assert!(KeyboardMapping::parse("129\n0\n127\n60\n69\n440.0\n0\n").is_err());
+        assert_eq!(
+            KeyboardMapping::parse("128\n0\n127\n60\n69\n440.0\n0\n0\n")
+                .unwrap()
+                .degrees
+                .len(),
+            128
+        );
+        assert_eq!(
+            ScalaScale::parse("name\n 999999999999\n 3/2\n"),
+            Err(TuningError::Missing("pitch"))
+        );
+        assert_eq!(
+            KeyboardMapping::parse("1\n0\n127\n60\n69\n440.0\n0\ny\n"),
+            Err(TuningError::Invalid {
+                line: 8,
+                field: "scale degree",
+                text: "y".to_string(),
+            })
+        );
+        let mapping =
+            KeyboardMapping::parse(&WHITE_KEYS.replace("\n60\n! Frequency", "\n61\n! Frequency"))
+                .unwrap();
+        assert_eq!(
+            Tuning::new(ScalaScale::parse(PTOLEMY).unwrap(), mapping),
+            Err(TuningError::UnmappedReference(61))
+        );
+    }
+
+    #[test]
+    fn twelve_tone_equal_temperament_needs_no_bends() {
+        let table = tuning("12-TET\n 1\n 100.0\n", None).retune_table();
+        for note in 0..128u8 {
+            let retuned = table[note as usize].unwrap();
+            assert_eq!(retuned.note, note);
+            assert!(retuned.bend.abs() < 1e-4, "{}: {}", note, retuned.bend);
+        }
+    }
+
+    #[test]
+    fn bends_to_the_tuned_pitch() {
+        // Meantone with A at 440 Hz: its pure third above C is flatter than 400 cents
+        let table = tuning(MEANQUAR, None).retune_table();
+        assert_close(pitch(&table, 69).unwrap(), 69.0);
+        let middle_c = 69.0 - 889.73529 / 100.0;
+        assert_close(pitch(&table, 60).unwrap(), middle_c);
+        assert_close(pitch(&table, 64).unwrap(), middle_c + cents(1.25) / 100.0);
+        assert_eq!(table[64].unwrap().note, 64);
+        assert!(table[64].unwrap().bend < 0.0);
+        assert_close(pitch(&table, 72).unwrap(), middle_c + 12.0);
+    }
+
+    #[test]
+    fn intervals_become_scale_steps() {
+        // On the white keys, C E G plays the scale's first, third and fifth degrees
+        let table = tuning(PTOLEMY, Some(WHITE_KEYS)).retune_table();
+        assert_close(pitch(&table, 60).unwrap(), 60.0);
+        assert_close(pitch(&table, 64).unwrap(), 60.0 + cents(1.25) / 100.0);
+        assert_close(pitch(&table, 67).unwrap(), 60.0 + cents(1.5) / 100.0);
+        assert_close(pitch(&table, 69).unwrap(), 60.0 + cents(5.0 / 3.0) / 100.0);
+        assert_close(pitch(&table, 48).unwrap(), 48.0);
+        assert_close(pitch(&table, 59).unwrap(), 48.0 + cents(15.0 / 8.0) / 100.0);
+
+        // Under a linear mapping a major third is four steps, which in 19-tone equal temperament
+        // is a little more than two and a half semitones
+        let edo19: String = std::iter::once("19-EDO\n 19\n".to_string())
+            .chain((1..=19).map(|step| format!(" {:.5}\n", step as f64 * 1200.0 / 19.0)))
+            .collect();
+        let mapping = KeyboardMapping {
+            reference_note: 60,
+            reference_frequency: 440.0 * (-9.0f64 / 12.0).exp2(),
+            ..KeyboardMapping::default()
+        };
+        let table = Tuning::new(ScalaScale::parse(&edo19).unwrap(), mapping)
+            .unwrap()
+            .retune_table();
+        assert_close(pitch(&table, 64).unwrap(), 60.0 + 4.0 * 12.0 / 19.0);
+        assert_eq!(table[64].unwrap().note, 63);
+        assert_close(pitch(&table, 79).unwrap(), 72.0);
+
+        // Keys whose steps would sound outside the MIDI range are left out
+        let mapping = KeyboardMapping {
+            reference_note: 60,
+            reference_frequency: 440.0 * (-9.0f64 / 12.0).exp2(),
+            ..KeyboardMapping::default()
+        };
+        let table = Tuning::new(
+            ScalaScale::parse("whole tones\n 1\n 200.0\n").unwrap(),
+            mapping,
+        )
+        .unwrap()
+        .retune_table();
+        assert_close(pitch(&table, 93).unwrap(), 126.0);
+        assert_eq!((table[0], table[29], table[94]), (None, None, None));
+        assert_close(pitch(&table, 30).unwrap(), 0.0);
+    }
+}